@@ -27,52 +27,524 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use aes::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit,
+use aes::cipher::{BlockDecrypt, BlockDecryptMut, BlockEncrypt, BlockEncryptMut, KeyInit, KeyIvInit,
     generic_array::GenericArray, Key, Iv, consts::U16};
 
 type Aes256CbcEnc = cbc::Encryptor<aes::Aes256>;
 type Aes256CbcDec = cbc::Decryptor<aes::Aes256>;
 
-use super::util::Block16;
+use super::util::{Block16, xor_block_16};
+use subtle::{Choice, ConstantTimeEq};
+use zeroize::Zeroize;
+
+type Scratch = Vec<GenericArray<u8, U16>>;
+
+/// Wipes the working copies of blocks, which hold plaintext on one side or the other. The
+/// expanded key schedules are wiped by the cipher types themselves when they are dropped.
+fn wipe(ga: &mut [GenericArray<u8, U16>]) {
+    for block in ga.iter_mut() {
+        block.as_mut_slice().zeroize();
+    }
+}
 
 pub fn cbc_encrypt(key: &[u8; 32], iv: Block16, blocks: &mut [Block16])
+{
+    cbc_encrypt_with(key, iv, blocks, &mut Vec::with_capacity(blocks.len()));
+}
+
+/// `cbc_encrypt`, working in `ga`, which is left wiped.
+fn cbc_encrypt_with(key: &[u8; 32], iv: Block16, blocks: &mut [Block16], ga: &mut Scratch)
 {
     // we get a mut slice of Block16 which is a [u8; 16], and we want a mut slice
     // of GenericArray::<u8, U16>. Unfortunately, I don't think there is any way
     // to do this transformation except either something awful and unsafe, or,
     // making a heap allocated copy into and out of the structures. Since the
     // data handled by the authenticator is small, and we value correctness,
-    // we are going to go the inefficient-but-safe route.
-    let mut ga = vec![];
+    // we are going to go the inefficient-but-safe route. The copy is sized up
+    // front, so that growing it doesn't leave stray plaintext in freed memory.
+    ga.clear();
+    ga.reserve(blocks.len());
     for block in blocks.iter() {
         ga.push(GenericArray::<u8, U16>::clone_from_slice(block));
     }
+    Aes256CbcEnc::new(Key::<Aes256CbcEnc>::from_slice(key), Iv::<Aes256CbcEnc>::from_slice(&iv))
+        .encrypt_blocks_mut(ga);
+    for (src, dst) in ga.iter().zip(blocks.iter_mut()) {
+        dst.copy_from_slice(src.as_slice());
+    }
+    wipe(ga);
+}
+
+/// Same as `cbc_encrypt`, but for a number of blocks known at compile time. The
+/// working copy lives on the stack, so no heap allocation happens; use this for the
+/// small, fixed-size secrets the vault handles all the time.
+pub fn cbc_encrypt_fixed<const N: usize>(key: &[u8; 32], iv: Block16, blocks: &mut [Block16; N])
+{
+    let mut ga = [GenericArray::<u8, U16>::default(); N];
+    for (dst, src) in ga.iter_mut().zip(blocks.iter()) {
+        dst.copy_from_slice(src);
+    }
     Aes256CbcEnc::new(Key::<Aes256CbcEnc>::from_slice(key), Iv::<Aes256CbcEnc>::from_slice(&iv))
         .encrypt_blocks_mut(&mut ga);
     for (src, dst) in ga.iter().zip(blocks.iter_mut()) {
         dst.copy_from_slice(src.as_slice());
     }
+    wipe(&mut ga);
 }
 
 pub fn cbc_decrypt(key: &[u8; 32], iv: Block16, blocks: &mut [Block16])
 {
-    let mut ga = vec![];
+    cbc_decrypt_with(key, iv, blocks, &mut Vec::with_capacity(blocks.len()));
+}
+
+/// `cbc_decrypt`, working in `ga`, which is left wiped.
+fn cbc_decrypt_with(key: &[u8; 32], iv: Block16, blocks: &mut [Block16], ga: &mut Scratch)
+{
+    ga.clear();
+    ga.reserve(blocks.len());
     for block in blocks.iter() {
         ga.push(GenericArray::<u8, U16>::clone_from_slice(block));
     }
     Aes256CbcDec::new(Key::<Aes256CbcDec>::from_slice(key), Iv::<Aes256CbcDec>::from_slice(&iv))
-        .decrypt_blocks_mut(&mut ga);
+        .decrypt_blocks_mut(ga);
     for (src, dst) in ga.iter().zip(blocks.iter_mut()) {
         dst.copy_from_slice(src.as_slice());
     }
+    wipe(ga);
+}
+
+/// Same as `cbc_encrypt`, but encrypts the blocks where they lie, one at a time, carrying
+/// the chaining value by hand. Nothing is allocated however many blocks there are, at the
+/// cost of handing the cipher one block at a time where `cbc_encrypt` hands it the whole batch.
+pub fn cbc_encrypt_inplace(key: &[u8; 32], iv: Block16, blocks: &mut [Block16])
+{
+    let cipher = aes::Aes256::new(Key::<aes::Aes256>::from_slice(key));
+    let mut prev = iv;
+    for block in blocks.iter_mut() {
+        xor_block_16(block, &prev);
+        cipher.encrypt_block(GenericArray::from_mut_slice(block));
+        prev = *block;
+    }
+}
+
+/// Same as `cbc_decrypt`, but decrypts the blocks where they lie, as `cbc_encrypt_inplace`.
+pub fn cbc_decrypt_inplace(key: &[u8; 32], iv: Block16, blocks: &mut [Block16])
+{
+    let cipher = aes::Aes256::new(Key::<aes::Aes256>::from_slice(key));
+    let mut prev = iv;
+    for block in blocks.iter_mut() {
+        let ciphertext = *block;
+        cipher.decrypt_block(GenericArray::from_mut_slice(block));
+        xor_block_16(block, &prev);
+        prev = ciphertext;
+    }
+}
+
+/// Decrypts a batch of independent CBC records that share a key, each under its own IV.
+/// The key schedule is expanded once for the whole batch, and blocks are decrypted in
+/// place, so no working copy is made either.
+pub fn cbc_decrypt_many(key: &[u8; 32], items: &mut [(Block16, &mut [Block16])])
+{
+    let cipher = aes::Aes256::new(Key::<aes::Aes256>::from_slice(key));
+    for (iv, blocks) in items.iter_mut() {
+        let mut prev = *iv;
+        for block in blocks.iter_mut() {
+            let ciphertext = *block;
+            cipher.decrypt_block(GenericArray::from_mut_slice(block));
+            xor_block_16(block, &prev);
+            prev = ciphertext;
+        }
+    }
+}
+
+/// Reasons a framed CBC record can't be split apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CbcError {
+    /// The record is too short to hold an IV, or the ciphertext to hold a padding block.
+    TooShort,
+    /// The payload after the IV isn't a whole number of blocks.
+    NotBlockAligned,
+    /// The last block doesn't end in valid PKCS#7 padding.
+    BadPadding,
+}
+
+/// Checks that `block` ends in valid PKCS#7 padding and returns the number of padding bytes.
+///
+/// All 16 bytes are inspected and folded into a single `Choice` no matter where (or whether)
+/// the padding is wrong, so the run time doesn't depend on the padding length or on the
+/// position of a bad byte. Only the final valid/invalid verdict is visible to the caller,
+/// which is the least a padding check can reveal; callers must still not report *why*
+/// a record failed to decrypt.
+pub fn validate_pkcs7(block: &Block16) -> Result<usize, CbcError> {
+    let pad = block[15];
+    // pad must be in 1..=16: subtracting one maps that range onto 0..=15, and 0 wraps around
+    let in_range = ((pad as u16).wrapping_sub(1) >> 4).ct_eq(&0);
+    let mut bad = Choice::from(0);
+    for i in 0..16 {
+        // 1 if byte `15 - i` is part of the padding, i.e. i < pad, computed without a comparison
+        let in_pad = Choice::from(((i as u16).wrapping_sub(pad as u16) >> 15) as u8);
+        bad |= in_pad & !block[15 - i].ct_eq(&pad);
+    }
+    if bool::from(in_range & !bad) {
+        Ok(pad as usize)
+    } else {
+        Err(CbcError::BadPadding)
+    }
+}
+
+/// Encrypts `data` of any length, PKCS#7 padded out to whole blocks. A `data` that is
+/// already a whole number of blocks gains a full block of padding, so the padding can
+/// always be told apart from the data.
+pub fn cbc_encrypt_padded(key: &[u8; 32], iv: Block16, data: &[u8]) -> Vec<Block16> {
+    let pad = 16 - data.len() % 16;
+    let mut blocks: Vec<Block16> = vec![[pad as u8; 16]; data.len() / 16 + 1];
+    for (block, chunk) in blocks.iter_mut().zip(data.chunks(16)) {
+        block[..chunk.len()].copy_from_slice(chunk);
+    }
+    cbc_encrypt(key, iv, &mut blocks);
+    blocks
+}
+
+/// Decrypts blocks made by `cbc_encrypt_padded` and strips the padding. The padding is
+/// checked by `validate_pkcs7`, so a tampered record fails with `BadPadding` in the same
+/// time however the padding was damaged.
+pub fn cbc_decrypt_padded(
+    key: &[u8; 32],
+    iv: Block16,
+    blocks: &[Block16],
+) -> Result<Vec<u8>, CbcError> {
+    let last = match blocks.len() {
+        0 => return Err(CbcError::TooShort),
+        n => n - 1,
+    };
+    let mut plaintext = blocks.to_vec();
+    cbc_decrypt(key, iv, &mut plaintext);
+    let result = validate_pkcs7(&plaintext[last]).map(|pad| {
+        let mut data: Vec<u8> = Vec::with_capacity(plaintext.len() * 16);
+        data.extend(plaintext.iter().flatten());
+        data.truncate(data.len() - pad);
+        data
+    });
+    plaintext.iter_mut().for_each(|block| block.zeroize());
+    result
+}
+
+/// Lays out a CBC record for storage as `IV || ciphertext`.
+pub fn cbc_frame(iv: Block16, ciphertext: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(iv.len() + ciphertext.len());
+    framed.extend_from_slice(&iv);
+    framed.extend_from_slice(ciphertext);
+    framed
+}
+
+/// Splits a record made by `cbc_frame` back into its IV and ciphertext.
+pub fn cbc_unframe(data: &[u8]) -> Result<(Block16, &[u8]), CbcError> {
+    let mut iv: Block16 = Default::default();
+    if data.len() < iv.len() {
+        return Err(CbcError::TooShort);
+    }
+    let (head, ciphertext) = data.split_at(iv.len());
+    if ciphertext.len() % iv.len() != 0 {
+        return Err(CbcError::NotBlockAligned);
+    }
+    iv.copy_from_slice(head);
+    Ok((iv, ciphertext))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::util::xor_block_16;
     use aes::Aes256Soft as Aes256;
     use aes::cipher::{BlockEncrypt, KeyInit, generic_array::GenericArray, BlockDecrypt};
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    // Counts heap allocations made by the current thread, so that tests running in
+    // parallel don't disturb each other's counts.
+    struct CountingAlloc;
+    thread_local! {
+        static ALLOCS: Cell<usize> = const { Cell::new(0) };
+    }
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCS.with(|a| a.set(a.get() + 1));
+            System.alloc(layout)
+        }
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+    #[global_allocator]
+    static GLOBAL: CountingAlloc = CountingAlloc;
+
+    fn allocs_during(f: impl FnOnce()) -> usize {
+        let before = ALLOCS.with(|a| a.get());
+        f();
+        ALLOCS.with(|a| a.get()) - before
+    }
+
+    #[test]
+    fn test_cbc_encrypt_fixed() {
+        // The fixed-size version matches the slice version, without touching the heap.
+        let key = &[
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+            0x0e, 0x0f, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b,
+            0x1c, 0x1d, 0x1e, 0x1f,
+        ];
+        let iv = [
+            0x30, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x3b, 0x3c, 0x3d,
+            0x3e, 0x3f,
+        ];
+        let one = [[
+            0x20, 0x21, 0x22, 0x23, 0x24, 0x25, 0x26, 0x27, 0x28, 0x29, 0x2a, 0x2b, 0x2c, 0x2d,
+            0x2e, 0x2f,
+        ]];
+        let two = [
+            [
+                0x20, 0x21, 0x22, 0x23, 0x24, 0x25, 0x26, 0x27, 0x28, 0x29, 0x2a, 0x2b, 0x2c, 0x2d,
+                0x2e, 0x2f,
+            ],
+            [
+                0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x4b, 0x4c, 0x4d,
+                0x4e, 0x4f,
+            ],
+        ];
+
+        let mut expected = one;
+        cbc_encrypt(&key, iv, &mut expected);
+        let mut blocks = one;
+        assert_eq!(allocs_during(|| cbc_encrypt_fixed(&key, iv, &mut blocks)), 0);
+        assert_eq!(blocks, expected);
+
+        let mut expected = two;
+        cbc_encrypt(&key, iv, &mut expected);
+        let mut blocks = two;
+        assert_eq!(allocs_during(|| cbc_encrypt_fixed(&key, iv, &mut blocks)), 0);
+        assert_eq!(blocks, expected);
+
+        // sanity check that the counter actually sees the slice version allocate
+        let mut blocks = two;
+        assert!(allocs_during(|| cbc_encrypt(&key, iv, &mut blocks)) > 0);
+    }
+
+    #[test]
+    fn test_cbc_inplace() {
+        // The in-place versions match the copying ones, without touching the heap.
+        let key = &[
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+            0x0e, 0x0f, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b,
+            0x1c, 0x1d, 0x1e, 0x1f,
+        ];
+        let iv = [
+            0x30, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x3b, 0x3c, 0x3d,
+            0x3e, 0x3f,
+        ];
+        for len in [0usize, 1, 2, 7, 64] {
+            let mut plaintext: Vec<Block16> = vec![Default::default(); len];
+            for i in 0..len {
+                for j in 0..16 {
+                    plaintext[i][j] = (i * 16 + j) as u8;
+                }
+            }
+            let mut expected = plaintext.clone();
+            cbc_encrypt(&key, iv, &mut expected);
+            let mut blocks = plaintext.clone();
+            assert_eq!(allocs_during(|| cbc_encrypt_inplace(&key, iv, &mut blocks)), 0);
+            assert_eq!(blocks, expected);
+
+            cbc_decrypt(&key, iv, &mut expected);
+            assert_eq!(allocs_during(|| cbc_decrypt_inplace(&key, iv, &mut blocks)), 0);
+            assert_eq!(blocks, expected);
+            assert_eq!(blocks, plaintext);
+        }
+    }
+
+    #[test]
+    fn test_cbc_scratch_wiped() {
+        // The working copy holds plaintext going into encryption and coming out of
+        // decryption; either way it is zero once the operation returns.
+        let key = &[0x11; 32];
+        let iv = [0x22; 16];
+        let plaintext: Vec<Block16> = (0..5).map(|i| [0x30 + i as u8; 16]).collect();
+        let mut scratch: Scratch = Vec::new();
+
+        let mut blocks = plaintext.clone();
+        cbc_encrypt_with(key, iv, &mut blocks, &mut scratch);
+        assert_eq!(scratch.len(), 5);
+        assert!(scratch.iter().all(|block| block.iter().all(|&b| b == 0)));
+
+        let mut expected = plaintext.clone();
+        cbc_encrypt(key, iv, &mut expected);
+        assert_eq!(blocks, expected);
+
+        cbc_decrypt_with(key, iv, &mut blocks, &mut scratch);
+        assert_eq!(blocks, plaintext);
+        assert_eq!(scratch.len(), 5);
+        assert!(scratch.iter().all(|block| block.iter().all(|&b| b == 0)));
+    }
+
+    #[test]
+    fn test_cbc_decrypt_many() {
+        // Batch decryption matches decrypting each record on its own, IV by IV.
+        let key = &[
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+            0x0e, 0x0f, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b,
+            0x1c, 0x1d, 0x1e, 0x1f,
+        ];
+        let mut records: Vec<(Block16, Vec<Block16>)> = vec![];
+        for (n, len) in [3usize, 0, 1, 5].iter().enumerate() {
+            let mut iv: Block16 = Default::default();
+            for j in 0..16 {
+                iv[j] = (0x30 + n * 16 + j) as u8;
+            }
+            let mut blocks: Vec<Block16> = vec![Default::default(); *len];
+            for i in 0..*len {
+                for j in 0..16 {
+                    blocks[i][j] = ((n + i) * 16 + j) as u8;
+                }
+            }
+            cbc_encrypt(&key, iv, &mut blocks);
+            records.push((iv, blocks));
+        }
+
+        let mut expected = records.clone();
+        for (iv, blocks) in expected.iter_mut() {
+            cbc_decrypt(&key, *iv, blocks);
+        }
+
+        let mut items: Vec<(Block16, &mut [Block16])> =
+            records.iter_mut().map(|(iv, blocks)| (*iv, &mut blocks[..])).collect();
+        cbc_decrypt_many(&key, &mut items);
+        assert_eq!(records, expected);
+    }
+
+    #[test]
+    fn test_cbc_frame_unframe() {
+        let iv = [
+            0x30, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x3b, 0x3c, 0x3d,
+            0x3e, 0x3f,
+        ];
+        for blocks in 0..4 {
+            let ciphertext: Vec<u8> = (0..blocks * 16).map(|i| i as u8).collect();
+            let framed = cbc_frame(iv, &ciphertext);
+            assert_eq!(framed.len(), 16 + ciphertext.len());
+            assert_eq!(&framed[..16], &iv);
+            assert_eq!(cbc_unframe(&framed), Ok((iv, &ciphertext[..])));
+        }
+    }
+
+    #[test]
+    fn test_cbc_unframe_invalid() {
+        let data = [0x55; 48];
+        for len in 0..16 {
+            assert_eq!(cbc_unframe(&data[..len]), Err(CbcError::TooShort));
+        }
+        assert_eq!(cbc_unframe(&data[..17]), Err(CbcError::NotBlockAligned));
+        assert_eq!(cbc_unframe(&data[..47]), Err(CbcError::NotBlockAligned));
+    }
+
+    #[test]
+    fn test_validate_pkcs7() {
+        for pad in 1..=16u8 {
+            let mut block: Block16 = [0xA5; 16];
+            for b in block[16 - pad as usize..].iter_mut() {
+                *b = pad;
+            }
+            assert_eq!(validate_pkcs7(&block), Ok(pad as usize));
+            // corrupting any one padding byte makes the block invalid
+            for i in 16 - pad as usize..15 {
+                let mut corrupt = block;
+                corrupt[i] ^= 0x01;
+                assert_eq!(validate_pkcs7(&corrupt), Err(CbcError::BadPadding));
+            }
+        }
+        assert_eq!(validate_pkcs7(&[0; 16]), Err(CbcError::BadPadding));
+        assert_eq!(validate_pkcs7(&[17; 16]), Err(CbcError::BadPadding));
+        assert_eq!(validate_pkcs7(&[0xFF; 16]), Err(CbcError::BadPadding));
+    }
+
+    // Timing on a shared or loaded machine is too noisy for this to pass reliably, so it doesn't
+    // run by default. Run it by hand on a quiet machine with
+    // `cargo test --release test_validate_pkcs7_timing -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_validate_pkcs7_timing() {
+        // Statistical sanity check, not a proof: the best-case time of a large batch should be
+        // about the same for every padding length and for padding that is wrong early or late.
+        // A check that stops at the first bad byte would show a spread of several times here.
+        use std::hint::black_box;
+        use std::time::Instant;
+        const BATCH: usize = 2000;
+        const ROUNDS: usize = 25;
+
+        let mut inputs: Vec<Block16> = vec![];
+        for pad in 1..=16u8 {
+            let mut block: Block16 = [0x5A; 16];
+            for b in block[16 - pad as usize..].iter_mut() {
+                *b = pad;
+            }
+            inputs.push(block);
+        }
+        // full-length padding that is wrong right next to the length byte, and at the far end
+        let mut early = [16u8; 16];
+        early[14] = 0;
+        inputs.push(early);
+        let mut late = [16u8; 16];
+        late[0] = 0;
+        inputs.push(late);
+
+        let best: Vec<u128> = inputs.iter().map(|block| {
+            (0..ROUNDS).map(|_| {
+                let start = Instant::now();
+                for _ in 0..BATCH {
+                    black_box(validate_pkcs7(black_box(block))).ok();
+                }
+                start.elapsed().as_nanos()
+            }).min().unwrap()
+        }).collect();
+        let fastest = *best.iter().min().unwrap();
+        let slowest = *best.iter().max().unwrap();
+        assert!(slowest < fastest * 2, "timing spread too large: {:?}", best);
+    }
+
+    #[test]
+    fn test_cbc_padded() {
+        let key = &[
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+            0x0e, 0x0f, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b,
+            0x1c, 0x1d, 0x1e, 0x1f,
+        ];
+        let iv = [
+            0x30, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x3b, 0x3c, 0x3d,
+            0x3e, 0x3f,
+        ];
+        for len in 0..50 {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let blocks = cbc_encrypt_padded(&key, iv, &data);
+            // a whole number of blocks gets a full extra block of padding
+            assert_eq!(blocks.len(), len / 16 + 1);
+            assert_eq!(cbc_decrypt_padded(&key, iv, &blocks), Ok(data));
+        }
+
+        // a block multiple decrypts to a last block that is all padding
+        let blocks = cbc_encrypt_padded(&key, iv, &[0x77; 32]);
+        let mut raw = blocks.clone();
+        cbc_decrypt(&key, iv, &mut raw);
+        assert_eq!(raw, vec![[0x77; 16], [0x77; 16], [16; 16]]);
+
+        // tampering with the ciphertext ahead of the last block garbles its padding
+        for i in 0..16 {
+            let mut tampered = blocks.clone();
+            tampered[1][i] ^= 0x01;
+            assert_eq!(cbc_decrypt_padded(&key, iv, &tampered), Err(CbcError::BadPadding));
+        }
+        // padding that is wrong but well-formed is caught too
+        let mut bad = [[0x77; 16], [0x77; 16]];
+        bad[1][15] = 2;
+        cbc_encrypt(&key, iv, &mut bad);
+        assert_eq!(cbc_decrypt_padded(&key, iv, &bad), Err(CbcError::BadPadding));
+        assert_eq!(cbc_decrypt_padded(&key, iv, &[]), Err(CbcError::TooShort));
+    }
 
     #[test]
     fn test_cbc_encrypt_decrypt() {
@@ -0,0 +1,133 @@
+// Xous maintainer's note:
+//
+// AES-CMAC (NIST SP 800-38B, RFC 4493), as a thin adapter over the Rustcrypto `cmac`
+// crate in the same style as cbc.rs. The AES-128 variant is there mostly so that the
+// RFC 4493 test vectors, which are all AES-128, can be checked.
+//
+// Tags must be checked with `verify`, never with `==`, which can stop at the first
+// differing byte and so leak how much of a forged tag was right.
+
+use aes::cipher::generic_array::GenericArray;
+use cmac::{Cmac, Mac};
+
+use super::util::{Block16, ct_eq_block_16};
+
+fn tag<M: Mac>(mut mac: M, msg: &[u8]) -> Block16 {
+    mac.update(msg);
+    let mut tag: Block16 = Default::default();
+    tag.copy_from_slice(&mac.finalize().into_bytes());
+    tag
+}
+
+/// The AES-256-CMAC of `msg` under `key`.
+pub fn cmac(key: &[u8; 32], msg: &[u8]) -> Block16
+{
+    tag(<Cmac<aes::Aes256> as Mac>::new(GenericArray::from_slice(key)), msg)
+}
+
+/// The AES-128-CMAC of `msg` under `key`.
+pub fn cmac_128(key: &[u8; 16], msg: &[u8]) -> Block16
+{
+    tag(<Cmac<aes::Aes128> as Mac>::new(GenericArray::from_slice(key)), msg)
+}
+
+/// Checks `tag` against the AES-256-CMAC of `msg` under `key`, in constant time.
+pub fn verify(key: &[u8; 32], msg: &[u8], tag: &Block16) -> bool
+{
+    ct_eq_block_16(&cmac(key, msg), tag)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The example messages of RFC 4493 and NIST SP 800-38B, taken 0, 16, 40 and 64 bytes long
+    const MSG: [u8; 64] = [
+        0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93,
+        0x17, 0x2a, 0xae, 0x2d, 0x8a, 0x57, 0x1e, 0x03, 0xac, 0x9c, 0x9e, 0xb7, 0x6f, 0xac,
+        0x45, 0xaf, 0x8e, 0x51, 0x30, 0xc8, 0x1c, 0x46, 0xa3, 0x5c, 0xe4, 0x11, 0xe5, 0xfb,
+        0xc1, 0x19, 0x1a, 0x0a, 0x52, 0xef, 0xf6, 0x9f, 0x24, 0x45, 0xdf, 0x4f, 0x9b, 0x17,
+        0xad, 0x2b, 0x41, 0x7b, 0xe6, 0x6c, 0x37, 0x10,
+    ];
+    const LENGTHS: [usize; 4] = [0, 16, 40, 64];
+
+    #[test]
+    fn test_cmac_128_rfc4493() {
+        let key = [
+            0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf,
+            0x4f, 0x3c,
+        ];
+        let tags: [Block16; 4] = [
+            [
+                0xbb, 0x1d, 0x69, 0x29, 0xe9, 0x59, 0x37, 0x28, 0x7f, 0xa3, 0x7d, 0x12, 0x9b, 0x75,
+                0x67, 0x46,
+            ],
+            [
+                0x07, 0x0a, 0x16, 0xb4, 0x6b, 0x4d, 0x41, 0x44, 0xf7, 0x9b, 0xdd, 0x9d, 0xd0, 0x4a,
+                0x28, 0x7c,
+            ],
+            [
+                0xdf, 0xa6, 0x67, 0x47, 0xde, 0x9a, 0xe6, 0x30, 0x30, 0xca, 0x32, 0x61, 0x14, 0x97,
+                0xc8, 0x27,
+            ],
+            [
+                0x51, 0xf0, 0xbe, 0xbf, 0x7e, 0x3b, 0x9d, 0x92, 0xfc, 0x49, 0x74, 0x17, 0x79, 0x36,
+                0x3c, 0xfe,
+            ],
+        ];
+        for (len, tag) in LENGTHS.iter().zip(tags.iter()) {
+            assert_eq!(cmac_128(&key, &MSG[..*len]), *tag);
+        }
+    }
+
+    #[test]
+    fn test_cmac_256_sp800_38b() {
+        let key = [
+            0x60, 0x3d, 0xeb, 0x10, 0x15, 0xca, 0x71, 0xbe, 0x2b, 0x73, 0xae, 0xf0, 0x85, 0x7d,
+            0x77, 0x81, 0x1f, 0x35, 0x2c, 0x07, 0x3b, 0x61, 0x08, 0xd7, 0x2d, 0x98, 0x10, 0xa3,
+            0x09, 0x14, 0xdf, 0xf4,
+        ];
+        let tags: [Block16; 4] = [
+            [
+                0x02, 0x89, 0x62, 0xf6, 0x1b, 0x7b, 0xf8, 0x9e, 0xfc, 0x6b, 0x55, 0x1f, 0x46, 0x67,
+                0xd9, 0x83,
+            ],
+            [
+                0x28, 0xa7, 0x02, 0x3f, 0x45, 0x2e, 0x8f, 0x82, 0xbd, 0x4b, 0xf2, 0x8d, 0x8c, 0x37,
+                0xc3, 0x5c,
+            ],
+            [
+                0xaa, 0xf3, 0xd8, 0xf1, 0xde, 0x56, 0x40, 0xc2, 0x32, 0xf5, 0xb1, 0x69, 0xb9, 0xc9,
+                0x11, 0xe6,
+            ],
+            [
+                0xe1, 0x99, 0x21, 0x90, 0x54, 0x9f, 0x6e, 0xd5, 0x69, 0x6a, 0x2c, 0x05, 0x6c, 0x31,
+                0x54, 0x10,
+            ],
+        ];
+        for (len, tag) in LENGTHS.iter().zip(tags.iter()) {
+            assert_eq!(cmac(&key, &MSG[..*len]), *tag);
+            assert!(verify(&key, &MSG[..*len], tag));
+        }
+    }
+
+    #[test]
+    fn test_verify_rejects() {
+        let key = [0x42; 32];
+        let tag = cmac(&key, &MSG);
+        assert!(verify(&key, &MSG, &tag));
+        // any flipped bit in the tag, the message or the key fails
+        for i in 0..16 {
+            let mut forged = tag;
+            forged[i] ^= 0x80;
+            assert!(!verify(&key, &MSG, &forged));
+        }
+        let mut msg = MSG;
+        msg[63] ^= 0x01;
+        assert!(!verify(&key, &msg, &tag));
+        assert!(!verify(&key, &MSG[..63], &tag));
+        let mut other = key;
+        other[0] ^= 0x01;
+        assert!(!verify(&other, &MSG, &tag));
+    }
+}
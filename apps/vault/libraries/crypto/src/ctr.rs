@@ -0,0 +1,105 @@
+// Xous maintainer's note:
+//
+// AES-256 in counter mode, as a thin adapter over the Rustcrypto `ctr` crate in the
+// same style as cbc.rs. The nonce is the initial 128-bit counter block, incremented
+// as a big-endian integer for each block of keystream.
+//
+// As with any stream cipher, a (key, nonce) pair must never be used for two different
+// messages: XORing the two ciphertexts cancels the keystream.
+
+use aes::cipher::{KeyIvInit, StreamCipher, Key, Iv};
+
+use super::util::Block16;
+
+type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
+
+/// XORs `data` with the keystream for `key` and `nonce`, in place. CTR mode is its own
+/// inverse, so this both encrypts and decrypts. `data` may be any length; a partial final
+/// block just uses the front of its keystream block.
+pub fn ctr_xor(key: &[u8; 32], nonce: Block16, data: &mut [u8])
+{
+    Aes256Ctr::new(Key::<Aes256Ctr>::from_slice(key), Iv::<Aes256Ctr>::from_slice(&nonce))
+        .apply_keystream(data);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use aes::Aes256Soft as Aes256;
+    use aes::cipher::{BlockEncrypt, KeyInit, generic_array::GenericArray};
+
+    // NIST SP 800-38A, F.5.5 CTR-AES256.Encrypt
+    const KEY: [u8; 32] = [
+        0x60, 0x3d, 0xeb, 0x10, 0x15, 0xca, 0x71, 0xbe, 0x2b, 0x73, 0xae, 0xf0, 0x85, 0x7d,
+        0x77, 0x81, 0x1f, 0x35, 0x2c, 0x07, 0x3b, 0x61, 0x08, 0xd7, 0x2d, 0x98, 0x10, 0xa3,
+        0x09, 0x14, 0xdf, 0xf4,
+    ];
+    const NONCE: Block16 = [
+        0xf0, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa, 0xfb, 0xfc, 0xfd,
+        0xfe, 0xff,
+    ];
+    const PLAINTEXT: [u8; 64] = [
+        0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93,
+        0x17, 0x2a, 0xae, 0x2d, 0x8a, 0x57, 0x1e, 0x03, 0xac, 0x9c, 0x9e, 0xb7, 0x6f, 0xac,
+        0x45, 0xaf, 0x8e, 0x51, 0x30, 0xc8, 0x1c, 0x46, 0xa3, 0x5c, 0xe4, 0x11, 0xe5, 0xfb,
+        0xc1, 0x19, 0x1a, 0x0a, 0x52, 0xef, 0xf6, 0x9f, 0x24, 0x45, 0xdf, 0x4f, 0x9b, 0x17,
+        0xad, 0x2b, 0x41, 0x7b, 0xe6, 0x6c, 0x37, 0x10,
+    ];
+    const CIPHERTEXT: [u8; 64] = [
+        0x60, 0x1e, 0xc3, 0x13, 0x77, 0x57, 0x89, 0xa5, 0xb7, 0xa7, 0xf5, 0x04, 0xbb, 0xf3,
+        0xd2, 0x28, 0xf4, 0x43, 0xe3, 0xca, 0x4d, 0x62, 0xb5, 0x9a, 0xca, 0x84, 0xe9, 0x90,
+        0xca, 0xca, 0xf5, 0xc5, 0x2b, 0x09, 0x30, 0xda, 0xa2, 0x3d, 0xe9, 0x4c, 0xe8, 0x70,
+        0x17, 0xba, 0x2d, 0x84, 0x98, 0x8d, 0xdf, 0xc9, 0xc5, 0x8d, 0xb6, 0x7a, 0xad, 0xa6,
+        0x13, 0xc2, 0xdd, 0x08, 0x45, 0x79, 0x41, 0xa6,
+    ];
+
+    // The keystream built block by block from the raw cipher, as a reference that doesn't
+    // go through the `ctr` crate.
+    fn reference_xor(key: &[u8; 32], nonce: Block16, data: &mut [u8]) {
+        let cipher = Aes256::new(GenericArray::from_slice(key));
+        let mut counter = u128::from_be_bytes(nonce);
+        for chunk in data.chunks_mut(16) {
+            let mut keystream = counter.to_be_bytes();
+            cipher.encrypt_block(GenericArray::from_mut_slice(&mut keystream));
+            for (b, k) in chunk.iter_mut().zip(keystream.iter()) {
+                *b ^= k;
+            }
+            counter = counter.wrapping_add(1);
+        }
+    }
+
+    #[test]
+    fn test_ctr_nist_vector() {
+        let mut data = PLAINTEXT;
+        ctr_xor(&KEY, NONCE, &mut data);
+        assert_eq!(data, CIPHERTEXT);
+        ctr_xor(&KEY, NONCE, &mut data);
+        assert_eq!(data, PLAINTEXT);
+    }
+
+    #[test]
+    fn test_ctr_reference() {
+        // a nonce that carries across the whole 128 bits partway through
+        let mut nonce = [0xff; 16];
+        nonce[15] = 0xfe;
+        for len in [0usize, 1, 15, 16, 17, 33, 64] {
+            let mut expected = PLAINTEXT[..len].to_vec();
+            reference_xor(&KEY, nonce, &mut expected);
+            let mut data = PLAINTEXT[..len].to_vec();
+            ctr_xor(&KEY, nonce, &mut data);
+            assert_eq!(data, expected);
+        }
+    }
+
+    #[test]
+    fn test_ctr_roundtrip_odd_lengths() {
+        for len in [1usize, 3, 7, 13, 31, 47, 63] {
+            let mut data = PLAINTEXT[..len].to_vec();
+            ctr_xor(&KEY, NONCE, &mut data);
+            // the ciphertext is a prefix of the full-length one
+            assert_eq!(data, &CIPHERTEXT[..len]);
+            ctr_xor(&KEY, NONCE, &mut data);
+            assert_eq!(data, &PLAINTEXT[..len]);
+        }
+    }
+}
@@ -13,8 +13,8 @@
 // limitations under the License.
 
 use super::Hash256;
+use super::util::{ct_eq_block_16, ct_eq_bytes};
 use arrayref::array_ref;
-use subtle::ConstantTimeEq;
 
 const BLOCK_SIZE: usize = 64;
 const HASH_SIZE: usize = 32;
@@ -24,7 +24,7 @@ where
     H: Hash256,
 {
     let expected_mac = hmac_256::<H>(key, contents);
-    bool::from(expected_mac.ct_eq(mac))
+    ct_eq_bytes(&expected_mac, mac)
 }
 
 // FIDO2's PIN verification is just matching the first 16 bytes of the HMAC
@@ -34,7 +34,7 @@ where
     H: Hash256,
 {
     let expected_mac = hmac_256::<H>(key, contents);
-    bool::from(array_ref![expected_mac, 0, 16].ct_eq(pin))
+    ct_eq_block_16(array_ref![expected_mac, 0, 16], pin)
 }
 
 pub fn hmac_256<H>(key: &[u8], contents: &[u8]) -> [u8; HASH_SIZE]
@@ -14,7 +14,10 @@
 
 pub mod rng256;
 pub mod cbc;
-mod util;
+pub mod ctr;
+pub mod cmac;
+pub mod pbkdf2;
+pub mod util;
 pub mod hmac;
 pub mod sha256;
 pub mod ecdh;
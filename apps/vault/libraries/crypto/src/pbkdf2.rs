@@ -0,0 +1,85 @@
+// Xous maintainer's note:
+//
+// This module is not part of the OpenSK reference implementation. It follows the
+// convention of cbc.rs: a thin adapter over the Rustcrypto implementation, so that
+// the vault has one place to stretch passwords into keys.
+
+use ::hmac::Hmac;
+use sha2::Sha256;
+use zeroize::{Zeroize, Zeroizing};
+
+/// The fewest iterations a password should be stretched with. Each iteration costs an
+/// attacker a pair of SHA-256 compressions per guess, so more is better, bounded by how long
+/// the user is willing to wait on the device; this is the floor, not a target.
+pub const PBKDF2_MIN_ITERATIONS: u32 = 100_000;
+
+/// Derives `out.len()` bytes of key material from `password` and `salt` with PBKDF2, using
+/// HMAC-SHA256 as the PRF. `iterations` should be at least `PBKDF2_MIN_ITERATIONS` for anything
+/// a user typed in. Panics if `iterations` is 0, which PBKDF2 doesn't define.
+///
+/// `out` holds the key once this returns, so wiping it is up to the caller; `StretchedKey`
+/// does that for a 256-bit key.
+pub fn pbkdf2_hmac_sha256(password: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) {
+    assert!(iterations > 0, "PBKDF2 needs at least one iteration");
+    ::pbkdf2::pbkdf2::<Hmac<Sha256>>(password, salt, iterations, out);
+}
+
+/// A 256-bit key stretched from a password, e.g. to key `cbc_encrypt`. The key is wiped when
+/// it goes out of scope, so it doesn't linger in memory once the vault is done with it.
+///
+/// Only the key itself can be wiped: the HMAC state inside the Rustcrypto implementation lives
+/// and dies on its stack, where this adapter can't reach it.
+pub struct StretchedKey([u8; 32]);
+
+impl StretchedKey {
+    pub fn derive(password: &[u8], salt: &[u8], iterations: u32) -> StretchedKey {
+        // the buffer is wiped when it's dropped, whether the derivation returns or panics
+        let mut buf = Zeroizing::new([0; 32]);
+        pbkdf2_hmac_sha256(password, salt, iterations, &mut *buf);
+        StretchedKey(*buf)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl Drop for StretchedKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The test vectors of RFC 6070, with SHA-256 standing in for SHA-1.
+    fn check(password: &[u8], salt: &[u8], iterations: u32, expected: &str) {
+        let expected = hex::decode(expected).unwrap();
+        let mut out = vec![0; expected.len()];
+        pbkdf2_hmac_sha256(password, salt, iterations, &mut out);
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_pbkdf2_known_answers() {
+        check(b"password", b"salt", 1,
+            "120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b");
+        check(b"password", b"salt", 2,
+            "ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43");
+        check(b"password", b"salt", 4096,
+            "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a");
+        check(b"passwordPASSWORDpassword", b"saltSALTsaltSALTsaltSALTsaltSALTsalt", 4096,
+            "348c89dbcbd32b2f32d814b8116e84cf2b17347ebc1800181c4e2a1fb8dd53e1c635518c7dac47e9");
+        check(b"pass\0word", b"sa\0lt", 4096,
+            "89b69d0516f829893c696226650a8687");
+    }
+
+    #[test]
+    fn test_stretched_key() {
+        let key = StretchedKey::derive(b"password", b"salt", 4096);
+        assert_eq!(key.as_bytes().to_vec(),
+            hex::decode("c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a").unwrap());
+    }
+}
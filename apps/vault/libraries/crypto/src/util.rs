@@ -14,10 +14,23 @@
 
 #[cfg(test)]
 use subtle::CtOption;
+use subtle::ConstantTimeEq;
 
 pub type Block16 = [u8; 16];
 
-#[allow(dead_code)]  // used by OpenSK but not our implementation
+/// Compares two blocks in constant time. Use this rather than `==`, which can return at
+/// the first differing byte, whenever either side is a secret or derived from one: MACs
+/// and tags, PIN hashes, unwrapped keys.
+pub fn ct_eq_block_16(a: &Block16, b: &Block16) -> bool {
+    a.ct_eq(b).into()
+}
+
+/// As `ct_eq_block_16`, for byte strings of any length. The run time depends only on the
+/// lengths, which are taken as public: strings of different lengths are simply unequal.
+pub fn ct_eq_bytes(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
 #[inline(always)]
 pub fn xor_block_16(block: &mut Block16, mask: &Block16) {
     for i in 0..16 {
@@ -40,3 +53,34 @@ impl<T> ToOption<T> for CtOption<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand_chacha::ChaCha8Rng;
+    use rand_core::{RngCore, SeedableRng};
+
+    #[test]
+    fn test_ct_eq_matches_eq() {
+        let mut rng = ChaCha8Rng::seed_from_u64(783);
+        for round in 0..1000 {
+            let mut a: Block16 = Default::default();
+            rng.fill_bytes(&mut a);
+            // equal, differing in one byte, or unrelated
+            let mut b = a;
+            match round % 3 {
+                0 => {}
+                1 => b[rng.next_u32() as usize % 16] ^= 1 << (rng.next_u32() % 8),
+                _ => rng.fill_bytes(&mut b),
+            }
+            assert_eq!(ct_eq_block_16(&a, &b), a == b);
+            assert_eq!(ct_eq_bytes(&a, &b), a == b);
+            let len = rng.next_u32() as usize % 17;
+            assert_eq!(ct_eq_bytes(&a[..len], &b[..len]), a[..len] == b[..len]);
+        }
+        // a prefix is not equal to the whole
+        let a = [7u8; 16];
+        assert!(!ct_eq_bytes(&a[..15], &a));
+        assert!(ct_eq_bytes(&[], &[]));
+    }
+}
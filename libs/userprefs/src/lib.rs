@@ -12,6 +12,11 @@ const TIME_SERVER_UTC_OFFSET: &'static str = "utc_offset";
 /// This is the offset from UTC to the display time zone. This can vary when the user changes time zones.
 const TIME_SERVER_TZ_OFFSET: &'static str = "tz_offset";
 
+// USB-related consts
+
+/// The identity the USB device core replaces Precursor's own with, if any.
+const USB_DEVICE_DESCRIPTOR: &'static str = "usb_device_descriptor";
+
 #[derive(Debug)]
 pub enum Error {
     EncodeError(EncodeError),
@@ -53,6 +58,18 @@ pub struct UserPrefs {
     pub headset_volume: u32,
 }
 
+/// The VID/PID and strings the USB device core identifies itself with, as given to
+/// `UsbHid::set_device_descriptor`. A `serial` of `None` keeps the serial number derived from
+/// the SoC's DNA.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbDeviceDescriptor {
+    pub vid: u16,
+    pub pid: u16,
+    pub manufacturer: String,
+    pub product: String,
+    pub serial: Option<String>,
+}
+
 pub struct Manager {
     pddb_handle: Pddb,
 }
@@ -144,6 +161,40 @@ impl Manager {
     pub fn set_utc_offset(&self, offset: i64) -> Result<(), Error> {
         self.store_i64(offset, TIME_SERVER_UTC_OFFSET)
     }
+
+    /// The USB device descriptor set by `set_usb_device_descriptor`, if any. The status service
+    /// hands it to the USB device server once the PDDB is mounted.
+    pub fn usb_device_descriptor(&self) -> Result<Option<UsbDeviceDescriptor>, Error> {
+        let bytes = self.pddb_get_key(USB_DEVICE_DESCRIPTOR)?;
+
+        if bytes.is_empty() {
+            return Ok(None);
+        }
+
+        let (fields, _): (Option<(u16, u16, String, String, Option<String>)>, usize) =
+            bincode::decode_from_slice(&bytes, bincode::config::standard())?;
+
+        Ok(fields.map(|(vid, pid, manufacturer, product, serial)| UsbDeviceDescriptor {
+            vid,
+            pid,
+            manufacturer,
+            product,
+            serial,
+        }))
+    }
+
+    /// Stores the USB device descriptor to set on every boot, or `None` to go back to Precursor's
+    /// own. This doesn't change the descriptor in use; call `UsbHid::set_device_descriptor` for that.
+    pub fn set_usb_device_descriptor(&self, descriptor: Option<&UsbDeviceDescriptor>) -> Result<(), Error> {
+        // the key is written over in place and never shrinks, so a shorter value leaves old bytes
+        // behind it; decoding stops at the end of the value, so `None` is written out as one too
+        let fields = descriptor.map(|d| {
+            (d.vid, d.pid, d.manufacturer.clone(), d.product.clone(), d.serial.clone())
+        });
+        let bytes = bincode::encode_to_vec(fields, bincode::config::standard())?;
+
+        self.pddb_store_key(USB_DEVICE_DESCRIPTOR, &bytes)
+    }
 }
 
 impl Default for Manager {
@@ -20,6 +20,7 @@ use cipher::{
     BlockSizeUser, Key, KeyInit, KeySizeUser, ParBlocksSizeUser,
 };
 use core::fmt;
+use zeroize::Zeroize;
 use fixslice::{BatchBlocks, FixsliceBlocks, FixsliceKeys128, FixsliceKeys192, FixsliceKeys256};
 
 macro_rules! define_aes_impl {
@@ -48,6 +49,9 @@ macro_rules! define_aes_impl {
             pub fn key_size(&self) -> usize {
                 $key_bits as usize
             }
+            pub fn clear(&mut self) {
+                self.keys.zeroize();
+            }
 
             #[inline(always)]
             pub(crate) fn get_enc_backend(&self) -> $name_back_enc<'_> {
@@ -60,6 +64,13 @@ macro_rules! define_aes_impl {
             }
         }
 
+        /// The key schedule is as good as the key, so it doesn't outlive the cipher.
+        impl Drop for $name {
+            fn drop(&mut self) {
+                self.clear();
+            }
+        }
+
         impl KeySizeUser for $name {
             type KeySize = $key_size;
         }
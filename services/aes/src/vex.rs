@@ -440,6 +440,13 @@ macro_rules! define_aes_impl {
             }
         }
 
+        /// The key schedule is as good as the key, so it doesn't outlive the cipher.
+        impl Drop for $name {
+            fn drop(&mut self) {
+                self.clear();
+            }
+        }
+
         impl KeySizeUser for $name {
             type KeySize = $key_size;
         }
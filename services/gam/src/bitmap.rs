@@ -11,13 +11,18 @@
  * have some unused Words at the end of the Array. More space efficient tiling strategies
  * are possible - but likely with a processing and code complexity overhead.
  *
+ * Tiles with identical content can share storage: each entry in the mosaic records the
+ * bound of a logical Tile and an index into a pool of unique Tiles. Bitmap::dedup()
+ * collapses identical Tiles into one, and any write to a shared Tile first gives the
+ * logical Tile its own copy, so mutating one never alters the others.
+ *
  * author: nworbnhoj
  */
 
 use std::cmp::{max, min};
 use std::convert::TryInto;
 use std::io::Read;
-use std::ops::Deref;
+use std::mem::size_of;
 
 use graphics_server::api::*;
 use graphics_server::PixelColor;
@@ -26,23 +31,215 @@ mod img;
 pub use img::*;
 mod decode_png;
 pub use decode_png::*;
+mod bmp;
+pub use bmp::*;
 mod greyscale;
 pub use greyscale::*;
 mod shrink;
 pub use shrink::*;
+mod enlarge;
+pub use enlarge::*;
+mod gamma;
+pub use gamma::*;
 mod dither;
 pub use dither::*;
 
+/// A destination that a Bitmap can be rendered into, pixel by pixel. This keeps the
+/// rendering logic independent of the graphics-server, so a Bitmap can be drawn into
+/// an in-memory buffer (for testing) or some other display.
+pub trait PixelSink {
+    fn set_pixel(&mut self, point: Point, color: PixelColor);
+    /// the width and height of the sink in pixels; points outside are never set
+    fn dimensions(&self) -> Point;
+}
+
+/// A destination that takes whole Tiles, such as a gam canvas. Like `PixelSink`, this keeps
+/// the code that decides what to send independent of the graphics-server.
+pub trait TileSink {
+    type Error;
+    fn push_tile(&mut self, tile: Tile) -> Result<(), Self::Error>;
+}
+
+/// Push `frames` to `sink` as an animation at `fps` frames per second. The first frame is sent
+/// whole; after that, only the Tiles that differ from the previous frame are sent. Every frame is
+/// clipped to the bound of the first. `sleep_ms` is called to wait out each frame period, e.g.
+/// with the ticktimer; the time spent sending a frame is not deducted, so a frame that changes a
+/// lot stays up a little longer.
+pub fn play_sequence<S: TileSink>(
+    frames: &[Bitmap],
+    fps: u8,
+    sink: &mut S,
+    mut sleep_ms: impl FnMut(usize),
+) -> Result<(), S::Error> {
+    let period_ms = 1000 / max(fps, 1) as usize;
+    let clip = match frames.first() {
+        Some(first) => first.bound,
+        None => return Ok(()),
+    };
+    let mut prev: Option<&Bitmap> = None;
+    for (i, frame) in frames.iter().enumerate() {
+        for tile in frame.changed_tiles(prev, clip) {
+            sink.push_tile(tile)?;
+        }
+        if i + 1 < frames.len() {
+            sleep_ms(period_ms);
+        }
+        prev = Some(frame);
+    }
+    Ok(())
+}
+
+/// The order that pixels are packed into each byte of an exported Word. A Bitmap always
+/// stores Words LsbFirst, with the leftmost pixel in bit 0; some external displays expect
+/// the leftmost pixel of each byte in bit 7 instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitOrder {
+    LsbFirst,
+    MsbFirst,
+}
+
+impl BitOrder {
+    /// Repack an internal Word into this bit order. The order of the bytes is unchanged.
+    pub fn pack(&self, word: Word) -> Word {
+        match self {
+            BitOrder::LsbFirst => word,
+            BitOrder::MsbFirst => word.swap_bytes().reverse_bits(),
+        }
+    }
+}
+
+/// Screen-door densities for `Bitmap::fill_pattern()`, laid out on a 2x2 ordered dither
+/// matrix. The pattern is anchored to the Bitmap's origin rather than to the filled
+/// rectangle, so neighbouring fills with the same density join up without a seam.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DitherPattern {
+    /// one pixel in four is Dark
+    Quarter,
+    /// a checkerboard
+    Half,
+    /// three pixels in four are Dark
+    ThreeQuarters,
+}
+
+impl DitherPattern {
+    /// The pattern for line `y`, as a Word aligned to a word boundary in the line.
+    pub fn word(&self, y: i16) -> Word {
+        let even = y % 2 == 0;
+        match (self, even) {
+            (DitherPattern::Quarter, true) => 0x5555_5555,
+            (DitherPattern::Quarter, false) => 0,
+            (DitherPattern::Half, true) => 0x5555_5555,
+            (DitherPattern::Half, false) => 0xAAAA_AAAA,
+            (DitherPattern::ThreeQuarters, true) => Word::MAX,
+            (DitherPattern::ThreeQuarters, false) => 0xAAAA_AAAA,
+        }
+    }
+}
+
+/// How `Bitmap::blit()` combines each source pixel with the destination pixel beneath it,
+/// with Dark as set and Light as clear.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlitOp {
+    /// the source pixel replaces the destination
+    Copy,
+    /// Dark if either is Dark
+    Or,
+    /// Dark only if both are Dark
+    And,
+    /// Dark if exactly one is Dark
+    Xor,
+}
+
+impl BlitOp {
+    /// The result of combining the `src` pixel with the `dest` pixel beneath it
+    pub fn apply(&self, src: PixelColor, dest: PixelColor) -> PixelColor {
+        let (src, dest): (bool, bool) = (src.into(), dest.into());
+        PixelColor::from(match self {
+            BlitOp::Copy => src,
+            BlitOp::Or => src | dest,
+            BlitOp::And => src & dest,
+            BlitOp::Xor => src ^ dest,
+        })
+    }
+}
+
+/// Reasons a Bitmap can't be built, or a point in it can't be reached. The fallible
+/// `try_` methods return these; their infallible counterparts panic or, for accessors,
+/// log a warning and fall back to the first Tile as they always have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitmapError {
+    /// the point lies outside the Bitmap
+    OutOfBounds,
+    /// a dimension is negative, doesn't fit in a Point, or is wider than a Tile can hold; or the
+    /// Bitmap would take more memory to build than it was allowed
+    SizeTooLarge,
+    /// the Tiles leave part of the area they span uncovered
+    TileGap,
+    /// more than one Tile covers the same part of the area they span
+    TileOverlap,
+    /// a BitmapPatch was made for a Bitmap with a different mosaic of Tiles
+    PatchMismatch,
+}
+
+/// A logical Tile in the mosaic: where it sits, and which unique Tile holds its content.
+#[derive(Debug, Clone, Copy)]
+struct TileRef {
+    bound: Rectangle,
+    unique: usize,
+}
+
+/// The transform applied by Bitmap::from_image_fitted() to make an image fit its target.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FitTransform {
+    /// the image was rotated by 90 degrees (after scaling)
+    pub rotated: bool,
+    /// the output width over the input width, measured before rotation
+    pub scale: f32,
+    /// the translation applied to place the top-left of the image at the top-left of the target
+    pub offset: Point,
+}
+
+/// One changed Tile in a BitmapPatch: its new content, and where it sits in the mosaic.
+/// Each one fits in a page, so a patch can go over a link a Tile at a time.
+#[derive(Debug, Clone, Copy, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct PatchTile {
+    pub index: u32,
+    pub tile: Tile,
+}
+
+/// The Tiles of a Bitmap that differ from an earlier frame, made by Bitmap::make_patch()
+/// and applied to a copy of that frame with Bitmap::apply_patch().
+#[derive(Debug, Clone)]
+pub struct BitmapPatch {
+    /// the number of Tiles in the mosaic of the Bitmap the patch was made from
+    pub tile_count: u32,
+    pub tiles: Vec<PatchTile>,
+}
+
 #[derive(Debug)]
 pub struct Bitmap {
     width: usize,
     pub bound: Rectangle,
     tile_bits: usize,
-    mosaic: Vec<Tile>,
+    mosaic: Vec<TileRef>,
+    unique: Vec<Tile>,
+    /// how many logical Tiles in the mosaic share each unique Tile
+    refs: Vec<usize>,
 }
 
 impl Bitmap {
+    /// A blank Bitmap whose bottom-right pixel is `size`, so Point::new(0, 0) is a single
+    /// pixel. Panics where try_new() would fail.
     pub fn new(size: Point) -> Self {
+        Self::try_new(size).unwrap()
+    }
+
+    /// As new(), but fails with SizeTooLarge if either coordinate of `size` is negative, which
+    /// would leave no pixels at all, or the width is more than a Tile can hold.
+    pub fn try_new(size: Point) -> Result<Self, BitmapError> {
+        if size.x < 0 || size.y < 0 || size.x as i32 + 1 > BITS_PER_TILE as i32 {
+            return Err(BitmapError::SizeTooLarge);
+        }
         let mut mosaic: Vec<Tile> = Vec::new();
         let mut tl = Point::new(0, 0);
         let mut br = Point::new(size.x, 0);
@@ -63,27 +260,163 @@ impl Bitmap {
             tl = Point::new(0, br.y + 1);
             br = Point::new(size.x, tl.y);
         }
-        Self {
-            width: size.x as usize + 1,
-            bound: Rectangle::new(Point::new(0, 0), size),
+        Ok(Self::from_mosaic(
+            size.x as usize + 1,
+            Rectangle::new(Point::new(0, 0), size),
             tile_bits,
             mosaic,
+        ))
+    }
+
+    /// Assemble a Bitmap where every Tile in the mosaic has its own storage
+    fn from_mosaic(width: usize, bound: Rectangle, tile_bits: usize, tiles: Vec<Tile>) -> Self {
+        Self {
+            width,
+            bound,
+            tile_bits,
+            mosaic: tiles
+                .iter()
+                .enumerate()
+                .map(|(unique, tile)| TileRef { bound: tile.bound(), unique })
+                .collect(),
+            refs: vec![1; tiles.len()],
+            unique: tiles,
         }
     }
 
     pub fn from_img(img: &Img, fit: Option<Point>) -> Self {
-        Bitmap::from_iter(
+        Self::try_from_img(img, fit).unwrap()
+    }
+
+    pub fn try_from_img(img: &Img, fit: Option<Point>) -> Result<Self, BitmapError> {
+        Bitmap::try_from_iter(
             img.iter().cloned(),
             img.px_type,
-            Point::new(
-                img.width().try_into().unwrap(),
-                img.height().try_into().unwrap(),
-            ),
+            Self::px_size(img.width(), img.height())?,
             fit,
         )
     }
 
+    /// Scale an image to `width` pixels wide, keeping its aspect ratio. Unlike `fit`, which
+    /// only ever shrinks, the image is enlarged when `width` is wider than the image.
+    /// A `gamma` curve is applied to the scaled pixels before dithering, if given: photos
+    /// tend to come out dark on the display, which a gamma below 1.0 lightens.
+    pub fn new_resize(img: &Img, width: usize, gamma: Option<f32>) -> Self {
+        Self::try_new_resize(img, width, gamma).unwrap()
+    }
+
+    pub fn try_new_resize(
+        img: &Img,
+        width: usize,
+        gamma: Option<f32>,
+    ) -> Result<Self, BitmapError> {
+        let px_size = Self::px_size(img.width(), img.height())?;
+        let (bm, _, _) = Self::from_iter_to(
+            img.iter().cloned(),
+            img.px_type,
+            px_size,
+            false,
+            width,
+            DitherScheme::Burkes,
+            gamma,
+        )?;
+        Ok(bm)
+    }
+
+    /// Convert an image with a hard threshold rather than dithering: each grey pixel darker
+    /// than `cutoff` is Dark, and the rest Light. Sharp edges in text and line art stay clean
+    /// without dither noise, and it is faster as no error is carried between pixels. The image
+    /// is neither scaled nor rotated. DEFAULT_CUTOFF matches the dither threshold.
+    pub fn from_img_threshold(image: &Img, cutoff: u8) -> Self {
+        Self::try_from_img_threshold(image, cutoff).unwrap()
+    }
+
+    pub fn try_from_img_threshold(image: &Img, cutoff: u8) -> Result<Self, BitmapError> {
+        let px_size = Self::px_size(image.width(), image.height())?;
+        let width = image.width();
+        if width > BITS_PER_TILE {
+            return Err(BitmapError::SizeTooLarge);
+        }
+        let words = image
+            .iter()
+            .cloned()
+            .to_grey(image.px_type)
+            .threshold(cutoff, width);
+        Ok(Self::from_words(words, width, px_size.y))
+    }
+
+    /// As try_from_img(), but fails with SizeTooLarge before allocating anything if building the
+    /// Bitmap would take more than `max_bytes` of heap, as estimated by estimate_bytes(). Large
+    /// images can exhaust the heap on Precursor, which is otherwise a panic.
+    pub fn try_from_img_within(
+        img: &Img,
+        fit: Option<Point>,
+        max_bytes: usize,
+    ) -> Result<Self, BitmapError> {
+        let px_size = Self::px_size(img.width(), img.height())?;
+        if Self::estimate_bytes(px_size, fit, true)? > max_bytes {
+            return Err(BitmapError::SizeTooLarge);
+        }
+        Bitmap::try_from_iter(img.iter().cloned(), img.px_type, px_size, fit)
+    }
+
+    /// An upper bound on the heap it takes to build a Bitmap from an image of `px_size` pixels,
+    /// scaled to `fit`: the Tiles of the result, and a second set when it has to be rotated to
+    /// fit, along with the slack of the Vecs holding them and the dither's rows of carried error.
+    /// The pixels are streamed through, so the image itself doesn't count.
+    pub fn estimate_bytes(
+        px_size: Point,
+        fit: Option<Point>,
+        allow_rotate: bool,
+    ) -> Result<usize, BitmapError> {
+        let from_width: usize = px_size.x.try_into().map_err(|_| BitmapError::SizeTooLarge)?;
+        let from_height: usize = px_size.y.try_into().map_err(|_| BitmapError::SizeTooLarge)?;
+        if from_width == 0 {
+            return Ok(0);
+        }
+        let (rotate, to_width) = match fit {
+            Some(fit) => Self::fit(px_size, fit, allow_rotate),
+            None => (false, from_width),
+        };
+        if to_width > BITS_PER_TILE {
+            return Err(BitmapError::SizeTooLarge);
+        }
+        let to_height = (from_height * to_width + from_width - 1) / from_width;
+        // the Tiles are pushed one at a time, so the Vec holding them may have doubled past need
+        let mosaic_bytes = |width: usize, height: usize| {
+            let width_words = max(1, (width + BITS_PER_WORD - 1) / BITS_PER_WORD);
+            let rows_per_tile = max(1, WORDS_PER_TILE / width_words);
+            let tiles = (height + rows_per_tile - 1) / rows_per_tile;
+            tiles.next_power_of_two() * size_of::<Tile>() + tiles * size_of::<TileRef>()
+        };
+        let mut bytes = mosaic_bytes(to_width, to_height);
+        if rotate {
+            bytes += mosaic_bytes(to_height, to_width);
+        }
+        // the dither keeps the error carried as far ahead as its diffusion reaches
+        let diffusion = DitherScheme::Burkes.diffusion();
+        let reach_x = diffusion.iter().map(|&(dx, _, _)| dx).max().unwrap_or(0) as usize;
+        let reach_y = diffusion.iter().map(|&(_, dy, _)| dy).max().unwrap_or(0) as usize;
+        bytes += (to_width * reach_y + reach_x + 1) * size_of::<i16>();
+        Ok(bytes)
+    }
+
+    /// an image size in pixels as a Point, if it can be represented as one
+    fn px_size(width: usize, height: usize) -> Result<Point, BitmapError> {
+        match (width.try_into(), height.try_into()) {
+            (Ok(x), Ok(y)) => Ok(Point::new(x, y)),
+            _ => Err(BitmapError::SizeTooLarge),
+        }
+    }
+
     pub fn from_png<R: Read>(png: &mut DecodePng<R>, fit: Option<Point>) -> Self {
+        Self::try_from_png(png, fit).unwrap()
+    }
+
+    pub fn try_from_png<R: Read>(
+        png: &mut DecodePng<R>,
+        fit: Option<Point>,
+    ) -> Result<Self, BitmapError> {
         // Png Colortypes: 0=Grey, 2=Rgb, 3=Palette, 4=GreyAlpha, 6=Rgba.
         let px_type = match (png.color_type(), png.bit_depth()) {
             (0, 1 | 2 | 4) => PixelType::U0, // Unsupported
@@ -98,11 +431,59 @@ impl Bitmap {
             (6, 16) => PixelType::U16x4,
             (_, _) => PixelType::U0, // Invalid combination
         };
-        let px_size = Point::new(
-            png.width().try_into().unwrap(),
-            png.height().try_into().unwrap(),
-        );
-        Bitmap::from_iter(png, px_type, px_size, fit)
+        let px_size = Self::px_size(png.width() as usize, png.height() as usize)?;
+        Bitmap::try_from_iter(png, px_type, px_size, fit)
+    }
+
+    /// Decode an uncompressed 24 or 32 bit BMP, and dither it into a Bitmap scaled to `fit`
+    pub fn from_bmp(bytes: &[u8], fit: Option<Point>) -> Self {
+        Self::try_from_bmp(bytes, fit).unwrap()
+    }
+
+    pub fn try_from_bmp(bytes: &[u8], fit: Option<Point>) -> Result<Self, BmpError> {
+        let img = decode_bmp(bytes)?;
+        Ok(Self::try_from_img(&img, fit)?)
+    }
+
+    /// Scale, dither and (if allowed and it gives a larger image) rotate an image in a single
+    /// pass, placing the result at the top-left of `target`. The pixels stream straight from
+    /// the image through the greyscale, shrink and dither stages into the Bitmap's tiles,
+    /// so the only intermediate buffer is the one rotate90() needs when it rotates.
+    pub fn from_image_fitted(
+        image: &Img,
+        target: Rectangle,
+        scheme: DitherScheme,
+        allow_rotate: bool,
+    ) -> (Self, FitTransform) {
+        Self::try_from_image_fitted(image, target, scheme, allow_rotate).unwrap()
+    }
+
+    pub fn try_from_image_fitted(
+        image: &Img,
+        target: Rectangle,
+        scheme: DitherScheme,
+        allow_rotate: bool,
+    ) -> Result<(Self, FitTransform), BitmapError> {
+        let px_size = Self::px_size(image.width(), image.height())?;
+        let fit = Self::px_size(
+            (target.width() + 1) as usize,
+            (target.height() + 1) as usize,
+        )?;
+        let (mut bm, rotated, to_width) = Self::from_iter_with(
+            image.iter().cloned(),
+            image.px_type,
+            px_size,
+            Some(fit),
+            allow_rotate,
+            scheme,
+        )?;
+        bm.translate(target.tl);
+        let transform = FitTransform {
+            rotated,
+            scale: to_width as f32 / image.width() as f32,
+            offset: target.tl,
+        };
+        Ok((bm, transform))
     }
 
     pub fn from_iter<I: Iterator<Item = u8>>(
@@ -111,17 +492,85 @@ impl Bitmap {
         px_size: Point,
         fit: Option<Point>,
     ) -> Self {
-        let burkes = BURKES.to_vec();
-        let from_width: usize = px_size.x.try_into().unwrap();
+        Self::try_from_iter(bytes, px_type, px_size, fit).unwrap()
+    }
+
+    pub fn try_from_iter<I: Iterator<Item = u8>>(
+        bytes: I,
+        px_type: PixelType,
+        px_size: Point,
+        fit: Option<Point>,
+    ) -> Result<Self, BitmapError> {
+        let (bm, _, _) =
+            Self::from_iter_with(bytes, px_type, px_size, fit, true, DitherScheme::Burkes)?;
+        Ok(bm)
+    }
+
+    /// returns the Bitmap along with whether it was rotated and the width it was scaled to
+    fn from_iter_with<I: Iterator<Item = u8>>(
+        bytes: I,
+        px_type: PixelType,
+        px_size: Point,
+        fit: Option<Point>,
+        allow_rotate: bool,
+        scheme: DitherScheme,
+    ) -> Result<(Self, bool, usize), BitmapError> {
+        let from_width: usize = px_size
+            .x
+            .try_into()
+            .map_err(|_| BitmapError::SizeTooLarge)?;
         let (rotate, to_width) = match fit {
-            Some(fit) => Self::fit(px_size, fit),
+            Some(fit) => Self::fit(px_size, fit, allow_rotate),
             None => (false, from_width),
         };
+        Self::from_iter_to(bytes, px_type, px_size, rotate, to_width, scheme, None)
+    }
+
+    /// returns the Bitmap scaled to `to_width`, with the `gamma` curve if any, and rotated if
+    /// asked, along with whether it was rotated and the width it was scaled to
+    fn from_iter_to<I: Iterator<Item = u8>>(
+        bytes: I,
+        px_type: PixelType,
+        px_size: Point,
+        rotate: bool,
+        to_width: usize,
+        scheme: DitherScheme,
+        gamma: Option<f32>,
+    ) -> Result<(Self, bool, usize), BitmapError> {
+        let diffusion = scheme.diffusion();
+        let from_width: usize = px_size
+            .x
+            .try_into()
+            .map_err(|_| BitmapError::SizeTooLarge)?;
+        if to_width > BITS_PER_TILE {
+            return Err(BitmapError::SizeTooLarge);
+        }
+        // at most one of shrink and grow has any effect, the other passes the pixels through
         let words = bytes
             .to_grey(px_type)
             .shrink(from_width, to_width)
-            .dither(&burkes, to_width);
+            .grow(from_width, to_width)
+            .gamma(gamma.unwrap_or(1.0))
+            .dither(&diffusion, to_width);
+        // an enlarged image has more rows than the original
+        let max_y: i16 = match from_width > 0 && to_width > from_width {
+            true => (px_size.y as usize * to_width + from_width - 1) / from_width,
+            false => px_size.y as usize,
+        }
+        .try_into()
+        .map_err(|_| BitmapError::SizeTooLarge)?;
 
+        let mut bm = Self::from_words(words, to_width, max_y);
+        if rotate {
+            Ok((bm.rotate90(), true, to_width))
+        } else {
+            Ok((bm, false, to_width))
+        }
+    }
+
+    /// Assemble a Bitmap `to_width` pixels wide from rows of packed pixel Words, stopping
+    /// after row `max_y`
+    fn from_words<W: Iterator<Item = u32>>(words: W, to_width: usize, max_y: i16) -> Self {
         let mut mosaic: Vec<Tile> = Vec::new();
 
         let to_width: i16 = to_width.try_into().unwrap();
@@ -139,7 +588,7 @@ impl Bitmap {
             }
             if y > tile.max_bound().br.y {
                 mosaic.push(tile);
-                if y > px_size.y {
+                if y > max_y {
                     break;
                 }
                 bound = Rectangle::new(Point::new(x, y), Point::new(to_width - 1, y));
@@ -157,25 +606,22 @@ impl Bitmap {
         let max = tile.max_bound();
         let tile_bits = to_width * (max.br.y - max.tl.y + 1);
 
-        let mut bm = Self {
-            width: to_width.try_into().unwrap(),
+        Self::from_mosaic(
+            to_width.try_into().unwrap(),
             bound,
-            tile_bits: tile_bits.try_into().unwrap(),
+            tile_bits.try_into().unwrap(),
             mosaic,
-        };
-
-        if rotate {
-            bm.rotate90()
-        } else {
-            bm
-        }
+        )
     }
 
-    fn fit(from: Point, into: Point) -> (bool, usize) {
+    fn fit(from: Point, into: Point, allow_rotate: bool) -> (bool, usize) {
         let (from_x, from_y) = (from.x as f32, from.y as f32);
         let (into_x, into_y) = (into.x as f32, into.y as f32);
         let portrait_scale = (into_x / from_x).min(into_y / from_y);
-        let landscape_scale = (into_x / from_y).min(into_y / from_x);
+        let landscape_scale = match allow_rotate {
+            true => (into_x / from_y).min(into_y / from_x),
+            false => 0.0,
+        };
         if portrait_scale >= 1.0 {
             log::info!("show image as is");
             (false, from.x.try_into().unwrap())
@@ -202,30 +648,61 @@ impl Bitmap {
     }
 
     fn get_tile_index(&self, point: Point) -> usize {
-        if self.bound.intersects_point(point) {
-            let x = point.x as usize;
-            let y = point.y as usize;
-            (x + y * self.width) / self.tile_bits
-        } else {
+        self.tile_index(point).unwrap_or_else(|_| {
             log::warn!("Out of bounds {:?}", point);
             0
+        })
+    }
+
+    fn tile_index(&self, point: Point) -> Result<usize, BitmapError> {
+        if !self.bound.intersects_point(point) {
+            return Err(BitmapError::OutOfBounds);
+        }
+        let x = point.x as usize;
+        let y = point.y as usize;
+        let index = (x + y * self.width) / self.tile_bits;
+        match index < self.mosaic.len() {
+            true => Ok(index),
+            false => Err(BitmapError::OutOfBounds),
         }
     }
 
-    fn hull(mosaic: &Vec<Tile>) -> Rectangle {
+    /// The Rectangle enclosing every Tile in the mosaic, along with the total area of the
+    /// Tiles and the area of the Rectangle, which differ if the Tiles leave gaps or overlap.
+    fn hull_area(mosaic: &Vec<Tile>) -> (Rectangle, i32, i32) {
         let mut hull_tl = Point::new(i16::MAX, i16::MAX);
         let mut hull_br = Point::new(i16::MIN, i16::MIN);
         let mut tile_area = 0;
-        for (_i, tile) in mosaic.iter().enumerate() {
+        for tile in mosaic.iter() {
             let tile_bound = tile.bound();
             hull_tl.x = min(hull_tl.x, tile_bound.tl.x);
             hull_tl.y = min(hull_tl.y, tile_bound.tl.y);
             hull_br.x = max(hull_br.x, tile_bound.br.x);
             hull_br.y = max(hull_br.y, tile_bound.br.y);
-            tile_area +=
-                (1 + tile_bound.br.x - tile_bound.tl.x) * (1 + tile_bound.br.y - tile_bound.tl.y);
+            tile_area += (1 + tile_bound.br.x as i32 - tile_bound.tl.x as i32)
+                * (1 + tile_bound.br.y as i32 - tile_bound.tl.y as i32);
+        }
+        let hull_area =
+            (1 + hull_br.x as i32 - hull_tl.x as i32) * (1 + hull_br.y as i32 - hull_tl.y as i32);
+        (Rectangle::new(hull_tl, hull_br), tile_area, hull_area)
+    }
+
+    fn try_hull(mosaic: &Vec<Tile>) -> Result<Rectangle, BitmapError> {
+        if mosaic.is_empty() {
+            return Err(BitmapError::TileGap);
         }
-        let hull_area = (1 + hull_br.x - hull_tl.x) * (1 + hull_br.y - hull_tl.y);
+        let (hull, tile_area, hull_area) = Self::hull_area(mosaic);
+        if tile_area < hull_area {
+            Err(BitmapError::TileGap)
+        } else if tile_area > hull_area {
+            Err(BitmapError::TileOverlap)
+        } else {
+            Ok(hull)
+        }
+    }
+
+    fn hull(mosaic: &Vec<Tile>) -> Rectangle {
+        let (hull, tile_area, hull_area) = Self::hull_area(mosaic);
         if tile_area < hull_area {
             log::warn!(
                 "Bitmap Tile gaps: tile_area={} hull_area={} {:?}",
@@ -241,41 +718,339 @@ impl Bitmap {
                 mosaic
             );
         }
-        Rectangle::new(hull_tl, hull_br)
+        hull
+    }
+
+    /// A copy of the unique Tile behind `tref`, moved to where the logical Tile sits
+    fn materialize(&self, tref: &TileRef) -> Tile {
+        let mut tile = self.unique[tref.unique];
+        tile.translate(tref.bound.tl - tile.bound().tl);
+        tile.set_bound(tref.bound);
+        tile
+    }
+
+    /// The logical Tiles in the mosaic, in order
+    pub fn tiles(&self) -> impl Iterator<Item = Tile> + '_ {
+        self.mosaic.iter().map(move |tref| self.materialize(tref))
+    }
+
+    /// The number of distinct Tiles actually stored, which is less than the number of
+    /// Tiles in the mosaic when identical Tiles have been collapsed by dedup().
+    pub fn unique_tile_count(&self) -> usize {
+        self.unique.len()
+    }
+
+    /// Store each distinct Tile only once. Useful for repetitive content such as backgrounds
+    /// and borders; writes to a shared Tile copy it first, so this is never visible to callers.
+    pub fn dedup(&mut self) {
+        let mut unique: Vec<Tile> = Vec::new();
+        let mut refs: Vec<usize> = Vec::new();
+        for i in 0..self.mosaic.len() {
+            let tile = self.materialize(&self.mosaic[i]);
+            let index = match unique.iter().position(|u| Self::same_content(u, &tile)) {
+                Some(index) => index,
+                None => {
+                    unique.push(tile);
+                    refs.push(0);
+                    unique.len() - 1
+                }
+            };
+            refs[index] += 1;
+            self.mosaic[i].unique = index;
+        }
+        self.unique = unique;
+        self.refs = refs;
+    }
+
+    fn same_content(a: &Tile, b: &Tile) -> bool {
+        let (a_bound, b_bound) = (a.bound(), b.bound());
+        if a.size() != b.size() || a_bound.tl.x != b_bound.tl.x {
+            return false;
+        }
+        (0..=a_bound.br.y - a_bound.tl.y).all(|line| {
+            a.get_line(Point::new(a_bound.tl.x, a_bound.tl.y + line))
+                == b.get_line(Point::new(b_bound.tl.x, b_bound.tl.y + line))
+        })
+    }
+
+    /// The Tiles that differ from `previous`, for bringing a copy of `previous` up to date
+    /// with apply_patch() without sending the whole Bitmap. If `previous` is laid out in a
+    /// different mosaic of Tiles, every Tile is in the patch; if nothing changed, none are.
+    pub fn make_patch(&self, previous: &Bitmap) -> BitmapPatch {
+        let same_layout = self.mosaic.len() == previous.mosaic.len()
+            && self
+                .mosaic
+                .iter()
+                .zip(previous.mosaic.iter())
+                .all(|(a, b)| Self::same_bound(&a.bound, &b.bound));
+        let tiles = (0..self.mosaic.len())
+            .filter(|&i| {
+                !same_layout
+                    || !Self::same_content(
+                        &self.materialize(&self.mosaic[i]),
+                        &previous.materialize(&previous.mosaic[i]),
+                    )
+            })
+            .map(|i| PatchTile {
+                index: i as u32,
+                tile: self.materialize(&self.mosaic[i]),
+            })
+            .collect();
+        BitmapPatch {
+            tile_count: self.mosaic.len() as u32,
+            tiles,
+        }
+    }
+
+    /// Overwrite the Tiles named in `patch`. Nothing is changed if the patch doesn't fit this
+    /// Bitmap's mosaic of Tiles, i.e. it wasn't made against a copy of this Bitmap.
+    pub fn apply_patch(&mut self, patch: &BitmapPatch) -> Result<(), BitmapError> {
+        if patch.tile_count as usize != self.mosaic.len() {
+            return Err(BitmapError::PatchMismatch);
+        }
+        for entry in patch.tiles.iter() {
+            match self.mosaic.get(entry.index as usize) {
+                Some(tref) if Self::same_bound(&tref.bound, &entry.tile.bound()) => {}
+                _ => return Err(BitmapError::PatchMismatch),
+            }
+        }
+        for entry in patch.tiles.iter() {
+            let index = entry.index as usize;
+            let unique = self.mosaic[index].unique;
+            if self.refs[unique] > 1 {
+                self.split(index, entry.tile);
+            } else {
+                self.unique[unique] = entry.tile;
+            }
+        }
+        Ok(())
+    }
+
+    fn same_bound(a: &Rectangle, b: &Rectangle) -> bool {
+        (a.tl, a.br) == (b.tl, b.br)
+    }
+
+    /// Map a point in the logical Tile at `index` to the unique Tile holding it, and the
+    /// equivalent point in that Tile
+    fn locate(&self, index: usize, point: Point) -> (usize, Point) {
+        let tref = self.mosaic[index];
+        let tile = &self.unique[tref.unique];
+        (tref.unique, point - tref.bound.tl + tile.bound().tl)
+    }
+
+    /// Like locate(), but first gives the logical Tile its own copy if it shares storage
+    fn locate_mut(&mut self, index: usize, point: Point) -> (usize, Point) {
+        let tref = self.mosaic[index];
+        if self.refs[tref.unique] > 1 {
+            let tile = self.materialize(&tref);
+            self.split(index, tile);
+        }
+        self.locate(index, point)
+    }
+
+    /// Move the logical Tile at `index` off the unique Tile it shares, onto new storage holding `tile`
+    fn split(&mut self, index: usize, tile: Tile) {
+        self.refs[self.mosaic[index].unique] -= 1;
+        self.unique.push(tile);
+        self.refs.push(1);
+        self.mosaic[index].unique = self.unique.len() - 1;
+    }
+
+    /// Writes within max_bound may grow a Tile's bound, so carry that back to the mosaic
+    fn sync_bound(&mut self, index: usize, unique: usize) {
+        let bound = self.unique[unique].bound();
+        let tl = self.mosaic[index].bound.tl;
+        self.mosaic[index].bound = Rectangle::new(tl, tl + (bound.br - bound.tl));
     }
 
     pub fn get_tile(&self, point: Point) -> Tile {
         let tile = self.get_tile_index(point);
-        self.mosaic.as_slice()[tile]
+        self.materialize(&self.mosaic[tile])
     }
 
-    fn get_mut_tile(&mut self, point: Point) -> &mut Tile {
-        let tile = self.get_tile_index(point);
-        &mut self.mosaic.as_mut_slice()[tile]
+    pub fn try_get_tile(&self, point: Point) -> Result<Tile, BitmapError> {
+        let tile = self.tile_index(point)?;
+        Ok(self.materialize(&self.mosaic[tile]))
     }
 
     pub fn get_line(&self, point: Point) -> Vec<Word> {
-        self.get_tile(point).get_line(point)
+        let (unique, local) = self.locate(self.get_tile_index(point), point);
+        self.unique[unique].get_line(local)
+    }
+
+    pub fn try_get_line(&self, point: Point) -> Result<Vec<Word>, BitmapError> {
+        let (unique, local) = self.locate(self.tile_index(point)?, point);
+        Ok(self.unique[unique].get_line(local))
+    }
+
+    /// Borrows the words of the line through `point` from the tile holding it, for scanline
+    /// processing without the allocation of `get_line()`. Returns `None` outside the Bitmap.
+    pub fn row_words(&self, point: Point) -> Option<&[Word]> {
+        let (unique, local) = self.locate(self.tile_index(point).ok()?, point);
+        self.unique[unique].row_words(local.y)
+    }
+
+    /// The number of Dark pixels in the Bitmap. Only pixels inside each tile's bound are
+    /// counted, so stray bits in the padding at the end of a line never inflate the total.
+    pub fn popcount(&self) -> u32 {
+        let mut count = 0;
+        for tref in self.mosaic.iter() {
+            let tile = &self.unique[tref.unique];
+            let bound = tile.bound();
+            let width = (bound.br.x - bound.tl.x + 1) as usize;
+            let (full, partial) = (width / BITS_PER_WORD, width % BITS_PER_WORD);
+            for y in bound.tl.y..=bound.br.y {
+                let words = match tile.row_words(y) {
+                    Some(words) => words,
+                    None => continue,
+                };
+                count += words[..full].iter().map(|w| w.count_ones()).sum::<u32>();
+                if partial > 0 {
+                    count += (words[full] & ((1 << partial) - 1)).count_ones();
+                }
+            }
+        }
+        count
+    }
+
+    /// Turn every Dark pixel Light and every Light pixel Dark, as for dark-mode rendering or a
+    /// selection highlight. The padding bits past the right edge of each line are left as they
+    /// were, so that inverting twice leaves every Word exactly as it started.
+    pub fn invert(&mut self) {
+        // a shared Tile is stored once, so it is inverted once
+        for tile in self.unique.iter_mut() {
+            let bound = tile.bound();
+            let width = (bound.br.x - bound.tl.x + 1) as usize;
+            let (full, partial) = (width / BITS_PER_WORD, width % BITS_PER_WORD);
+            for y in bound.tl.y..=bound.br.y {
+                let words = match tile.row_words_mut(y) {
+                    Some(words) => words,
+                    None => continue,
+                };
+                words[..full].iter_mut().for_each(|w| *w = !*w);
+                if partial > 0 {
+                    words[full] ^= (1 << partial) - 1;
+                }
+            }
+        }
     }
 
     fn get_word(&self, point: Point) -> Word {
-        self.get_tile(point).get_word(point)
+        let (unique, local) = self.locate(self.get_tile_index(point), point);
+        self.unique[unique].get_word(local)
     }
 
     fn set_word(&mut self, point: Point, word: Word) {
-        self.get_mut_tile(point).set_word(point, word);
+        let index = self.get_tile_index(point);
+        let (unique, local) = self.locate_mut(index, point);
+        self.unique[unique].set_word(local, word);
+        self.sync_bound(index, unique);
     }
 
     pub fn get_pixel(&self, point: Point) -> PixelColor {
-        self.get_tile(point).get_pixel(point)
+        let (unique, local) = self.locate(self.get_tile_index(point), point);
+        self.unique[unique].get_pixel(local)
+    }
+
+    pub fn try_get_pixel(&self, point: Point) -> Result<PixelColor, BitmapError> {
+        let (unique, local) = self.locate(self.tile_index(point)?, point);
+        Ok(self.unique[unique].get_pixel(local))
     }
 
     pub fn set_pixel(&mut self, point: Point, color: PixelColor) {
-        self.get_mut_tile(point).set_pixel(point, color)
+        self.set_pixel_at(self.get_tile_index(point), point, color);
+    }
+
+    pub fn try_set_pixel(&mut self, point: Point, color: PixelColor) -> Result<(), BitmapError> {
+        self.set_pixel_at(self.tile_index(point)?, point, color);
+        Ok(())
+    }
+
+    fn set_pixel_at(&mut self, index: usize, point: Point, color: PixelColor) {
+        let (unique, local) = self.locate_mut(index, point);
+        self.unique[unique].set_pixel(local, color);
+        self.sync_bound(index, unique);
+    }
+
+    /// Shade `r` (clipped to the Bitmap) with `pattern`. Words that lie wholly inside `r` are
+    /// written in one go; only the partial words at either end of a line are merged bit by bit.
+    pub fn fill_pattern(&mut self, r: Rectangle, pattern: DitherPattern) {
+        let tl = Point::new(max(r.tl.x, self.bound.tl.x), max(r.tl.y, self.bound.tl.y));
+        let br = Point::new(min(r.br.x, self.bound.br.x), min(r.br.y, self.bound.br.y));
+        if tl.x > br.x || tl.y > br.y {
+            return;
+        }
+        let bpw = BITS_PER_WORD as i16;
+        for y in tl.y..=br.y {
+            let fill = pattern.word(y);
+            let mut x = tl.x;
+            while x <= br.x {
+                let word_start = x - x % bpw;
+                let last = min(word_start + bpw - 1, br.x);
+                let point = Point::new(x, y);
+                if x == word_start && last == word_start + bpw - 1 {
+                    self.set_word(point, fill);
+                } else {
+                    let bits = (last - x + 1) as u32;
+                    let mask = (Word::MAX >> (BITS_PER_WORD as u32 - bits)) << (x - word_start);
+                    let word = self.get_word(point);
+                    self.set_word(point, (word & !mask) | (fill & mask));
+                }
+                x = last + 1;
+            }
+        }
+    }
+
+    /// Walk the mosaic and write every pixel to `sink`, clipped to the sink dimensions.
+    pub fn render_to(&self, sink: &mut impl PixelSink) {
+        let dim = sink.dimensions();
+        for tile in self.tiles() {
+            let bound = tile.bound();
+            for y in max(bound.tl.y, 0)..=min(bound.br.y, dim.y - 1) {
+                for x in max(bound.tl.x, 0)..=min(bound.br.x, dim.x - 1) {
+                    let point = Point::new(x, y);
+                    sink.set_pixel(point, tile.get_pixel(point));
+                }
+            }
+        }
+    }
+
+    /// Iterate over the Words that hold the pixels within `region` (clipped to `bound`),
+    /// together with the Point of the first pixel in each Word. Words are aligned to the
+    /// left edge of the Bitmap, so the first Word on each line may start left of `region`
+    /// and the last may run past its right edge.
+    pub fn words_in(
+        &self,
+        region: Rectangle,
+        order: BitOrder,
+    ) -> impl Iterator<Item = (Point, Word)> + '_ {
+        let tl = Point::new(
+            max(region.tl.x, self.bound.tl.x),
+            max(region.tl.y, self.bound.tl.y),
+        );
+        let br = Point::new(
+            min(region.br.x, self.bound.br.x),
+            min(region.br.y, self.bound.br.y),
+        );
+        let bpw = BITS_PER_WORD as i16;
+        let first_x = tl.x - (tl.x - self.bound.tl.x) % bpw;
+        let (lines, words) = match tl.x > br.x || tl.y > br.y {
+            true => (0, 0),
+            false => (br.y - tl.y + 1, (br.x - first_x) / bpw + 1),
+        };
+        (0..lines).flat_map(move |line| {
+            (0..words).map(move |word| {
+                let point = Point::new(first_x + word * bpw, tl.y + line);
+                (point, order.pack(self.get_word(point)))
+            })
+        })
     }
 
     pub fn translate(&mut self, offset: Point) {
-        for tile in self.mosaic.as_mut_slice() {
+        for tref in self.mosaic.iter_mut() {
+            tref.bound.translate(offset);
+        }
+        for tile in self.unique.iter_mut() {
             tile.translate(offset);
         }
         self.bound.tl.x += offset.x;
@@ -331,39 +1106,153 @@ impl Bitmap {
         }
         r90
     }
-}
 
-impl Deref for Bitmap {
-    type Target = Vec<Tile>;
+    /// A mirror image of this Bitmap, left to right, over the same bound.
+    pub fn flip_horizontal(&self) -> Self {
+        let bpw = BITS_PER_WORD as i16;
+        let tl = self.bound.tl;
+        let width = (self.bound.br.x - tl.x + 1) as usize;
+        let words = (width + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        // unused bits at the end of the last word of a line
+        let pad = words * BITS_PER_WORD - width;
+        let mut flipped = Bitmap::new(self.bound.br - tl);
+        let mut line: Vec<Word> = vec![0; words];
+        for y in 0..=(self.bound.br.y - tl.y) {
+            for (i, word) in line.iter_mut().enumerate() {
+                *word = self.get_word(Point::new(tl.x + i as i16 * bpw, tl.y + y));
+            }
+            // reversing the order of the words, and of the bits within them, mirrors the line
+            // about the end of its last word; shifting out the padding (which lands at the start,
+            // and so also drops any stray bits it held) brings it back in line with the edge
+            for i in 0..words {
+                let hi = line[words - 1 - i].reverse_bits();
+                let lo = if i + 1 < words { line[words - 2 - i].reverse_bits() } else { 0 };
+                let word = if pad == 0 { hi } else { (hi >> pad) | (lo << (BITS_PER_WORD - pad)) };
+                flipped.set_word(Point::new(i as i16 * bpw, y), word);
+            }
+        }
+        flipped.translate(tl);
+        flipped
+    }
+
+    /// A mirror image of this Bitmap, top to bottom, over the same bound.
+    pub fn flip_vertical(&self) -> Self {
+        let bpw = BITS_PER_WORD as i16;
+        let tl = self.bound.tl;
+        let size = self.bound.br - tl;
+        let mut flipped = Bitmap::new(size);
+        for y in 0..=size.y {
+            let mut x = 0;
+            while x <= size.x {
+                flipped.set_word(Point::new(x, size.y - y), self.get_word(Point::new(tl.x + x, tl.y + y)));
+                x += bpw;
+            }
+        }
+        flipped.translate(tl);
+        flipped
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.mosaic
+    /// Lay `tiles` out left-to-right, top-to-bottom in `columns` columns, `spacing` pixels apart,
+    /// in a single Bitmap sized to fit them all. Every cell is as large as the largest input, and
+    /// inputs smaller than that sit in the top-left of their cell.
+    pub fn grid(tiles: &[&Bitmap], columns: usize, spacing: i16) -> Bitmap {
+        let columns = max(columns, 1);
+        let rows = (tiles.len() + columns - 1) / columns;
+        let spacing = max(spacing, 0);
+        let cell_x = tiles.iter().map(|bm| bm.bound.br.x - bm.bound.tl.x + 1).max().unwrap_or(1);
+        let cell_y = tiles.iter().map(|bm| bm.bound.br.y - bm.bound.tl.y + 1).max().unwrap_or(1);
+        let pitch = Point::new(cell_x + spacing, cell_y + spacing);
+        let size = Point::new(
+            min(columns, max(tiles.len(), 1)) as i16 * pitch.x - spacing - 1,
+            max(rows, 1) as i16 * pitch.y - spacing - 1,
+        );
+        let mut grid = Bitmap::new(size);
+        for (i, bm) in tiles.iter().enumerate() {
+            let at = Point::new((i % columns) as i16 * pitch.x, (i / columns) as i16 * pitch.y);
+            grid.blit(bm, at, BlitOp::Copy);
+        }
+        grid
     }
-}
 
-impl From<[Option<Tile>; 6]> for Bitmap {
-    fn from(tiles: [Option<Tile>; 6]) -> Self {
-        let mut mosaic: Vec<Tile> = Vec::new();
-        let mut tile_size = Point::new(0, 0);
-        for t in 0..tiles.len() {
-            if tiles[t].is_some() {
-                let tile = tiles[t].unwrap();
-                mosaic.push(tile);
-                if tile_size.x == 0 {
-                    tile_size = tile.size();
-                }
+    /// The Tiles of this Bitmap, clipped to `clip`, holding any pixel that differs from `prev`.
+    /// A pixel outside `prev` counts as different, and with no `prev` every Tile is returned.
+    pub fn changed_tiles(&self, prev: Option<&Bitmap>, clip: Rectangle) -> Vec<Tile> {
+        let mut changed = Vec::new();
+        for mut tile in self.tiles() {
+            let bound = tile.bound();
+            let tl = Point::new(max(bound.tl.x, clip.tl.x), max(bound.tl.y, clip.tl.y));
+            let br = Point::new(min(bound.br.x, clip.br.x), min(bound.br.y, clip.br.y));
+            if tl.x > br.x || tl.y > br.y {
+                continue;
+            }
+            let differs = match prev {
+                None => true,
+                Some(prev) => (tl.y..=br.y).any(|y| {
+                    (tl.x..=br.x).any(|x| {
+                        let point = Point::new(x, y);
+                        prev.try_get_pixel(point).map_or(true, |px| px != tile.get_pixel(point))
+                    })
+                }),
+            };
+            if differs {
+                tile.crop(Rectangle::new(tl, br));
+                changed.push(tile);
             }
         }
+        changed
+    }
 
-        Self {
-            width: (tile_size.x + 1) as usize,
-            bound: Self::hull(&mosaic),
-            tile_bits: (tile_size.x + 1) as usize * (tile_size.y + 1) as usize,
-            mosaic: mosaic,
+    /// Combine every pixel of `src` with this Bitmap by `op`, with the top-left of `src` placed
+    /// at `at`, as when drawing an icon onto a larger canvas. Pixels that fall outside this
+    /// Bitmap are dropped.
+    pub fn blit(&mut self, src: &Bitmap, at: Point, op: BlitOp) {
+        let offset = at - src.bound.tl;
+        for y in src.bound.tl.y..=src.bound.br.y {
+            for x in src.bound.tl.x..=src.bound.br.x {
+                let dest = Point::new(x, y) + offset;
+                if self.bound.intersects_point(dest) {
+                    let pixel = match op {
+                        BlitOp::Copy => src.get_pixel(Point::new(x, y)),
+                        op => op.apply(src.get_pixel(Point::new(x, y)), self.get_pixel(dest)),
+                    };
+                    self.set_pixel(dest, pixel);
+                }
+            }
         }
     }
 }
 
+impl Bitmap {
+    /// Like `Bitmap::from()`, but refuses Tiles that leave gaps in, or overlap within, the
+    /// area they span, rather than just logging a warning.
+    pub fn try_from_tiles(tiles: [Option<Tile>; 6]) -> Result<Self, BitmapError> {
+        let mosaic: Vec<Tile> = tiles.iter().flatten().copied().collect();
+        let hull = Self::try_hull(&mosaic)?;
+        Ok(Self::from_tiles(hull, mosaic))
+    }
+
+    fn from_tiles(hull: Rectangle, mosaic: Vec<Tile>) -> Self {
+        let tile_size = mosaic
+            .iter()
+            .map(|tile| tile.size())
+            .find(|size| size.x != 0)
+            .unwrap_or(Point::new(0, 0));
+        Self::from_mosaic(
+            (tile_size.x + 1) as usize,
+            hull,
+            (tile_size.x + 1) as usize * (tile_size.y + 1) as usize,
+            mosaic,
+        )
+    }
+}
+
+impl From<[Option<Tile>; 6]> for Bitmap {
+    fn from(tiles: [Option<Tile>; 6]) -> Self {
+        let mosaic: Vec<Tile> = tiles.iter().flatten().copied().collect();
+        Self::from_tiles(Self::hull(&mosaic), mosaic)
+    }
+}
+
 impl<'a> From<&Img> for Bitmap {
     fn from(image: &Img) -> Self {
         Bitmap::from_img(image, None)
@@ -375,16 +1264,692 @@ impl<'a> From<&Img> for Bitmap {
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    struct MemSink {
+        size: Point,
+        pixels: Vec<PixelColor>,
+    }
+
+    impl MemSink {
+        fn new(size: Point) -> Self {
+            Self {
+                size,
+                pixels: vec![PixelColor::Light; (size.x * size.y) as usize],
+            }
+        }
+
+        fn get(&self, x: i16, y: i16) -> PixelColor {
+            self.pixels[(x + y * self.size.x) as usize]
+        }
+    }
+
+    impl PixelSink for MemSink {
+        fn set_pixel(&mut self, point: Point, color: PixelColor) {
+            self.pixels[(point.x + point.y * self.size.x) as usize] = color;
+        }
+
+        fn dimensions(&self) -> Point {
+            self.size
+        }
+    }
+
+    #[test]
+    fn render_to_test() {
+        let mut bm = Bitmap::new(Point::new(40, 300));
+        let dark = [(0, 0), (39, 0), (5, 7), (33, 150), (0, 299), (40, 300)];
+        for (x, y) in dark {
+            bm.set_pixel(Point::new(x, y), PixelColor::Dark);
+        }
+        // the sink is smaller than the bitmap, so (40, 300) is clipped
+        let mut sink = MemSink::new(Point::new(40, 300));
+        bm.render_to(&mut sink);
+        for y in 0..300 {
+            for x in 0..40 {
+                let expected = match dark.contains(&(x, y)) {
+                    true => PixelColor::Dark,
+                    false => PixelColor::Light,
+                };
+                assert_eq!(sink.get(x, y), expected, "pixel ({}, {})", x, y);
+            }
+        }
+    }
+    #[test]
+    fn words_in_test() {
+        let mut bm = Bitmap::new(Point::new(99, 99));
+        // mark the first and last pixel of each line so every word is distinguishable
+        for y in 0..=99 {
+            bm.set_pixel(Point::new(0, y), PixelColor::Dark);
+            bm.set_pixel(Point::new(99, y), PixelColor::Dark);
+        }
+        let region = Rectangle::new(Point::new(40, 40), Point::new(59, 59));
+        let words: Vec<(Point, Word)> = bm.words_in(region, BitOrder::LsbFirst).collect();
+        // x=40..=59 falls within the second word of each line, which starts at x=32
+        assert_eq!(words.len(), 20);
+        for (i, (point, word)) in words.iter().enumerate() {
+            assert_eq!(*point, Point::new(32, 40 + i as i16));
+            assert_eq!(*word, 0);
+        }
+
+        // a region straddling a word boundary and the edge of the bitmap is clipped
+        let region = Rectangle::new(Point::new(30, 95), Point::new(120, 120));
+        let words: Vec<(Point, Word)> = bm.words_in(region, BitOrder::LsbFirst).collect();
+        let lines: Vec<i16> = words.iter().map(|(p, _)| p.y).collect();
+        let expected: Vec<i16> = (95..=99).flat_map(|y| [y; 4]).collect();
+        assert_eq!(lines, expected);
+        for (i, (point, word)) in words.iter().enumerate() {
+            // the first word on each line starts at x=0, left of the region
+            assert_eq!(point.x, (i % 4) as i16 * 32);
+            match point.x {
+                0 => assert_eq!(*word, 1),
+                96 => assert_eq!(*word, 1 << (99 % 32)),
+                _ => assert_eq!(*word, 0),
+            }
+        }
+
+        let outside = Rectangle::new(Point::new(200, 200), Point::new(210, 210));
+        assert_eq!(bm.words_in(outside, BitOrder::LsbFirst).count(), 0);
+    }
+
     #[test]
+    fn bit_order_test() {
+        let mut bm = Bitmap::new(Point::new(63, 3));
+        for (x, y) in [(0, 0), (9, 0), (31, 1), (33, 2), (60, 3), (63, 3)] {
+            bm.set_pixel(Point::new(x, y), PixelColor::Dark);
+        }
+        let lsb: Vec<(Point, Word)> = bm.words_in(bm.bound, BitOrder::LsbFirst).collect();
+        let msb: Vec<(Point, Word)> = bm.words_in(bm.bound, BitOrder::MsbFirst).collect();
+        assert_eq!(lsb.len(), msb.len());
+        for ((lsb_point, lsb_word), (msb_point, msb_word)) in lsb.iter().zip(msb.iter()) {
+            assert_eq!(lsb_point, msb_point);
+            for (l, m) in lsb_word.to_le_bytes().iter().zip(msb_word.to_le_bytes().iter()) {
+                assert_eq!(l.reverse_bits(), *m);
+            }
+        }
+        // the leftmost pixel lands in bit 0 or bit 7 of the first byte
+        assert_eq!(lsb[0].1, 0x0000_0201);
+        assert_eq!(msb[0].1, 0x0000_4080);
+        // pixel 31 is the top bit of the last byte, so it moves to the bottom bit of that byte
+        assert_eq!(lsb[2].1, 0x8000_0000);
+        assert_eq!(msb[2].1, 0x0100_0000);
+    }
+
+    #[test]
+    fn dedup_test() {
+        // three full tiles, each 4 words wide by 255 lines
+        let mut bm = Bitmap::new(Point::new(99, 3 * 255 - 1));
+        assert_eq!(bm.tiles().count(), 3);
+        assert_eq!(bm.unique_tile_count(), 3);
+        bm.dedup();
+        assert_eq!(bm.unique_tile_count(), 1);
+        assert_eq!(bm.refs, [3]);
+
+        // writing to one logical tile gives it a private copy, leaving the others blank
+        bm.set_pixel(Point::new(10, 300), PixelColor::Dark);
+        assert_eq!(bm.unique_tile_count(), 2);
+        assert_eq!(bm.refs, [2, 1]);
+        assert_eq!(bm.get_pixel(Point::new(10, 300)), PixelColor::Dark);
+        assert_eq!(bm.get_pixel(Point::new(10, 45)), PixelColor::Light);
+        assert_eq!(bm.get_pixel(Point::new(10, 555)), PixelColor::Light);
+        // a private tile is written in place
+        bm.set_pixel(Point::new(11, 300), PixelColor::Dark);
+        assert_eq!(bm.unique_tile_count(), 2);
+        let tops: Vec<i16> = bm.tiles().map(|t| t.bound().tl.y).collect();
+        assert_eq!(tops, [0, 255, 510]);
+        assert_eq!(bm.tiles().nth(1).unwrap().get_pixel(Point::new(11, 300)), PixelColor::Dark);
 
+        // once the content matches again, dedup collapses it back to one tile
+        for y in [45, 555] {
+            bm.set_pixel(Point::new(10, y), PixelColor::Dark);
+            bm.set_pixel(Point::new(11, y), PixelColor::Dark);
+        }
+        assert_eq!(bm.unique_tile_count(), 3);
+        assert_eq!(bm.refs, [1, 1, 1]);
+        bm.dedup();
+        assert_eq!(bm.unique_tile_count(), 1);
+        for y in [45, 300, 555] {
+            assert_eq!(bm.get_pixel(Point::new(11, y)), PixelColor::Dark);
+            assert_eq!(bm.get_pixel(Point::new(12, y)), PixelColor::Light);
+        }
+    }
+
+    #[test]
+    fn from_image_fitted_test() {
+        // a portrait image: 40 wide, 100 tall, dark in the top half
+        let (width, height) = (40, 100);
+        let mut pixels = vec![0u8; width * height / 2];
+        pixels.extend(vec![255u8; width * height / 2]);
+        let img = Img::new(pixels, width, PixelType::U8);
+        // a landscape target, offset from the origin
+        let target = Rectangle::new(Point::new(10, 20), Point::new(209, 79));
+
+        let (bm, transform) = Bitmap::from_image_fitted(&img, target, DitherScheme::Burkes, true);
+        assert!(transform.rotated);
+        assert_eq!(transform.scale, 1.0);
+        assert_eq!(transform.offset, target.tl);
+        assert_eq!(bm.bound.tl, target.tl);
+        assert!(bm.bound.br.x <= target.br.x && bm.bound.br.y <= target.br.y);
+        // rotated, the image is now 100 wide and 40 tall
+        assert_eq!(bm.bound.br.x - bm.bound.tl.x + 1, height as i16);
+        assert_eq!(bm.bound.br.y - bm.bound.tl.y + 1, width as i16);
+
+        // without rotation the image has to shrink to fit the 60 pixel height
+        let (bm, transform) = Bitmap::from_image_fitted(&img, target, DitherScheme::Burkes, false);
+        assert!(!transform.rotated);
+        assert!(transform.scale < 1.0);
+        assert!(bm.bound.br.x <= target.br.x && bm.bound.br.y <= target.br.y);
+        // and it keeps its portrait orientation
+        assert!(bm.bound.br.y - bm.bound.tl.y > bm.bound.br.x - bm.bound.tl.x);
+    }
+
+    #[test]
+    fn new_resize_test() {
+        // 20 wide, 10 tall, with 0 in the top half and 255 in the bottom half
+        let (width, height) = (20, 10);
+        let mut pixels = vec![0u8; width * height / 2];
+        pixels.extend(vec![255u8; width * height / 2]);
+        let img = Img::new(pixels, width, PixelType::U8);
+
+        // enlarged to 3 times the width, and so 3 times the height
+        let bm = Bitmap::new_resize(&img, 3 * width, None);
+        assert_eq!(bm.bound.br.x - bm.bound.tl.x + 1, 3 * width as i16);
+        assert_eq!(bm.bound.br.y - bm.bound.tl.y + 1, 3 * height as i16);
+        assert_eq!(bm.get_pixel(Point::new(30, 14)), PixelColor::Light);
+        assert_eq!(bm.get_pixel(Point::new(30, 15)), PixelColor::Dark);
+
+        // and shrunk to half
+        let bm = Bitmap::new_resize(&img, width / 2, None);
+        assert_eq!(bm.bound.br.x - bm.bound.tl.x + 1, width as i16 / 2);
+        assert_eq!(bm.bound.br.y - bm.bound.tl.y + 1, height as i16 / 2);
+
+        // a mid-grey image dithers to more Light pixels once gamma lightens it
+        let img = Img::new(vec![100u8; width * height], width, PixelType::U8);
+        let plain = Bitmap::new_resize(&img, width, None);
+        let light = Bitmap::new_resize(&img, width, Some(1.0 / 2.2));
+        assert_eq!(plain.popcount(), Bitmap::new_resize(&img, width, Some(1.0)).popcount());
+        assert!(light.popcount() > plain.popcount());
+    }
+
+    #[test]
+    fn from_img_threshold_test() {
+        // a mid-grey image comes out as a flat colour either side of the cutoff
+        let (width, height) = (50, 40);
+        let img = Img::new(vec![100u8; width * height], width, PixelType::U8);
+        let bm = Bitmap::from_img_threshold(&img, DEFAULT_CUTOFF);
+        assert_eq!(bm.bound.br.x - bm.bound.tl.x + 1, width as i16);
+        assert_eq!(bm.bound.br.y - bm.bound.tl.y + 1, height as i16);
+        // pixels below the cutoff are Dark, and pixels at or above it are Light. Like Dither,
+        // Threshold packs each pixel as `PixelColor as u32`, the polarity the frame buffer is
+        // drawn in, so read the colour back the same way
+        let shade = |bm: &Bitmap, point: Point| {
+            match (bm.get_word(point) >> (point.x as usize % BITS_PER_WORD)) & 1 {
+                bit if bit == PixelColor::Dark as u32 => PixelColor::Dark,
+                _ => PixelColor::Light,
+            }
+        };
+        let dark = Bitmap::from_img_threshold(&img, 101);
+        let light = Bitmap::from_img_threshold(&img, 100);
+        for point in [Point::new(0, 0), Point::new(49, 39), Point::new(33, 21)] {
+            assert_eq!(shade(&bm, point), PixelColor::Dark);
+            assert_eq!(shade(&dark, point), PixelColor::Dark);
+            assert_eq!(shade(&light, point), PixelColor::Light);
+        }
+    }
+
+    #[test]
+    fn invert_test() {
+        // a width that leaves padding at the end of each line
+        let mut bm = Bitmap::new(Point::new(40, 60));
+        bm.fill_pattern(Rectangle::new(Point::new(3, 5), Point::new(37, 50)), DitherPattern::Half);
+        bm.set_pixel(Point::new(40, 60), PixelColor::Dark);
+        // every Word of every line, padding included
+        let words = |bm: &Bitmap| -> Vec<Word> {
+            let mut words = Vec::new();
+            for tile in bm.tiles() {
+                for y in tile.bound().tl.y..=tile.bound().br.y {
+                    words.extend_from_slice(tile.row_words(y).unwrap());
+                }
+            }
+            words
+        };
+        let before = words(&bm);
+        let (area, dark) = ((41 * 61) as u32, bm.popcount());
+
+        bm.invert();
+        assert_eq!(bm.popcount(), area - dark);
+        assert_eq!(bm.get_pixel(Point::new(40, 60)), PixelColor::Light);
+        assert_eq!(bm.get_pixel(Point::new(0, 0)), PixelColor::Dark);
+
+        bm.invert();
+        assert_eq!(words(&bm), before);
+        assert_eq!(bm.popcount(), dark);
+    }
+
+    #[test]
+    fn blit_test() {
+        // a 4x4 icon, Dark in its left half
+        let mut icon = Bitmap::new(Point::new(3, 3));
+        for y in 0..4 {
+            for x in 0..2 {
+                icon.set_pixel(Point::new(x, y), PixelColor::Dark);
+            }
+        }
+        // a canvas Dark in its top half
+        let canvas = || {
+            let mut canvas = Bitmap::new(Point::new(15, 15));
+            for y in 0..8 {
+                for x in 0..16 {
+                    canvas.set_pixel(Point::new(x, y), PixelColor::Dark);
+                }
+            }
+            canvas
+        };
+        let dark = |bm: &Bitmap, x, y| bm.get_pixel(Point::new(x, y)) == PixelColor::Dark;
+
+        // placed across the middle, so each quarter of the icon lands on a different pair
+        let at = Point::new(4, 6);
+        let cases = [
+            (BlitOp::Copy, [true, false, true, false]),
+            (BlitOp::Or, [true, true, true, false]),
+            (BlitOp::And, [true, false, false, false]),
+            (BlitOp::Xor, [false, true, true, false]),
+        ];
+        for (op, expect) in cases.iter() {
+            let mut bm = canvas();
+            bm.blit(&icon, at, *op);
+            // (icon Dark, canvas Dark), (icon Light, canvas Dark), then over a Light canvas
+            let got = [dark(&bm, 4, 6), dark(&bm, 6, 6), dark(&bm, 4, 9), dark(&bm, 6, 9)];
+            assert_eq!(&got, expect, "{:?}", op);
+            // pixels outside the icon are untouched
+            assert!(dark(&bm, 3, 6) && dark(&bm, 8, 7) && !dark(&bm, 3, 9) && !dark(&bm, 8, 9));
+        }
+
+        // clipped at the right and bottom edges of the canvas
+        let mut bm = Bitmap::new(Point::new(15, 15));
+        bm.blit(&icon, Point::new(14, 14), BlitOp::Copy);
+        assert!(dark(&bm, 14, 14) && dark(&bm, 15, 15));
+        assert_eq!(bm.size(), (15, 15));
+        // and at the left and top, where only the Light right half of the icon lands
+        let mut bm = Bitmap::new(Point::new(15, 15));
+        bm.blit(&icon, Point::new(-2, -2), BlitOp::Copy);
+        assert_eq!(bm.popcount(), 0);
+        bm.blit(&icon, Point::new(-1, -1), BlitOp::Copy);
+        assert!(dark(&bm, 0, 0) && dark(&bm, 0, 2) && !dark(&bm, 1, 0));
+    }
+
+    #[test]
+    fn from_bmp_test() {
+        // a 3x2 24 bit BMP, bottom-up with each 9 byte row padded to 12: a white bottom row
+        // and a black top row
+        let mut bytes = b"BM".to_vec();
+        for word in [14 + 40 + 24u32, 0, 14 + 40, 40, 3, 2] {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes.extend_from_slice(&[1, 0, 24, 0]);
+        bytes.extend_from_slice(&[0; 24]);
+        bytes.extend_from_slice(&[255; 9]);
+        bytes.extend_from_slice(&[0; 3]);
+        bytes.extend_from_slice(&[0; 12]);
+
+        let bm = Bitmap::from_bmp(&bytes, None);
+        assert_eq!(bm.size(), (2, 1));
+        // the rows come out top-down, as in new_resize_test()
+        assert_eq!(bm.get_pixel(Point::new(1, 0)), PixelColor::Light);
+        assert_eq!(bm.get_pixel(Point::new(1, 1)), PixelColor::Dark);
+
+        assert_eq!(Bitmap::try_from_bmp(&bytes[..60], None).err(), Some(BmpError::Truncated));
+    }
+
+    #[test]
+    fn new_size_test() {
+        // the size is the bottom-right pixel, so 0x0 is a single pixel
+        let mut bm = Bitmap::new(Point::new(0, 0));
+        assert_eq!(bm.size(), (0, 0));
+        assert_eq!(bm.mosaic.len(), 1);
+        bm.set_pixel(Point::new(0, 0), PixelColor::Dark);
+        assert_eq!(bm.popcount(), 1);
+        assert_eq!(bm.try_get_pixel(Point::new(1, 0)), Err(BitmapError::OutOfBounds));
+        let bm = Bitmap::new(Point::new(1, 1));
+        assert_eq!(bm.popcount(), 0);
+        assert_eq!(bm.try_get_pixel(Point::new(1, 1)), Ok(PixelColor::Light));
+
+        // no pixels at all
+        for size in [Point::new(-1, 0), Point::new(0, -1), Point::new(-5, -5)] {
+            assert_eq!(Bitmap::try_new(size).err(), Some(BitmapError::SizeTooLarge));
+        }
+
+        // a Tile of one Word wide lines holds WORDS_PER_TILE lines
+        let rows = WORDS_PER_TILE as i16;
+        let bm = Bitmap::new(Point::new(31, rows - 1));
+        assert_eq!(bm.mosaic.len(), 1);
+        let mut bm = Bitmap::new(Point::new(31, rows));
+        assert_eq!(bm.mosaic.len(), 2);
+        for y in [rows - 1, rows] {
+            bm.set_pixel(Point::new(31, y), PixelColor::Dark);
+            assert_eq!(bm.get_pixel(Point::new(31, y)), PixelColor::Dark);
+        }
+        assert_eq!(bm.popcount(), 2);
+    }
+
+    #[test]
+    fn bitmap_error_test() {
+        // a line one pixel wider than a Tile can hold
+        let wide = BITS_PER_TILE as i16;
+        assert!(Bitmap::try_new(Point::new(wide - 1, 10)).is_ok());
+        let err = Bitmap::try_new(Point::new(wide, 10)).unwrap_err();
+        assert_eq!(err, BitmapError::SizeTooLarge);
+        let img = Img::new(vec![0u8; wide as usize + 1], wide as usize + 1, PixelType::U8);
+        assert_eq!(Bitmap::try_from_img(&img, None).unwrap_err(), BitmapError::SizeTooLarge);
+        // fitting shrinks it to something that does fit
+        assert!(Bitmap::try_from_img(&img, Some(Point::new(336, 536))).is_ok());
+
+        let mut bm = Bitmap::try_new(Point::new(99, 99)).unwrap();
+        let before = bm.get_tile(Point::new(0, 0)).bound();
+        for outside in [Point::new(100, 5), Point::new(5, -1), Point::new(-1, 5)] {
+            assert_eq!(bm.try_get_pixel(outside), Err(BitmapError::OutOfBounds));
+            assert_eq!(bm.try_set_pixel(outside, PixelColor::Dark), Err(BitmapError::OutOfBounds));
+            assert_eq!(bm.try_get_line(outside), Err(BitmapError::OutOfBounds));
+            assert!(bm.try_get_tile(outside).is_err());
+        }
+        // the rejected writes didn't land in the first Tile instead
+        let after = bm.get_tile(Point::new(0, 0)).bound();
+        assert_eq!((after.tl, after.br), (before.tl, before.br));
+        assert_eq!(bm.try_set_pixel(Point::new(99, 99), PixelColor::Dark), Ok(()));
+        assert_eq!(bm.try_get_pixel(Point::new(99, 99)), Ok(PixelColor::Dark));
+    }
+
+    #[test]
+    fn memory_budget_test() {
+        let (width, height) = (800, 1200);
+        let img = Img::new(vec![128u8; width * height], width, PixelType::U8);
+        let fit = Some(Point::new(336, 536));
+        let px_size = Point::new(width as i16, height as i16);
+        let fitted = Bitmap::estimate_bytes(px_size, fit, true).unwrap();
+        // fitting to the screen takes a handful of Tiles, not the dozens of the full image
+        assert!(fitted * 3 < Bitmap::estimate_bytes(px_size, None, true).unwrap());
+        assert!(fitted >= 336 * 536 / 8);
+
+        let err = Bitmap::try_from_img_within(&img, fit, fitted - 1).unwrap_err();
+        assert_eq!(err, BitmapError::SizeTooLarge);
+        let bm = Bitmap::try_from_img_within(&img, fit, fitted).unwrap();
+        assert!(bm.bound.br.x < 336 && bm.bound.br.y < 536);
+        // the estimate covers what was actually allocated for the Tiles
+        assert!(bm.unique.capacity() * size_of::<Tile>() <= fitted);
+    }
+
+    #[test]
+    fn patch_test() {
+        // 200 pixels wide takes 7 words a line, so 600 lines span 5 Tiles
+        let size = Point::new(199, 599);
+        let frame = |dots: &[(i16, i16)]| {
+            let mut bm = Bitmap::new(size);
+            for &(x, y) in dots {
+                bm.set_pixel(Point::new(x, y), PixelColor::Dark);
+            }
+            bm
+        };
+        let mut previous = frame(&[(10, 10), (150, 400)]);
+        let current = frame(&[(10, 10), (150, 400), (20, 300), (199, 599)]);
+        assert_eq!(current.mosaic.len(), 5);
+
+        let patch = current.make_patch(&previous);
+        assert_eq!(patch.tile_count, 5);
+        assert_eq!(patch.tiles.iter().map(|t| t.index).collect::<Vec<u32>>(), vec![2, 4]);
+        previous.apply_patch(&patch).unwrap();
+        assert!(previous.make_patch(&current).tiles.is_empty());
+        for (a, b) in previous.tiles().zip(current.tiles()) {
+            assert!(Bitmap::same_content(&a, &b));
+        }
+        assert!(current.make_patch(&current).tiles.is_empty());
+
+        // a patch onto deduped storage only changes the Tiles it names
+        let mut blank = frame(&[]);
+        blank.dedup();
+        // the four full Tiles share storage; the last, shorter one has its own
+        assert_eq!(blank.unique_tile_count(), 2);
+        blank.apply_patch(&frame(&[(5, 200)]).make_patch(&frame(&[]))).unwrap();
+        assert_eq!(blank.unique_tile_count(), 3);
+        assert_eq!(blank.get_pixel(Point::new(5, 200)), PixelColor::Dark);
+        assert_eq!(blank.popcount(), 1);
+
+        // a patch for a different layout is refused, and leaves the target as it was
+        let mut other = Bitmap::new(Point::new(99, 599));
+        assert_eq!(other.apply_patch(&patch), Err(BitmapError::PatchMismatch));
+        let mut shifted = frame(&[]);
+        shifted.translate(Point::new(0, 1));
+        assert_eq!(shifted.apply_patch(&patch), Err(BitmapError::PatchMismatch));
+        assert_eq!(shifted.popcount(), 0);
+    }
+
+    #[test]
+    fn try_from_tiles_test() {
+        let tile = |tl: (i16, i16), br: (i16, i16)| {
+            Some(Tile::new(Rectangle::new(Point::new(tl.0, tl.1), Point::new(br.0, br.1))))
+        };
+        let adjacent = [tile((0, 0), (31, 9)), tile((0, 10), (31, 19)), None, None, None, None];
+        let bm = Bitmap::try_from_tiles(adjacent).unwrap();
+        assert_eq!((bm.bound.tl, bm.bound.br), (Point::new(0, 0), Point::new(31, 19)));
+
+        let gap = [tile((0, 0), (31, 9)), None, tile((0, 20), (31, 29)), None, None, None];
+        assert_eq!(Bitmap::try_from_tiles(gap).unwrap_err(), BitmapError::TileGap);
+        let overlap = [tile((0, 0), (31, 9)), tile((0, 5), (31, 14)), None, None, None, None];
+        assert_eq!(Bitmap::try_from_tiles(overlap).unwrap_err(), BitmapError::TileOverlap);
+        assert_eq!(Bitmap::try_from_tiles([None; 6]).unwrap_err(), BitmapError::TileGap);
+    }
+
+    #[test]
+    fn row_words_test() {
+        let mut bm = Bitmap::new(Point::new(99, 199));
+        for y in 0..=199 {
+            bm.set_pixel(Point::new(y % 100, y), PixelColor::Dark);
+        }
+        for y in [0, 1, 63, 64, 100, 150, 199] {
+            let point = Point::new(0, y);
+            assert_eq!(bm.row_words(point).unwrap(), bm.get_line(point).as_slice());
+        }
+        assert!(bm.row_words(Point::new(0, 200)).is_none());
+        assert!(bm.row_words(Point::new(-1, 0)).is_none());
+
+        let mut tile = Tile::new(Rectangle::new(Point::new(0, 10), Point::new(40, 20)));
+        tile.row_words_mut(12).unwrap()[1] = 0b101;
+        assert_eq!(tile.row_words(12).unwrap(), tile.get_line(Point::new(0, 12)).as_slice());
+        assert_eq!(tile.get_pixel(Point::new(32, 12)), PixelColor::Dark);
+        assert_eq!(tile.get_pixel(Point::new(33, 12)), PixelColor::Light);
+        assert!(tile.row_words(9).is_none());
+        assert!(tile.row_words(21).is_none());
+    }
+
+    #[test]
+    fn popcount_test() {
+        // 100 px wide lines span three full words and a partial one; 599 lines span three tiles,
+        // the last of them only partly used
+        let mut bm = Bitmap::new(Point::new(99, 598));
+        assert_eq!(bm.popcount(), 0);
+        let mut expected = 0;
+        for y in 0..=598 {
+            for x in 0..=99 {
+                if (x + y) % 2 == 0 {
+                    bm.set_pixel(Point::new(x, y), PixelColor::Dark);
+                    expected += 1;
+                }
+            }
+        }
+        assert!(bm.mosaic.len() > 2);
+        assert_eq!(bm.popcount(), expected);
+
+        // bits in the padding past the right edge of a line don't count
+        let line = Point::new(0, 598);
+        let padding = bm.get_word(Point::new(96, 598)) | !0xF;
+        bm.set_word(Point::new(96, 598), padding);
+        assert_eq!(bm.get_line(line)[3].count_ones(), 30);
+        assert_eq!(bm.popcount(), expected);
+    }
+
+    #[test]
+    fn fill_pattern_test() {
+        let mut bm = Bitmap::new(Point::new(99, 99));
+        let r = Rectangle::new(Point::new(3, 5), Point::new(70, 40));
+        bm.fill_pattern(r, DitherPattern::Half);
+        let area = (70 - 3 + 1) * (40 - 5 + 1);
+        let mut set = 0;
+        for y in 0..=99 {
+            for x in 0..=99 {
+                let dark = bm.get_pixel(Point::new(x, y)) == PixelColor::Dark;
+                if x < 3 || x > 70 || y < 5 || y > 40 {
+                    assert!(!dark, "pixel outside the rectangle set at {}, {}", x, y);
+                } else if dark {
+                    set += 1;
+                }
+            }
+        }
+        assert!((set * 2 - area as i32).abs() <= 36, "{} of {} set", set, area);
+
+        // the other densities, clipped to the bound, and refilling over existing content
+        let mut bm = Bitmap::new(Point::new(99, 99));
+        bm.fill_pattern(Rectangle::new(Point::new(-10, -10), Point::new(200, 200)), DitherPattern::Quarter);
+        assert_eq!(bm.popcount(), 100 * 100 / 4);
+        bm.fill_pattern(Rectangle::new(Point::new(0, 0), Point::new(99, 99)), DitherPattern::ThreeQuarters);
+        assert_eq!(bm.popcount(), 100 * 100 * 3 / 4);
+        bm.fill_pattern(Rectangle::new(Point::new(150, 0), Point::new(200, 99)), DitherPattern::Half);
+        assert_eq!(bm.popcount(), 100 * 100 * 3 / 4);
+    }
+
+    #[test]
+    fn flip_test() {
+        // 45 px lines end in a partial word, 64 px lines in a full one; 250 lines span two tiles
+        for &size in [Point::new(44, 249), Point::new(63, 20), Point::new(0, 0)].iter() {
+            let mut bm = Bitmap::new(size);
+            for y in 0..=size.y {
+                for x in 0..=size.x {
+                    if (x * 7 + y * 3) % 5 == 0 || x == 0 {
+                        bm.set_pixel(Point::new(x, y), PixelColor::Dark);
+                    }
+                }
+            }
+            // a stray bit in the padding of a line mustn't be mirrored into view
+            if size.x == 44 {
+                let padding = bm.get_word(Point::new(32, 0)) | 1 << 31;
+                bm.set_word(Point::new(32, 0), padding);
+            }
+            let h = bm.flip_horizontal();
+            let v = bm.flip_vertical();
+            assert!(Bitmap::same_bound(&h.bound, &bm.bound));
+            assert!(Bitmap::same_bound(&v.bound, &bm.bound));
+            for y in 0..=size.y {
+                for x in 0..=size.x {
+                    let px = bm.get_pixel(Point::new(x, y));
+                    assert_eq!(h.get_pixel(Point::new(size.x - x, y)), px, "h {} {}", x, y);
+                    assert_eq!(v.get_pixel(Point::new(x, size.y - y)), px, "v {} {}", x, y);
+                }
+            }
+            assert_eq!(h.popcount(), bm.popcount());
+            // flipping back restores the line, less the stray bit
+            let mut line = bm.get_line(Point::new(0, 0));
+            if size.x == 44 {
+                line[1] &= !(1 << 31);
+            }
+            assert_eq!(h.flip_horizontal().get_line(Point::new(0, 0)), line);
+        }
+
+        // a translated Bitmap is flipped in place (across by a whole word, as Tile's pixel
+        // accessors take the bit within a word from the absolute x)
+        let mut bm = Bitmap::new(Point::new(39, 9));
+        bm.set_pixel(Point::new(2, 3), PixelColor::Dark);
+        bm.translate(Point::new(32, 20));
+        let h = bm.flip_horizontal();
+        assert!(Bitmap::same_bound(&h.bound, &bm.bound));
+        assert_eq!(h.get_pixel(Point::new(32 + 39 - 2, 23)), PixelColor::Dark);
+        assert_eq!(h.popcount(), 1);
+        let v = bm.flip_vertical();
+        assert_eq!(v.get_pixel(Point::new(34, 20 + 9 - 3)), PixelColor::Dark);
+        assert_eq!(v.popcount(), 1);
+    }
+
+    #[test]
+    fn grid_test() {
+        let sizes = [Point::new(9, 9), Point::new(19, 4), Point::new(4, 14), Point::new(7, 7)];
+        let bitmaps: Vec<Bitmap> = sizes
+            .iter()
+            .map(|&size| {
+                let mut bm = Bitmap::new(size);
+                bm.set_pixel(Point::new(1, 2), PixelColor::Dark);
+                bm
+            })
+            .collect();
+        let refs: Vec<&Bitmap> = bitmaps.iter().collect();
+        let grid = Bitmap::grid(&refs, 2, 3);
+        // cells are 20x15, the size of the widest and tallest inputs, with 3 pixels between them
+        assert_eq!(grid.size(), (42, 32));
+        let markers = [Point::new(1, 2), Point::new(24, 2), Point::new(1, 20), Point::new(24, 20)];
+        for &marker in markers.iter() {
+            assert_eq!(grid.get_pixel(marker), PixelColor::Dark);
+        }
+        let mut dark = 0;
+        for y in 0..=32 {
+            for x in 0..=42 {
+                if grid.get_pixel(Point::new(x, y)) == PixelColor::Dark {
+                    dark += 1;
+                }
+            }
+        }
+        assert_eq!(dark, markers.len());
+    }
+
+    struct MockTileSink {
+        pushed: Vec<Rectangle>,
+    }
+
+    impl TileSink for MockTileSink {
+        type Error = ();
+        fn push_tile(&mut self, tile: Tile) -> Result<(), ()> {
+            self.pushed.push(tile.bound());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn play_sequence_test() {
+        // tall enough to span several tiles
+        let size = Point::new(99, 599);
+        let first = Bitmap::new(size);
+        let tile_count = first.tiles().count();
+        assert!(tile_count > 2);
+        let mut second = Bitmap::new(size);
+        second.set_pixel(Point::new(50, 0), PixelColor::Dark);
+        let mut third = Bitmap::new(size);
+        third.set_pixel(Point::new(50, 0), PixelColor::Dark);
+        third.set_pixel(Point::new(50, 599), PixelColor::Dark);
+        // a larger frame is clipped to the first; its extra rows and columns never go out
+        let mut fourth = Bitmap::new(Point::new(199, 799));
+        fourth.set_pixel(Point::new(50, 0), PixelColor::Dark);
+        fourth.set_pixel(Point::new(50, 599), PixelColor::Dark);
+        fourth.set_pixel(Point::new(150, 700), PixelColor::Dark);
+
+        let mut sink = MockTileSink { pushed: Vec::new() };
+        let frames = [first, second, third, fourth];
+        let mut sleeps = Vec::new();
+        play_sequence(&frames, 20, &mut sink, |ms| sleeps.push(ms)).unwrap();
+        assert_eq!(sleeps, vec![50, 50, 50]);
+        // all of the first frame, then the top tile, then the bottom tile, then nothing
+        assert_eq!(sink.pushed.len(), tile_count + 2);
+        let top = sink.pushed[tile_count];
+        assert_eq!((top.tl.y, top.br.x), (0, 99));
+        let bottom = sink.pushed[tile_count + 1];
+        assert_eq!((bottom.br.x, bottom.br.y), (99, 599));
+    }
+
+    #[test]
     fn bitmap_test() {
         let x_size = 100;
         let y_size = 10;
-        let bm = Bitmap::new(Point::new(x_size, y_size));
-        assert_equal!(bm.size.x, x_size);
-        assert_equal!(bm.size.y, y_size);
-        assert_equal!(bm.get(5, 5), PixelColor::Light);
-        bm.set(5, 5, PixelColor::Dark);
-        assert_equal!(bm.get(5, 5), PixelColor::Dark);
+        let mut bm = Bitmap::new(Point::new(x_size, y_size));
+        assert_eq!(bm.size(), (x_size as usize, y_size as usize));
+        assert_eq!(bm.get_pixel(Point::new(5, 5)), PixelColor::Light);
+        bm.set_pixel(Point::new(5, 5), PixelColor::Dark);
+        assert_eq!(bm.get_pixel(Point::new(5, 5)), PixelColor::Dark);
+
+        // an out of bounds point is logged and falls back to the first Tile
+        let outside = Point::new(x_size + 1, y_size + 1);
+        assert_eq!(bm.tile_index(outside), Err(BitmapError::OutOfBounds));
+        assert_eq!(bm.get_tile_index(outside), 0);
     }
 }
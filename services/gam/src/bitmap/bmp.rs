@@ -0,0 +1,201 @@
+/*
+ * A minimal decoder for uncompressed 24 and 32 bit Windows BMP images.
+ *
+ * The BMP format is a 14 byte file header followed by a DIB header and the pixels
+ * - The file header begins with the signature "BM" and gives the offset of the pixels.
+ * - The DIB header gives the width, height, bits per pixel and compression. A negative
+ *   height means the rows are stored top-down, otherwise they are stored bottom-up.
+ * - Pixels are stored blue, green, red (and alpha), and each row is padded to 4 bytes.
+ * - 32 bit images may use BI_BITFIELDS, which is accepted when the masks give the usual
+ *   blue, green, red, alpha byte order.
+ */
+
+use std::convert::TryInto;
+
+use crate::bitmap::{BitmapError, Img, PixelType};
+
+const BMP_SIGNATURE: [u8; 2] = *b"BM";
+const FILE_HEADER_LEN: usize = 14;
+/// the BITMAPINFOHEADER, the smallest DIB header with a compression field
+const INFO_HEADER_LEN: usize = 40;
+// compression methods
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+/// the red, green and blue masks of a BI_BITFIELDS image stored as blue, green, red, alpha
+const BGRA_MASKS: [u32; 3] = [0x00FF_0000, 0x0000_FF00, 0x0000_00FF];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BmpError {
+    /// the bytes don't begin with the BMP signature
+    NotBmp,
+    /// the bytes end before the headers or pixels they describe
+    Truncated,
+    /// the pixels are run-length encoded, or otherwise compressed
+    Compressed,
+    /// the pixels are indices into a palette (8 bits per pixel or fewer)
+    Palettized,
+    /// the header is an old OS/2 one, or the pixels are neither 24 nor 32 bit
+    Unsupported,
+    /// the image was decoded, but couldn't be made into a Bitmap
+    Bitmap(BitmapError),
+}
+
+impl From<BitmapError> for BmpError {
+    fn from(err: BitmapError) -> Self {
+        BmpError::Bitmap(err)
+    }
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> Result<u16, BmpError> {
+    match bytes.get(at..at + 2) {
+        Some(b) => Ok(u16::from_le_bytes(b.try_into().unwrap())),
+        None => Err(BmpError::Truncated),
+    }
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> Result<u32, BmpError> {
+    match bytes.get(at..at + 4) {
+        Some(b) => Ok(u32::from_le_bytes(b.try_into().unwrap())),
+        None => Err(BmpError::Truncated),
+    }
+}
+
+/// Decode an uncompressed 24 bit (U8x3) or 32 bit (U8x4) BMP into an Img, with the rows
+/// top-down and the pixels red, green, blue (and alpha) as Img expects.
+pub fn decode_bmp(bytes: &[u8]) -> Result<Img, BmpError> {
+    if bytes.get(0..2) != Some(&BMP_SIGNATURE[..]) {
+        return Err(BmpError::NotBmp);
+    }
+    let offset = u32_at(bytes, 10)? as usize;
+    let header_len = u32_at(bytes, FILE_HEADER_LEN)? as usize;
+    if header_len < INFO_HEADER_LEN {
+        return Err(BmpError::Unsupported);
+    }
+    let info = FILE_HEADER_LEN;
+    let width = u32_at(bytes, info + 4)? as i32;
+    let height = u32_at(bytes, info + 8)? as i32;
+    let bpp = u16_at(bytes, info + 14)?;
+    let compression = u32_at(bytes, info + 16)?;
+
+    let px_type = match bpp {
+        24 => PixelType::U8x3,
+        32 => PixelType::U8x4,
+        1 | 2 | 4 | 8 => return Err(BmpError::Palettized),
+        _ => return Err(BmpError::Unsupported),
+    };
+    match compression {
+        BI_RGB => {}
+        BI_BITFIELDS if bpp == 32 => {
+            // the masks follow a BITMAPINFOHEADER, or are part of a larger header
+            let masks = [
+                u32_at(bytes, info + INFO_HEADER_LEN)?,
+                u32_at(bytes, info + INFO_HEADER_LEN + 4)?,
+                u32_at(bytes, info + INFO_HEADER_LEN + 8)?,
+            ];
+            if masks != BGRA_MASKS {
+                return Err(BmpError::Unsupported);
+            }
+        }
+        _ => return Err(BmpError::Compressed),
+    }
+    if width <= 0 || height == 0 || height == i32::MIN {
+        return Err(BmpError::Bitmap(BitmapError::SizeTooLarge));
+    }
+    let (width, top_down) = (width as usize, height < 0);
+    let height = height.unsigned_abs() as usize;
+
+    let px_len = px_type.bytes();
+    // each row is padded out to a multiple of 4 bytes
+    let stride = (width * px_len + 3) / 4 * 4;
+    let end = stride
+        .checked_mul(height)
+        .and_then(|len| len.checked_add(offset))
+        .ok_or(BmpError::Truncated)?;
+    let data = bytes.get(offset..end).ok_or(BmpError::Truncated)?;
+
+    let mut pixels: Vec<u8> = Vec::with_capacity(width * height * px_len);
+    for y in 0..height {
+        let row = if top_down { y } else { height - 1 - y };
+        let row = &data[row * stride..row * stride + width * px_len];
+        for px in row.chunks(px_len) {
+            // blue, green, red (alpha) to red, green, blue (alpha)
+            pixels.extend_from_slice(&[px[2], px[1], px[0]]);
+            pixels.extend_from_slice(&px[3..]);
+        }
+    }
+    Ok(Img::new(pixels, width, px_type))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a BMP with a BITMAPINFOHEADER around `data`, which must already be padded
+    fn bmp(width: i32, height: i32, bpp: u16, compression: u32, data: &[u8]) -> Vec<u8> {
+        let offset = (FILE_HEADER_LEN + INFO_HEADER_LEN) as u32;
+        let mut bytes = BMP_SIGNATURE.to_vec();
+        bytes.extend_from_slice(&(offset + data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(&offset.to_le_bytes());
+        bytes.extend_from_slice(&(INFO_HEADER_LEN as u32).to_le_bytes());
+        bytes.extend_from_slice(&width.to_le_bytes());
+        bytes.extend_from_slice(&height.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&bpp.to_le_bytes());
+        bytes.extend_from_slice(&compression.to_le_bytes());
+        bytes.extend_from_slice(&[0; 20]);
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn decode_24_bit_test() {
+        // 2x2, bottom-up, each 6 byte row padded to 8
+        let data = [
+            1, 2, 3, 4, 5, 6, 0, 0, // bottom row
+            7, 8, 9, 10, 11, 12, 0, 0, // top row
+        ];
+        let img = decode_bmp(&bmp(2, 2, 24, BI_RGB, &data)).unwrap();
+        assert!(matches!(img.px_type, PixelType::U8x3));
+        assert_eq!((img.width(), img.height()), (2, 2));
+        assert_eq!(img.pixels, vec![9, 8, 7, 12, 11, 10, 3, 2, 1, 6, 5, 4]);
+
+        // the same rows stored top-down
+        let img = decode_bmp(&bmp(2, -2, 24, BI_RGB, &data)).unwrap();
+        assert_eq!(img.pixels, vec![3, 2, 1, 6, 5, 4, 9, 8, 7, 12, 11, 10]);
+    }
+
+    #[test]
+    fn decode_32_bit_test() {
+        let data = [1, 2, 3, 255, 4, 5, 6, 128];
+        let img = decode_bmp(&bmp(1, 2, 32, BI_RGB, &data)).unwrap();
+        assert!(matches!(img.px_type, PixelType::U8x4));
+        assert_eq!(img.pixels, vec![6, 5, 4, 128, 3, 2, 1, 255]);
+    }
+
+    #[test]
+    fn reject_test() {
+        let data = [0u8; 8];
+        assert_eq!(decode_bmp(b"PNG").err(), Some(BmpError::NotBmp));
+        assert_eq!(
+            decode_bmp(&bmp(2, 2, 8, BI_RGB, &data)).err(),
+            Some(BmpError::Palettized)
+        );
+        assert_eq!(
+            decode_bmp(&bmp(2, 2, 16, BI_RGB, &data)).err(),
+            Some(BmpError::Unsupported)
+        );
+        // BI_RLE8
+        assert_eq!(
+            decode_bmp(&bmp(2, 1, 24, 1, &data)).err(),
+            Some(BmpError::Compressed)
+        );
+        // a 2x2 image needs 16 bytes of pixels
+        assert_eq!(
+            decode_bmp(&bmp(2, 2, 24, BI_RGB, &data)).err(),
+            Some(BmpError::Truncated)
+        );
+        let bytes = bmp(2, 2, 24, BI_RGB, &data);
+        assert_eq!(decode_bmp(&bytes[..20]).err(), Some(BmpError::Truncated));
+    }
+}
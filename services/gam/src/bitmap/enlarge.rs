@@ -0,0 +1,118 @@
+/*
+ * Enlarge is an adaptor Iterator to increase the width & height of a raster image
+ */
+
+pub struct Enlarge<I> {
+    /// iterator over inbound pixels
+    iter: I,
+    /// width of the inbound image
+    in_width: usize,
+    /// width of the outbound image
+    out_width: usize,
+    /// the inbound row currently being replicated
+    row: Vec<u8>,
+    /// the y coord of the inbound row held in the buffer, if any
+    in_y: Option<usize>,
+    /// the current x coord of the outbound image
+    out_x: usize,
+    /// the current y coord of the outbound image
+    out_y: usize,
+}
+
+impl<I: Iterator<Item = u8>> Enlarge<I> {
+    fn new(iter: I, in_width: usize, out_width: usize) -> Enlarge<I> {
+        Self {
+            iter,
+            in_width,
+            out_width,
+            row: Vec::with_capacity(in_width),
+            in_y: None,
+            out_x: 0,
+            out_y: 0,
+        }
+    }
+
+    /// the inbound index sampled for the given outbound index
+    fn source(&self, out: usize) -> usize {
+        out * self.in_width / self.out_width
+    }
+}
+
+/// Adaptor Iterator to enlarge an image dimensions from in_width to out_width
+impl<I: Iterator<Item = u8>> Iterator for Enlarge<I> {
+    type Item = u8;
+
+    /// Enlarges an image from in_width to out_width by nearest-neighbour.
+    /// Each outbound pixel takes the value of the inbound pixel it falls within,
+    /// so with out_width = 3 x in_width each inbound pixel becomes a 3x3 block.
+    /// Note that with a non-integer scale the blocks will be of variable size ±1.
+    /// Only a single inbound row is buffered, and a partial inbound row at the end
+    /// of the image is discarded.
+    fn next(&mut self) -> Option<Self::Item> {
+        // if there is no increase in image size then simple return image as-is
+        if self.in_width == 0 || self.out_width <= self.in_width {
+            return self.iter.next();
+        }
+        if self.out_x == 0 {
+            let in_y = self.source(self.out_y);
+            // skip forward to the inbound row for this outbound row
+            while self.in_y < Some(in_y) {
+                self.row.clear();
+                self.row.extend(self.iter.by_ref().take(self.in_width));
+                if self.row.len() < self.in_width {
+                    return None;
+                }
+                self.in_y = Some(self.in_y.map_or(0, |y| y + 1));
+            }
+        }
+        let pixel = self.row[self.source(self.out_x)];
+        // prepare for the next pixel in the row or column
+        self.out_x += 1;
+        if self.out_x >= self.out_width {
+            self.out_x = 0;
+            self.out_y += 1;
+        }
+        Some(pixel)
+    }
+}
+
+pub trait EnlargeIterator: Iterator<Item = u8> + Sized {
+    fn grow(self, in_width: usize, out_width: usize) -> Enlarge<Self> {
+        Enlarge::new(self, in_width, out_width)
+    }
+}
+
+impl<I: Iterator<Item = u8>> EnlargeIterator for I {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grow_integer_scale() {
+        let img = vec![1u8, 2, 3, 4];
+        let out: Vec<u8> = img.into_iter().grow(2, 4).collect();
+        assert_eq!(out, vec![1, 1, 2, 2, 1, 1, 2, 2, 3, 3, 4, 4, 3, 3, 4, 4]);
+    }
+
+    #[test]
+    fn grow_fractional_scale() {
+        let (in_width, in_height, out_width) = (20, 7, 47);
+        let img: Vec<u8> = (0..in_width * in_height).map(|i| i as u8).collect();
+        let out: Vec<u8> = img.iter().cloned().grow(in_width, out_width).collect();
+        let out_height = (in_height * out_width + in_width - 1) / in_width;
+        assert_eq!(out.len(), out_width * out_height);
+        // the first and last pixels land on the corners of the inbound image
+        assert_eq!(out[0], img[0]);
+        assert_eq!(out[out.len() - 1], img[img.len() - 1]);
+    }
+
+    #[test]
+    fn grow_passthrough() {
+        let img = vec![1u8, 2, 3, 4, 5, 6];
+        let out: Vec<u8> = img.clone().into_iter().grow(3, 3).collect();
+        assert_eq!(out, img);
+        let out: Vec<u8> = img.clone().into_iter().grow(3, 2).collect();
+        assert_eq!(out, img);
+    }
+}
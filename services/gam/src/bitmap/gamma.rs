@@ -0,0 +1,74 @@
+/*
+ * Gamma is an adaptor Iterator to apply a gamma curve to greyscale pixels
+ */
+
+pub struct Gamma<I> {
+    /// iterator over inbound pixels
+    iter: I,
+    /// the outbound value for each inbound value, or None if the curve is flat
+    lut: Option<[u8; 256]>,
+}
+
+impl<I: Iterator<Item = u8>> Gamma<I> {
+    fn new(iter: I, g: f32) -> Gamma<I> {
+        // the curve is tabulated once, rather than a powf() per pixel
+        let lut = if g == 1.0 || !g.is_finite() || g <= 0.0 {
+            None
+        } else {
+            let mut lut = [0u8; 256];
+            let max = u8::MAX as f32;
+            for (i, out) in lut.iter_mut().enumerate() {
+                *out = (max * (i as f32 / max).powf(g)).round() as u8;
+            }
+            Some(lut)
+        };
+        Self { iter, lut }
+    }
+}
+
+/// Adaptor Iterator to apply a gamma curve to u8 greyscale pixels
+impl<I: Iterator<Item = u8>> Iterator for Gamma<I> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let grey = self.iter.next()?;
+        match &self.lut {
+            Some(lut) => Some(lut[grey as usize]),
+            None => Some(grey),
+        }
+    }
+}
+
+pub trait GammaIterator: Iterator<Item = u8> + Sized {
+    /// raises each pixel (as a fraction of white) to the power `g`: below 1.0 lightens the
+    /// mid-tones, above 1.0 darkens them, and 1.0 (or a `g` that isn't positive) leaves them
+    fn gamma(self, g: f32) -> Gamma<Self> {
+        Gamma::new(self, g)
+    }
+}
+
+impl<I: Iterator<Item = u8>> GammaIterator for I {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gamma_test() {
+        let ramp: Vec<u8> = (0..=255).collect();
+        // a flat curve, or a nonsense one, passes the pixels through
+        for g in [1.0, 0.0, -2.0, f32::NAN] {
+            let out: Vec<u8> = ramp.iter().cloned().gamma(g).collect();
+            assert_eq!(out, ramp);
+        }
+        let light: Vec<u8> = ramp.iter().cloned().gamma(1.0 / 2.2).collect();
+        let dark: Vec<u8> = ramp.iter().cloned().gamma(2.2).collect();
+        // black and white are fixed, and the mid-tones move
+        for out in [&light, &dark] {
+            assert_eq!((out[0], out[255]), (0, 255));
+            assert!(out.windows(2).all(|w| w[0] <= w[1]));
+        }
+        assert!(light[128] > 128 && dark[128] < 128);
+        assert_eq!(light[128], 186);
+    }
+}
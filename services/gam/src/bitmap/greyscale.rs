@@ -7,14 +7,39 @@
 use crate::PixelType;
 use std::convert::TryInto;
 
+/// Rec. 709 luma weights for (red, green, blue)
+pub const REC709_WEIGHTS: (u32, u32, u32) = (2126, 7152, 722);
+
 pub struct GreyScale<I> {
     iter: I,
     px_type: PixelType,
+    weights: (u32, u32, u32),
 }
 
 impl<I: Iterator<Item = u8>> GreyScale<I> {
     fn new(iter: I, px_type: PixelType) -> GreyScale<I> {
-        Self { iter, px_type }
+        let (r, g, b) = REC709_WEIGHTS;
+        Self::with_weights(iter, px_type, r, g, b)
+    }
+
+    /// Converts colour pixels with custom weights for red, green and blue. Only the ratio
+    /// of the weights matters: they are normalized by their sum, so (1, 1, 1) is a plain
+    /// average. Weights that are all zero fall back to the Rec. 709 defaults.
+    pub fn with_weights(iter: I, px_type: PixelType, r: u32, g: u32, b: u32) -> GreyScale<I> {
+        let weights = match (r, g, b) {
+            (0, 0, 0) => REC709_WEIGHTS,
+            weights => weights,
+        };
+        Self {
+            iter,
+            px_type,
+            weights,
+        }
+    }
+
+    /// As `with_weights`, with the weights for red, green and blue given as one tuple.
+    pub fn new_weighted(iter: I, px_type: PixelType, (r, g, b): (u32, u32, u32)) -> GreyScale<I> {
+        Self::with_weights(iter, px_type, r, g, b)
     }
 }
 
@@ -38,14 +63,14 @@ impl<I: Iterator<Item = u8>> Iterator for GreyScale<I> {
                 let r = self.iter.next();
                 let g = self.iter.next();
                 let b = self.iter.next();
-                grey(r, g, b)
+                grey(r, g, b, self.weights)
             }
             PixelType::U8x4 => {
                 let r = self.iter.next();
                 let g = self.iter.next();
                 let b = self.iter.next();
                 let _alpha = self.iter.next();
-                grey(r, g, b)
+                grey(r, g, b, self.weights)
             }
             PixelType::U16 => match self.iter.next() {
                 Some(gr) => {
@@ -70,7 +95,7 @@ impl<I: Iterator<Item = u8>> Iterator for GreyScale<I> {
                 let _lower_bits = self.iter.next();
                 let b = self.iter.next();
                 let _lower_bits = self.iter.next();
-                grey(r, g, b)
+                grey(r, g, b, self.weights)
             }
             PixelType::U16x4 => {
                 let r = self.iter.next();
@@ -81,7 +106,7 @@ impl<I: Iterator<Item = u8>> Iterator for GreyScale<I> {
                 let _lower_bits = self.iter.next();
                 let _alpha = self.iter.next();
                 let _lower_bits = self.iter.next();
-                grey(r, g, b)
+                grey(r, g, b, self.weights)
             }
             _ => {
                 log::warn!("unsupported PixelType {:?}", self.px_type);
@@ -92,16 +117,15 @@ impl<I: Iterator<Item = u8>> Iterator for GreyScale<I> {
 }
 
 // chromatic coversion from RGB to Greyscale
-fn grey(r: Option<u8>, g: Option<u8>, b: Option<u8>) -> Option<u8> {
-    const R: u32 = 2126;
-    const G: u32 = 7152;
-    const B: u32 = 722;
-    const BLACK: u32 = R + G + B;
+fn grey(r: Option<u8>, g: Option<u8>, b: Option<u8>, weights: (u32, u32, u32)) -> Option<u8> {
+    // in u64, so that any u32 weights are safe from overflow
+    let (weight_r, weight_g, weight_b) = (weights.0 as u64, weights.1 as u64, weights.2 as u64);
+    let black: u64 = weight_r + weight_g + weight_b;
     if r.is_some() && g.is_some() && b.is_some() {
-        let grey_r = R * r.unwrap() as u32;
-        let grey_g = G * g.unwrap() as u32;
-        let grey_b = B * b.unwrap() as u32;
-        let grey: u8 = ((grey_r + grey_g + grey_b) / BLACK).try_into().unwrap();
+        let grey_r = weight_r * r.unwrap() as u64;
+        let grey_g = weight_g * g.unwrap() as u64;
+        let grey_b = weight_b * b.unwrap() as u64;
+        let grey: u8 = ((grey_r + grey_g + grey_b) / black).try_into().unwrap();
         Some(grey)
     } else {
         None
@@ -113,6 +137,86 @@ pub trait GreyScaleIterator: Iterator<Item = u8> + Sized {
     fn to_grey(self, px_type: PixelType) -> GreyScale<Self> {
         GreyScale::new(self, px_type)
     }
+    /// converts pixels of PixelType to u8 greyscale, weighting red, green and blue as given
+    fn to_grey_weighted(self, px_type: PixelType, r: u32, g: u32, b: u32) -> GreyScale<Self> {
+        GreyScale::with_weights(self, px_type, r, g, b)
+    }
 }
 
 impl<I: Iterator<Item = u8>> GreyScaleIterator for I {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weights_test() {
+        let rgb: Vec<u8> = vec![
+            255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255, 30, 60, 90, 1, 2, 4,
+        ];
+        // the defaults are Rec. 709, as they always were
+        let default: Vec<u8> = rgb.iter().cloned().to_grey(PixelType::U8x3).collect();
+        assert_eq!(default, vec![54, 182, 18, 255, 55, 1]);
+        let (r, g, b) = REC709_WEIGHTS;
+        let rec709: Vec<u8> = rgb
+            .iter()
+            .cloned()
+            .to_grey_weighted(PixelType::U8x3, r, g, b)
+            .collect();
+        assert_eq!(rec709, default);
+
+        // equal weights of any size are a plain average
+        for w in [1, 7, 1_000_000] {
+            let average: Vec<u8> = rgb
+                .iter()
+                .cloned()
+                .to_grey_weighted(PixelType::U8x3, w, w, w)
+                .collect();
+            assert_eq!(average, vec![85, 85, 85, 255, 60, 2]);
+        }
+        // alpha is still skipped with custom weights
+        let rgba: Vec<u8> = vec![30, 60, 90, 0, 255, 0, 0, 255];
+        let grey: Vec<u8> =
+            GreyScale::with_weights(rgba.into_iter(), PixelType::U8x4, 2, 1, 1).collect();
+        assert_eq!(grey, vec![52, 127]);
+    }
+
+    #[test]
+    fn channel_test() {
+        let rgb: Vec<u8> = vec![200, 10, 20, 0, 255, 255, 77, 88, 99];
+        // a weight on a single channel extracts that channel
+        let red: Vec<u8> = rgb
+            .iter()
+            .cloned()
+            .to_grey_weighted(PixelType::U8x3, 1, 0, 0)
+            .collect();
+        assert_eq!(red, vec![200, 0, 77]);
+        let red: Vec<u8> =
+            GreyScale::new_weighted(rgb.iter().cloned(), PixelType::U8x3, (1, 0, 0)).collect();
+        assert_eq!(red, vec![200, 0, 77]);
+        let blue: Vec<u8> = rgb
+            .into_iter()
+            .to_grey_weighted(PixelType::U8x3, 0, 0, 5)
+            .collect();
+        assert_eq!(blue, vec![20, 255, 99]);
+    }
+
+    #[test]
+    fn rgba_test() {
+        let rgb: Vec<u8> = vec![255, 0, 0, 0, 255, 0, 0, 0, 255, 30, 60, 90, 1, 2, 4];
+        let rgba: Vec<u8> = rgb
+            .chunks(3)
+            .flat_map(|px| px.iter().cloned().chain(Some(0xFF)))
+            .collect();
+        let from_rgb: Vec<u8> = rgb.into_iter().to_grey(PixelType::U8x3).collect();
+        let from_rgba: Vec<u8> = rgba.iter().cloned().to_grey(PixelType::U8x4).collect();
+        assert_eq!(from_rgba, from_rgb);
+        // a partial pixel at the end is dropped rather than read as black
+        let partial: Vec<u8> = rgba[..rgba.len() - 2]
+            .iter()
+            .cloned()
+            .to_grey(PixelType::U8x4)
+            .collect();
+        assert_eq!(partial, from_rgb[..from_rgb.len() - 1]);
+    }
+}
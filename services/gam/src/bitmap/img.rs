@@ -4,6 +4,7 @@
  * author: nworbnhoj
  */
 
+use crate::bitmap::{GreyScaleIterator, ShrinkIterator};
 use std::ops::Deref;
 
 #[derive(Debug, Clone, Copy)]
@@ -19,6 +20,23 @@ pub enum PixelType {
     U16x4,
 }
 
+impl PixelType {
+    /// the number of bytes each pixel of this type occupies
+    pub fn bytes(&self) -> usize {
+        match self {
+            PixelType::U0 => 0,
+            PixelType::U8 => 1,
+            PixelType::U8x2 => 2,
+            PixelType::U8x3 => 3,
+            PixelType::U8x4 => 4,
+            PixelType::U16 => 2,
+            PixelType::U16x2 => 4,
+            PixelType::U16x3 => 6,
+            PixelType::U16x4 => 8,
+        }
+    }
+}
+
 /*
  * Image as a minimal flat buffer of u8; accessible by (x, y)
  *
@@ -33,6 +51,8 @@ pub struct Img {
 }
 
 impl Img {
+    /// Keeps the pixels in their source PixelType, so color is available until
+    /// to_greyscale() is called.
     pub fn new(pixels: Vec<u8>, width: usize, px_type: PixelType) -> Self {
         Self {
             pixels,
@@ -40,25 +60,56 @@ impl Img {
             px_type,
         }
     }
+
+    /// Converts the pixels to U8 greyscale up front, so a color image only ever
+    /// occupies a single byte per pixel once constructed.
+    pub fn new_greyscale(pixels: Vec<u8>, width: usize, px_type: PixelType) -> Self {
+        let pixels: Vec<u8> = pixels.into_iter().to_grey(px_type).collect();
+        Img::new(pixels, width, PixelType::U8)
+    }
     pub fn width(&self) -> usize {
         self.width
     }
     pub fn height(&self) -> usize {
         match self.px_type {
-            PixelType::U8 => self.pixels.len() / self.width,
-            PixelType::U8x2 => self.pixels.len() / (self.width * 2),
-            PixelType::U8x3 => self.pixels.len() / (self.width * 3),
-            PixelType::U8x4 => self.pixels.len() / (self.width * 4),
-            PixelType::U16 => self.pixels.len() / (self.width * 2),
-            PixelType::U16x2 => self.pixels.len() / (self.width * 4),
-            PixelType::U16x3 => self.pixels.len() / (self.width * 6),
-            PixelType::U16x4 => self.pixels.len() / (self.width * 8),
-            _ => {
+            PixelType::U0 => {
                 log::warn!("PixelType not implemented");
                 0
             }
+            px_type => self.pixels.len() / (self.width * px_type.bytes()),
         }
     }
+
+    /// The raw bytes of the pixel at (x, y), in the Img's PixelType
+    pub fn pixel(&self, x: usize, y: usize) -> Option<&[u8]> {
+        let bytes = self.px_type.bytes();
+        if x >= self.width || y >= self.height() {
+            return None;
+        }
+        let start = (y * self.width + x) * bytes;
+        Some(&self.pixels[start..start + bytes])
+    }
+
+    /// A U8 greyscale copy of the Img, at the same size
+    pub fn to_greyscale(&self) -> Img {
+        let pixels: Vec<u8> = self.pixels.iter().cloned().to_grey(self.px_type).collect();
+        Img::new(pixels, self.width, PixelType::U8)
+    }
+
+    /// Downscale to out_width (preserving the aspect ratio) as a continuous-tone
+    /// U8 greyscale Img, without dithering. Images are never enlarged, so an
+    /// out_width >= width only converts to greyscale.
+    pub fn resize(&self, out_width: usize) -> Img {
+        let out_width = out_width.min(self.width);
+        let pixels: Vec<u8> = self
+            .pixels
+            .iter()
+            .cloned()
+            .to_grey(self.px_type)
+            .shrink(self.width, out_width)
+            .collect();
+        Img::new(pixels, out_width, PixelType::U8)
+    }
 }
 
 impl Deref for Img {
@@ -68,3 +119,47 @@ impl Deref for Img {
         &self.pixels
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resize_test() {
+        // a 4x4 ramp 0, 16, 32 .. 240
+        let ramp: Vec<u8> = (0..16).map(|i| i * 16).collect();
+        let img = Img::new(ramp, 4, PixelType::U8);
+        let small = img.resize(2);
+        assert_eq!(small.width(), 2);
+        assert_eq!(small.height(), 2);
+        // each pixel is the average of a 2x2 block
+        assert_eq!(small.pixels, vec![40, 72, 168, 200]);
+
+        let same = img.resize(8);
+        assert_eq!(same.width(), 4);
+        assert_eq!(same.pixels, img.pixels);
+    }
+
+    #[test]
+    fn to_greyscale_test() {
+        // a 2x2 RGB image
+        let rgb = vec![0, 0, 0, 200, 100, 50, 255, 255, 255, 10, 20, 30];
+        let img = Img::new(rgb.clone(), 2, PixelType::U8x3);
+        assert_eq!(img.height(), 2);
+        // the color survives construction
+        assert_eq!(img.pixel(1, 0), Some(&[200, 100, 50][..]));
+        assert_eq!(img.pixel(0, 1), Some(&[255, 255, 255][..]));
+        assert_eq!(img.pixel(2, 0), None);
+        assert_eq!(img.pixel(0, 2), None);
+
+        let grey = img.to_greyscale();
+        assert!(matches!(grey.px_type, PixelType::U8));
+        assert_eq!((grey.width(), grey.height()), (2, 2));
+        // (2126 * 200 + 7152 * 100 + 722 * 50) / 10000
+        assert_eq!(grey.pixel(1, 0), Some(&[117][..]));
+        assert_eq!(grey.pixels, vec![0, 117, 255, 18]);
+        // the original is untouched, and the eager path gives the same result
+        assert_eq!(img.pixels, rgb);
+        assert_eq!(Img::new_greyscale(rgb, 2, PixelType::U8x3).pixels, grey.pixels);
+    }
+}
@@ -23,10 +23,12 @@ pub struct Shrink<I> {
     out_y: usize,
     /// a buffer the width of the outbound image to stove horizontal averages
     buf: Vec<u16>,
+    /// the horizontal averages of the inbound row currently being read
+    row: Vec<u16>,
     /// the width of the current stri in the inbound image
     y_div: u16,
-    /// the x coord of the final pixel in the inbound image
-    out_x_last: usize,
+    /// set once the inbound iterator has run dry
+    exhausted: bool,
 }
 
 impl<I: Iterator<Item = u8>> Shrink<I> {
@@ -38,15 +40,8 @@ impl<I: Iterator<Item = u8>> Shrink<I> {
         } else {
             vec![0u16; out_width]
         };
-
-        // Pretabulate horizontal pixel positions
-        let mut in_x_cap: Vec<u16> = Vec::with_capacity(out_width);
-        let max_width: u16 = (in_width - 1).try_into().unwrap();
-        for out_x in 1..=out_width {
-            let in_x: u16 = (scale * out_x as f32) as u16;
-            in_x_cap.push((in_x).min(max_width));
-        }
-        let out_x_last = out_width;
+        let row = buf.clone();
+        let in_x_cap = strip_caps(scale, in_width, out_width);
         Self {
             iter,
             out_width,
@@ -56,8 +51,9 @@ impl<I: Iterator<Item = u8>> Shrink<I> {
             out_x: 0,
             out_y: 1,
             buf,
+            row,
             y_div: 0,
-            out_x_last,
+            exhausted: false,
         }
     }
 
@@ -66,6 +62,24 @@ impl<I: Iterator<Item = u8>> Shrink<I> {
         (self.out_x, self.out_y)
     }
 }
+
+/// Pretabulate the trailing edge of each inbound strip, so that the strips
+/// exactly tile the inbound width.
+fn strip_caps(scale: f32, in_width: usize, out_width: usize) -> Vec<u16> {
+    let mut in_x_cap: Vec<u16> = Vec::with_capacity(out_width);
+    let max_width: u16 = (in_width - 1).try_into().unwrap();
+    for out_x in 1..=out_width {
+        let in_x: u16 = strip_end(scale, out_x).try_into().unwrap();
+        in_x_cap.push((in_x).min(max_width));
+    }
+    in_x_cap
+}
+
+/// The last inbound index covered by the given (1-based) outbound index
+fn strip_end(scale: f32, out: usize) -> usize {
+    ((scale * out as f32).round() as usize).max(1) - 1
+}
+
 /// Adaptor Iterator to shrink an image dimensions from in_width to out_width
 impl<I: Iterator<Item = u8>> Iterator for Shrink<I> {
     type Item = u8;
@@ -77,6 +91,8 @@ impl<I: Iterator<Item = u8>> Iterator for Shrink<I> {
     /// vertical and horizontal strips. For example, when in_width = 3 x out_width
     /// each outbound pixel will be the average of 9 pixels in a 3x3 inbound block.
     /// Note that with a non-integer scale the strips will be of variable width ±1.
+    /// A partial inbound row at the end of the image is discarded, and a short final
+    /// horizontal strip is averaged over the rows available.
     fn next(&mut self) -> Option<Self::Item> {
         // if there is no reduction in image size then simple return image as-is
         if self.scale <= 1.0 {
@@ -85,32 +101,43 @@ impl<I: Iterator<Item = u8>> Iterator for Shrink<I> {
                 None => None,
             };
         }
-        // processed the last inbound pixel
-        if self.out_x > self.out_x_last {
-            return None;
-        }
         // take the average of pixels in the horizontal, and then vertical.
-        let in_y_cap = (self.scale * self.out_y as f32) as usize;
-        while self.in_y <= in_y_cap {
-            let mut in_x = 0;
-            for (out_x, in_x_cap) in self.in_x_cap.iter().enumerate() {
-                let mut x_total: u16 = 0;
-                let mut x_div: u16 = 0;
-                while in_x <= *in_x_cap {
-                    x_total += match self.iter.next() {
-                        Some(pixel) => pixel as u16,
-                        None => {
-                            self.out_x_last = out_x - 1;
-                            0
-                        }
-                    };
-                    in_x += 1;
-                    x_div += 1;
+        if self.out_x == 0 {
+            let in_y_cap = strip_end(self.scale, self.out_y);
+            while self.in_y <= in_y_cap && !self.exhausted {
+                let mut in_x = 0;
+                for (out_x, in_x_cap) in self.in_x_cap.iter().enumerate() {
+                    let mut x_total: u16 = 0;
+                    let mut x_div: u16 = 0;
+                    while in_x <= *in_x_cap {
+                        match self.iter.next() {
+                            Some(pixel) => x_total += pixel as u16,
+                            None => {
+                                self.exhausted = true;
+                                break;
+                            }
+                        };
+                        in_x += 1;
+                        x_div += 1;
+                    }
+                    if self.exhausted {
+                        break;
+                    }
+                    self.row[out_x] = x_total / x_div;
+                }
+                if self.exhausted {
+                    break;
+                }
+                for (total, avg) in self.buf.iter_mut().zip(self.row.iter()) {
+                    *total += avg;
                 }
-                self.buf[out_x] += x_total / x_div;
+                self.in_y += 1;
+                self.y_div += 1;
+            }
+            // processed the last inbound pixel
+            if self.y_div == 0 {
+                return None;
             }
-            self.in_y += 1;
-            self.y_div += 1;
         }
         // calculate the average of the sum of pixels in the buffer, and reset buffer
         let pixel: u8 = (self.buf[self.out_x] / self.y_div).try_into().unwrap();
@@ -133,3 +160,125 @@ pub trait ShrinkIterator: Iterator<Item = u8> + Sized {
 }
 
 impl<I: Iterator<Item = u8>> ShrinkIterator for I {}
+
+/// Row-wise counterpart to Shrink: consumes one inbound row at a time and yields
+/// one shrunk outbound row at a time, so only a single row (plus the outbound
+/// accumulator) is held in memory. The arithmetic is identical to Shrink, and the
+/// outbound rows can be flattened into Dither:
+/// `rows.shrink_rows(in_width, out_width).flatten().dither(&burkes, out_width)`
+pub struct ShrinkRows<I> {
+    /// iterator over inbound rows of pixels
+    iter: I,
+    /// width of the outbound image
+    out_width: usize,
+    /// the scale factor between inbound and outbound images (ie in_width/out_width)
+    scale: f32,
+    /// a pre-tabulated list of the trailing edge of each inbound strip of pixels
+    in_x_cap: Vec<u16>,
+    /// the current y coord of the inbound image
+    in_y: usize,
+    /// the y coord of the next outbound row (1-based, as in Shrink)
+    out_y: usize,
+}
+
+impl<I: Iterator<Item = Vec<u8>>> ShrinkRows<I> {
+    fn new(iter: I, in_width: usize, out_width: usize) -> ShrinkRows<I> {
+        let scale = in_width as f32 / out_width as f32;
+        Self {
+            iter,
+            out_width,
+            scale,
+            in_x_cap: strip_caps(scale, in_width, out_width),
+            in_y: 0,
+            out_y: 1,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Vec<u8>>> Iterator for ShrinkRows<I> {
+    type Item = Vec<u8>;
+
+    /// Pulls the inbound rows making up the next horizontal strip and returns
+    /// their average as a single outbound row. A short final strip is averaged
+    /// over the rows that are available.
+    fn next(&mut self) -> Option<Self::Item> {
+        // if there is no reduction in image size then simple return rows as-is
+        if self.scale <= 1.0 {
+            return self.iter.next();
+        }
+        let mut buf = vec![0u16; self.out_width];
+        let mut y_div: u16 = 0;
+        let in_y_cap = strip_end(self.scale, self.out_y);
+        while self.in_y <= in_y_cap {
+            let row = match self.iter.next() {
+                Some(row) => row,
+                None => break,
+            };
+            let mut in_x = 0;
+            for (out_x, in_x_cap) in self.in_x_cap.iter().enumerate() {
+                let mut x_total: u16 = 0;
+                let mut x_div: u16 = 0;
+                while in_x <= *in_x_cap {
+                    x_total += *row.get(in_x as usize).unwrap_or(&0) as u16;
+                    in_x += 1;
+                    x_div += 1;
+                }
+                buf[out_x] += x_total / x_div;
+            }
+            self.in_y += 1;
+            y_div += 1;
+        }
+        if y_div == 0 {
+            return None;
+        }
+        self.out_y += 1;
+        Some(buf.iter().map(|total| (total / y_div) as u8).collect())
+    }
+}
+
+pub trait ShrinkRowsIterator: Iterator<Item = Vec<u8>> + Sized {
+    fn shrink_rows(self, in_width: usize, out_width: usize) -> ShrinkRows<Self> {
+        ShrinkRows::new(self, in_width, out_width)
+    }
+}
+
+impl<I: Iterator<Item = Vec<u8>>> ShrinkRowsIterator for I {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compare(in_width: usize, in_height: usize, out_width: usize, out_height: usize) {
+        let img: Vec<u8> = (0..in_width * in_height)
+            .map(|i| ((i * 7 + i / in_width * 13) % 256) as u8)
+            .collect();
+        let by_pixel: Vec<u8> = img
+            .iter()
+            .cloned()
+            .shrink(in_width, out_width)
+            .collect();
+        let by_row: Vec<u8> = img
+            .chunks(in_width)
+            .map(|row| row.to_vec())
+            .shrink_rows(in_width, out_width)
+            .flatten()
+            .collect();
+        assert_eq!(by_pixel.len(), out_width * out_height);
+        assert_eq!(by_pixel, by_row);
+    }
+
+    #[test]
+    fn shrink_rows_matches_shrink() {
+        // integer and fractional scale factors, and a short final strip
+        compare(90, 90, 30, 30);
+        compare(100, 100, 30, 30);
+        compare(64, 40, 17, 11);
+    }
+
+    #[test]
+    fn shrink_rows_passthrough() {
+        let rows = vec![vec![1u8, 2, 3], vec![4, 5, 6]];
+        let out: Vec<Vec<u8>> = rows.clone().into_iter().shrink_rows(3, 3).collect();
+        assert_eq!(out, rows);
+    }
+}
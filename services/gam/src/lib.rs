@@ -13,7 +13,7 @@ pub use apps::*;
 #[cfg(feature="ditherpunk")]
 pub mod bitmap;
 #[cfg(feature="ditherpunk")]
-pub use bitmap::{Bitmap, Img, PixelType, DecodePng};
+pub use bitmap::{Bitmap, BitmapPatch, DitherPattern, Img, PixelType, DecodePng, PixelSink, TileSink};
 
 use graphics_server::api::{TextOp, TextView};
 use graphics_server::api::{Gid, Line, Circle, RoundedRectangle, TokenClaim};
@@ -206,9 +206,9 @@ impl Gam {
     }
     #[cfg(feature="ditherpunk")]
     pub fn draw_bitmap(&self, gid: Gid, bm: &Bitmap) -> Result<(), xous::Error> {
-        for (_i, tile) in bm.iter().enumerate(){
+        for (_i, tile) in bm.tiles().enumerate(){
             let gt = GamTile {
-                tile: *tile,
+                tile,
                 canvas: gid,
             };
             let buf = Buffer::into_buf(gt).or(Err(xous::Error::InternalError))?;
@@ -217,6 +217,15 @@ impl Gam {
         };
         Ok(())
     }
+    /// Shows `frames` on the canvas one after another, at `fps` frames per second, e.g. for a
+    /// splash animation. After the first frame only the tiles that changed are sent, and every
+    /// frame is clipped to the bound of the first. Blocks until the last frame is up.
+    #[cfg(feature="ditherpunk")]
+    pub fn play_bitmap_sequence(&self, gid: Gid, frames: &[Bitmap], fps: u8) -> Result<(), xous::Error> {
+        let tt = ticktimer_server::Ticktimer::new().unwrap();
+        let mut canvas = CanvasTileSink { conn: self.conn, gid };
+        bitmap::play_sequence(frames, fps, &mut canvas, |ms| { tt.sleep_ms(ms).ok(); })
+    }
     pub fn draw_circle(&self, gid: Gid, circ: Circle) -> Result<(), xous::Error> {
         let go = GamObject {
                 canvas: gid,
@@ -515,6 +524,25 @@ impl Gam {
     }
 }
 
+/// Sends each Tile to a canvas, as `draw_bitmap()` does
+#[cfg(feature="ditherpunk")]
+struct CanvasTileSink {
+    conn: CID,
+    gid: Gid,
+}
+#[cfg(feature="ditherpunk")]
+impl TileSink for CanvasTileSink {
+    type Error = xous::Error;
+    fn push_tile(&mut self, tile: Tile) -> Result<(), xous::Error> {
+        let gt = GamTile {
+            tile,
+            canvas: self.gid,
+        };
+        let buf = Buffer::into_buf(gt).or(Err(xous::Error::InternalError))?;
+        buf.lend(self.conn, Opcode::RenderTile.to_u32().unwrap()).map(|_| ())
+    }
+}
+
 use core::sync::atomic::{AtomicU32, Ordering};
 static REFCOUNT: AtomicU32 = AtomicU32::new(0);
 impl Drop for Gam {
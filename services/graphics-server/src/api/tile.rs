@@ -126,6 +126,28 @@ impl Tile {
         }
     }
 
+    /// Borrows the words of line `y` straight out of the tile, without the allocation of
+    /// `get_line()`. Returns `None` if `y` is outside the tile.
+    pub fn row_words(&self, y: i16) -> Option<&[Word]> {
+        let range = self.row_range(y)?;
+        Some(&self.words[range])
+    }
+
+    /// As `row_words()`, but the words can be written in place.
+    pub fn row_words_mut(&mut self, y: i16) -> Option<&mut [Word]> {
+        let range = self.row_range(y)?;
+        Some(&mut self.words[range])
+    }
+
+    fn row_range(&self, y: i16) -> Option<core::ops::Range<usize>> {
+        if y < self.bound.tl.y || y > self.bound.br.y {
+            return None;
+        }
+        let width: usize = self.width_words.try_into().unwrap();
+        let first_word = (y - self.bound.tl.y) as usize * width;
+        Some(first_word..first_word + width)
+    }
+
     /// Adding a line outside of bound (but within max_bound) will expant bound.
     pub fn set_line(&self, _point: Point, _pixels: Vec<PixelColor>) {
         log::warn!("not implemented");
@@ -59,6 +59,9 @@ pub(crate) enum Opcode {
     GpioDataOut, //(u32),
     GpioDataIn,
     GpioDataDrive, //(u32),
+    /// read-modify-write of only the bits in the mask, so that drivers sharing the port don't clobber each other
+    GpioDataOutMasked, //(u32, u32),
+    GpioDataDriveMasked, //(u32, u32),
     GpioIntMask, //(u32),
     GpioIntAsFalling, //(u32),
     GpioIntPending,
@@ -16,6 +16,8 @@ impl Llio {
     pub fn gpio_dout(&self, _d: u32) {}
     pub fn gpio_din(&self, ) -> u32 { 0xDEAD_BEEF }
     pub fn gpio_drive(&self, _d: u32) {}
+    pub fn gpio_dout_masked(&self, _d: u32, _mask: u32) {}
+    pub fn gpio_drive_masked(&self, _d: u32, _mask: u32) {}
     pub fn gpio_int_mask(&self, _d: u32) {}
     pub fn gpio_int_as_falling(&self, _d: u32) {}
     pub fn gpio_int_pending(&self, ) -> u32 { 0x0 }
@@ -248,6 +248,14 @@ impl Llio {
     pub fn gpio_drive(&mut self, d: u32) {
         self.gpio_csr.wfo(utra::gpio::DRIVE_DRIVE, d);
     }
+    pub fn gpio_dout_masked(&mut self, d: u32, mask: u32) {
+        let dout = self.gpio_csr.rf(utra::gpio::OUTPUT_OUTPUT);
+        self.gpio_csr.wfo(utra::gpio::OUTPUT_OUTPUT, (dout & !mask) | (d & mask));
+    }
+    pub fn gpio_drive_masked(&mut self, d: u32, mask: u32) {
+        let drive = self.gpio_csr.rf(utra::gpio::DRIVE_DRIVE);
+        self.gpio_csr.wfo(utra::gpio::DRIVE_DRIVE, (drive & !mask) | (d & mask));
+    }
     pub fn gpio_int_mask(&mut self, d: u32) {
         self.gpio_csr.wfo(utra::gpio::INTENA_INTENA, d);
     }
@@ -291,6 +291,18 @@ impl Llio {
             Message::new_scalar(Opcode::GpioDataDrive.to_usize().unwrap(), dir as usize, 0, 0, 0)
         ).map(|_| ())
     }
+    /// Sets only the output bits selected by `mask` to their values in `d`; the others keep their state.
+    pub fn gpio_data_out_masked(&self, d: u8, mask: u8) -> Result<(), xous::Error> {
+        send_message(self.conn,
+            Message::new_scalar(Opcode::GpioDataOutMasked.to_usize().unwrap(), d as usize, mask as usize, 0, 0)
+        ).map(|_| ())
+    }
+    /// Sets only the direction bits selected by `mask` to their values in `dir`; the others keep their state.
+    pub fn gpio_data_direction_masked(&self, dir: u8, mask: u8) -> Result<(), xous::Error> {
+        send_message(self.conn,
+            Message::new_scalar(Opcode::GpioDataDriveMasked.to_usize().unwrap(), dir as usize, mask as usize, 0, 0)
+        ).map(|_| ())
+    }
     pub fn gpio_debug_powerdown(&self, ena: bool) -> Result<(), xous::Error> {
         let arg = if ena { 1 } else { 0 };
         send_message(self.conn,
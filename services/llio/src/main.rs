@@ -188,6 +188,12 @@ fn main() -> ! {
             Some(Opcode::GpioDataDrive) => msg_scalar_unpack!(msg, d, _, _, _, {
                 llio.gpio_drive(d as u32);
             }),
+            Some(Opcode::GpioDataOutMasked) => msg_scalar_unpack!(msg, d, mask, _, _, {
+                llio.gpio_dout_masked(d as u32, mask as u32);
+            }),
+            Some(Opcode::GpioDataDriveMasked) => msg_scalar_unpack!(msg, d, mask, _, _, {
+                llio.gpio_drive_masked(d as u32, mask as u32);
+            }),
             Some(Opcode::GpioIntMask) => msg_scalar_unpack!(msg, d, _, _, _, {
                 llio.gpio_int_mask(d as u32);
             }),
@@ -291,11 +291,11 @@ impl Modals {
         bm.translate(margin);
 
         let mut tiles: [Option<Tile>; 6] = [None; 6];
-        for (t, tile) in bm.iter().enumerate() {
+        for (t, tile) in bm.tiles().enumerate() {
             if t >= tiles.len() {
                 continue;
             }
-            tiles[t] = Some(*tile);
+            tiles[t] = Some(tile);
         }
 
         let spec = ManagedImage {
@@ -1,3 +1,5 @@
+use num_traits::{FromPrimitive, ToPrimitive};
+
 pub(crate) const SERVER_NAME_SPINOR: &str     = "_SPINOR Hardware Interface Server_";
 
 #[cfg(any(feature="precursor", feature="renode"))]
@@ -34,8 +36,66 @@ pub(crate) enum Opcode {
     /// internal interrupt handler ops
     EccError = 11,
 
-    /// read out the ECC log
+    /// read out (and clear) the oldest entries of the ECC log
     EccLog = 12,
+
+    /// mark a region as write-protected at runtime. Only the SocToken holder may add or remove regions.
+    AddProtectedRegion = 13,
+    RemoveProtectedRegion = 14,
+    /// check if a range overlaps any protected region
+    IsProtected = 15,
+
+    /// check that a range reads back as all 0xFF
+    VerifyErased = 16,
+
+    /// report the first write or erase failure since the caller's last barrier
+    Barrier = 17,
+
+    /// like AcquireExclusive, but waits up to a caller-specified time for the lock to free up
+    AcquireExclusiveTimeout = 18,
+    /// internal message from the timeout thread: gives up on a pending AcquireExclusiveTimeout
+    AcquireTimeoutExpired = 19,
+
+    /// SHA-256 over the entire FLASH array
+    AttestWholeChip = 20,
+
+    /// limit the sustained write and erase rate, in bytes per second. 0 is unlimited.
+    SetWriteThrottle = 21,
+
+    /// issue RDID and return the manufacturer, memory type and capacity bytes
+    ReadJedecId = 22,
+
+    /// erase one sector and program it with new contents in a single request, verifying the result
+    ReplaceSector = 23,
+
+    /// read out the counts of WriteRegion and BulkErase durations, optionally clearing them
+    GetLatencyHistogram = 24,
+    /// clear the latency counts
+    ResetLatencyHistogram = 25,
+
+    /// check an A/B slot's contents, then program the word that makes it the active slot
+    CommitAbSwitch = 26,
+
+    /// queue a WriteRegion and return a job id straight away, without waiting for the write
+    SubmitWrite = 27,
+    /// report whether a submitted write is still queued, or how it finished
+    PollWrite = 28,
+    /// block until a submitted write has finished
+    WaitWrite = 29,
+    /// internal message: run the oldest queued write
+    RunWriteJob = 30,
+
+    /// read up to a page from FLASH, for callers that can't map it themselves
+    ReadRegion = 31,
+
+    /// hook a callback for the progress of bulk erases, writes and attestation
+    RegisterProgressCallback = 32,
+
+    /// write up to WRITE_STREAM_MAX bytes, a sector at a time, from a single lent buffer
+    WriteStream = 33,
+
+    /// like VerifyErased, but over the 64kiB blocks a BulkErase of the same range would erase
+    CheckErased = 34,
 }
 // Erase/Write are uninterruptable operations. Split suspend/resume
 // into a separate server to asynchronously manage this.
@@ -47,6 +107,21 @@ pub(crate) enum SusResOps {
     Quit,
 }
 
+#[derive(Debug, num_derive::FromPrimitive, num_derive::ToPrimitive)]
+pub(crate) enum ProgressEvent {
+    /// (bytes done, bytes total) of the operation in progress
+    Progress,
+    /// exit the callback thread
+    Drop,
+}
+
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone)]
+pub(crate) struct ScalarHook {
+    pub sid: (u32, u32, u32, u32),
+    pub id: u32,  // ID of the scalar message to send through (e.g. the discriminant of the Enum on the caller's side API)
+    pub cid: xous::CID,   // caller-side connection ID for the scalar message to route to. Created by the caller before hooking.
+}
+
 #[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Clone, Copy)]
 pub(crate) struct WriteRegion {
     /// the exclusive access ID
@@ -57,12 +132,147 @@ pub(crate) struct WriteRegion {
     pub clean_patch: bool,
     /// length of data to write
     pub len: u32,
+    /// set to read the region back after the write and return its CRC32 in `crc`. This adds a read
+    /// pass over the region, so it costs latency, and fails with `AccessDenied` if another process
+    /// has the region mapped.
+    pub verify_crc: bool,
+    /// CRC32 (IEEE) of what was read back, if `verify_crc` was set
+    pub crc: u32,
+    /// start of the sector being erased or written when an erase or program failure was reported
+    pub fail_addr: Option<u32>,
     /// return code
     pub result: Option<SpinorError>,
     /// data to write - up to one page
     pub data: [u8; 4096],
 }
 
+/// the most data a single WriteStream can carry
+pub const WRITE_STREAM_MAX: usize = 0x1_0000;
+/// The buffer lent with a WriteStream is hand-packed rather than an rkyv struct, so that 64kiB of data
+/// doesn't have to be copied through the stack on either side: the header takes the first page, and
+/// the data follows it, page-aligned.
+#[cfg_attr(test, allow(dead_code))]
+pub(crate) const WRITE_STREAM_DATA_OFFSET: usize = 0x1000;
+#[cfg_attr(test, allow(dead_code))]
+pub(crate) const WRITE_STREAM_BUF_LEN: usize = WRITE_STREAM_DATA_OFFSET + WRITE_STREAM_MAX;
+
+#[cfg_attr(test, allow(dead_code))]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct WriteStreamHeader {
+    /// the exclusive access ID
+    pub id: [u32; 4],
+    /// start address for the write; address 0 is start of FLASH. Must be sector-aligned.
+    pub start: u32,
+    /// length of the data following the header
+    pub len: u32,
+    /// return code
+    pub result: Option<SpinorError>,
+    /// offset into the data of the sector that failed, if `result` is an error
+    pub fail_offset: u32,
+}
+#[cfg_attr(test, allow(dead_code))]
+impl WriteStreamHeader {
+    /// how `None` is encoded in the `result` word
+    const NO_RESULT: u32 = u32::MAX;
+
+    pub fn from_words(words: &[u32]) -> Self {
+        WriteStreamHeader {
+            id: [words[0], words[1], words[2], words[3]],
+            start: words[4],
+            len: words[5],
+            result: if words[6] == Self::NO_RESULT { None } else {
+                Some(FromPrimitive::from_u32(words[6]).unwrap_or(SpinorError::ImplementationError))
+            },
+            fail_offset: words[7],
+        }
+    }
+    pub fn to_words(&self, words: &mut [u32]) {
+        words[..4].copy_from_slice(&self.id);
+        words[4] = self.start;
+        words[5] = self.len;
+        words[6] = self.result.map_or(Self::NO_RESULT, |e| e.to_u32().unwrap());
+        words[7] = self.fail_offset;
+    }
+}
+
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Clone, Copy)]
+pub(crate) struct WriteJob {
+    /// the write to queue. Its `result` only says whether the write was accepted into the queue.
+    pub wr: WriteRegion,
+    /// the id to poll or wait on, assigned by the server if the write was accepted
+    pub job: u32,
+}
+
+/// The state of a write queued with `SubmitWrite`.
+#[derive(Debug, Clone, Copy)]
+pub enum WriteStatus {
+    /// still waiting its turn, or being written
+    InProgress,
+    /// finished, with the result it would have returned from a blocking write
+    Done(SpinorError),
+}
+impl WriteStatus {
+    /// Packs the status for a scalar reply; `None` stands for a job id the server doesn't know.
+    #[allow(dead_code)]
+    pub(crate) fn to_scalar(status: Option<WriteStatus>) -> (usize, usize) {
+        match status {
+            None => (0, 0),
+            Some(WriteStatus::InProgress) => (1, 0),
+            Some(WriteStatus::Done(e)) => (2, e.to_usize().unwrap()),
+        }
+    }
+    #[allow(dead_code)]
+    pub(crate) fn from_scalar(code: usize, err: usize) -> Option<WriteStatus> {
+        match code {
+            1 => Some(WriteStatus::InProgress),
+            2 => Some(WriteStatus::Done(FromPrimitive::from_usize(err).unwrap_or(SpinorError::ImplementationError))),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Clone, Copy)]
+pub(crate) struct ReplaceSector {
+    /// the exclusive access ID
+    pub id: [u32; 4],
+    /// start address of the sector; address 0 is start of FLASH. Must be aligned to SPINOR_ERASE_SIZE.
+    pub start: u32,
+    /// return code
+    pub result: Option<SpinorError>,
+    /// the new contents of the whole sector
+    pub data: [u8; 4096],
+}
+
+/// value of an unprogrammed word in an A/B marker log
+pub const AB_MARKER_ERASED: u32 = 0xFFFF_FFFF;
+
+/// Reads an A/B marker log: a run of words, each programmed once by `commit_ab_switch`, in order.
+/// The active slot is the last word programmed; `None` if no commit has happened yet.
+pub fn ab_active_slot(log: &[u8]) -> Option<u32> {
+    log.chunks_exact(4)
+        .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+        .take_while(|&word| word != AB_MARKER_ERASED)
+        .last()
+}
+
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Clone, Copy)]
+pub(crate) struct AbCommit {
+    /// the ID of the caller, which must hold the exclusive lock
+    pub id: [u32; 4],
+    /// address of the marker word to program; address 0 is start of FLASH.
+    pub marker_addr: u32,
+    /// the value to program into the marker word, naming the slot that becomes active
+    pub new_active: u32,
+    /// start of the slot that becomes active
+    pub slot_start: u32,
+    /// length of the slot
+    pub slot_len: u32,
+    /// SHA-256 the slot's contents must match, if any
+    pub slot_hash: Option<[u8; 32]>,
+    /// return code
+    pub result: Option<SpinorError>,
+}
+
 #[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Clone, Copy)]
 pub(crate) struct BulkErase {
     /// the exclusive access ID
@@ -73,6 +283,143 @@ pub(crate) struct BulkErase {
     pub len: u32,
     /// return code
     pub result: Option<SpinorError>,
+    /// start of the block that failed to erase, if the result is `EraseFailed`
+    pub fail_addr: Option<u32>,
+}
+
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Clone, Copy)]
+pub(crate) struct AcquireTimeout {
+    /// the exclusive access ID
+    pub id: [u32; 4],
+    /// how long to wait for the lock before giving up, in milliseconds
+    pub timeout_ms: u32,
+    /// return code
+    pub result: Option<SpinorError>,
+}
+
+/// RDID response of the Macronix MX66UM1G45G fitted to Precursor
+pub const PRECURSOR_JEDEC_ID: u32 = 0x3B_80_C2;
+
+/// The three identification bytes returned by the JEDEC RDID (0x9F) command.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct JedecId {
+    pub manufacturer: u8,
+    pub memory_type: u8,
+    pub capacity: u8,
+}
+impl JedecId {
+    /// Unpacks an ID as it travels over IPC: manufacturer in the low byte, capacity in the third.
+    pub fn from_u32(id: u32) -> Self {
+        JedecId {
+            manufacturer: id as u8,
+            memory_type: (id >> 8) as u8,
+            capacity: (id >> 16) as u8,
+        }
+    }
+    pub fn to_u32(&self) -> u32 {
+        self.manufacturer as u32 | (self.memory_type as u32) << 8 | (self.capacity as u32) << 16
+    }
+    /// Size of the part in bytes, decoded from the capacity byte. Most parts encode log2(bytes)
+    /// directly; Macronix's octal parts (manufacturer 0xC2, capacity 0x3x) add 0x20 to that.
+    pub fn capacity_bytes(&self) -> Option<u32> {
+        let log2 = match (self.manufacturer, self.capacity) {
+            (0xC2, c @ 0x30..=0x3F) => c - 0x20,
+            (_, c) => c,
+        };
+        1u32.checked_shl(log2 as u32).filter(|_| log2 > 0)
+    }
+}
+
+/// number of buckets in a latency histogram. Bucket 0 counts operations that took under 1ms,
+/// bucket `n` those that took `2^(n-1)..2^n` ms, and the last bucket also counts anything longer.
+pub const LATENCY_BUCKETS: usize = 16;
+
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Clone, Copy)]
+pub struct LatencyHistogram {
+    /// counts of WriteRegion durations, by bucket
+    pub write: [u32; LATENCY_BUCKETS],
+    /// counts of BulkErase durations, by bucket
+    pub erase: [u32; LATENCY_BUCKETS],
+    /// clear the counts once they have been read out
+    pub reset: bool,
+}
+impl LatencyHistogram {
+    pub fn new(reset: bool) -> Self {
+        LatencyHistogram { write: [0; LATENCY_BUCKETS], erase: [0; LATENCY_BUCKETS], reset }
+    }
+}
+
+/// the most ECC log entries returned by one read of the log
+pub const ECC_LOG_ENTRIES: usize = 32;
+
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EccLogEntry {
+    /// FLASH address of the 16-byte chunk that failed its ECC check
+    pub address: u32,
+    /// the ECC status code the part reported for the failure
+    pub syndrome: u32,
+    /// set if the error was a single bit flip that the part corrected; otherwise the data read back was bad
+    pub corrected: bool,
+    /// when the server recorded the error, in ticktimer milliseconds
+    pub timestamp: u64,
+}
+
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Clone, Copy)]
+pub(crate) struct EccLog {
+    /// the oldest entries in the log; only the first `count` are valid
+    pub entries: [EccLogEntry; ECC_LOG_ENTRIES],
+    pub count: u32,
+}
+impl EccLog {
+    pub fn new() -> Self {
+        EccLog { entries: [EccLogEntry::default(); ECC_LOG_ENTRIES], count: 0 }
+    }
+}
+
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Clone, Copy)]
+pub(crate) struct ChipDigest {
+    /// SHA-256 of the whole FLASH array, from address 0 up
+    pub digest: [u8; 32],
+    /// return code
+    pub result: Option<SpinorError>,
+}
+
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Clone, Copy)]
+pub(crate) struct ProtectedRegion {
+    /// the SoC token of the caller
+    pub id: [u32; 4],
+    /// start address of the region; address 0 is start of FLASH.
+    pub start: u32,
+    /// length of the region. Ignored on removal, which matches on `start` only.
+    pub len: u32,
+    /// return code
+    pub result: Option<SpinorError>,
+}
+
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Clone, Copy)]
+pub(crate) struct VerifyErased {
+    /// start address of the check; address 0 is start of FLASH. For CheckErased, which unlike BulkErase
+    /// takes unaligned ranges, the server rounds it down to 64kiB and returns the rounded value.
+    pub start: u32,
+    /// length of the region to check. For CheckErased, the server rounds it up to cover the last 64kiB
+    /// block and returns the rounded value.
+    pub len: u32,
+    /// address of the first byte that is not 0xFF, if any
+    pub first_dirty: Option<u32>,
+    /// return code
+    pub result: Option<SpinorError>,
+}
+
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Clone, Copy)]
+pub(crate) struct ReadRegion {
+    /// start address of the read; address 0 is start of FLASH.
+    pub start: u32,
+    /// length of the read
+    pub len: u32,
+    /// data read back - up to one page
+    pub data: [u8; 4096],
+    /// return code
+    pub result: Option<SpinorError>,
 }
 
 #[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Clone, Copy, num_derive::FromPrimitive, num_derive::ToPrimitive)]
@@ -0,0 +1,101 @@
+use crate::api::{EccLog, EccLogEntry, ECC_LOG_ENTRIES};
+use std::collections::VecDeque;
+
+// The ECC status of each 512Mbit half of the part is a code byte, repeated in both bytes of its
+// 16 bits of the status word because of DDR. Bits 5:4 of the code give the type of failure; the
+// first failure in the field, 0xb3, was a double-bit error that could only be detected.
+const FAIL_TYPE_SHIFT: u32 = 4;
+const FAIL_TYPE_MASK: u32 = 0b11;
+const FAIL_TYPE_CORRECTED: u32 = 0b01;
+
+/// Errors reported by the ECC interrupt, oldest first, until they are read out with `EccLog`.
+/// The interrupt keeps re-reporting the last failure, so an error already in the log isn't
+/// recorded again, and once the log is full new errors are dropped.
+pub(crate) struct EccErrors {
+    entries: VecDeque<EccLogEntry>,
+    capacity: usize,
+}
+
+impl EccErrors {
+    pub fn new(capacity: usize) -> Self {
+        EccErrors { entries: VecDeque::new(), capacity }
+    }
+
+    /// Records the errors in one interrupt report: `status` is the status word, and `lower_addr` and
+    /// `upper_addr` the failing addresses in the lower and upper halves of the part. A half whose
+    /// status is 0 has no error. Returns the number of errors that were newly recorded.
+    pub fn record(&mut self, status: u32, lower_addr: u32, upper_addr: u32, timestamp: u64) -> usize {
+        let mut recorded = 0;
+        for (code, address) in [(status & 0xFF, lower_addr), ((status >> 16) & 0xFF, upper_addr)] {
+            if code == 0 || self.entries.iter().any(|e| e.address == address && e.syndrome == code) {
+                continue;
+            }
+            if self.entries.len() >= self.capacity {
+                log::warn!("ECC log overflow, error not stored");
+                continue;
+            }
+            self.entries.push_back(EccLogEntry {
+                address,
+                syndrome: code,
+                corrected: (code >> FAIL_TYPE_SHIFT) & FAIL_TYPE_MASK == FAIL_TYPE_CORRECTED,
+                timestamp,
+            });
+            recorded += 1;
+        }
+        recorded
+    }
+
+    /// Moves the oldest entries into `log`, as many as it holds, and sets its `count`. Any entries
+    /// that don't fit are left for the next read.
+    pub fn drain_into(&mut self, log: &mut EccLog) {
+        let count = self.entries.len().min(ECC_LOG_ENTRIES);
+        for (dst, src) in log.entries.iter_mut().zip(self.entries.drain(..count)) {
+            *dst = src;
+        }
+        log.count = count as u32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ecc_record() {
+        let mut errors = EccErrors::new(4);
+        // the first failure seen in the field: a double-bit error in the upper half
+        assert_eq!(errors.record(0xb3b3_0000, 0x330_5080, 0x730_5030, 10), 1);
+        // the interrupt reporting it again doesn't add an entry
+        assert_eq!(errors.record(0xb3b3_0000, 0x330_5080, 0x730_5030, 20), 0);
+        // a corrected error in the lower half, alongside the same upper one
+        assert_eq!(errors.record(0xb3b3_9191, 0x12_3450, 0x730_5030, 30), 1);
+
+        let mut log = EccLog::new();
+        errors.drain_into(&mut log);
+        assert_eq!(log.count, 2);
+        assert_eq!(log.entries[0], EccLogEntry { address: 0x730_5030, syndrome: 0xb3, corrected: false, timestamp: 10 });
+        assert_eq!(log.entries[1], EccLogEntry { address: 0x12_3450, syndrome: 0x91, corrected: true, timestamp: 30 });
+        // a read drains the log
+        errors.drain_into(&mut log);
+        assert_eq!(log.count, 0);
+    }
+
+    #[test]
+    fn test_ecc_bounds() {
+        let mut errors = EccErrors::new(ECC_LOG_ENTRIES + 8);
+        for i in 0..ECC_LOG_ENTRIES as u32 + 10 {
+            errors.record(0x91, i * 0x10, 0, i as u64);
+        }
+        // the log is full, so the last two were dropped
+        let mut log = EccLog::new();
+        errors.drain_into(&mut log);
+        assert_eq!(log.count as usize, ECC_LOG_ENTRIES);
+        assert_eq!(log.entries[0].address, 0);
+        // what didn't fit in the first read comes out in the next one
+        errors.drain_into(&mut log);
+        assert_eq!(log.count, 8);
+        assert_eq!(log.entries[0].address, ECC_LOG_ENTRIES as u32 * 0x10);
+        errors.drain_into(&mut log);
+        assert_eq!(log.count, 0);
+    }
+}
@@ -0,0 +1,74 @@
+use crate::api::{LatencyHistogram, LATENCY_BUCKETS};
+
+/// Histogram bucket for an operation that took `ms`: bucket 0 is under 1ms, bucket `n` is
+/// `2^(n-1)..2^n` ms, and the last bucket also takes everything longer than that.
+pub(crate) fn latency_bucket(ms: u64) -> usize {
+    ((u64::BITS - ms.leading_zeros()) as usize).min(LATENCY_BUCKETS - 1)
+}
+
+/// Running distribution of how long writes and erases take, for tuning the PDDB's use of the
+/// FLASH. Counts saturate rather than wrap, so a histogram that's never reset stays usable.
+pub(crate) struct Latencies {
+    write: [u32; LATENCY_BUCKETS],
+    erase: [u32; LATENCY_BUCKETS],
+}
+
+impl Latencies {
+    pub fn new() -> Self {
+        Latencies { write: [0; LATENCY_BUCKETS], erase: [0; LATENCY_BUCKETS] }
+    }
+
+    pub fn record_write(&mut self, ms: u64) {
+        let bucket = &mut self.write[latency_bucket(ms)];
+        *bucket = bucket.saturating_add(1);
+    }
+
+    pub fn record_erase(&mut self, ms: u64) {
+        let bucket = &mut self.erase[latency_bucket(ms)];
+        *bucket = bucket.saturating_add(1);
+    }
+
+    /// Copies the counts into `hist`, clearing them afterwards if `hist.reset` is set
+    pub fn report(&mut self, hist: &mut LatencyHistogram) {
+        hist.write = self.write;
+        hist.erase = self.erase;
+        if hist.reset {
+            self.reset();
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.write = [0; LATENCY_BUCKETS];
+        self.erase = [0; LATENCY_BUCKETS];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_buckets() {
+        assert_eq!(latency_bucket(0), 0);
+        assert_eq!(latency_bucket(1), 1);
+        assert_eq!(latency_bucket(2), 2);
+        assert_eq!(latency_bucket(3), 2);
+        assert_eq!(latency_bucket(4), 3);
+        assert_eq!(latency_bucket(255), 8);
+        assert_eq!(latency_bucket(256), 9);
+        assert_eq!(latency_bucket(u64::MAX), LATENCY_BUCKETS - 1);
+
+        let mut lat = Latencies::new();
+        lat.record_write(3);
+        lat.record_write(3);
+        lat.record_erase(400);
+        let mut hist = LatencyHistogram::new(true);
+        lat.report(&mut hist);
+        assert_eq!(hist.write[2], 2);
+        assert_eq!(hist.erase[9], 1);
+        assert_eq!(hist.write.iter().sum::<u32>() + hist.erase.iter().sum::<u32>(), 3);
+        // reading with reset set clears the counts
+        lat.report(&mut hist);
+        assert_eq!(hist.write.iter().sum::<u32>() + hist.erase.iter().sum::<u32>(), 0);
+    }
+}
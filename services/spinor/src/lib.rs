@@ -10,24 +10,86 @@ use std::sync::Mutex;
 #[cfg(test)]
 lazy_static! {
     static ref EMU_FLASH: Mutex<Vec<u8>> = Mutex::new(vec![]);
+    // an address that ignores writes, to emulate a failing cell
+    static ref EMU_STUCK: Mutex<Option<usize>> = Mutex::new(None);
+    // emulates the server's record of failures since the last barrier
+    static ref EMU_SESSION_ERR: Mutex<Option<SpinorError>> = Mutex::new(None);
+    // emulates the owner of the server's exclusive lock
+    static ref EMU_LOCK_HOLDER: Mutex<Option<[u32; 4]>> = Mutex::new(None);
+    // the part the emulated server reports for RDID
+    static ref EMU_JEDEC_ID: Mutex<u32> = Mutex::new(PRECURSOR_JEDEC_ID);
+    /// durations of emulated writes and erases
+    static ref EMU_LATENCY: Mutex<latency::Latencies> = Mutex::new(latency::Latencies::new());
+    /// errors the emulated ECC interrupt has reported
+    static ref EMU_ECC: Mutex<ecc::EccErrors> = Mutex::new(ecc::EccErrors::new(512));
+    // writes queued with SubmitWrite but not yet run, the results of those that have been, and the next job id
+    static ref EMU_WRITE_JOBS: Mutex<std::collections::VecDeque<(u32, WriteRegion)>> = Mutex::new(std::collections::VecDeque::new());
+    static ref EMU_WRITE_RESULTS: Mutex<std::collections::BTreeMap<u32, SpinorError>> = Mutex::new(std::collections::BTreeMap::new());
+    static ref EMU_NEXT_JOB: Mutex<u32> = Mutex::new(0);
 }
 
 pub mod api;
 pub use api::*;
+// server-side bookkeeping; it is only pulled into the library so its unit tests run
+#[cfg(test)]
+mod regions;
+#[cfg(test)]
+mod throttle;
+#[cfg(test)]
+mod latency;
+#[cfg(test)]
+mod validate;
+#[cfg(test)]
+mod ecc;
+#[cfg(test)]
+mod waiters;
 
 use xous::{CID, send_message, Message};
+use core::cell::Cell;
 use num_traits::*;
 use xous_ipc::Buffer;
 
+impl SpinorError {
+    /// `NoError` becomes `Ok(())`; every other code is returned as the error.
+    pub fn into_result(self) -> Result<(), SpinorError> {
+        match self {
+            SpinorError::NoError => Ok(()),
+            e => Err(e),
+        }
+    }
+    /// Reads the `result` field of a reply. A reply the server never filled in means the two sides
+    /// disagree about the protocol, which is reported as an `ImplementationError`.
+    pub fn from_reply(result: Option<SpinorError>) -> Result<(), SpinorError> {
+        result.map_or(Err(SpinorError::ImplementationError), SpinorError::into_result)
+    }
+    /// Reads an error code returned in a scalar reply. Codes this side doesn't know are an `ImplementationError`.
+    pub fn from_code(code: usize) -> Result<(), SpinorError> {
+        FromPrimitive::from_usize(code).map_or(Err(SpinorError::ImplementationError), SpinorError::into_result)
+    }
+}
+
+/// Reads the reply of an opcode that grants or refuses a lock with a 1 or 0 in a scalar: a refusal
+/// is `BusyTryAgain`, and a reply of the wrong shape an `IpcError`.
+fn lock_reply(response: Result<xous::Result, xous::Error>) -> Result<(), SpinorError> {
+    match response {
+        Ok(xous::Result::Scalar1(0)) => Err(SpinorError::BusyTryAgain),
+        Ok(xous::Result::Scalar1(_)) => Ok(()),
+        _ => Err(SpinorError::IpcError),
+    }
+}
+
 #[derive(Debug)]
 pub struct Spinor {
     conn: CID,
     token: [u32; 4],
+    acquire_timeout_ms: Option<u32>,
+    progress_sid: Option<xous::SID>,
+    last_fail_addr: Cell<Option<u32>>,
 }
 impl Spinor {
     #[cfg(test)]
     pub fn new() -> Self {
-        Spinor { conn: 0, token: [0, 0, 0, 0] }
+        Spinor { conn: 0, token: [0, 0, 0, 0], acquire_timeout_ms: None, progress_sid: None, last_fail_addr: Cell::new(None) }
     }
 
     #[cfg(not(test))]
@@ -44,9 +106,44 @@ impl Spinor {
                 trng.get_u32().unwrap(),
                 trng.get_u32().unwrap(),
             ],
+            acquire_timeout_ms: None,
+            progress_sid: None,
+            last_fail_addr: Cell::new(None),
         })
     }
 
+    /// The address of the sector (or, for `bulk_erase`, the 64kiB block) that failed in the most recent
+    /// write or erase to fail with `EraseFailed`, `WriteFailed` or `VerifyFailed`, so that just that
+    /// sector can be retried. It is cleared by each write or erase request this object sends, so
+    /// it describes the failure of the call that just returned, not some earlier one.
+    pub fn last_fail_addr(&self) -> Option<u32> {
+        self.last_fail_addr.get()
+    }
+
+    /// Hooks a callback for the progress of bulk erases, writes queued with `submit_write`, and
+    /// `attest_whole_chip`. Reports arrive as a scalar message with ID `id` on `cid`, with the bytes
+    /// done in arg1 and the bytes total in arg2. Each region a `patch` or other plain write sends is
+    /// reported on its own as it completes, with done equal to total, as the server doesn't know
+    /// how many more are coming. Reports may be dropped if the receiver falls behind, so treat them
+    /// as a hint for a progress bar, not as a record of what completed.
+    pub fn hook_progress_callback(&mut self, id: u32, cid: CID) -> Result<(), xous::Error> {
+        if self.progress_sid.is_none() {
+            let sid = xous::create_server().unwrap();
+            self.progress_sid = Some(sid);
+            let sid_tuple = sid.to_u32();
+            xous::create_thread_4(progress_cb_server, sid_tuple.0 as usize, sid_tuple.1 as usize, sid_tuple.2 as usize, sid_tuple.3 as usize).unwrap();
+            let hookdata = ScalarHook {
+                sid: sid_tuple,
+                id,
+                cid,
+            };
+            let buf = Buffer::into_buf(hookdata).or(Err(xous::Error::InternalError))?;
+            buf.lend(self.conn, Opcode::RegisterProgressCallback.to_u32().unwrap()).map(|_|())
+        } else {
+            Err(xous::Error::MemoryInUse) // can't hook it twice
+        }
+    }
+
     /// this returns the minimum alignment for an erase block. `write` and `erase` operations
     /// benefit in performance if the requests are aligned to this number.
     pub fn erase_alignment(&self) -> u32 {
@@ -90,57 +187,515 @@ impl Spinor {
     }
 
     #[cfg(not(test))]
-    fn send_write_region(&self, wr: &WriteRegion) -> Result<(), SpinorError> {
+    /// Returns the `crc` of the reply, which is only meaningful if `verify_crc` was set.
+    fn send_write_region(&self, wr: &WriteRegion) -> Result<u32, SpinorError> {
         let mut buf = Buffer::into_buf(*wr).or(Err(SpinorError::IpcError))?;
         buf.lend_mut(self.conn, Opcode::WriteRegion.to_u32().unwrap()).or(Err(SpinorError::IpcError))?;
 
         match buf.to_original::<WriteRegion, _>() {
             Ok(wr) => {
-                if let Some(res) = wr.result {
-                    match res {
-                        SpinorError::NoError => Ok(()),
-                        _ => Err(res)
-                    }
-                } else {
-                    Err(SpinorError::ImplementationError)
-                }
+                self.last_fail_addr.set(wr.fail_addr);
+                SpinorError::from_reply(wr.result).map(|_| wr.crc)
             }
             _ => Err(SpinorError::ImplementationError)
         }
     }
 
     #[cfg(test)]
-    fn send_write_region(&self, wr: &WriteRegion) -> Result<(), SpinorError> {
+    fn send_write_region(&self, wr: &WriteRegion) -> Result<u32, SpinorError> {
+        self.last_fail_addr.set(None);
+        validate::check_write_region(wr)?;
+        let started = std::time::Instant::now();
         let mut i = 0;
         if !wr.clean_patch {
-            assert!((wr.start & 0xFFF) == 0, "erasing is required, but start address is not erase-sector aligned");
             for addr in wr.start..wr.start + 4096 {
                 EMU_FLASH.lock().unwrap()[addr as usize] = 0xFF;
             }
         }
         for addr in wr.start..wr.start + wr.len {
             assert!(EMU_FLASH.lock().unwrap()[addr as usize] == 0xFF, "attempt to write memory that's not erased");
-            EMU_FLASH.lock().unwrap()[addr as usize] = wr.data[i];
+            if *EMU_STUCK.lock().unwrap() != Some(addr as usize) {
+                EMU_FLASH.lock().unwrap()[addr as usize] = wr.data[i];
+            }
             i += 1;
         }
+        EMU_LATENCY.lock().unwrap().record_write(started.elapsed().as_millis() as u64);
+        // read back the data, as the hardware does with its program-fail status
+        let flash = EMU_FLASH.lock().unwrap();
+        let readback = &flash[wr.start as usize..(wr.start + wr.len) as usize];
+        if readback != &wr.data[..wr.len as usize] {
+            EMU_SESSION_ERR.lock().unwrap().get_or_insert(SpinorError::VerifyFailed);
+            self.last_fail_addr.set(Some(wr.start));
+            return Err(SpinorError::VerifyFailed);
+        }
+        Ok(if wr.verify_crc { crc::crc32::checksum_ieee(readback) } else { 0 })
+    }
+
+    #[cfg(not(test))]
+    fn send_barrier(&self) -> Result<(), SpinorError> {
+        let response = send_message(self.conn,
+            Message::new_blocking_scalar(Opcode::Barrier.to_usize().unwrap(),
+                self.token[0] as usize,
+                self.token[1] as usize,
+                self.token[2] as usize,
+                self.token[3] as usize,
+            )
+        ).or(Err(SpinorError::IpcError))?;
+        if let xous::Result::Scalar1(code) = response {
+            SpinorError::from_code(code)
+        } else {
+            Err(SpinorError::IpcError)
+        }
+    }
+
+    #[cfg(test)]
+    fn send_barrier(&self) -> Result<(), SpinorError> {
+        match EMU_SESSION_ERR.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns once every write and erase previously accepted from this object has completed and
+    /// passed its program/erase status check, or the first error that occurred since the last barrier.
+    /// Use this to order metadata updates after the data they point to is durable.
+    pub fn barrier(&self) -> Result<(), SpinorError> {
+        self.send_barrier()
+    }
+
+    #[cfg(not(test))]
+    fn send_replace_sector(&self, rs: &ReplaceSector) -> Result<(), SpinorError> {
+        let mut buf = Buffer::into_buf(*rs).or(Err(SpinorError::IpcError))?;
+        buf.lend_mut(self.conn, Opcode::ReplaceSector.to_u32().unwrap()).or(Err(SpinorError::IpcError))?;
+
+        match buf.to_original::<ReplaceSector, _>() {
+            Ok(rs) => SpinorError::from_reply(rs.result),
+            _ => Err(SpinorError::ImplementationError)
+        }
+    }
+
+    #[cfg(test)]
+    fn send_replace_sector(&self, rs: &ReplaceSector) -> Result<(), SpinorError> {
+        let stuck = *EMU_STUCK.lock().unwrap();
+        let mut flash = EMU_FLASH.lock().unwrap();
+        let sector = &mut flash[rs.start as usize..(rs.start + SPINOR_ERASE_SIZE) as usize];
+        for byte in sector.iter_mut() {
+            *byte = 0xFF;
+        }
+        for (addr, (dst, &src)) in (rs.start as usize..).zip(sector.iter_mut().zip(rs.data.iter())) {
+            if stuck != Some(addr) {
+                // programming can only clear bits
+                *dst &= src;
+            }
+        }
+        if sector != &rs.data[..] {
+            EMU_SESSION_ERR.lock().unwrap().get_or_insert(SpinorError::VerifyFailed);
+            return Err(SpinorError::VerifyFailed);
+        }
+        Ok(())
+    }
+
+    /// Replaces the entire contents of the erase sector at `start` with `data`. The erase, the program
+    /// and the read-back check all happen in one request under one hold of the exclusive lock, so no
+    /// other client's write can land in between. `start` is an offset from the start of FLASH, and
+    /// must be aligned to `erase_alignment()`.
+    pub fn replace_sector(&self, start: u32, data: &[u8; 4096]) -> Result<(), SpinorError> {
+        if (start & (SPINOR_ERASE_SIZE - 1)) != 0 {
+            return Err(SpinorError::AlignmentError);
+        }
+        // acquire a write lock on the unit
+        #[cfg(not(test))]
+        self.acquire_exclusive()?;
+        let rs = ReplaceSector {
+            id: self.token,
+            start,
+            result: None,
+            data: *data,
+        };
+        let ret = self.send_replace_sector(&rs);
+        // release the write lock before exiting
+        #[cfg(not(test))]
+        self.release_exclusive();
+        ret
+    }
+
+    #[cfg(not(test))]
+    fn send_commit_ab_switch(&self, ab: &AbCommit) -> Result<(), SpinorError> {
+        let mut buf = Buffer::into_buf(*ab).or(Err(SpinorError::IpcError))?;
+        buf.lend_mut(self.conn, Opcode::CommitAbSwitch.to_u32().unwrap()).or(Err(SpinorError::IpcError))?;
+
+        match buf.to_original::<AbCommit, _>() {
+            Ok(ab) => SpinorError::from_reply(ab.result),
+            _ => Err(SpinorError::ImplementationError)
+        }
+    }
+
+    #[cfg(test)]
+    fn send_commit_ab_switch(&self, ab: &AbCommit) -> Result<(), SpinorError> {
+        use sha2::Digest;
+        let mut flash = EMU_FLASH.lock().unwrap();
+        if let Some(expected) = ab.slot_hash {
+            let mut hasher = sha2::Sha256::new();
+            hasher.update(&flash[ab.slot_start as usize..(ab.slot_start + ab.slot_len) as usize]);
+            if hasher.finalize().as_slice() != &expected[..] {
+                EMU_SESSION_ERR.lock().unwrap().get_or_insert(SpinorError::VerifyFailed);
+                return Err(SpinorError::VerifyFailed);
+            }
+        }
+        let marker = &mut flash[ab.marker_addr as usize..ab.marker_addr as usize + 4];
+        let current = u32::from_le_bytes([marker[0], marker[1], marker[2], marker[3]]);
+        if current & ab.new_active != ab.new_active {
+            EMU_SESSION_ERR.lock().unwrap().get_or_insert(SpinorError::AbortNotErased);
+            return Err(SpinorError::AbortNotErased);
+        }
+        for (dst, src) in marker.iter_mut().zip(ab.new_active.to_le_bytes().iter()) {
+            *dst &= src;
+        }
+        Ok(())
+    }
+
+    /// Makes the A/B slot at `slot_start..slot_start+slot_len` the active one, by programming
+    /// `new_active` into the marker word at `marker_addr`, e.g. after writing a firmware image into
+    /// the inactive slot. If `slot_hash` is given, the slot is first checked against it, and the
+    /// switch is refused with `VerifyFailed` if it doesn't match.
+    ///
+    /// The marker word is the only thing programmed, and it goes last, so a crash at any point
+    /// before the commit leaves the old slot active. It's programmed without an erase, so it has to
+    /// be an erased word: keep the markers in a log, commit each switch to the next erased word, and
+    /// read the active slot back with `ab_active_slot()`. Erasing a full log is not atomic, so start
+    /// a new log in another sector before erasing the old one. The slot has to be aligned to
+    /// `erase_alignment()` and the marker to a word; addresses are offsets from the start of FLASH.
+    pub fn commit_ab_switch(&self, marker_addr: u32, new_active: u32, slot_start: u32, slot_len: u32, slot_hash: Option<[u8; 32]>) -> Result<(), SpinorError> {
+        if (marker_addr & 3) != 0 || (slot_start & (SPINOR_ERASE_SIZE - 1)) != 0 || (slot_len & (SPINOR_ERASE_SIZE - 1)) != 0 {
+            return Err(SpinorError::AlignmentError);
+        }
+        if new_active == AB_MARKER_ERASED {
+            // indistinguishable from a commit that never happened
+            return Err(SpinorError::InvalidRequest);
+        }
+        // acquire a write lock on the unit
+        #[cfg(not(test))]
+        self.acquire_exclusive()?;
+        let ab = AbCommit {
+            id: self.token,
+            marker_addr,
+            new_active,
+            slot_start,
+            slot_len,
+            slot_hash,
+            result: None,
+        };
+        let ret = self.send_commit_ab_switch(&ab);
+        // release the write lock before exiting
+        #[cfg(not(test))]
+        self.release_exclusive();
+        ret
+    }
+
+    #[cfg(not(test))]
+    fn send_submit_write(&self, job: &WriteJob) -> Result<u32, SpinorError> {
+        let mut buf = Buffer::into_buf(*job).or(Err(SpinorError::IpcError))?;
+        buf.lend_mut(self.conn, Opcode::SubmitWrite.to_u32().unwrap()).or(Err(SpinorError::IpcError))?;
+
+        match buf.to_original::<WriteJob, _>() {
+            Ok(job) => SpinorError::from_reply(job.wr.result).map(|_| job.job),
+            _ => Err(SpinorError::ImplementationError)
+        }
+    }
+
+    #[cfg(test)]
+    fn send_submit_write(&self, job: &WriteJob) -> Result<u32, SpinorError> {
+        let mut next = EMU_NEXT_JOB.lock().unwrap();
+        let id = *next;
+        *next = next.wrapping_add(1);
+        EMU_WRITE_JOBS.lock().unwrap().push_back((id, job.wr));
+        Ok(id)
+    }
+
+    #[cfg(not(test))]
+    fn send_write_status(&self, op: Opcode, job: u32) -> Result<WriteStatus, SpinorError> {
+        match send_message(self.conn,
+            Message::new_blocking_scalar(op.to_usize().unwrap(), job as usize, 0, 0, 0)
+        ) {
+            Ok(xous::Result::Scalar2(code, err)) => WriteStatus::from_scalar(code, err).ok_or(SpinorError::InvalidRequest),
+            _ => Err(SpinorError::IpcError),
+        }
+    }
+
+    #[cfg(test)]
+    fn send_write_status(&self, op: Opcode, job: u32) -> Result<WriteStatus, SpinorError> {
+        // the emulated worker runs one queued write per poll, and as many as it takes for a wait
+        let wait = matches!(op, Opcode::WaitWrite);
+        loop {
+            let next = EMU_WRITE_JOBS.lock().unwrap().pop_front();
+            if let Some((id, wr)) = next {
+                let result = match self.send_write_region(&wr) {
+                    Ok(_) => SpinorError::NoError,
+                    Err(e) => e,
+                };
+                EMU_WRITE_RESULTS.lock().unwrap().insert(id, result);
+            }
+            let status = if EMU_WRITE_JOBS.lock().unwrap().iter().any(|(id, _)| *id == job) {
+                Some(WriteStatus::InProgress)
+            } else {
+                EMU_WRITE_RESULTS.lock().unwrap().get(&job).map(|&e| WriteStatus::Done(e))
+            };
+            let (code, err) = WriteStatus::to_scalar(status);
+            let status = WriteStatus::from_scalar(code, err).ok_or(SpinorError::InvalidRequest)?;
+            if !(wait && matches!(status, WriteStatus::InProgress)) {
+                return Ok(status);
+            }
+        }
+    }
+
+    /// Queues a write of `data` at `start` and returns a job id straight away, so the caller can
+    /// get on with preparing the next write while this one is programmed. The sector at `start` is
+    /// erased first, so `start` has to be aligned to `erase_alignment()`, and `data` can't be longer
+    /// than a sector. Writes run in the order they were submitted; check on them with `poll_write()`
+    /// or `wait_write()`. Failures are also reported by the next `barrier()`.
+    pub fn submit_write(&self, start: u32, data: &[u8]) -> Result<u32, SpinorError> {
+        if (start & (SPINOR_ERASE_SIZE - 1)) != 0 {
+            return Err(SpinorError::AlignmentError);
+        }
+        if data.len() > SPINOR_ERASE_SIZE as usize {
+            return Err(SpinorError::InvalidRequest);
+        }
+        let mut job = WriteJob {
+            wr: WriteRegion {
+                id: self.token,
+                start,
+                clean_patch: false,
+                len: data.len() as u32,
+                verify_crc: false,
+                crc: 0,
+                fail_addr: None,
+                result: None,
+                data: [0xFF; 4096],
+            },
+            job: 0,
+        };
+        job.wr.data[..data.len()].copy_from_slice(data);
+        // the lock only has to be held while the write is queued: the server runs it ahead of the release
+        #[cfg(not(test))]
+        self.acquire_exclusive()?;
+        let ret = self.send_submit_write(&job);
+        #[cfg(not(test))]
+        self.release_exclusive();
+        ret
+    }
+
+    /// Writes `data` at `start` like `submit_write`, but waits for it to finish, then reads the sector
+    /// back and returns the CRC32 (IEEE) of the `data.len()` bytes that landed, so that a large image
+    /// can be checked against its expected CRC without a second round trip to read it out. The read
+    /// pass adds latency to every write, and the write fails with `AccessDenied` if another process
+    /// (such as the PDDB) has the sector mapped.
+    pub fn write_with_crc(&self, start: u32, data: &[u8]) -> Result<u32, SpinorError> {
+        if (start & (SPINOR_ERASE_SIZE - 1)) != 0 {
+            return Err(SpinorError::AlignmentError);
+        }
+        if data.len() > SPINOR_ERASE_SIZE as usize {
+            return Err(SpinorError::InvalidRequest);
+        }
+        let mut wr = WriteRegion {
+            id: self.token,
+            start,
+            clean_patch: false,
+            len: data.len() as u32,
+            verify_crc: true,
+            crc: 0,
+            fail_addr: None,
+            result: None,
+            data: [0xFF; 4096],
+        };
+        wr.data[..data.len()].copy_from_slice(data);
+        #[cfg(not(test))]
+        self.acquire_exclusive()?;
+        let ret = self.send_write_region(&wr);
+        #[cfg(not(test))]
+        self.release_exclusive();
+        ret
+    }
+
+    #[cfg(not(test))]
+    fn send_write_stream(&self, start: u32, data: &[u8]) -> Result<(), (SpinorError, u32)> {
+        let mut buf = xous::syscall::map_memory(None, None, WRITE_STREAM_BUF_LEN, xous::MemoryFlags::R | xous::MemoryFlags::W)
+            .or(Err((SpinorError::IpcError, 0)))?;
+        let header = WriteStreamHeader {
+            id: self.token,
+            start,
+            len: data.len() as u32,
+            result: None,
+            fail_offset: 0,
+        };
+        header.to_words(buf.as_slice_mut::<u32>());
+        buf.as_slice_mut::<u8>()[WRITE_STREAM_DATA_OFFSET..WRITE_STREAM_DATA_OFFSET + data.len()].copy_from_slice(data);
+        let msg = xous::MemoryMessage {
+            id: Opcode::WriteStream.to_usize().unwrap(),
+            buf,
+            offset: None,
+            valid: xous::MemorySize::new(WRITE_STREAM_DATA_OFFSET + data.len()),
+        };
+        let ret = match send_message(self.conn, Message::MutableBorrow(msg)) {
+            Ok(xous::Result::MemoryReturned(_, _)) => {
+                let header = WriteStreamHeader::from_words(buf.as_slice::<u32>());
+                SpinorError::from_reply(header.result).map_err(|e| (e, header.fail_offset))
+            }
+            _ => Err((SpinorError::IpcError, 0)),
+        };
+        xous::syscall::unmap_memory(buf).expect("couldn't unmap WriteStream buffer");
+        ret
+    }
+
+    #[cfg(test)]
+    fn send_write_stream(&self, start: u32, data: &[u8]) -> Result<(), (SpinorError, u32)> {
+        if (start & (SPINOR_ERASE_SIZE - 1)) != 0 {
+            return Err((SpinorError::AlignmentError, 0));
+        }
+        let mut wr = WriteRegion {
+            id: self.token,
+            start,
+            clean_patch: false,
+            len: 0,
+            verify_crc: false,
+            crc: 0,
+            fail_addr: None,
+            result: None,
+            data: [0xFF; 4096],
+        };
+        for (index, sector) in data.chunks(SPINOR_ERASE_SIZE as usize).enumerate() {
+            let offset = index as u32 * SPINOR_ERASE_SIZE;
+            wr.start = start + offset;
+            wr.len = sector.len() as u32;
+            wr.data[..sector.len()].copy_from_slice(sector);
+            self.send_write_region(&wr).map_err(|e| (e, offset))?;
+        }
         Ok(())
     }
 
+    /// Writes `data` at `start` a sector at a time, sending up to `WRITE_STREAM_MAX` bytes per request
+    /// instead of one request per sector, for bulk provisioning of large images. Each sector is erased
+    /// before it is written, so `start` has to be aligned to `erase_alignment()`, and the unused end of
+    /// the last sector reads back as 0xFF. The exclusive lock is held for the whole of `data`. On
+    /// failure, returns the first error along with the offset into `data` of the sector that failed;
+    /// the sectors before it have been written. Use `patch()` for small changes to existing data.
+    pub fn write_stream(&self, start: u32, data: &[u8]) -> Result<(), (SpinorError, u32)> {
+        if (start & (SPINOR_ERASE_SIZE - 1)) != 0 {
+            return Err((SpinorError::AlignmentError, 0));
+        }
+        use core::convert::TryFrom;
+        if u32::try_from(data.len()).ok().and_then(|len| start.checked_add(len)).is_none() {
+            return Err((SpinorError::InvalidRequest, 0));
+        }
+        #[cfg(not(test))]
+        self.acquire_exclusive().map_err(|e| (e, 0))?;
+        let mut ret = Ok(());
+        for (index, chunk) in data.chunks(WRITE_STREAM_MAX).enumerate() {
+            let offset = (index * WRITE_STREAM_MAX) as u32;
+            ret = self.send_write_stream(start + offset, chunk).map_err(|(e, fail)| (e, offset + fail));
+            if ret.is_err() {
+                break;
+            }
+        }
+        #[cfg(not(test))]
+        self.release_exclusive();
+        ret
+    }
+
+    /// Reports whether the write queued as `job` is still in progress, or how it finished. Only the
+    /// most recent results are kept, so a long-finished job is eventually forgotten and reported as
+    /// `InvalidRequest`, as is an id that was never handed out.
+    pub fn poll_write(&self, job: u32) -> Result<WriteStatus, SpinorError> {
+        self.send_write_status(Opcode::PollWrite, job)
+    }
+
+    /// Blocks until the write queued as `job` has finished, and returns its result.
+    pub fn wait_write(&self, job: u32) -> Result<(), SpinorError> {
+        match self.send_write_status(Opcode::WaitWrite, job)? {
+            WriteStatus::Done(e) => e.into_result(),
+            WriteStatus::InProgress => Err(SpinorError::ImplementationError),
+        }
+    }
+
+    /// Sets how long `patch` and `bulk_erase` wait for another client to give up the exclusive
+    /// write lock before failing with `BusyTryAgain`. With `None` (the default) the lock is
+    /// polled a handful of times without sleeping, which is fine for short contention but gives
+    /// up quickly behind a long erase.
+    pub fn set_acquire_timeout(&mut self, timeout_ms: Option<u32>) {
+        self.acquire_timeout_ms = timeout_ms;
+    }
+
+    #[cfg(not(test))]
+    fn acquire_exclusive(&self) -> Result<(), SpinorError> {
+        if let Some(timeout_ms) = self.acquire_timeout_ms {
+            // the server parks this request until the lock frees up or the timeout expires
+            let at = AcquireTimeout {
+                id: self.token,
+                timeout_ms,
+                result: None,
+            };
+            let mut buf = Buffer::into_buf(at).or(Err(SpinorError::IpcError))?;
+            buf.lend_mut(self.conn, Opcode::AcquireExclusiveTimeout.to_u32().unwrap()).or(Err(SpinorError::IpcError))?;
+            return match buf.to_original::<AcquireTimeout, _>() {
+                Ok(at) => SpinorError::from_reply(at.result),
+                _ => Err(SpinorError::IpcError),
+            };
+        }
+        const RETRY_LIMIT: usize = 5;
+        for i in 0..RETRY_LIMIT {
+            let response = send_message(self.conn,
+                Message::new_blocking_scalar(Opcode::AcquireExclusive.to_usize().unwrap(),
+                    self.token[0] as usize,
+                    self.token[1] as usize,
+                    self.token[2] as usize,
+                    self.token[3] as usize,
+                )
+            );
+            match lock_reply(response) {
+                Err(SpinorError::BusyTryAgain) if i < RETRY_LIMIT - 1 => xous::yield_slice(),
+                result => return result,
+            }
+        }
+        Err(SpinorError::BusyTryAgain)
+    }
+
+    #[cfg(test)]
+    fn acquire_exclusive(&self) -> Result<(), SpinorError> {
+        let mut holder = EMU_LOCK_HOLDER.lock().unwrap();
+        match *holder {
+            Some(id) if id != self.token => {
+                if let Some(timeout_ms) = self.acquire_timeout_ms {
+                    std::thread::sleep(std::time::Duration::from_millis(timeout_ms as u64));
+                }
+                Err(SpinorError::BusyTryAgain)
+            }
+            _ => {
+                *holder = Some(self.token);
+                Ok(())
+            }
+        }
+    }
+
+    #[cfg(not(test))]
+    fn release_exclusive(&self) {
+        let _ = send_message(self.conn,
+            Message::new_blocking_scalar(Opcode::ReleaseExclusive.to_usize().unwrap(), 0, 0, 0, 0)
+        ).expect("couldn't send ReleaseExclusive message");
+    }
+
+    #[cfg(test)]
+    fn release_exclusive(&self) {
+        *EMU_LOCK_HOLDER.lock().unwrap() = None;
+    }
+
     #[cfg(not(test))]
     fn send_bulk_erase(&self, be: &BulkErase) -> Result<(), SpinorError> {
         let mut buf = Buffer::into_buf(*be).or(Err(SpinorError::IpcError))?;
         buf.lend_mut(self.conn, Opcode::BulkErase.to_u32().unwrap()).or(Err(SpinorError::IpcError))?;
 
         match buf.to_original::<BulkErase, _>() {
-            Ok(wr) => {
-                if let Some(res) = wr.result {
-                    match res {
-                        SpinorError::NoError => Ok(()),
-                        _ => Err(res)
-                    }
-                } else {
-                    Err(SpinorError::ImplementationError)
-                }
+            Ok(be) => {
+                self.last_fail_addr.set(be.fail_addr);
+                SpinorError::from_reply(be.result)
             }
             _ => Err(SpinorError::ImplementationError)
         }
@@ -148,11 +703,20 @@ impl Spinor {
 
     #[cfg(test)]
     fn send_bulk_erase(&self, be: &BulkErase) -> Result<(), SpinorError> {
+        let started = std::time::Instant::now();
         let mut i = 0;
+        self.last_fail_addr.set(None);
         for addr in be.start..be.start + be.len {
+            // a stuck cell fails the erase of its block, as the hardware does with its erase-fail status
+            if *EMU_STUCK.lock().unwrap() == Some(addr as usize) && EMU_FLASH.lock().unwrap()[addr as usize] != 0xFF {
+                self.last_fail_addr.set(Some(addr & !(SPINOR_BULK_ERASE_SIZE - 1)));
+                EMU_SESSION_ERR.lock().unwrap().get_or_insert(SpinorError::EraseFailed);
+                return Err(SpinorError::EraseFailed);
+            }
             EMU_FLASH.lock().unwrap()[addr as usize] = 0xFF;
             i += 1;
         }
+        EMU_LATENCY.lock().unwrap().record_erase(started.elapsed().as_millis() as u64);
         Ok(())
     }
 
@@ -173,41 +737,18 @@ impl Spinor {
         }
         // acquire a write lock on the unit
         #[cfg(not(test))]
-        {
-            const RETRY_LIMIT: usize = 5;
-            for i in 0..RETRY_LIMIT {
-                let response = send_message(self.conn,
-                    Message::new_blocking_scalar(Opcode::AcquireExclusive.to_usize().unwrap(),
-                        self.token[0] as usize,
-                        self.token[1] as usize,
-                        self.token[2] as usize,
-                        self.token[3] as usize,
-                    )
-                ).expect("couldn't send AcquireExclusive message to Spinor hardware!");
-                if let xous::Result::Scalar1(result) = response {
-                    if result == 0 {
-                        if i == RETRY_LIMIT - 1 {
-                            return Err(SpinorError::BusyTryAgain)
-                        }
-                        xous::yield_slice();
-                    } else {
-                        break;
-                    }
-                }
-            }
-        }
+        self.acquire_exclusive()?;
         let be = BulkErase {
             id: self.token,
             start,
             len,
-            result: None
+            result: None,
+            fail_addr: None,
         };
         let ret = self.send_bulk_erase(&be);
         // release the write lock before exiting
         #[cfg(not(test))]
-        let _ = send_message(self.conn,
-            Message::new_blocking_scalar(Opcode::ReleaseExclusive.to_usize().unwrap(), 0, 0, 0, 0)
-        ).expect("couldn't send ReleaseExclusive message");
+        self.release_exclusive();
         ret
     }
 
@@ -242,29 +783,7 @@ impl Spinor {
         }
         // acquire a write lock on the unit
         #[cfg(not(test))]
-        {
-            const RETRY_LIMIT: usize = 5;
-            for i in 0..RETRY_LIMIT {
-                let response = send_message(self.conn,
-                    Message::new_blocking_scalar(Opcode::AcquireExclusive.to_usize().unwrap(),
-                        self.token[0] as usize,
-                        self.token[1] as usize,
-                        self.token[2] as usize,
-                        self.token[3] as usize,
-                    )
-                ).expect("couldn't send AcquireExclusive message to Spinor hardware!");
-                if let xous::Result::Scalar1(result) = response {
-                    if result == 0 {
-                        if i == RETRY_LIMIT - 1 {
-                            return Err(SpinorError::BusyTryAgain)
-                        }
-                        xous::yield_slice();
-                    } else {
-                        break;
-                    }
-                }
-            }
-        }
+        self.acquire_exclusive()?;
 
         // pre-allocate a buffer that we'll use repeatedly to communicate with the server
         let mut wr = WriteRegion {
@@ -272,6 +791,9 @@ impl Spinor {
             start: 0,
             data: [0xFF; 4096],
             len: 0,
+            verify_crc: false,
+            crc: 0,
+            fail_addr: None,
             result: None,
             clean_patch: false,
         };
@@ -326,7 +848,7 @@ impl Spinor {
                 wr.clean_patch = true;
                 wr.start = patch_start.expect("check region did not intersect patch region; this shouldn't be possible.");
                 wr.len = data_index as u32;
-                ret = self.send_write_region(&wr);
+                ret = self.send_write_region(&wr).map(|_| ());
                 if ret.is_err() {
                     break;
                 }
@@ -364,7 +886,7 @@ impl Spinor {
                 // if the requested patch data happens to be identical to the existing data already, don't even send
                 // the request.
                 if dirty {
-                    ret = self.send_write_region(&wr);
+                    ret = self.send_write_region(&wr).map(|_| ());
                     if ret.is_err() {
                         break; // abort fast if we encounter an error
                     }
@@ -374,28 +896,285 @@ impl Spinor {
 
         // release the write lock before exiting
         #[cfg(not(test))]
-        let _ = send_message(self.conn,
-            Message::new_blocking_scalar(Opcode::ReleaseExclusive.to_usize().unwrap(), 0, 0, 0, 0)
-        ).expect("couldn't send ReleaseExclusive message");
+        self.release_exclusive();
 
         ret
     }
 
-    /// these functions are intended for use by the suspend/resume manager. most functions wouldn't have a need to call this.
-    pub fn acquire_suspend_lock(&self) -> Result<bool, xous::Error> {
+    fn send_protected_region(&self, op: Opcode, start: u32, len: u32) -> Result<(), SpinorError> {
+        let pr = ProtectedRegion {
+            id: self.token,
+            start,
+            len,
+            result: None,
+        };
+        let mut buf = Buffer::into_buf(pr).or(Err(SpinorError::IpcError))?;
+        buf.lend_mut(self.conn, op.to_u32().unwrap()).or(Err(SpinorError::IpcError))?;
+
+        match buf.to_original::<ProtectedRegion, _>() {
+            Ok(pr) => SpinorError::from_reply(pr.result),
+            _ => Err(SpinorError::ImplementationError)
+        }
+    }
+    /// Marks `start..start+len` as write-protected until it is removed again. Any `patch` or `bulk_erase`
+    /// that touches the region is rejected with `AccessDenied`. Only the SoC token holder may add regions;
+    /// regions may not overlap each other.
+    pub fn add_protected_region(&self, start: u32, len: u32) -> Result<(), SpinorError> {
+        self.send_protected_region(Opcode::AddProtectedRegion, start, len)
+    }
+    /// Removes the protected region that starts at `start`. Only the SoC token holder may remove regions.
+    pub fn remove_protected_region(&self, start: u32) -> Result<(), SpinorError> {
+        self.send_protected_region(Opcode::RemoveProtectedRegion, start, 0)
+    }
+    /// Returns `true` if any part of `start..start+len` falls within a protected region.
+    pub fn is_protected(&self, start: u32, len: u32) -> Result<bool, xous::Error> {
         let response = send_message(self.conn,
-            Message::new_blocking_scalar(Opcode::AcquireSuspendLock.to_usize().unwrap(), 0, 0, 0, 0)
-        ).expect("Couldn't issue AcquireSuspendLock message");
+            Message::new_blocking_scalar(Opcode::IsProtected.to_usize().unwrap(), start as usize, len as usize, 0, 0)
+        )?;
         if let xous::Result::Scalar1(result) = response {
-            if result != 0 {
-                Ok(true)
-            } else {
-                Ok(false)
-            }
+            Ok(result != 0)
         } else {
             Err(xous::Error::InternalError)
         }
     }
+
+    #[cfg(not(test))]
+    fn send_verify_erased(&self, ve: &VerifyErased) -> Result<Option<u32>, SpinorError> {
+        let mut buf = Buffer::into_buf(*ve).or(Err(SpinorError::IpcError))?;
+        buf.lend_mut(self.conn, Opcode::VerifyErased.to_u32().unwrap()).or(Err(SpinorError::IpcError))?;
+
+        match buf.to_original::<VerifyErased, _>() {
+            Ok(ve) => SpinorError::from_reply(ve.result).map(|_| ve.first_dirty),
+            _ => Err(SpinorError::ImplementationError)
+        }
+    }
+
+    #[cfg(test)]
+    fn send_verify_erased(&self, ve: &VerifyErased) -> Result<Option<u32>, SpinorError> {
+        let flash = EMU_FLASH.lock().unwrap();
+        Ok(flash[ve.start as usize..(ve.start + ve.len) as usize].iter()
+            .position(|&b| b != 0xFF)
+            .map(|offset| ve.start + offset as u32))
+    }
+
+    /// Checks that `start..start+len` reads back as all 0xFF, e.g. as a precondition for a clean patch.
+    /// Returns `None` if the whole range is erased, or `Some(address)` of the first byte that is not 0xFF.
+    /// `start` is an offset from the start of FLASH. The range must not be mapped by another process,
+    /// as the server needs to map it to do the check.
+    pub fn verify_erased(&self, start: u32, len: u32) -> Result<Option<u32>, SpinorError> {
+        if len == 0 {
+            return Err(SpinorError::InvalidRequest);
+        }
+        let ve = VerifyErased {
+            start,
+            len,
+            first_dirty: None,
+            result: None,
+        };
+        self.send_verify_erased(&ve)
+    }
+
+    #[cfg(not(test))]
+    fn send_check_erased(&self, ve: &VerifyErased) -> Result<Option<u32>, SpinorError> {
+        let mut buf = Buffer::into_buf(*ve).or(Err(SpinorError::IpcError))?;
+        buf.lend_mut(self.conn, Opcode::CheckErased.to_u32().unwrap()).or(Err(SpinorError::IpcError))?;
+
+        match buf.to_original::<VerifyErased, _>() {
+            Ok(ve) => SpinorError::from_reply(ve.result).map(|_| ve.first_dirty),
+            _ => Err(SpinorError::ImplementationError)
+        }
+    }
+
+    #[cfg(test)]
+    fn send_check_erased(&self, ve: &VerifyErased) -> Result<Option<u32>, SpinorError> {
+        let (start, len) = validate::bulk_erase_span(ve.start, ve.len).ok_or(SpinorError::InvalidRequest)?;
+        self.send_verify_erased(&VerifyErased { start, len, ..*ve })
+    }
+
+    /// Checks whether a `bulk_erase` of `start..start+len` would have anything to do, so that provisioning
+    /// tools can skip redundant erases. Unlike `bulk_erase`, which requires `start` and `len` to be
+    /// 64kiB-aligned, this accepts any range and rounds it out to the whole 64kiB blocks an erase of it
+    /// would have to cover: `start` down to the block it's in, and the end up to the end of the block
+    /// holding the last byte. So a dirty byte outside the range, but in one of those blocks, is reported,
+    /// and the aligned span to pass on to `bulk_erase` is the rounded one. Returns `None` if the whole of
+    /// those blocks reads back as 0xFF, or `Some(address)` of the first byte that doesn't. Like
+    /// `verify_erased`, the blocks must not be mapped by another process.
+    pub fn check_erased(&self, start: u32, len: u32) -> Result<Option<u32>, SpinorError> {
+        let ve = VerifyErased {
+            start,
+            len,
+            first_dirty: None,
+            result: None,
+        };
+        self.send_check_erased(&ve)
+    }
+
+    #[cfg(not(test))]
+    fn send_read_region(&self, rr: &ReadRegion) -> Result<ReadRegion, SpinorError> {
+        let mut buf = Buffer::into_buf(*rr).or(Err(SpinorError::IpcError))?;
+        buf.lend_mut(self.conn, Opcode::ReadRegion.to_u32().unwrap()).or(Err(SpinorError::IpcError))?;
+
+        match buf.to_original::<ReadRegion, _>() {
+            Ok(rr) => SpinorError::from_reply(rr.result).map(|_| rr),
+            _ => Err(SpinorError::ImplementationError)
+        }
+    }
+
+    #[cfg(test)]
+    fn send_read_region(&self, rr: &ReadRegion) -> Result<ReadRegion, SpinorError> {
+        let flash = EMU_FLASH.lock().unwrap();
+        match rr.start.checked_add(rr.len) {
+            Some(end) if end as usize <= flash.len() && rr.len > 0 && rr.len as usize <= rr.data.len() => {},
+            _ => return Err(SpinorError::InvalidRequest),
+        }
+        let mut reply = *rr;
+        reply.data[..rr.len as usize].copy_from_slice(&flash[rr.start as usize..(rr.start + rr.len) as usize]);
+        reply.result = Some(SpinorError::NoError);
+        Ok(reply)
+    }
+
+    /// Reads `buf.len()` bytes of FLASH starting at `start`, for callers that can't map FLASH themselves.
+    /// At most one page (4096 bytes) can be read per call. `start` is an offset from the start of FLASH.
+    /// Like `verify_erased`, the range must not be mapped by another process.
+    pub fn read_region(&self, start: u32, buf: &mut [u8]) -> Result<(), SpinorError> {
+        let rr = ReadRegion {
+            start,
+            len: buf.len() as u32,
+            data: [0; 4096],
+            result: None,
+        };
+        if buf.is_empty() || buf.len() > rr.data.len() {
+            return Err(SpinorError::InvalidRequest);
+        }
+        let rr = self.send_read_region(&rr)?;
+        buf.copy_from_slice(&rr.data[..buf.len()]);
+        Ok(())
+    }
+
+    #[cfg(not(test))]
+    fn send_attest_whole_chip(&self, cd: &ChipDigest) -> Result<[u8; 32], SpinorError> {
+        let mut buf = Buffer::into_buf(*cd).or(Err(SpinorError::IpcError))?;
+        buf.lend_mut(self.conn, Opcode::AttestWholeChip.to_u32().unwrap()).or(Err(SpinorError::IpcError))?;
+
+        match buf.to_original::<ChipDigest, _>() {
+            Ok(cd) => SpinorError::from_reply(cd.result).map(|_| cd.digest),
+            _ => Err(SpinorError::ImplementationError)
+        }
+    }
+
+    #[cfg(test)]
+    fn send_attest_whole_chip(&self, _cd: &ChipDigest) -> Result<[u8; 32], SpinorError> {
+        use sha2::Digest;
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(EMU_FLASH.lock().unwrap().as_slice());
+        let mut digest = [0u8; 32];
+        digest.copy_from_slice(&hasher.finalize());
+        Ok(digest)
+    }
+
+    /// Returns the SHA-256 of the entire FLASH array, for integrity attestation. The server won't
+    /// start while a write is in progress, and holds off writes and suspends until it's done.
+    /// Hashing 128MiB takes a long time, during which the server can't do anything else.
+    /// Fails with `AccessDenied` if any part of the array is mapped by another process.
+    pub fn attest_whole_chip(&self) -> Result<[u8; 32], SpinorError> {
+        let cd = ChipDigest {
+            digest: [0; 32],
+            result: None,
+        };
+        self.send_attest_whole_chip(&cd)
+    }
+
+    /// Caps the sustained rate of writes and erases to `bytes_per_second`, to limit current draw
+    /// and heat, e.g. when the battery is low. 0 removes the limit, which is the default. The
+    /// limit applies to everyone using the FLASH, and is lifted while a suspend is pending.
+    pub fn set_write_throttle(&self, bytes_per_second: u32) -> Result<(), xous::Error> {
+        send_message(self.conn,
+            Message::new_scalar(Opcode::SetWriteThrottle.to_usize().unwrap(), bytes_per_second as usize, 0, 0, 0)
+        ).map(|_| ())
+    }
+
+    #[cfg(not(test))]
+    fn send_read_jedec_id(&self) -> Result<u32, SpinorError> {
+        match send_message(self.conn,
+            Message::new_blocking_scalar(Opcode::ReadJedecId.to_usize().unwrap(), 0, 0, 0, 0)
+        ) {
+            Ok(xous::Result::Scalar2(id, code)) => SpinorError::from_code(code).map(|_| id as u32),
+            _ => Err(SpinorError::IpcError),
+        }
+    }
+
+    #[cfg(test)]
+    fn send_read_jedec_id(&self) -> Result<u32, SpinorError> {
+        Ok(*EMU_JEDEC_ID.lock().unwrap())
+    }
+
+    /// Reads the manufacturer, memory type and capacity of the FLASH part that's fitted, for
+    /// software that supports more than one part. Fails with `BusyTryAgain` during a suspend.
+    pub fn read_jedec_id(&self) -> Result<JedecId, SpinorError> {
+        self.send_read_jedec_id().map(JedecId::from_u32)
+    }
+
+    #[cfg(not(test))]
+    fn send_latency_histogram(&self, hist: &LatencyHistogram) -> Result<LatencyHistogram, SpinorError> {
+        let mut buf = Buffer::into_buf(*hist).or(Err(SpinorError::IpcError))?;
+        buf.lend_mut(self.conn, Opcode::GetLatencyHistogram.to_u32().unwrap()).or(Err(SpinorError::IpcError))?;
+        buf.to_original::<LatencyHistogram, _>().or(Err(SpinorError::ImplementationError))
+    }
+
+    #[cfg(test)]
+    fn send_latency_histogram(&self, hist: &LatencyHistogram) -> Result<LatencyHistogram, SpinorError> {
+        let mut hist = *hist;
+        EMU_LATENCY.lock().unwrap().report(&mut hist);
+        Ok(hist)
+    }
+
+    /// Returns how many `WriteRegion` and `BulkErase` operations have fallen into each latency
+    /// bucket since the counts were last cleared; see `LATENCY_BUCKETS` for the bucket sizes. The
+    /// counts cover every client of the server. With `reset` set they are cleared once read.
+    pub fn latency_histogram(&self, reset: bool) -> Result<LatencyHistogram, SpinorError> {
+        self.send_latency_histogram(&LatencyHistogram::new(reset))
+    }
+
+    #[cfg(not(test))]
+    fn send_ecc_log(&self, log: &EccLog) -> Result<EccLog, SpinorError> {
+        let mut buf = Buffer::into_buf(*log).or(Err(SpinorError::IpcError))?;
+        buf.lend_mut(self.conn, Opcode::EccLog.to_u32().unwrap()).or(Err(SpinorError::IpcError))?;
+        buf.to_original::<EccLog, _>().or(Err(SpinorError::ImplementationError))
+    }
+
+    #[cfg(test)]
+    fn send_ecc_log(&self, log: &EccLog) -> Result<EccLog, SpinorError> {
+        let mut log = *log;
+        EMU_ECC.lock().unwrap().drain_into(&mut log);
+        Ok(log)
+    }
+
+    /// Reads out the ECC errors the server has recorded, oldest first, and clears them from its log.
+    /// At most `ECC_LOG_ENTRIES` come back per call; call again until an empty list comes back to
+    /// read the whole log. Each error is only recorded once, however often the hardware reports it.
+    pub fn ecc_log(&self) -> Result<Vec<EccLogEntry>, SpinorError> {
+        let log = self.send_ecc_log(&EccLog::new())?;
+        Ok(log.entries.iter().take(log.count as usize).cloned().collect())
+    }
+
+    /// Clears the latency counts without reading them.
+    pub fn reset_latency_histogram(&self) -> Result<(), xous::Error> {
+        send_message(self.conn,
+            Message::new_scalar(Opcode::ResetLatencyHistogram.to_usize().unwrap(), 0, 0, 0, 0)
+        ).map(|_| ())
+    }
+
+    /// these functions are intended for use by the suspend/resume manager. most functions wouldn't have a need to call this.
+    pub fn acquire_suspend_lock(&self) -> Result<bool, xous::Error> {
+        let response = send_message(self.conn,
+            Message::new_blocking_scalar(Opcode::AcquireSuspendLock.to_usize().unwrap(), 0, 0, 0, 0)
+        );
+        match lock_reply(response) {
+            Ok(()) => Ok(true),
+            Err(SpinorError::BusyTryAgain) => Ok(false),
+            Err(_) => Err(xous::Error::InternalError),
+        }
+    }
     pub fn release_suspend_lock(&self) -> Result<(), xous::Error> {
         // we ignore the result and just turn it into () once we get anything back, as release_suspend "can't fail"
         send_message(self.conn,
@@ -417,7 +1196,38 @@ impl Drop for Spinor {
         }
         // if there was object-specific state (such as a one-time use server for async callbacks, specific to the object instance),
         // de-allocate those items here. They don't need a reference count because they are object-specific
+        if let Some(sid) = self.progress_sid.take() {
+            let cid = xous::connect(sid).unwrap();
+            xous::send_message(cid,
+                Message::new_blocking_scalar(ProgressEvent::Drop.to_usize().unwrap(), 0, 0, 0, 0)
+            ).unwrap();
+            unsafe{xous::disconnect(cid).unwrap();}
+        }
+    }
+}
+
+/// handles progress reports from the server, in the library user's process space.
+fn progress_cb_server(sid0: usize, sid1: usize, sid2: usize, sid3: usize) {
+    let sid = xous::SID::from_u32(sid0 as u32, sid1 as u32, sid2 as u32, sid3 as u32);
+    loop {
+        let msg = xous::receive_message(sid).unwrap();
+        match FromPrimitive::from_usize(msg.body.id()) {
+            Some(ProgressEvent::Progress) => xous::msg_scalar_unpack!(msg, cid, id, done, total, {
+                // directly pass the scalar message onto the CID with the ID memorized in the original hook
+                send_message(cid as u32,
+                    Message::new_scalar(id, done, total, 0, 0)
+                ).unwrap();
+            }),
+            Some(ProgressEvent::Drop) => {
+                if msg.body.is_blocking() {
+                    xous::return_scalar(msg.sender, 0).unwrap();
+                }
+                break; // this exits the loop and kills the thread
+            }
+            None => (),
+        }
     }
+    xous::destroy_server(sid).unwrap();
 }
 
 #[cfg(test)]
@@ -451,6 +1261,9 @@ mod tests {
             clean_patch: true,
             data: [0; 4096],
             len: 4,
+            verify_crc: false,
+            crc: 0,
+            fail_addr: None,
             result: None
         };
         wr.data[0] = 0xAA;
@@ -506,6 +1319,436 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_write_with_crc() {
+        init_emu_flash(4);
+        flash_fill_rand();
+        let spinor = Spinor::new();
+        let data: Vec<u8> = (0..0x800).map(|i| (i * 7) as u8).collect();
+        let crc = spinor.write_with_crc(0x1000, &data).unwrap();
+        assert_eq!(crc, crc::crc32::checksum_ieee(&data));
+        // the CRC only covers the bytes written, not the rest of the erased sector
+        assert_ne!(crc, crc::crc32::checksum_ieee(&EMU_FLASH.lock().unwrap()[0x1000..0x2000]));
+        assert!(matches!(spinor.write_with_crc(0x1010, &data), Err(SpinorError::AlignmentError)));
+        assert!(matches!(spinor.write_with_crc(0x1000, &[0; 4097]), Err(SpinorError::InvalidRequest)));
+    }
+
+    #[test]
+    fn test_ecc_log() {
+        let spinor = Spinor::new();
+        // start from a clean slate, whatever other tests have done
+        while !spinor.ecc_log().unwrap().is_empty() {}
+
+        // inject reports as the interrupt handler would send them, including a repeat
+        for i in 0..40u32 {
+            EMU_ECC.lock().unwrap().record(0x9191, 0x10_0000 + i * 0x10, 0, i as u64);
+        }
+        EMU_ECC.lock().unwrap().record(0xb3b3_0000, 0, 0x730_5030, 40);
+        EMU_ECC.lock().unwrap().record(0xb3b3_0000, 0, 0x730_5030, 41);
+
+        let first = spinor.ecc_log().unwrap();
+        assert_eq!(first.len(), ECC_LOG_ENTRIES);
+        assert_eq!(first[0], EccLogEntry { address: 0x10_0000, syndrome: 0x91, corrected: true, timestamp: 0 });
+        let rest = spinor.ecc_log().unwrap();
+        assert_eq!(rest.len(), 40 + 1 - ECC_LOG_ENTRIES);
+        assert_eq!(rest.last(), Some(&EccLogEntry { address: 0x730_5030, syndrome: 0xb3, corrected: false, timestamp: 40 }));
+        assert!(spinor.ecc_log().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_write_stream() {
+        init_emu_flash(24);
+        flash_fill_rand();
+        let spinor = Spinor::new();
+        // spans more than one request, and ends part way into a sector
+        let data: Vec<u8> = (0..WRITE_STREAM_MAX + 0x2800).map(|i| (i % 251) as u8).collect();
+        spinor.write_stream(0x2000, &data).unwrap();
+        {
+            let flash = EMU_FLASH.lock().unwrap();
+            assert!(flash[0x2000..0x2000 + data.len()] == data[..]);
+            // the rest of the last sector was erased
+            assert!(flash[0x2000 + data.len()..0x2000 + WRITE_STREAM_MAX + 0x3000].iter().all(|&b| b == 0xFF));
+        }
+        assert!(matches!(spinor.write_stream(0x2010, &data), Err((SpinorError::AlignmentError, 0))));
+
+        // a failure reports the offset of the sector it happened in
+        *EMU_STUCK.lock().unwrap() = Some(0x2000 + WRITE_STREAM_MAX + 0x1004);
+        assert!(matches!(spinor.write_stream(0x2000, &data), Err((SpinorError::VerifyFailed, o)) if o as usize == WRITE_STREAM_MAX + 0x1000));
+        *EMU_STUCK.lock().unwrap() = None;
+        spinor.barrier().ok();
+    }
+
+    #[test]
+    fn test_fail_addr() {
+        init_emu_flash(64);
+        flash_fill_rand();
+        let spinor = Spinor::new();
+        let region = EMU_FLASH.lock().unwrap().clone();
+        let patch = [0x42u8; 0x3000];
+
+        // a stuck cell in the third sector of a patch spanning four
+        *EMU_STUCK.lock().unwrap() = Some(0x1_3456);
+        assert!(matches!(spinor.patch(&region, 0, &patch, 0x1_1800), Err(SpinorError::VerifyFailed)));
+        assert_eq!(spinor.last_fail_addr(), Some(0x1_3000));
+        spinor.barrier().ok();
+
+        // the same cell fails the erase of its 64kiB block, once it holds something
+        EMU_FLASH.lock().unwrap()[0x1_3456] = 0;
+        assert!(matches!(spinor.bulk_erase(0, 0x4_0000), Err(SpinorError::EraseFailed)));
+        assert_eq!(spinor.last_fail_addr(), Some(0x1_0000));
+        spinor.barrier().ok();
+
+        // a request that succeeds clears it
+        *EMU_STUCK.lock().unwrap() = None;
+        spinor.bulk_erase(0, 0x4_0000).unwrap();
+        assert_eq!(spinor.last_fail_addr(), None);
+    }
+
+    #[test]
+    fn test_check_erased() {
+        init_emu_flash(48);
+        let spinor = Spinor::new();
+        assert_eq!(spinor.check_erased(0, 0x3_0000).unwrap(), None);
+        assert!(matches!(spinor.check_erased(0, 0), Err(SpinorError::InvalidRequest)));
+
+        // a dirty byte is found anywhere in the blocks the range rounds out to
+        EMU_FLASH.lock().unwrap()[0x1_0010] = 0;
+        assert_eq!(spinor.check_erased(0x1_8000, 0x100).unwrap(), Some(0x1_0010));
+        assert_eq!(spinor.check_erased(0x0_8000, 0x8001).unwrap(), Some(0x1_0010));
+        assert_eq!(spinor.check_erased(0x0_8000, 0x8000).unwrap(), None);
+        assert_eq!(spinor.check_erased(0x2_0000, 0x1_0000).unwrap(), None);
+    }
+
+    #[test]
+    fn test_verify_erased() {
+        init_emu_flash(4);
+        let spinor = Spinor::new();
+        assert_eq!(spinor.verify_erased(0, 4 * 4096).unwrap(), None);
+        assert!(spinor.verify_erased(0x1000, 0).is_err());
+
+        EMU_FLASH.lock().unwrap()[0x1234] = 0xFE;
+        assert_eq!(spinor.verify_erased(0, 4 * 4096).unwrap(), Some(0x1234));
+        assert_eq!(spinor.verify_erased(0x1000, 0x1000).unwrap(), Some(0x1234));
+        assert_eq!(spinor.verify_erased(0x1234, 1).unwrap(), Some(0x1234));
+        // ranges on either side of the dirty byte are still erased
+        assert_eq!(spinor.verify_erased(0x1000, 0x234).unwrap(), None);
+        assert_eq!(spinor.verify_erased(0x1235, 0x2000).unwrap(), None);
+    }
+
+    #[test]
+    fn test_read_region() {
+        init_emu_flash(4);
+        for (addr, byte) in EMU_FLASH.lock().unwrap().iter_mut().enumerate() {
+            *byte = addr as u8;
+        }
+        let spinor = Spinor::new();
+        // a read that straddles a page boundary
+        let mut buf = [0u8; 0x20];
+        spinor.read_region(0xFF0, &mut buf).unwrap();
+        for (i, &byte) in buf.iter().enumerate() {
+            assert_eq!(byte, (0xFF0 + i) as u8);
+        }
+        let mut page = [0u8; 4096];
+        spinor.read_region(3 * 4096, &mut page).unwrap();
+        assert_eq!(page[4095], 0xFF);
+
+        // empty, over-long and out of range reads are refused
+        assert!(matches!(spinor.read_region(0, &mut []), Err(SpinorError::InvalidRequest)));
+        assert!(matches!(spinor.read_region(0, &mut [0u8; 4097]), Err(SpinorError::InvalidRequest)));
+        assert!(matches!(spinor.read_region(4 * 4096 - 1, &mut [0u8; 2]), Err(SpinorError::InvalidRequest)));
+        assert!(matches!(spinor.read_region(u32::MAX, &mut [0u8; 2]), Err(SpinorError::InvalidRequest)));
+    }
+
+    #[test]
+    fn test_barrier() {
+        init_emu_flash(4);
+        *EMU_STUCK.lock().unwrap() = None;
+        *EMU_SESSION_ERR.lock().unwrap() = None;
+        let spinor = Spinor::new();
+        let region = EMU_FLASH.lock().unwrap().clone();
+        let data = [0x5Au8; 256];
+        for i in 0..3u32 {
+            assert!(spinor.patch(&region, 0, &data, i * 0x1000 + 0x100).is_ok());
+        }
+        assert!(spinor.barrier().is_ok());
+        for i in 0..3usize {
+            assert_eq!(&EMU_FLASH.lock().unwrap()[i * 0x1000 + 0x100..i * 0x1000 + 0x200], &data[..]);
+        }
+
+        // a write that doesn't read back is reported by the next barrier, and only that one
+        *EMU_STUCK.lock().unwrap() = Some(0x3180);
+        let region = EMU_FLASH.lock().unwrap().clone();
+        assert!(spinor.patch(&region, 0, &data, 0x3100).is_err());
+        assert!(matches!(spinor.barrier(), Err(SpinorError::VerifyFailed)));
+        assert!(spinor.barrier().is_ok());
+        *EMU_STUCK.lock().unwrap() = None;
+    }
+
+    #[test]
+    fn test_acquire_timeout() {
+        // another client is in the middle of a long operation
+        *EMU_LOCK_HOLDER.lock().unwrap() = Some([1, 2, 3, 4]);
+        let mut spinor = Spinor::new();
+        spinor.set_acquire_timeout(Some(50));
+        let start = std::time::Instant::now();
+        assert!(matches!(spinor.acquire_exclusive(), Err(SpinorError::BusyTryAgain)));
+        assert!(start.elapsed() >= std::time::Duration::from_millis(50));
+        // the failed attempt did not disturb the holder
+        assert_eq!(*EMU_LOCK_HOLDER.lock().unwrap(), Some([1, 2, 3, 4]));
+
+        // once the lock is free, the timed acquisition succeeds without waiting
+        *EMU_LOCK_HOLDER.lock().unwrap() = None;
+        assert!(spinor.acquire_exclusive().is_ok());
+        assert_eq!(*EMU_LOCK_HOLDER.lock().unwrap(), Some([0, 0, 0, 0]));
+        spinor.release_exclusive();
+        assert_eq!(*EMU_LOCK_HOLDER.lock().unwrap(), None);
+    }
+
+    #[test]
+    fn test_attest_whole_chip() {
+        init_emu_flash(2);
+        for (i, byte) in EMU_FLASH.lock().unwrap().iter_mut().enumerate() {
+            *byte = (i % 251) as u8;
+        }
+        let spinor = Spinor::new();
+        // reference digest of the pattern, computed independently
+        const REFERENCE: [u8; 32] = [
+            0x25, 0xdf, 0x24, 0x49, 0xb2, 0xe5, 0xa3, 0x5f, 0xea, 0x14, 0xe0, 0x2a, 0x71, 0x58, 0xe2, 0x83,
+            0x80, 0x1a, 0x10, 0x69, 0xc9, 0xf8, 0x46, 0x31, 0xb9, 0xa9, 0xda, 0xcb, 0x2f, 0x80, 0x9a, 0x7f,
+        ];
+        assert_eq!(spinor.attest_whole_chip().unwrap(), REFERENCE);
+
+        // any change to the array changes the digest
+        EMU_FLASH.lock().unwrap()[0x1FFF] ^= 1;
+        assert_ne!(spinor.attest_whole_chip().unwrap(), REFERENCE);
+    }
+
+    #[test]
+    fn test_read_jedec_id() {
+        let spinor = Spinor::new();
+        let id = spinor.read_jedec_id().unwrap();
+        assert_eq!((id.manufacturer, id.memory_type, id.capacity), (0xC2, 0x80, 0x3B));
+        // 1Gbit, i.e. SPINOR_SIZE_BYTES
+        assert_eq!(id.capacity_bytes(), Some(128 * 1024 * 1024));
+        assert_eq!(id.to_u32(), PRECURSOR_JEDEC_ID);
+
+        // the 512Mbit sibling, and a part using the plain log2 encoding (256Mbit)
+        *EMU_JEDEC_ID.lock().unwrap() = 0x3A_80_C2;
+        assert_eq!(spinor.read_jedec_id().unwrap().capacity_bytes(), Some(64 * 1024 * 1024));
+        *EMU_JEDEC_ID.lock().unwrap() = 0x19_20_EF;
+        assert_eq!(spinor.read_jedec_id().unwrap().capacity_bytes(), Some(32 * 1024 * 1024));
+        // a blank bus reads back as all ones
+        *EMU_JEDEC_ID.lock().unwrap() = 0xFF_FF_FF;
+        assert_eq!(spinor.read_jedec_id().unwrap().capacity_bytes(), None);
+        *EMU_JEDEC_ID.lock().unwrap() = PRECURSOR_JEDEC_ID;
+    }
+
+    #[test]
+    fn test_replace_sector() {
+        init_emu_flash(4);
+        *EMU_STUCK.lock().unwrap() = None;
+        *EMU_SESSION_ERR.lock().unwrap() = None;
+        // old contents with bits cleared that the new contents need set, so only an erase gets them back
+        for (i, byte) in EMU_FLASH.lock().unwrap().iter_mut().enumerate() {
+            *byte = (i as u8) & 0x0F;
+        }
+        let before = EMU_FLASH.lock().unwrap().clone();
+        let mut data = [0u8; 4096];
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = 0xF0 | (i % 13) as u8;
+        }
+        // a tail of 0xFF checks the erase itself, as nothing is programmed there
+        for byte in data[0xF00..].iter_mut() {
+            *byte = 0xFF;
+        }
+        let spinor = Spinor::new();
+        assert!(spinor.replace_sector(0x2000, &data).is_ok());
+        assert!(spinor.barrier().is_ok());
+        let flash = EMU_FLASH.lock().unwrap().clone();
+        assert_eq!(&flash[0x2000..0x3000], &data[..]);
+        // the neighbouring sectors are untouched
+        assert_eq!(&flash[..0x2000], &before[..0x2000]);
+        assert_eq!(&flash[0x3000..], &before[0x3000..]);
+
+        // unaligned requests are refused without touching anything
+        assert!(matches!(spinor.replace_sector(0x2100, &[0; 4096]), Err(SpinorError::AlignmentError)));
+        assert_eq!(*EMU_FLASH.lock().unwrap(), flash);
+
+        // a cell that won't program fails the read-back, and is reported by the barrier
+        *EMU_STUCK.lock().unwrap() = Some(0x1010);
+        assert!(matches!(spinor.replace_sector(0x1000, &[0; 4096]), Err(SpinorError::VerifyFailed)));
+        assert!(matches!(spinor.barrier(), Err(SpinorError::VerifyFailed)));
+        *EMU_STUCK.lock().unwrap() = None;
+    }
+
+    #[test]
+    fn test_latency_histogram() {
+        init_emu_flash(16);
+        let spinor = Spinor::new();
+        // start from a clean slate, whatever other tests have done
+        spinor.latency_histogram(true).unwrap();
+
+        let mut wr = WriteRegion {
+            id: [0, 0, 0, 0],
+            start: 0,
+            clean_patch: false,
+            data: [0x5A; 4096],
+            len: 4096,
+            verify_crc: false,
+            crc: 0,
+            fail_addr: None,
+            result: None
+        };
+        for sector in 0..5 {
+            wr.start = sector * SPINOR_ERASE_SIZE;
+            spinor.send_write_region(&wr).unwrap();
+        }
+        spinor.bulk_erase(0, SPINOR_BULK_ERASE_SIZE).unwrap();
+
+        let hist = spinor.latency_histogram(false).unwrap();
+        assert_eq!(hist.write.iter().sum::<u32>(), 5);
+        assert_eq!(hist.erase.iter().sum::<u32>(), 1);
+        // the counts survive a plain read, and are cleared by a read with reset
+        assert_eq!(spinor.latency_histogram(true).unwrap().write.iter().sum::<u32>(), 5);
+        let hist = spinor.latency_histogram(false).unwrap();
+        assert_eq!(hist.write.iter().sum::<u32>() + hist.erase.iter().sum::<u32>(), 0);
+    }
+
+    #[test]
+    fn test_commit_ab_switch() {
+        use sha2::Digest;
+        fn hash(data: &[u8]) -> [u8; 32] {
+            let mut hasher = sha2::Sha256::new();
+            hasher.update(data);
+            let mut digest = [0u8; 32];
+            digest.copy_from_slice(&hasher.finalize());
+            digest
+        }
+        const SLOT_A: u32 = 0x0000;
+        const SLOT_B: u32 = 0x2000;
+        const SLOT_LEN: u32 = 0x2000;
+        const LOG: usize = 0x7000;
+        fn active_slot() -> Option<u32> {
+            ab_active_slot(&EMU_FLASH.lock().unwrap()[LOG..LOG + 0x1000])
+        }
+        init_emu_flash(8);
+        *EMU_SESSION_ERR.lock().unwrap() = None;
+        let spinor = Spinor::new();
+        assert_eq!(active_slot(), None);
+
+        let image_a = [0xA5u8; 4096];
+        spinor.replace_sector(SLOT_A, &image_a).unwrap();
+        spinor.replace_sector(SLOT_A + 0x1000, &image_a).unwrap();
+        let hash_a = hash(&EMU_FLASH.lock().unwrap()[SLOT_A as usize..(SLOT_A + SLOT_LEN) as usize]);
+        spinor.commit_ab_switch(LOG as u32, 0xA, SLOT_A, SLOT_LEN, Some(hash_a)).unwrap();
+        assert_eq!(active_slot(), Some(0xA));
+
+        // the new image is written, but we "crash" before the commit: slot A is still active
+        let image_b = [0x5Au8; 4096];
+        spinor.replace_sector(SLOT_B, &image_b).unwrap();
+        spinor.replace_sector(SLOT_B + 0x1000, &image_b).unwrap();
+        assert_eq!(active_slot(), Some(0xA));
+
+        // a slot that doesn't match its hash isn't switched to
+        let hash_b = hash(&EMU_FLASH.lock().unwrap()[SLOT_B as usize..(SLOT_B + SLOT_LEN) as usize]);
+        assert!(matches!(
+            spinor.commit_ab_switch(LOG as u32 + 4, 0xB, SLOT_B, SLOT_LEN, Some(hash_a)),
+            Err(SpinorError::VerifyFailed)
+        ));
+        assert!(matches!(spinor.barrier(), Err(SpinorError::VerifyFailed)));
+        assert_eq!(active_slot(), Some(0xA));
+
+        // after the commit, slot B is active, and stays so whatever happens next
+        spinor.commit_ab_switch(LOG as u32 + 4, 0xB, SLOT_B, SLOT_LEN, Some(hash_b)).unwrap();
+        assert_eq!(active_slot(), Some(0xB));
+
+        // a marker can't be reprogrammed in place, nor can a misaligned one be written
+        assert!(matches!(
+            spinor.commit_ab_switch(LOG as u32, 0xB, SLOT_B, SLOT_LEN, None),
+            Err(SpinorError::AbortNotErased)
+        ));
+        assert!(matches!(spinor.barrier(), Err(SpinorError::AbortNotErased)));
+        assert!(matches!(
+            spinor.commit_ab_switch(LOG as u32 + 6, 0xB, SLOT_B, SLOT_LEN, None),
+            Err(SpinorError::AlignmentError)
+        ));
+        assert_eq!(active_slot(), Some(0xB));
+    }
+
+    #[test]
+    fn test_submit_write() {
+        init_emu_flash(4);
+        *EMU_STUCK.lock().unwrap() = None;
+        *EMU_SESSION_ERR.lock().unwrap() = None;
+        let spinor = Spinor::new();
+        let first = [0x11u8; 4096];
+        let second: Vec<u8> = (0..100).map(|i| i as u8).collect();
+        let a = spinor.submit_write(0x1000, &first).unwrap();
+        let b = spinor.submit_write(0x3000, &second).unwrap();
+        assert!(b > a, "job ids aren't increasing");
+        // nothing has been written yet
+        assert!(EMU_FLASH.lock().unwrap().iter().all(|&byte| byte == 0xFF));
+
+        let mut pending = vec![a, b];
+        let mut polls = 0;
+        while !pending.is_empty() {
+            polls += 1;
+            assert!(polls < 10, "writes never completed");
+            pending.retain(|&job| match spinor.poll_write(job).unwrap() {
+                WriteStatus::InProgress => true,
+                WriteStatus::Done(e) => {
+                    assert!(matches!(e, SpinorError::NoError), "job {} failed: {:?}", job, e);
+                    false
+                }
+            });
+        }
+        let flash = EMU_FLASH.lock().unwrap().clone();
+        assert_eq!(&flash[0x1000..0x2000], &first[..]);
+        assert_eq!(&flash[0x3000..0x3000 + second.len()], &second[..]);
+        assert!(flash[0x3000 + second.len()..].iter().all(|&byte| byte == 0xFF));
+        assert!(flash[..0x1000].iter().all(|&byte| byte == 0xFF));
+        // a finished job can be waited on, and an unknown one is refused
+        assert!(spinor.wait_write(b).is_ok());
+        assert!(matches!(spinor.poll_write(b + 100), Err(SpinorError::InvalidRequest)));
+
+        // a failed write is reported by the wait, and by the next barrier
+        *EMU_STUCK.lock().unwrap() = Some(0x2004);
+        let c = spinor.submit_write(0x2000, &[0u8; 16]).unwrap();
+        assert!(matches!(spinor.wait_write(c), Err(SpinorError::VerifyFailed)));
+        assert!(matches!(spinor.barrier(), Err(SpinorError::VerifyFailed)));
+        *EMU_STUCK.lock().unwrap() = None;
+
+        assert!(matches!(spinor.submit_write(0x2100, &[0u8; 16]), Err(SpinorError::AlignmentError)));
+        assert!(matches!(spinor.submit_write(0x2000, &[0u8; 4097]), Err(SpinorError::InvalidRequest)));
+    }
+
+    #[test]
+    fn test_error_round_trip() {
+        let mut codes = 0;
+        while let Some(e) = <SpinorError as FromPrimitive>::from_usize(codes) {
+            codes += 1;
+            let code = e.to_usize().unwrap();
+            // each code comes back out as itself, whether it arrived in a reply struct or a scalar
+            for result in [e.into_result(), SpinorError::from_reply(Some(e)), SpinorError::from_code(code)] {
+                match (e, result) {
+                    (SpinorError::NoError, Ok(())) => {}
+                    (_, Err(got)) => assert_eq!(got.to_usize().unwrap(), code, "{:?} came back as {:?}", e, got),
+                    (_, Ok(())) => panic!("{:?} came back as success", e),
+                }
+            }
+        }
+        assert!(codes > 10, "SpinorError isn't numbered from 0");
+        // replies that don't carry a code the client understands are an implementation error
+        assert!(matches!(SpinorError::from_reply(None), Err(SpinorError::ImplementationError)));
+        assert!(matches!(SpinorError::from_code(codes), Err(SpinorError::ImplementationError)));
+
+        // lock opcodes answer 1 or 0
+        assert!(lock_reply(Ok(xous::Result::Scalar1(1))).is_ok());
+        assert!(matches!(lock_reply(Ok(xous::Result::Scalar1(0))), Err(SpinorError::BusyTryAgain)));
+        assert!(matches!(lock_reply(Ok(xous::Result::Scalar2(1, 0))), Err(SpinorError::IpcError)));
+        assert!(matches!(lock_reply(Err(xous::Error::ServerNotFound)), Err(SpinorError::IpcError)));
+    }
+
     fn init_emu_flash(sectors: usize) {
         EMU_FLASH.lock().unwrap().clear();
         for _ in 0..sectors * 4096 {
@@ -3,6 +3,18 @@
 
 mod api;
 use api::*;
+mod regions;
+use regions::*;
+mod throttle;
+use throttle::*;
+mod latency;
+use latency::*;
+mod validate;
+use validate::*;
+mod ecc;
+use ecc::*;
+mod waiters;
+use waiters::*;
 
 use num_traits::*;
 use xous_ipc::Buffer;
@@ -10,7 +22,7 @@ use xous::{msg_blocking_scalar_unpack, msg_scalar_unpack};
 
 use core::sync::atomic::{AtomicBool, Ordering};
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 
 #[cfg(any(feature="precursor", feature="renode"))]
 mod implementation {
@@ -402,6 +414,7 @@ mod implementation {
         softirq: utralib::CSR<u32>,
         cur_op: Option<FlashOp>,
         ticktimer: ticktimer_server::Ticktimer,
+        throttle: WriteThrottle,
         // TODO: refactor ecup command to use spinor to operate the reads
         #[cfg(feature="extra_flush")]
         flusher: MemoryRange,
@@ -440,6 +453,7 @@ mod implementation {
                 susres: RegManager::new(csr.as_mut_ptr() as *mut u32),
                 cur_op: None,
                 ticktimer: ticktimer_server::Ticktimer::new().unwrap(),
+                throttle: WriteThrottle::new(),
                 #[cfg(feature="extra_flush")]
                 flusher,
             };
@@ -470,6 +484,10 @@ mod implementation {
             spinor.softirq.wfo(utra::spinor_soft_int::SOFTINT_SOFTINT, 1);
             while SPINOR_RUNNING.load(Ordering::SeqCst) {}
             spinor.id = SPINOR_RESULT.load(Ordering::SeqCst);
+            let jedec_id = JedecId::from_u32(spinor.id);
+            if jedec_id.capacity_bytes() != Some(SPINOR_SIZE_BYTES) {
+                log::error!("FLASH part {:x?} does not match the expected size of {} bytes", jedec_id, SPINOR_SIZE_BYTES);
+            }
 
             spinor
         }
@@ -555,7 +573,73 @@ mod implementation {
             core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
         }
 
+        /// Re-issues RDID, rather than trusting the ID read at boot, so callers see the part that's there now.
+        pub(crate) fn read_jedec_id(&mut self) -> u32 {
+            self.cur_op = Some(FlashOp::ReadId);
+            self.id = self.call_spinor_context_blocking();
+            self.id
+        }
+
+        pub(crate) fn set_write_throttle(&mut self, bytes_per_second: u32) {
+            log::info!("write throttle set to {} bytes/s", bytes_per_second);
+            self.throttle.set_rate(bytes_per_second);
+        }
+
+        /// Waits out the write throttle before writing `bytes`
+        fn pace(&mut self, bytes: u32) {
+            let pause = self.throttle.pause_ms(bytes, self.ticktimer.elapsed_ms());
+            // a pending suspend is waiting for us to finish, so don't drag things out
+            if pause > 0 && !crate::SUSPEND_PENDING.load(Ordering::Relaxed) {
+                self.ticktimer.sleep_ms(pause as usize).ok();
+            }
+        }
+
         pub(crate) fn write_region(&mut self, wr: &mut WriteRegion) -> SpinorError {
+            wr.fail_addr = None;
+            match self.program_region(wr) {
+                SpinorError::NoError if wr.verify_crc => match self.readback_crc(wr.start, wr.len) {
+                    Ok(crc) => {
+                        wr.crc = crc;
+                        SpinorError::NoError
+                    }
+                    Err(e) => e,
+                },
+                r @ (SpinorError::EraseFailed | SpinorError::WriteFailed) => {
+                    wr.fail_addr = Some(wr.start);
+                    r
+                }
+                r => r,
+            }
+        }
+
+        /// CRC32 of `start..start+len` as read back through the memory-mapped interface. Like
+        /// `verify_erased`, this fails if another process has the range mapped.
+        fn readback_crc(&mut self, start: u32, len: u32) -> Result<u32, SpinorError> {
+            if len == 0 {
+                return Ok(crc::crc32::checksum_ieee(&[]));
+            }
+            let base = start & !(SPINOR_ERASE_SIZE - 1);
+            let offset = (start - base) as usize;
+            let map_len = (offset + len as usize + SPINOR_ERASE_SIZE as usize - 1) & !(SPINOR_ERASE_SIZE as usize - 1);
+            let window = match xous::syscall::map_memory(
+                xous::MemoryAddress::new((base + xous::FLASH_PHYS_BASE) as usize),
+                None,
+                map_len,
+                xous::MemoryFlags::R,
+            ) {
+                Ok(window) => window,
+                Err(e) => {
+                    log::warn!("couldn't map 0x{:08x}+0x{:x} for write CRC: {:?}", base, map_len, e);
+                    return Err(SpinorError::AccessDenied);
+                }
+            };
+            self.flush_dcache(start, len);
+            let crc = crc::crc32::checksum_ieee(&window.as_slice::<u8>()[offset..offset + len as usize]);
+            xous::syscall::unmap_memory(window).expect("couldn't unmap write CRC window");
+            Ok(crc)
+        }
+
+        fn program_region(&mut self, wr: &mut WriteRegion) -> SpinorError {
             /*let log_level = log::max_level();
             if wr.start >= 0x27_5000 && wr.start <= 0x28_8000 { // trigger a debug if we are doing a certain type of transaction
                 log::set_max_level(log::LevelFilter::Trace);
@@ -565,6 +649,7 @@ mod implementation {
             if wr.start + wr.len > SPINOR_SIZE_BYTES { // basic security check. this is necessary so we don't have wrap-around attacks on the SoC gateware region
                 return SpinorError::InvalidRequest;
             }
+            self.pace(wr.len);
 
             if !wr.clean_patch {
                 // the `lib.rs` side has ostensibly already done the following checks for us:
@@ -617,7 +702,131 @@ mod implementation {
             }
         }
 
-        pub(crate) fn bulk_erase(&mut self, be: &mut BulkErase) -> SpinorError {
+        /// Erases the sector at `rs.start` and programs all of it with `rs.data`, then reads it back.
+        pub(crate) fn replace_sector(&mut self, rs: &ReplaceSector) -> SpinorError {
+            let mut wr = WriteRegion {
+                id: rs.id,
+                start: rs.start,
+                clean_patch: false,
+                len: SPINOR_ERASE_SIZE,
+                verify_crc: false,
+                crc: 0,
+                fail_addr: None,
+                result: None,
+                data: rs.data,
+            };
+            match self.write_region(&mut wr) {
+                SpinorError::NoError => {},
+                e => return e,
+            }
+            // the E_FAIL/P_FAIL status has been checked by now; also compare the contents, unless another
+            // process has the sector mapped (the PDDB maps its whole region, so its data is checked on its side)
+            let window = match xous::syscall::map_memory(
+                xous::MemoryAddress::new((rs.start + xous::FLASH_PHYS_BASE) as usize),
+                None,
+                SPINOR_ERASE_SIZE as usize,
+                xous::MemoryFlags::R,
+            ) {
+                Ok(window) => window,
+                Err(e) => {
+                    log::debug!("couldn't map 0x{:08x} to verify replaced sector: {:?}", rs.start, e);
+                    return SpinorError::NoError;
+                }
+            };
+            self.flush_dcache(rs.start, SPINOR_ERASE_SIZE);
+            let matches = window.as_slice::<u8>() == &rs.data[..];
+            xous::syscall::unmap_memory(window).expect("couldn't unmap replaced sector");
+            if matches {
+                SpinorError::NoError
+            } else {
+                log::error!("replaced sector did not read back correctly: 0x{:08x}", rs.start);
+                SpinorError::VerifyFailed
+            }
+        }
+
+        /// Checks the slot against its hash, if one was given, then programs the marker word. The
+        /// marker word is the only thing written, and it's written last, so a reset at any point
+        /// before it lands leaves the old slot active.
+        pub(crate) fn commit_ab_switch(&mut self, ab: &AbCommit) -> SpinorError {
+            use sha2::Digest;
+            const WINDOW: u32 = 0x10_0000;
+            if ab.slot_start.checked_add(ab.slot_len).map_or(true, |end| end > SPINOR_SIZE_BYTES) {
+                return SpinorError::InvalidRequest;
+            }
+            if let Some(expected) = ab.slot_hash {
+                let mut hasher = sha2::Sha256::new();
+                for base in (ab.slot_start..ab.slot_start + ab.slot_len).step_by(WINDOW as usize) {
+                    let len = WINDOW.min(ab.slot_start + ab.slot_len - base);
+                    let window = match xous::syscall::map_memory(
+                        xous::MemoryAddress::new((base + xous::FLASH_PHYS_BASE) as usize),
+                        None,
+                        len as usize,
+                        xous::MemoryFlags::R,
+                    ) {
+                        Ok(window) => window,
+                        Err(e) => {
+                            log::warn!("couldn't map 0x{:08x}+0x{:x} to check A/B slot: {:?}", base, len, e);
+                            return SpinorError::AccessDenied;
+                        }
+                    };
+                    self.flush_dcache(base, len);
+                    hasher.update(window.as_slice::<u8>());
+                    xous::syscall::unmap_memory(window).expect("couldn't unmap A/B slot window");
+                }
+                if hasher.finalize().as_slice() != &expected[..] {
+                    log::error!("A/B slot at 0x{:08x} doesn't match its hash; not switching", ab.slot_start);
+                    return SpinorError::VerifyFailed;
+                }
+            }
+            let sector = ab.marker_addr & !(SPINOR_ERASE_SIZE - 1);
+            let window = match xous::syscall::map_memory(
+                xous::MemoryAddress::new((sector + xous::FLASH_PHYS_BASE) as usize),
+                None,
+                SPINOR_ERASE_SIZE as usize,
+                xous::MemoryFlags::R,
+            ) {
+                Ok(window) => window,
+                Err(e) => {
+                    log::warn!("couldn't map the A/B marker at 0x{:08x}: {:?}", ab.marker_addr, e);
+                    return SpinorError::AccessDenied;
+                }
+            };
+            let offset = ((ab.marker_addr - sector) / 4) as usize;
+            self.flush_dcache(sector, SPINOR_ERASE_SIZE);
+            // programming can only clear bits, so the marker has to be written without an erase
+            let current = window.as_slice::<u32>()[offset];
+            if current & ab.new_active != ab.new_active {
+                xous::syscall::unmap_memory(window).expect("couldn't unmap A/B marker");
+                return SpinorError::AbortNotErased;
+            }
+            let mut wr = WriteRegion {
+                id: ab.id,
+                start: ab.marker_addr,
+                clean_patch: true,
+                len: 4,
+                verify_crc: false,
+                crc: 0,
+                fail_addr: None,
+                result: None,
+                data: [0; 4096],
+            };
+            wr.data[..4].copy_from_slice(&ab.new_active.to_le_bytes());
+            let result = self.write_region(&mut wr);
+            self.flush_dcache(sector, SPINOR_ERASE_SIZE);
+            let landed = window.as_slice::<u32>()[offset] == ab.new_active;
+            xous::syscall::unmap_memory(window).expect("couldn't unmap A/B marker");
+            match result {
+                SpinorError::NoError if !landed => {
+                    log::error!("A/B marker at 0x{:08x} did not read back correctly", ab.marker_addr);
+                    SpinorError::VerifyFailed
+                }
+                r => r,
+            }
+        }
+
+        /// Erases `be.len` bytes a block at a time, calling `progress` with the bytes done and the
+        /// total after each block.
+        pub(crate) fn bulk_erase(&mut self, be: &mut BulkErase, mut progress: impl FnMut(u32, u32)) -> SpinorError {
             if (be.start & (SPINOR_BULK_ERASE_SIZE - 1)) != 0 {
                 log::warn!("Bulk erase start address is not block-aligned. Aborting.");
                 return SpinorError::AlignmentError;
@@ -627,17 +836,116 @@ mod implementation {
                 return SpinorError::AlignmentError;
             }
             for block in (be.start..be.start + be.len).step_by(SPINOR_BULK_ERASE_SIZE as usize) {
+                self.pace(SPINOR_BULK_ERASE_SIZE);
                 self.cur_op = Some(FlashOp::EraseBlock(block));
                 log::trace!("bulk erase: {:x?}", block);
                 let erase_result = self.call_spinor_context_blocking();
                 if erase_result & 0x40 != 0 {
                     log::error!("E_FAIL set, erase failed: result 0x{:02x}, block addr 0x{:08x}", erase_result, block);
+                    be.fail_addr = Some(block);
                     return SpinorError::EraseFailed;
                 }
+                progress(block + SPINOR_BULK_ERASE_SIZE - be.start, be.len);
             }
             return SpinorError::NoError
         }
 
+        /// Maps the requested range read-only and scans it. Returns the address of the first byte
+        /// that isn't 0xFF, or `None` if the whole range is erased.
+        pub(crate) fn verify_erased(&mut self, start: u32, len: u32) -> Result<Option<u32>, SpinorError> {
+            match start.checked_add(len) {
+                Some(end) if end <= SPINOR_SIZE_BYTES && len > 0 => {},
+                _ => return Err(SpinorError::InvalidRequest),
+            }
+            // mappings have to be page-aligned, so round the window out to the enclosing pages
+            let base = start & !(SPINOR_ERASE_SIZE - 1);
+            let offset = (start - base) as usize;
+            let map_len = (offset + len as usize + SPINOR_ERASE_SIZE as usize - 1) & !(SPINOR_ERASE_SIZE as usize - 1);
+            let window = match xous::syscall::map_memory(
+                xous::MemoryAddress::new((base + xous::FLASH_PHYS_BASE) as usize),
+                None,
+                map_len,
+                xous::MemoryFlags::R,
+            ) {
+                Ok(window) => window,
+                Err(e) => {
+                    // this fails if the range is already mapped by another process
+                    log::warn!("couldn't map 0x{:08x}+0x{:x} for erase check: {:?}", base, map_len, e);
+                    return Err(SpinorError::AccessDenied);
+                }
+            };
+            let first_dirty = window.as_slice::<u8>()[offset..offset + len as usize]
+                .iter()
+                .position(|&b| b != 0xFF)
+                .map(|index| start + index as u32);
+            xous::syscall::unmap_memory(window).expect("couldn't unmap erase check window");
+            Ok(first_dirty)
+        }
+
+        /// Maps the requested range read-only and copies it into the front of `data`. Like
+        /// `verify_erased`, this fails if another process has the range mapped.
+        pub(crate) fn read_region(&mut self, start: u32, len: u32, data: &mut [u8; 4096]) -> Result<(), SpinorError> {
+            match start.checked_add(len) {
+                Some(end) if end <= SPINOR_SIZE_BYTES && len > 0 && len as usize <= data.len() => {},
+                _ => return Err(SpinorError::InvalidRequest),
+            }
+            let base = start & !(SPINOR_ERASE_SIZE - 1);
+            let offset = (start - base) as usize;
+            let map_len = (offset + len as usize + SPINOR_ERASE_SIZE as usize - 1) & !(SPINOR_ERASE_SIZE as usize - 1);
+            let window = match xous::syscall::map_memory(
+                xous::MemoryAddress::new((base + xous::FLASH_PHYS_BASE) as usize),
+                None,
+                map_len,
+                xous::MemoryFlags::R,
+            ) {
+                Ok(window) => window,
+                Err(e) => {
+                    log::warn!("couldn't map 0x{:08x}+0x{:x} for read: {:?}", base, map_len, e);
+                    return Err(SpinorError::AccessDenied);
+                }
+            };
+            // the range may have been written since it was last read through the cache
+            self.flush_dcache(start, len);
+            data[..len as usize].copy_from_slice(&window.as_slice::<u8>()[offset..offset + len as usize]);
+            xous::syscall::unmap_memory(window).expect("couldn't unmap read window");
+            Ok(())
+        }
+
+        /// SHA-256 over all of FLASH, read a window at a time through the memory-mapped interface,
+        /// calling `progress` with the bytes hashed and the total after each window. Like
+        /// `verify_erased`, this fails if another process has any part of the array mapped.
+        pub(crate) fn attest_whole_chip(&mut self, mut progress: impl FnMut(u32, u32)) -> Result<[u8; 32], SpinorError> {
+            use sha2::Digest;
+            const WINDOW: u32 = 0x10_0000;
+            let mut hasher = sha2::Sha256::new();
+            for base in (0..SPINOR_SIZE_BYTES).step_by(WINDOW as usize) {
+                let window = match xous::syscall::map_memory(
+                    xous::MemoryAddress::new((base + xous::FLASH_PHYS_BASE) as usize),
+                    None,
+                    WINDOW as usize,
+                    xous::MemoryFlags::R,
+                ) {
+                    Ok(window) => window,
+                    Err(e) => {
+                        log::warn!("couldn't map 0x{:08x}+0x{:x} for attestation: {:?}", base, WINDOW, e);
+                        return Err(SpinorError::AccessDenied);
+                    }
+                };
+                // the window may have been written since it was last read through the cache, and a
+                // stale line would attest to what used to be there
+                self.flush_dcache(base, WINDOW);
+                hasher.update(window.as_slice::<u8>());
+                xous::syscall::unmap_memory(window).expect("couldn't unmap attestation window");
+                if base & 0xFF_FFFF == 0 {
+                    log::info!("attestation at 0x{:08x}", base);
+                }
+                progress(base + WINDOW, SPINOR_SIZE_BYTES);
+            }
+            let mut digest = [0u8; 32];
+            digest.copy_from_slice(&hasher.finalize());
+            Ok(digest)
+        }
+
         pub fn suspend(&mut self) {
             self.susres.suspend();
         }
@@ -668,9 +976,33 @@ mod implementation {
         pub(crate) fn write_region(&mut self, _wr: &mut WriteRegion) -> SpinorError {
             SpinorError::ImplementationError
         }
-        pub(crate) fn bulk_erase(&mut self, _be: &mut BulkErase) -> SpinorError {
+        pub(crate) fn replace_sector(&mut self, _rs: &ReplaceSector) -> SpinorError {
+            SpinorError::ImplementationError
+        }
+        pub(crate) fn bulk_erase(&mut self, _be: &mut BulkErase, _progress: impl FnMut(u32, u32)) -> SpinorError {
+            SpinorError::ImplementationError
+        }
+        pub(crate) fn commit_ab_switch(&mut self, _ab: &AbCommit) -> SpinorError {
             SpinorError::ImplementationError
         }
+        pub(crate) fn verify_erased(&mut self, _start: u32, _len: u32) -> Result<Option<u32>, SpinorError> {
+            Err(SpinorError::ImplementationError)
+        }
+        pub(crate) fn read_region(&mut self, _start: u32, _len: u32, _data: &mut [u8; 4096]) -> Result<(), SpinorError> {
+            Err(SpinorError::ImplementationError)
+        }
+        pub(crate) fn attest_whole_chip(&mut self, _progress: impl FnMut(u32, u32)) -> Result<[u8; 32], SpinorError> {
+            Err(SpinorError::ImplementationError)
+        }
+        pub(crate) fn set_write_throttle(&mut self, _bytes_per_second: u32) {
+        }
+        /// Reports the Precursor part, unless overridden with a hex ID in `SPINOR_JEDEC_ID`,
+        /// e.g. `SPINOR_JEDEC_ID=3a80c2` to look like the 512Mbit part.
+        pub(crate) fn read_jedec_id(&mut self) -> u32 {
+            std::env::var("SPINOR_JEDEC_ID").ok()
+                .and_then(|id| u32::from_str_radix(id.trim_start_matches("0x"), 16).ok())
+                .unwrap_or(PRECURSOR_JEDEC_ID)
+        }
     }
 }
 
@@ -679,6 +1011,60 @@ static OP_IN_PROGRESS: AtomicBool = AtomicBool::new(false);
 static SUSPEND_FAILURE: AtomicBool = AtomicBool::new(false);
 static SUSPEND_PENDING: AtomicBool = AtomicBool::new(false);
 
+/// A caller's progress hook: our connection to the callback server in its process, and the
+/// connection and message ID that server forwards each report to.
+#[derive(Debug, Copy, Clone)]
+struct ScalarCallback {
+    server_to_cb_cid: xous::CID,
+    cb_to_client_cid: xous::CID,
+    cb_to_client_id: u32,
+}
+
+fn do_hook(hookdata: ScalarHook, cb_conns: &mut Vec<ScalarCallback>) {
+    const MAX_PROGRESS_CALLBACKS: usize = 8;
+    if cb_conns.len() >= MAX_PROGRESS_CALLBACKS {
+        log::error!("ran out of space registering progress callback");
+        return;
+    }
+    let (s0, s1, s2, s3) = hookdata.sid;
+    let sid = xous::SID::from_u32(s0, s1, s2, s3);
+    match xous::connect(sid) {
+        Ok(server_to_cb_cid) => cb_conns.push(ScalarCallback {
+            server_to_cb_cid,
+            cb_to_client_cid: hookdata.cid,
+            cb_to_client_id: hookdata.id,
+        }),
+        Err(e) => log::error!("couldn't connect to progress callback server: {:?}", e),
+    }
+}
+// the callback servers belong to the callers, which shut them down when they drop their Spinor
+fn unhook(cb_conns: &mut Vec<ScalarCallback>) {
+    for scb in cb_conns.drain(..) {
+        unsafe{xous::disconnect(scb.server_to_cb_cid).ok();}
+    }
+}
+/// Tells every hooked caller that `done` of `total` bytes are finished.
+///
+/// This is called from inside write and erase loops, which hold off suspend until they finish. A
+/// blocking send there could wait on a caller that is itself waiting on us or on the suspend, so
+/// reports go out with `try_send_message`: a report that doesn't fit in the caller's queue is dropped,
+/// and a caller whose callback server has gone away is unhooked.
+fn report_progress(cb_conns: &mut Vec<ScalarCallback>, done: u32, total: u32) {
+    cb_conns.retain(|scb| {
+        match xous::try_send_message(scb.server_to_cb_cid,
+            xous::Message::new_scalar(ProgressEvent::Progress.to_usize().unwrap(),
+                scb.cb_to_client_cid as usize, scb.cb_to_client_id as usize, done as usize, total as usize)
+        ) {
+            Ok(_) | Err(xous::Error::ServerQueueFull) => true,
+            Err(e) => {
+                log::warn!("dropping progress callback: {:?}", e);
+                unsafe{xous::disconnect(scb.server_to_cb_cid).ok();}
+                false
+            }
+        }
+    });
+}
+
 fn susres_thread(sid0: usize, sid1: usize, sid2: usize, sid3: usize) {
     let susres_sid = xous::SID::from_u32(sid0 as u32, sid1 as u32, sid2 as u32, sid3 as u32);
     let xns = xous_names::XousNames::new().unwrap();
@@ -723,6 +1109,43 @@ fn susres_thread(sid0: usize, sid1: usize, sid2: usize, sid3: usize) {
     xous::destroy_server(susres_sid).unwrap();
 }
 
+/// Watches the deadlines of the callers parked on AcquireExclusiveTimeout. Each deadline sent
+/// over `deadlines` is in ticktimer ms; once the earliest one passes, the server is sent an
+/// `AcquireTimeoutExpired` and expires every waiter that is due, then sends the next deadline.
+fn acquire_timer_thread(conn: xous::CID, deadlines: std::sync::mpsc::Receiver<u64>) {
+    use std::sync::mpsc::RecvTimeoutError;
+    let tt = ticktimer_server::Ticktimer::new().unwrap();
+    let mut next: Option<u64> = None;
+    loop {
+        let received = match next {
+            None => deadlines.recv().or(Err(RecvTimeoutError::Disconnected)),
+            Some(deadline) => {
+                let now = tt.elapsed_ms();
+                if deadline <= now {
+                    next = None;
+                    xous::send_message(conn,
+                        xous::Message::new_scalar(Opcode::AcquireTimeoutExpired.to_usize().unwrap(), 0, 0, 0, 0)
+                    ).expect("couldn't expire AcquireExclusiveTimeout");
+                    continue;
+                }
+                deadlines.recv_timeout(std::time::Duration::from_millis(deadline - now))
+            }
+        };
+        match received {
+            Ok(deadline) => next = Some(next.map_or(deadline, |n| n.min(deadline))),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
+
+/// Completes a deferred AcquireExclusiveTimeout request, which releases the caller.
+fn reply_acquire_timeout(mut env: xous::MessageEnvelope, result: SpinorError) {
+    let mut buffer = unsafe { Buffer::from_memory_message_mut(env.body.memory_message_mut().unwrap()) };
+    let mut at = buffer.to_original::<AcquireTimeout, _>().unwrap();
+    at.result = Some(result);
+    buffer.replace(at).expect("couldn't return response code to AcquireTimeout");
+}
 
 fn main() -> ! {
     use crate::implementation::Spinor;
@@ -763,8 +1186,28 @@ fn main() -> ! {
     let mut client_id: Option<[u32; 4]> = None;
     let mut soc_token: Option<[u32; 4]> = None;
     const MAX_ERRLOG_LEN: usize = 512; // this will span a couple erase blocks if my math is right
-    let mut ecc_errors: HashSet<(u32, u32, u32, u32)> = HashSet::new();
+    let mut ecc_errors = EccErrors::new(MAX_ERRLOG_LEN);
     let mut staging_write_protect: bool = false;
+    let mut protected = ProtectedRegions::new();
+    let tt = ticktimer_server::Ticktimer::new().unwrap();
+    let mut latencies = Latencies::new();
+    // first failure seen by each client since its last barrier; all ops complete before we reply,
+    // so a barrier only has to report (and clear) what happened since the previous one.
+    let mut session_errors: HashMap<[u32; 4], SpinorError> = HashMap::new();
+    // callers parked on AcquireExclusiveTimeout, and the timer thread that watches their deadlines
+    let mut lock_waiters = LockWaiters::<xous::MessageEnvelope>::new();
+    let (deadline_tx, deadline_rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || acquire_timer_thread(handler_conn, deadline_rx));
+    // writes queued by SubmitWrite, oldest first, and the results of the most recent ones to finish
+    const MAX_WRITE_RESULTS: usize = 64;
+    let mut write_jobs: VecDeque<(u32, WriteRegion)> = VecDeque::new();
+    let mut write_results: BTreeMap<u32, SpinorError> = BTreeMap::new();
+    // callers blocked in WaitWrite: (job id, caller)
+    let mut write_waiters: Vec<(u32, xous::MessageSender)> = Vec::new();
+    let mut next_write_job: u32 = 0;
+    // callers hooked for progress reports, and the (bytes done, bytes total) of the queued writes
+    let mut progress_cbs: Vec<ScalarCallback> = Vec::new();
+    let mut write_progress: (u32, u32) = (0, 0);
 
     loop {
         let mut msg = xous::receive_message(spinor_sid).unwrap();
@@ -818,10 +1261,60 @@ fn main() -> ! {
                     xous::return_scalar(msg.sender, 0).unwrap();
                 }
             }),
+            Some(Opcode::AcquireExclusiveTimeout) => {
+                let parked = {
+                    let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                    let mut at = buffer.to_original::<AcquireTimeout, _>().unwrap();
+                    if soc_token.is_none() {
+                        at.result = Some(SpinorError::BusyTryAgain);
+                    } else if client_id.is_none() && !SUSPEND_PENDING.load(Ordering::Relaxed) {
+                        OP_IN_PROGRESS.store(true, Ordering::Relaxed);
+                        llio.wfi_override(true).expect("couldn't shut off WFI");
+                        client_id = Some(at.id);
+                        log::trace!("giving {:x?} an exclusive lock", client_id);
+                        SUSPEND_FAILURE.store(false, Ordering::Relaxed);
+                        at.result = Some(SpinorError::NoError);
+                    } else if at.timeout_ms == 0 || SUSPEND_PENDING.load(Ordering::Relaxed) {
+                        at.result = Some(SpinorError::BusyTryAgain);
+                    }
+                    if at.result.is_some() {
+                        buffer.replace(at).expect("couldn't return response code to AcquireTimeout");
+                        None
+                    } else {
+                        Some((at.id, at.timeout_ms))
+                    }
+                };
+                if let Some((id, timeout_ms)) = parked {
+                    // hold the response until either ReleaseExclusive hands the lock over, or the
+                    // deadline passes. A waiter that was handed the lock is no longer there to expire.
+                    let deadline = tt.elapsed_ms() + timeout_ms as u64;
+                    lock_waiters.park(id, deadline, msg);
+                    deadline_tx.send(deadline).expect("couldn't arm the AcquireExclusiveTimeout timer");
+                }
+            }
+            Some(Opcode::AcquireTimeoutExpired) => msg_scalar_unpack!(msg, _, _, _, _, {
+                for (id, env) in lock_waiters.expire(tt.elapsed_ms()) {
+                    log::debug!("{:x?} timed out waiting for the exclusive lock", id);
+                    reply_acquire_timeout(env, SpinorError::BusyTryAgain);
+                }
+                // the timer only keeps the earliest deadline, so give it the next one
+                if let Some(deadline) = lock_waiters.next_deadline() {
+                    deadline_tx.send(deadline).expect("couldn't arm the AcquireExclusiveTimeout timer");
+                }
+            }),
             Some(Opcode::ReleaseExclusive) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
-                client_id = None;
-                OP_IN_PROGRESS.store(false, Ordering::Relaxed);
-                llio.wfi_override(false).expect("couldn't restore WFI");
+                if let Some((id, env)) = lock_waiters.next() {
+                    // hand the lock straight to the oldest waiter. Suspends are already locked out
+                    // and WFI is already overridden, so only the owner changes.
+                    client_id = Some(id);
+                    log::trace!("handing the exclusive lock to {:x?}", client_id);
+                    SUSPEND_FAILURE.store(false, Ordering::Relaxed);
+                    reply_acquire_timeout(env, SpinorError::NoError);
+                } else {
+                    client_id = None;
+                    OP_IN_PROGRESS.store(false, Ordering::Relaxed);
+                    llio.wfi_override(false).expect("couldn't restore WFI");
+                }
                 xous::return_scalar(msg.sender, 1).unwrap();
             }),
             Some(Opcode::AcquireSuspendLock) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
@@ -839,29 +1332,32 @@ fn main() -> ! {
             Some(Opcode::WriteRegion) => {
                 let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
                 let mut wr = buffer.to_original::<WriteRegion, _>().unwrap();
-                let mut authorized = true;
-                if let Some(st) = soc_token {
-                    if staging_write_protect && ((wr.start >= xous::SOC_REGION_LOC) && (wr.start < xous::LOADER_LOC)) ||
-                    !staging_write_protect && ((wr.start >= xous::SOC_REGION_LOC) && (wr.start < xous::SOC_STAGING_GW_LOC)) {
-                        // if only the holder of the ID that matches the SoC token can write to the SOC flash area
-                        // other areas are not as strictly controlled because signature checks ostensibly should catch
-                        // attempts to modify them. However, access to the gateware definition would allow one to rewrite
-                        // the boot ROM, which would then change the trust root. Therefore, we check this region specifically.
-                        if st != wr.id {
-                            wr.result = Some(SpinorError::AccessDenied);
-                            authorized = false;
-                        }
+                // a dirty write erases the whole sector, even if fewer bytes are written back
+                let span = if wr.clean_patch { wr.len } else { wr.len.max(SPINOR_ERASE_SIZE) };
+                let authorized = match check_write_region(&wr)
+                    .and_then(|_| check_write_access(soc_token, staging_write_protect, &protected, wr.id, wr.start, span))
+                {
+                    Ok(()) => true,
+                    Err(e) => {
+                        wr.result = Some(e);
+                        false
                     }
-                } else {
-                    // the soc token MUST be initialized early on, if not, something bad has happened.
-                    wr.result = Some(SpinorError::AccessDenied);
-                    authorized = false;
-                }
+                };
                 if authorized {
                     match client_id {
                         Some(id) => {
                             if wr.id == id {
-                                wr.result = Some(spinor.write_region(&mut wr)); // note: this must reject out-of-bound length requests for security reasons
+                                let started = tt.elapsed_ms();
+                                let result = spinor.write_region(&mut wr); // note: this must reject out-of-bound length requests for security reasons
+                                latencies.record_write(tt.elapsed_ms() - started);
+                                if matches!(result, SpinorError::NoError) {
+                                    // a patch arrives a region at a time, and only the client knows how many
+                                    // more are coming, so each one reports as a step of its own
+                                    report_progress(&mut progress_cbs, wr.len, wr.len);
+                                } else {
+                                    session_errors.entry(wr.id).or_insert(result);
+                                }
+                                wr.result = Some(result);
                             } else {
                                 wr.result = Some(SpinorError::IdMismatch);
                             }
@@ -873,12 +1369,206 @@ fn main() -> ! {
                 }
                 buffer.replace(wr).expect("couldn't return response code to WriteRegion");
             },
+            Some(Opcode::WriteStream) => {
+                let mem = msg.body.memory_message_mut().unwrap();
+                if mem.buf.len() < WRITE_STREAM_BUF_LEN {
+                    log::error!("WriteStream buffer is too short: {}", mem.buf.len());
+                    continue;
+                }
+                let mut header = WriteStreamHeader::from_words(mem.buf.as_slice::<u32>());
+                let len = header.len as usize;
+                // the whole stream is checked up front, so a refused stream leaves FLASH untouched. Each
+                // sector is erased before it's written, so the span covers the whole of the last one.
+                let span = (len.min(WRITE_STREAM_MAX) as u32 + SPINOR_ERASE_SIZE - 1) & !(SPINOR_ERASE_SIZE - 1);
+                let checked = if len > WRITE_STREAM_MAX || header.start.checked_add(span).is_none() {
+                    Err(SpinorError::InvalidRequest)
+                } else if header.start & (SPINOR_ERASE_SIZE - 1) != 0 {
+                    Err(SpinorError::AlignmentError)
+                } else {
+                    check_write_access(soc_token, staging_write_protect, &protected, header.id, header.start, span)
+                        .and_then(|_| match client_id {
+                            Some(id) if id == header.id => Ok(()),
+                            Some(_) => Err(SpinorError::IdMismatch),
+                            None => Err(SpinorError::NoId),
+                        })
+                };
+                header.result = Some(match checked {
+                    Err(e) => e,
+                    Ok(()) => {
+                        let mut wr = WriteRegion {
+                            id: header.id,
+                            start: 0,
+                            clean_patch: false,
+                            len: 0,
+                            verify_crc: false,
+                            crc: 0,
+                            fail_addr: None,
+                            result: None,
+                            data: [0xFF; 4096],
+                        };
+                        let data = &mem.buf.as_slice::<u8>()[WRITE_STREAM_DATA_OFFSET..WRITE_STREAM_DATA_OFFSET + len];
+                        let mut result = SpinorError::NoError;
+                        for (index, sector) in data.chunks(SPINOR_ERASE_SIZE as usize).enumerate() {
+                            let offset = index as u32 * SPINOR_ERASE_SIZE;
+                            wr.start = header.start + offset;
+                            wr.len = sector.len() as u32;
+                            wr.data[..sector.len()].copy_from_slice(sector);
+                            let started = tt.elapsed_ms();
+                            result = spinor.write_region(&mut wr);
+                            latencies.record_write(tt.elapsed_ms() - started);
+                            if !matches!(result, SpinorError::NoError) {
+                                session_errors.entry(header.id).or_insert(result);
+                                header.fail_offset = offset;
+                                break;
+                            }
+                            report_progress(&mut progress_cbs, offset + wr.len, header.len);
+                        }
+                        result
+                    }
+                });
+                header.to_words(mem.buf.as_slice_mut::<u32>());
+            }
+            Some(Opcode::SubmitWrite) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut job = buffer.to_original::<WriteJob, _>().unwrap();
+                let span = if job.wr.clean_patch { job.wr.len } else { job.wr.len.max(SPINOR_ERASE_SIZE) };
+                job.wr.result = Some(match check_write_region(&job.wr)
+                    .and_then(|_| check_write_access(soc_token, staging_write_protect, &protected, job.wr.id, job.wr.start, span))
+                {
+                    Err(e) => e,
+                    Ok(()) => match client_id {
+                        Some(id) if id == job.wr.id => SpinorError::NoError,
+                        Some(_) => SpinorError::IdMismatch,
+                        None => SpinorError::NoId,
+                    },
+                });
+                if matches!(job.wr.result, Some(SpinorError::NoError)) {
+                    job.job = next_write_job;
+                    next_write_job = next_write_job.wrapping_add(1);
+                    write_jobs.push_back((job.job, job.wr));
+                    write_progress.1 = write_progress.1.saturating_add(job.wr.len);
+                    // the write runs when the server gets to this message, which is queued behind the
+                    // caller's submission and ahead of anything it sends later, such as ReleaseExclusive
+                    xous::send_message(handler_conn,
+                        xous::Message::new_scalar(Opcode::RunWriteJob.to_usize().unwrap(), 0, 0, 0, 0)
+                    ).expect("couldn't queue a submitted write");
+                }
+                buffer.replace(job).expect("couldn't return response code to SubmitWrite");
+            }
+            Some(Opcode::RunWriteJob) => msg_scalar_unpack!(msg, _, _, _, _, {
+                if let Some((job, mut wr)) = write_jobs.pop_front() {
+                    // the submitter held the lock when the write was queued; make sure it still does
+                    let result = match client_id {
+                        Some(id) if id == wr.id => {
+                            let started = tt.elapsed_ms();
+                            let result = spinor.write_region(&mut wr);
+                            latencies.record_write(tt.elapsed_ms() - started);
+                            result
+                        }
+                        Some(_) => SpinorError::IdMismatch,
+                        None => SpinorError::NoId,
+                    };
+                    if !matches!(result, SpinorError::NoError) {
+                        session_errors.entry(wr.id).or_insert(result);
+                    }
+                    write_results.insert(job, result);
+                    write_progress.0 = write_progress.0.saturating_add(wr.len);
+                    report_progress(&mut progress_cbs, write_progress.0, write_progress.1);
+                    if write_jobs.is_empty() {
+                        write_progress = (0, 0);
+                    }
+                    while write_results.len() > MAX_WRITE_RESULTS {
+                        let oldest = *write_results.keys().next().unwrap();
+                        write_results.remove(&oldest);
+                    }
+                    let (code, err) = WriteStatus::to_scalar(Some(WriteStatus::Done(result)));
+                    write_waiters.retain(|&(waiting_on, sender)| {
+                        if waiting_on == job {
+                            xous::return_scalar2(sender, code, err).unwrap();
+                            false
+                        } else {
+                            true
+                        }
+                    });
+                }
+            }),
+            Some(Opcode::PollWrite) => msg_blocking_scalar_unpack!(msg, job, _, _, _, {
+                let job = job as u32;
+                let status = if write_jobs.iter().any(|(j, _)| *j == job) {
+                    Some(WriteStatus::InProgress)
+                } else {
+                    write_results.get(&job).map(|&e| WriteStatus::Done(e))
+                };
+                let (code, err) = WriteStatus::to_scalar(status);
+                xous::return_scalar2(msg.sender, code, err).unwrap();
+            }),
+            Some(Opcode::WaitWrite) => {
+                let job = msg.body.scalar_message().unwrap().arg1 as u32;
+                if write_jobs.iter().any(|(j, _)| *j == job) {
+                    // no reply now: the caller stays blocked until RunWriteJob gets to the job
+                    write_waiters.push((job, msg.sender));
+                } else {
+                    let status = write_results.get(&job).map(|&e| WriteStatus::Done(e));
+                    let (code, err) = WriteStatus::to_scalar(status);
+                    xous::return_scalar2(msg.sender, code, err).unwrap();
+                }
+            }
+            Some(Opcode::ReplaceSector) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut rs = buffer.to_original::<ReplaceSector, _>().unwrap();
+                let result = if (rs.start & (SPINOR_ERASE_SIZE - 1)) != 0 {
+                    SpinorError::AlignmentError
+                } else if let Err(e) = check_write_access(soc_token, staging_write_protect, &protected, rs.id, rs.start, SPINOR_ERASE_SIZE) {
+                    e
+                } else {
+                    match client_id {
+                        // the caller holds the exclusive lock for the whole erase-program-verify sequence
+                        Some(id) if id == rs.id => {
+                            let result = spinor.replace_sector(&rs); // note: write_region rejects out-of-bound requests
+                            if !matches!(result, SpinorError::NoError) {
+                                session_errors.entry(rs.id).or_insert(result);
+                            }
+                            result
+                        }
+                        Some(_) => SpinorError::IdMismatch,
+                        None => SpinorError::NoId,
+                    }
+                };
+                rs.result = Some(result);
+                buffer.replace(rs).expect("couldn't return response code to ReplaceSector");
+            }
+            Some(Opcode::CommitAbSwitch) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut ab = buffer.to_original::<AbCommit, _>().unwrap();
+                let result = if (ab.marker_addr & 3) != 0
+                    || (ab.slot_start & (SPINOR_ERASE_SIZE - 1)) != 0
+                    || (ab.slot_len & (SPINOR_ERASE_SIZE - 1)) != 0 {
+                    SpinorError::AlignmentError
+                } else if let Err(e) = check_write_access(soc_token, staging_write_protect, &protected, ab.id, ab.marker_addr, 4) {
+                    e
+                } else {
+                    match client_id {
+                        Some(id) if id == ab.id => {
+                            let result = spinor.commit_ab_switch(&ab);
+                            if !matches!(result, SpinorError::NoError) {
+                                session_errors.entry(ab.id).or_insert(result);
+                            }
+                            result
+                        }
+                        Some(_) => SpinorError::IdMismatch,
+                        None => SpinorError::NoId,
+                    }
+                };
+                ab.result = Some(result);
+                buffer.replace(ab).expect("couldn't return response code to CommitAbSwitch");
+            }
             Some(Opcode::BulkErase) => {
                 let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
                 let mut wr = buffer.to_original::<BulkErase, _>().unwrap();
                 // bounds check to within the PDDB region for bulk erases. Please use standard patching for other regions.
                 let authorized =
-                    if (wr.start >= xous::PDDB_LOC) && ((wr.start + wr.len) <= (xous::PDDB_LOC + xous::PDDB_LEN)) {
+                    if (wr.start >= xous::PDDB_LOC) && ((wr.start + wr.len) <= (xous::PDDB_LOC + xous::PDDB_LEN))
+                    && !protected.overlaps(wr.start, wr.len) {
                         true
                     } else {
                         false
@@ -887,7 +1577,13 @@ fn main() -> ! {
                     match client_id {
                         Some(id) => {
                             if wr.id == id {
-                                wr.result = Some(spinor.bulk_erase(&mut wr)); // note: this must reject out-of-bound length requests for security reasons
+                                let started = tt.elapsed_ms();
+                                let result = spinor.bulk_erase(&mut wr, |done, total| report_progress(&mut progress_cbs, done, total)); // note: this must reject out-of-bound length requests for security reasons
+                                latencies.record_erase(tt.elapsed_ms() - started);
+                                if !matches!(result, SpinorError::NoError) {
+                                    session_errors.entry(wr.id).or_insert(result);
+                                }
+                                wr.result = Some(result);
                             } else {
                                 wr.result = Some(SpinorError::IdMismatch);
                             }
@@ -901,6 +1597,129 @@ fn main() -> ! {
                 }
                 buffer.replace(wr).expect("couldn't return response code to WriteRegion");
             }
+            Some(op @ Opcode::AddProtectedRegion) | Some(op @ Opcode::RemoveProtectedRegion) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut pr = buffer.to_original::<ProtectedRegion, _>().unwrap();
+                // only the SoC token holder may change the protection map
+                if soc_token.is_some() && soc_token == Some(pr.id) {
+                    pr.result = match op {
+                        Opcode::AddProtectedRegion => {
+                            match protected.add(pr.start, pr.len) {
+                                Ok(()) => {
+                                    log::info!("protected region added: 0x{:08x}, len 0x{:x}", pr.start, pr.len);
+                                    Some(SpinorError::NoError)
+                                }
+                                Err(e) => Some(e),
+                            }
+                        }
+                        _ => {
+                            if protected.remove(pr.start) {
+                                log::info!("protected region removed: 0x{:08x}", pr.start);
+                                Some(SpinorError::NoError)
+                            } else {
+                                Some(SpinorError::InvalidRequest)
+                            }
+                        }
+                    };
+                } else {
+                    pr.result = Some(SpinorError::AccessDenied);
+                }
+                buffer.replace(pr).expect("couldn't return response code to ProtectedRegion");
+            }
+            Some(Opcode::IsProtected) => msg_blocking_scalar_unpack!(msg, start, len, _, _, {
+                if protected.overlaps(start as u32, len as u32) {
+                    xous::return_scalar(msg.sender, 1).unwrap();
+                } else {
+                    xous::return_scalar(msg.sender, 0).unwrap();
+                }
+            }),
+            Some(Opcode::Barrier) => msg_blocking_scalar_unpack!(msg, id0, id1, id2, id3, {
+                // every accepted write has been programmed and had its P_FAIL/E_FAIL status checked by the
+                // time its WriteRegion returned, so all that's left is to report any failure since the last barrier.
+                let id = [id0 as u32, id1 as u32, id2 as u32, id3 as u32];
+                let result = session_errors.remove(&id).unwrap_or(SpinorError::NoError);
+                xous::return_scalar(msg.sender, result.to_usize().unwrap()).unwrap();
+            }),
+            Some(Opcode::VerifyErased) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut ve = buffer.to_original::<VerifyErased, _>().unwrap();
+                match spinor.verify_erased(ve.start, ve.len) {
+                    Ok(first_dirty) => {
+                        ve.first_dirty = first_dirty;
+                        ve.result = Some(SpinorError::NoError);
+                    }
+                    Err(e) => ve.result = Some(e),
+                }
+                buffer.replace(ve).expect("couldn't return response code to VerifyErased");
+            }
+            Some(Opcode::CheckErased) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut ve = buffer.to_original::<VerifyErased, _>().unwrap();
+                ve.result = Some(match bulk_erase_span(ve.start, ve.len) {
+                    Some((start, len)) => {
+                        ve.start = start;
+                        ve.len = len;
+                        match spinor.verify_erased(start, len) {
+                            Ok(first_dirty) => {
+                                ve.first_dirty = first_dirty;
+                                SpinorError::NoError
+                            }
+                            Err(e) => e,
+                        }
+                    }
+                    None => SpinorError::InvalidRequest,
+                });
+                buffer.replace(ve).expect("couldn't return response code to CheckErased");
+            }
+            Some(Opcode::ReadRegion) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut rr = buffer.to_original::<ReadRegion, _>().unwrap();
+                rr.result = Some(match spinor.read_region(rr.start, rr.len, &mut rr.data) {
+                    Ok(()) => SpinorError::NoError,
+                    Err(e) => e,
+                });
+                buffer.replace(rr).expect("couldn't return response code to ReadRegion");
+            }
+            Some(Opcode::GetLatencyHistogram) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut hist = buffer.to_original::<LatencyHistogram, _>().unwrap();
+                latencies.report(&mut hist);
+                buffer.replace(hist).expect("couldn't return LatencyHistogram");
+            }
+            Some(Opcode::ResetLatencyHistogram) => msg_scalar_unpack!(msg, _, _, _, _, {
+                latencies.reset();
+            }),
+            Some(Opcode::SetWriteThrottle) => msg_scalar_unpack!(msg, bytes_per_second, _, _, _, {
+                spinor.set_write_throttle(bytes_per_second as u32);
+            }),
+            Some(Opcode::ReadJedecId) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                if SUSPEND_PENDING.load(Ordering::Relaxed) {
+                    xous::return_scalar2(msg.sender, 0, SpinorError::BusyTryAgain.to_usize().unwrap()).unwrap();
+                } else {
+                    let id = spinor.read_jedec_id();
+                    xous::return_scalar2(msg.sender, id as usize, SpinorError::NoError.to_usize().unwrap()).unwrap();
+                }
+            }),
+            Some(Opcode::AttestWholeChip) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut cd = buffer.to_original::<ChipDigest, _>().unwrap();
+                if client_id.is_some() || SUSPEND_PENDING.load(Ordering::Relaxed) {
+                    // someone is part way through a multi-message patch, so the array isn't in a consistent state
+                    cd.result = Some(SpinorError::BusyTryAgain);
+                } else {
+                    // no write can start while this loop is busy hashing; just hold off suspends until we're done
+                    OP_IN_PROGRESS.store(true, Ordering::Relaxed);
+                    match spinor.attest_whole_chip(|done, total| report_progress(&mut progress_cbs, done, total)) {
+                        Ok(digest) => {
+                            cd.digest = digest;
+                            cd.result = Some(SpinorError::NoError);
+                        }
+                        Err(e) => cd.result = Some(e),
+                    }
+                    OP_IN_PROGRESS.store(false, Ordering::Relaxed);
+                }
+                buffer.replace(cd).expect("couldn't return response code to ChipDigest");
+            }
             Some(Opcode::EccError) => msg_scalar_unpack!(msg, hw_rep, status, lower_addr, upper_addr, {
                 /*
                   Historical notes:
@@ -922,12 +1741,7 @@ fn main() -> ! {
                       use two disjoint patch operations to merge its data, and to instead merge the write data
                       before patching. Error was cleared by erasing the block, and has not since been observed again.
                  */
-                if !ecc_errors.contains(&(hw_rep as u32, status as u32, lower_addr as u32, upper_addr as u32)) {
-                    if ecc_errors.len() < MAX_ERRLOG_LEN {
-                        ecc_errors.insert((hw_rep as u32, status as u32, lower_addr as u32, upper_addr as u32));
-                    } else {
-                        log::warn!("ECC log overflow, error not stored");
-                    }
+                if ecc_errors.record(status as u32, lower_addr as u32, upper_addr as u32, tt.elapsed_ms()) > 0 {
                     log::error!("ECC error reported: 0x{:x} 0x{:x} 0x{:x} 0x{:x}", hw_rep, status, lower_addr, upper_addr);
                     // how to read:
                     // first word is what address the HW PHY was set to when the interrupt flipped. This doesn't seem to be useful.
@@ -940,11 +1754,19 @@ fn main() -> ! {
                     //   by the ECC engine, but there's only an error if the status word indicates that.
                 }
             }),
+            Some(Opcode::RegisterProgressCallback) => {
+                let buffer = unsafe { Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
+                let hookdata = buffer.to_original::<ScalarHook, _>().unwrap();
+                do_hook(hookdata, &mut progress_cbs);
+            }
             Some(Opcode::EccLog) => {
-                for (index, entry) in ecc_errors.iter().enumerate() {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut log = buffer.to_original::<EccLog, _>().unwrap();
+                ecc_errors.drain_into(&mut log);
+                for (index, entry) in log.entries[..log.count as usize].iter().enumerate() {
                     log::info!("{}: {:x?}", index, entry);
                 }
-                ecc_errors.clear();
+                buffer.replace(log).expect("couldn't return EccLog");
             }
             None => {
                 log::error!("couldn't convert opcode");
@@ -954,6 +1776,7 @@ fn main() -> ! {
     }
     // clean up our program
     log::trace!("main loop exit, destroying servers");
+    unhook(&mut progress_cbs);
     let quitconn = xous::connect(susres_mgr_sid).unwrap();
     xous::send_message(quitconn, xous::Message::new_scalar(api::SusResOps::Quit.to_usize().unwrap(), 0, 0, 0, 0)).unwrap();
     unsafe{xous::disconnect(quitconn).unwrap();}
@@ -0,0 +1,181 @@
+use crate::api::SpinorError;
+use std::collections::BTreeMap;
+
+/// Address ranges that have been marked read-only at runtime, in addition to the fixed
+/// SoC gateware region check. Regions are keyed by start address and are not allowed
+/// to overlap each other, so an overlap query only needs to inspect the one region
+/// that starts closest below the end of the queried range.
+pub(crate) struct ProtectedRegions {
+    /// start -> length
+    regions: BTreeMap<u32, u32>,
+}
+
+impl ProtectedRegions {
+    pub fn new() -> Self {
+        ProtectedRegions { regions: BTreeMap::new() }
+    }
+
+    /// Adds a new protected region. Zero-length regions, regions that wrap around
+    /// the address space, and regions that overlap an existing region are rejected.
+    pub fn add(&mut self, start: u32, len: u32) -> Result<(), SpinorError> {
+        if len == 0 || start.checked_add(len).is_none() {
+            return Err(SpinorError::InvalidRequest);
+        }
+        if self.overlaps(start, len) {
+            return Err(SpinorError::InvalidRequest);
+        }
+        self.regions.insert(start, len);
+        Ok(())
+    }
+
+    /// Removes the region that starts at exactly `start`. Returns `true` if a region was removed.
+    pub fn remove(&mut self, start: u32) -> bool {
+        self.regions.remove(&start).is_some()
+    }
+
+    /// Returns `true` if any byte in `start..start+len` falls inside a protected region.
+    pub fn overlaps(&self, start: u32, len: u32) -> bool {
+        if len == 0 {
+            return false;
+        }
+        // do the math in u64 so requests that run off the end of the address space can't wrap
+        let end = start as u64 + len as u64;
+        let candidate = if end > u32::MAX as u64 {
+            self.regions.iter().next_back()
+        } else {
+            self.regions.range(..end as u32).next_back()
+        };
+        match candidate {
+            Some((&r_start, &r_len)) => r_start as u64 + r_len as u64 > start as u64,
+            None => false,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.regions.len()
+    }
+}
+
+/// Checks that the holder of `id` may write or erase `start..start+span`: the SoC gateware (and,
+/// unless staging is write protected, the staging area) is reserved to the SoC token holder, and
+/// nobody may touch a protected region.
+pub(crate) fn check_write_access(
+    soc_token: Option<[u32; 4]>,
+    staging_write_protect: bool,
+    protected: &ProtectedRegions,
+    id: [u32; 4],
+    start: u32,
+    span: u32
+) -> Result<(), SpinorError> {
+    if let Some(st) = soc_token {
+        if staging_write_protect && ((start >= xous::SOC_REGION_LOC) && (start < xous::LOADER_LOC)) ||
+        !staging_write_protect && ((start >= xous::SOC_REGION_LOC) && (start < xous::SOC_STAGING_GW_LOC)) {
+            // if only the holder of the ID that matches the SoC token can write to the SOC flash area
+            // other areas are not as strictly controlled because signature checks ostensibly should catch
+            // attempts to modify them. However, access to the gateware definition would allow one to rewrite
+            // the boot ROM, which would then change the trust root. Therefore, we check this region specifically.
+            if st != id {
+                return Err(SpinorError::AccessDenied);
+            }
+        }
+    } else {
+        // the soc token MUST be initialized early on, if not, something bad has happened.
+        return Err(SpinorError::AccessDenied);
+    }
+    if protected.overlaps(start, span) {
+        log::warn!("write to protected region denied: 0x{:08x}, len 0x{:x}", start, span);
+        return Err(SpinorError::AccessDenied);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protected_overlap() {
+        let mut pr = ProtectedRegions::new();
+        pr.add(0x1_0000, 0x1000).unwrap();
+        pr.add(0x3_0000, 0x2000).unwrap();
+
+        // fully inside
+        assert!(pr.overlaps(0x1_0100, 0x10));
+        assert!(pr.overlaps(0x3_1000, 0x1000));
+        // overlapping the leading and trailing edges
+        assert!(pr.overlaps(0xF000, 0x1001));
+        assert!(pr.overlaps(0x1_0FFF, 0x1000));
+        // spanning a whole region
+        assert!(pr.overlaps(0x2_0000, 0x2_0000));
+        // outside, including the bytes directly adjacent to each region
+        assert!(!pr.overlaps(0xF000, 0x1000));
+        assert!(!pr.overlaps(0x1_1000, 0x1000));
+        assert!(!pr.overlaps(0x2_F000, 0x1000));
+        assert!(!pr.overlaps(0x3_2000, 0x1000));
+        assert!(!pr.overlaps(0x1_0000, 0));
+    }
+
+    #[test]
+    fn test_protected_add_remove() {
+        let mut pr = ProtectedRegions::new();
+        pr.add(0x1000, 0x1000).unwrap();
+        // overlapping and degenerate regions are refused
+        assert!(pr.add(0x1800, 0x1000).is_err());
+        assert!(pr.add(0x0, 0x1001).is_err());
+        assert!(pr.add(0x8000, 0).is_err());
+        assert!(pr.add(0xFFFF_F000, 0x2000).is_err());
+        // adjacent regions are fine
+        pr.add(0x2000, 0x1000).unwrap();
+        assert_eq!(pr.len(), 2);
+
+        assert!(!pr.remove(0x1800));
+        assert!(pr.remove(0x1000));
+        assert!(!pr.overlaps(0x1000, 0x1000));
+        assert!(pr.overlaps(0x1000, 0x1001));
+    }
+
+    #[test]
+    fn test_check_write_access() {
+        const HOLDER: [u32; 4] = [1; 4];
+        const OTHER: [u32; 4] = [2; 4];
+        let mut pr = ProtectedRegions::new();
+        pr.add(0x100_0000, 0x1_0000).unwrap();
+        // (who, start, span, allowed) with the SoC token held by HOLDER and staging writable
+        let cases = [
+            // inside a protected region, and straddling either edge of it, is refused to anyone
+            (OTHER, 0x100_1000, 0x1000, false),
+            (HOLDER, 0x100_1000, 0x1000, false),
+            (OTHER, 0xFF_F000, 0x2000, false),
+            (OTHER, 0x100_F000, 0x2000, false),
+            (OTHER, 0xFF_0000, 0x3_0000, false),
+            // directly adjacent on either side, or well clear of it, is fine
+            (OTHER, 0xFF_F000, 0x1000, true),
+            (OTHER, 0x101_0000, 0x1000, true),
+            (OTHER, 0x200_0000, 0x1000, true),
+            // the SoC gateware is reserved to the token holder
+            (OTHER, xous::SOC_REGION_LOC, 0x1000, false),
+            (HOLDER, xous::SOC_REGION_LOC, 0x1000, true),
+            (OTHER, xous::SOC_STAGING_GW_LOC, 0x1000, true),
+        ];
+        for &(id, start, span, allowed) in cases.iter() {
+            assert_eq!(
+                check_write_access(Some(HOLDER), false, &pr, id, start, span).is_ok(),
+                allowed,
+                "id {:?}, 0x{:08x}+0x{:x}", id, start, span
+            );
+        }
+
+        // a write-protected staging area is reserved to the token holder as well
+        assert!(matches!(check_write_access(Some(HOLDER), true, &pr, OTHER, xous::SOC_STAGING_GW_LOC, 0x1000),
+            Err(SpinorError::AccessDenied)));
+        assert!(check_write_access(Some(HOLDER), true, &pr, HOLDER, xous::SOC_STAGING_GW_LOC, 0x1000).is_ok());
+        assert!(check_write_access(Some(HOLDER), true, &pr, OTHER, xous::LOADER_LOC, 0x1000).is_ok());
+
+        // nothing may be written before the SoC token is set, wherever it is
+        for &start in [xous::SOC_REGION_LOC, 0x200_0000].iter() {
+            assert!(matches!(check_write_access(None, false, &pr, HOLDER, start, 0x1000),
+                Err(SpinorError::AccessDenied)));
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::cmp::max;
+
+/// Paces writes to a sustained rate, for when the current draw (and heat) of continuous
+/// programming is a problem. The pause is taken before each unit of work rather than after,
+/// so a lone write never waits, and the lock isn't held for a trailing pause after the last one.
+/// There is no burst allowance: time spent idle doesn't bank credit for later writes.
+#[cfg_attr(not(target_os = "xous"), allow(dead_code))]
+pub(crate) struct WriteThrottle {
+    /// 0 means unthrottled
+    bytes_per_second: u32,
+    /// the earliest time the next write may start, in microseconds
+    next_start_us: u64,
+}
+
+#[cfg_attr(not(target_os = "xous"), allow(dead_code))]
+impl WriteThrottle {
+    pub fn new() -> Self {
+        WriteThrottle { bytes_per_second: 0, next_start_us: 0 }
+    }
+
+    pub fn set_rate(&mut self, bytes_per_second: u32) {
+        self.bytes_per_second = bytes_per_second;
+        self.next_start_us = 0;
+    }
+
+    /// Call just before writing `bytes`, with the current time. Returns how long to pause first.
+    pub fn pause_ms(&mut self, bytes: u32, now_ms: u64) -> u64 {
+        if self.bytes_per_second == 0 {
+            return 0;
+        }
+        let now_us = now_ms * 1000;
+        let start_us = max(self.next_start_us, now_us);
+        self.next_start_us = start_us + bytes as u64 * 1_000_000 / self.bytes_per_second as u64;
+        // round up, so the sustained rate never exceeds the limit
+        (start_us - now_us + 999) / 1000
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// How long it takes to write `blocks` blocks of `block_len`, if each takes `op_ms` to program
+    fn simulate(bytes_per_second: u32, blocks: usize, block_len: u32, op_ms: u64) -> u64 {
+        let mut throttle = WriteThrottle::new();
+        throttle.set_rate(bytes_per_second);
+        let mut now = 0;
+        for _ in 0..blocks {
+            now += throttle.pause_ms(block_len, now);
+            now += op_ms;
+        }
+        now
+    }
+
+    #[test]
+    fn test_throttled_bulk_write() {
+        // 64 sectors: 256kiB, at 2ms per sector
+        let unthrottled = simulate(0, 64, 4096, 2);
+        assert_eq!(unthrottled, 128);
+        // a limit above the natural rate changes nothing
+        assert_eq!(simulate(4 * 1024 * 1024, 64, 4096, 2), unthrottled);
+
+        // 64kiB/s: each sector starts 62.5ms after the previous one
+        let fast = simulate(64 * 1024, 64, 4096, 2);
+        assert!((63 * 62..=63 * 63 + 3).contains(&fast), "took {}ms", fast);
+        // half the rate takes twice as long
+        let slow = simulate(32 * 1024, 64, 4096, 2);
+        assert!((63 * 125..=63 * 125 + 3).contains(&slow), "took {}ms", slow);
+    }
+
+    #[test]
+    fn test_throttle_idle() {
+        let mut throttle = WriteThrottle::new();
+        throttle.set_rate(1000);
+        // the first write never waits
+        assert_eq!(throttle.pause_ms(500, 10_000), 0);
+        // an immediate follow-up waits out the first write's share of the budget
+        assert_eq!(throttle.pause_ms(500, 10_000), 500);
+        // after a long idle period there is no pause, but no banked credit either
+        assert_eq!(throttle.pause_ms(500, 20_000), 0);
+        assert_eq!(throttle.pause_ms(500, 20_100), 400);
+        // turning the throttle off takes effect right away
+        throttle.set_rate(0);
+        assert_eq!(throttle.pause_ms(500, 20_100), 0);
+    }
+}
@@ -0,0 +1,82 @@
+use crate::api::{SpinorError, WriteRegion, SPINOR_BULK_ERASE_SIZE, SPINOR_ERASE_SIZE};
+
+/// Checks the shape of a `WriteRegion` before anything touches FLASH.
+///
+/// A write that isn't a clean patch erases the sector at `start` and programs it in one go, so it
+/// must start on a sector boundary and stay within that sector; otherwise the erase lands on the
+/// wrong sector, or the tail of the data is programmed over a sector that was never erased. A clean
+/// patch promises the range is already erased, so it may start anywhere and span sectors.
+pub(crate) fn check_write_region(wr: &WriteRegion) -> Result<(), SpinorError> {
+    if wr.len as usize > wr.data.len() {
+        return Err(SpinorError::InvalidRequest);
+    }
+    let end = wr.start.checked_add(wr.len).ok_or(SpinorError::InvalidRequest)?;
+    if !wr.clean_patch {
+        if wr.start & (SPINOR_ERASE_SIZE - 1) != 0 {
+            return Err(SpinorError::AlignmentError);
+        }
+        if wr.len > 0 && wr.start / SPINOR_ERASE_SIZE != (end - 1) / SPINOR_ERASE_SIZE {
+            return Err(SpinorError::AlignmentError);
+        }
+    }
+    Ok(())
+}
+
+/// Rounds `start..start+len` out to the 64kiB blocks a `BulkErase` of it would have to cover, as
+/// `BulkErase` itself only takes aligned ranges: `start` down to the block it's in, and the end up
+/// to the end of the block holding the last byte. Returns `None` for an empty range, or one that
+/// wraps around the address space.
+pub(crate) fn bulk_erase_span(start: u32, len: u32) -> Option<(u32, u32)> {
+    let mask = SPINOR_BULK_ERASE_SIZE - 1;
+    let end = start.checked_add(len).filter(|_| len > 0)?;
+    let start = start & !mask;
+    let end = end.checked_add(mask)? & !mask;
+    Some((start, end - start))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wr(start: u32, len: u32, clean_patch: bool) -> WriteRegion {
+        WriteRegion {
+            id: [0, 0, 0, 0],
+            start,
+            clean_patch,
+            len,
+            verify_crc: false,
+            crc: 0,
+            fail_addr: None,
+            result: None,
+            data: [0; 4096],
+        }
+    }
+
+    #[test]
+    fn test_check_write_region() {
+        // a whole sector, part of a sector, and clean patches anywhere
+        assert!(check_write_region(&wr(0x2000, 0x1000, false)).is_ok());
+        assert!(check_write_region(&wr(0x2000, 0x10, false)).is_ok());
+        assert!(check_write_region(&wr(0x2FF0, 0x20, true)).is_ok());
+        assert!(check_write_region(&wr(0x2001, 0x1000, true)).is_ok());
+
+        // more than the data buffer holds
+        assert!(matches!(check_write_region(&wr(0x2000, 0x1001, false)), Err(SpinorError::InvalidRequest)));
+        assert!(matches!(check_write_region(&wr(0x2000, 0x1001, true)), Err(SpinorError::InvalidRequest)));
+        // wrapping around the address space
+        assert!(matches!(check_write_region(&wr(0xFFFF_FFF0, 0x20, true)), Err(SpinorError::InvalidRequest)));
+        // an erase-implied write that doesn't start on a sector, or crosses into the next one
+        assert!(matches!(check_write_region(&wr(0x2010, 0x10, false)), Err(SpinorError::AlignmentError)));
+        assert!(matches!(check_write_region(&wr(0x2FF0, 0x20, false)), Err(SpinorError::AlignmentError)));
+    }
+
+    #[test]
+    fn test_bulk_erase_span() {
+        assert_eq!(bulk_erase_span(0x1_0000, 0x1_0000), Some((0x1_0000, 0x1_0000)));
+        assert_eq!(bulk_erase_span(0x1_2345, 1), Some((0x1_0000, 0x1_0000)));
+        assert_eq!(bulk_erase_span(0x1_FFFF, 2), Some((0x1_0000, 0x2_0000)));
+        assert_eq!(bulk_erase_span(0x1_0000, 0), None);
+        assert_eq!(bulk_erase_span(0xFFFF_0000, 0x1_0000), None);
+        assert_eq!(bulk_erase_span(0xFFFF_0000, 0x1), None);
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::VecDeque;
+
+/// Callers parked on `AcquireExclusiveTimeout`, oldest first. Each one waits until either
+/// `ReleaseExclusive` hands it the lock, or its deadline (in ticktimer ms) passes. The deadlines
+/// are all watched by one timer thread, which only needs to know the earliest of them.
+pub(crate) struct LockWaiters<T> {
+    waiters: VecDeque<([u32; 4], u64, T)>,
+}
+
+impl<T> LockWaiters<T> {
+    pub fn new() -> Self {
+        LockWaiters { waiters: VecDeque::new() }
+    }
+
+    /// Parks `waiter`, on behalf of the client `id`, until `deadline`
+    pub fn park(&mut self, id: [u32; 4], deadline: u64, waiter: T) {
+        self.waiters.push_back((id, deadline, waiter));
+    }
+
+    /// Removes the oldest waiter, to be handed the lock
+    pub fn next(&mut self) -> Option<([u32; 4], T)> {
+        self.waiters.pop_front().map(|(id, _, waiter)| (id, waiter))
+    }
+
+    /// Removes and hands back, oldest first, every waiter whose deadline is at or before `now`
+    pub fn expire(&mut self, now: u64) -> Vec<([u32; 4], T)> {
+        let mut expired = Vec::new();
+        let mut waiting = VecDeque::with_capacity(self.waiters.len());
+        for (id, deadline, waiter) in self.waiters.drain(..) {
+            if deadline <= now {
+                expired.push((id, waiter));
+            } else {
+                waiting.push_back((id, deadline, waiter));
+            }
+        }
+        self.waiters = waiting;
+        expired
+    }
+
+    /// The earliest deadline of the waiters still parked, for re-arming the timer
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.waiters.iter().map(|(_, deadline, _)| *deadline).min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expire_before_release() {
+        let mut waiters = LockWaiters::new();
+        waiters.park([1; 4], 100, "first");
+        waiters.park([2; 4], 50, "second");
+        assert_eq!(waiters.next_deadline(), Some(50));
+        // nothing is due yet
+        assert!(waiters.expire(49).is_empty());
+        // the second caller gives up, and the first is left waiting
+        assert_eq!(waiters.expire(50), vec![([2; 4], "second")]);
+        assert_eq!(waiters.next_deadline(), Some(100));
+        // so the release goes to the first, and a late expiry finds nobody
+        assert_eq!(waiters.next(), Some(([1; 4], "first")));
+        assert!(waiters.expire(100).is_empty());
+        assert_eq!(waiters.next_deadline(), None);
+        assert_eq!(waiters.next(), None);
+    }
+
+    #[test]
+    fn test_release_before_expire() {
+        let mut waiters = LockWaiters::new();
+        waiters.park([1; 4], 100, "first");
+        waiters.park([2; 4], 50, "second");
+        // the lock goes to the oldest waiter, whatever its deadline
+        assert_eq!(waiters.next(), Some(([1; 4], "first")));
+        assert_eq!(waiters.next_deadline(), Some(50));
+        // its timer firing later has no effect, as it's no longer waiting
+        assert_eq!(waiters.expire(100), vec![([2; 4], "second")]);
+        assert!(waiters.expire(200).is_empty());
+        assert_eq!(waiters.next(), None);
+    }
+}
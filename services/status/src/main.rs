@@ -710,6 +710,21 @@ fn wrapped_main() -> ! {
             xous::Result::Ok
         });
 
+        // the USB device server comes up as a Precursor; switch it to the stored identity, if any
+        match prefs.usb_device_descriptor() {
+            Ok(Some(descriptor)) => usb_device_xous::UsbHid::new().set_device_descriptor(
+                descriptor.vid,
+                descriptor.pid,
+                &descriptor.manufacturer,
+                &descriptor.product,
+                descriptor.serial.as_deref(),
+            ).unwrap_or_else(|error| {
+                log::error!("cannot set USB device descriptor: {:?}", error)
+            }),
+            Ok(None) => {}
+            Err(error) => log::error!("cannot read USB device descriptor: {:?}", error),
+        }
+
         // keyboard mapping is restored directly by the keyboard hardware
         #[cfg(not(feature="no-codec"))]
         {
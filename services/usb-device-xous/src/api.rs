@@ -4,12 +4,80 @@ pub(crate) const SERVER_NAME_USB_DEVICE: &'static str = "_Xous USB device driver
 pub(crate) enum Opcode {
     /// Returns the link status
     LinkStatus,
+    /// Subscribe to a scalar message each time the link status changes
+    RegisterLinkStatusCallback,
     /// Send a keyboard code
     SendKeyCode,
+    /// Queue a keyboard code to go out as the host polls for it, returning right away
+    EnqueueKeys,
+    /// Set how many key presses `EnqueueKeys` holds before refusing more
+    SetKeyQueueDepth,
+    /// Send a consumer control (media key) code
+    SendConsumerCode,
     /// "Type" a string to the keyboard
     SendString,
+    /// Play back a scripted sequence of key presses; the caller is blocked until it finishes
+    PlayMacro,
+    /// Cut short the macro being played, releasing any keys it holds down
+    StopMacro,
+    /// Internal message from the macro player thread: writes the key report for a step
+    MacroReport,
+    /// Internal message from the macro player thread: the macro is over, so its caller can be released
+    MacroDone,
+    /// Move the mouse pointer by a relative amount
+    MouseMove,
+    /// Set which mouse buttons are held down
+    MouseButton,
+    /// Queue bytes to send to the host on the serial port
+    SerialWrite,
+    /// Take bytes the host sent on the serial port
+    SerialRead,
     /// Get the current LED state
     GetLedState,
+    /// Subscribe to a scalar message each time the host changes the keyboard LEDs
+    RegisterLedCallback,
+    /// Blocks the caller until the LED state changes, or a timeout passes
+    WaitLedChange,
+    /// Internal message from a timeout thread: gives up on a pending WaitLedChange
+    LedWaitTimeout,
+    /// Read the millisecond clock used to pace HID reports
+    GetClockMillis,
+    /// Set the delay between key-down and key-up on auto-up keycodes
+    SetAutoUpDelay,
+    /// Replace the table of usage codes substituted into outgoing key reports
+    SetKeyRemap,
+    /// Go back to sending usage codes as-is
+    ClearKeyRemap,
+    /// Pace `SendString` bursts against a fixed schedule, keeping the server runnable between keys
+    SetBurstPriority,
+    /// Type runs of the same character in `SendString` with the host's key repeat, or press each one
+    SetTypeRepeatMode,
+    /// Report the last error seen while the host was enumerating the device
+    GetLastEnumError,
+    /// Report the address the host assigned to the device, or 0 if it hasn't yet
+    GetDeviceAddress,
+    /// Wake a suspended host, if it allowed the device to
+    RemoteWakeup,
+    /// Report whether VBUS is present, i.e. a cable is plugged in, whether or not the host has enumerated us
+    GetVbusState,
+    /// Set the VBUS state reported in hosted mode; has no effect on hardware
+    SetSimulatedVbus,
+    /// Dry-run an endpoint buffer allocation: reports whether it would fit, and at what offset
+    CanAllocate,
+    /// Release a region of the descriptor memory that the built interfaces aren't using
+    FreeEndpoint,
+    /// List the regions allocated in the descriptor memory
+    DumpAllocations,
+    /// Replace the VID/PID and strings the device identifies itself with, and re-enumerate
+    SetDeviceDescriptor,
+    /// Set bMaxPacketSize0 and re-enumerate
+    SetEp0MaxPacketSize,
+    /// Get the current bMaxPacketSize0
+    GetEp0MaxPacketSize,
+    /// Mirror the host's Caps Lock state onto a GPIO pin
+    SetCapsFollower,
+    /// Choose which HID interfaces the keyboard view exposes, and re-enumerate
+    SetEnabledInterfaces,
     /// Switch to a specified device core
     SwitchCores,
     /// Makes sure a given core is selected
@@ -37,6 +105,11 @@ pub(crate) enum Opcode {
     SuspendResume,
     /// Exits the server
     Quit,
+
+    /// Feed the service an LED report as if it came from the host, for testing the LED
+    /// handling without a real host attached
+    #[cfg(not(target_os = "xous"))]
+    InjectLedReport,
 }
 
 #[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone)]
@@ -45,6 +118,172 @@ pub struct UsbString {
     pub sent: Option<u32>,
 }
 
+/// How the host's typematic (key repeat) is set up, for `SendString` to type runs of the same
+/// character by holding the key down rather than pressing it once per character.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TypeRepeat {
+    /// time from the key going down to the first repeat
+    pub delay_ms: u32,
+    /// time between repeats after that
+    pub interval_ms: u32,
+}
+impl Default for TypeRepeat {
+    /// What most desktop hosts use out of the box: 500ms, then about 30 characters a second
+    fn default() -> Self {
+        TypeRepeat { delay_ms: 500, interval_ms: 33 }
+    }
+}
+impl TypeRepeat {
+    /// How long to hold a key down for the host to type it `count` times. The press types the
+    /// first one and the repeats the rest; the release lands half an interval after the last
+    /// repeat, so a little jitter either way doesn't add or lose a character.
+    pub fn hold_ms(&self, count: usize) -> usize {
+        if count < 2 {
+            0
+        } else {
+            self.delay_ms as usize + (count - 2) * self.interval_ms as usize + self.interval_ms as usize / 2
+        }
+    }
+}
+
+#[derive(Debug, num_derive::FromPrimitive, num_derive::ToPrimitive)]
+pub(crate) enum EventCallback {
+    Event,
+    Drop,
+}
+
+/*
+Implementation note: as in the LLIO server, we use a ScalarHook with a two-stage message passing so
+we don't leak the local SID. The caller creates a dedicated, one-time use server and shares its SID
+with the USB server, which relays events through it to the caller's own connection.
+*/
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone)]
+pub(crate) struct ScalarHook {
+    pub sid: (u32, u32, u32, u32),
+    pub id: u32,  // ID of the scalar message to send through (e.g. the discriminant of the Enum on the caller's side API)
+    pub cid: xous::CID,   // caller-side connection ID for the scalar message to route to. Created by the caller before hooking.
+}
+
+/// Mouse buttons, as bits of the button mask given to `MouseButton`
+pub const MOUSE_BUTTON_LEFT: u8 = 0x1;
+pub const MOUSE_BUTTON_RIGHT: u8 = 0x2;
+pub const MOUSE_BUTTON_MIDDLE: u8 = 0x4;
+
+/// How the device identifies itself to the host, for `SetDeviceDescriptor`
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone)]
+pub struct DeviceDescriptor {
+    pub vid: u16,
+    pub pid: u16,
+    pub manufacturer: xous_ipc::String::<64>,
+    pub product: xous_ipc::String::<64>,
+    /// `None` keeps the serial number derived from the SoC's DNA
+    pub serial: Option<xous_ipc::String::<64>>,
+}
+/// How a `SetDeviceDescriptor` went
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum DescriptorResult {
+    /// not handled yet
+    Pending,
+    Ok,
+    /// the stacks can't be rebuilt again; the previous descriptor stays in effect
+    RebuildLimit,
+    /// one of the strings isn't UTF-8
+    InvalidString,
+}
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone)]
+pub(crate) struct DescriptorRequest {
+    pub descriptor: DeviceDescriptor,
+    /// filled in by the server
+    pub result: DescriptorResult,
+}
+
+/// Most bytes a `SerialWrite` or `SerialRead` moves in one message; the size of the serial
+/// port's own buffers
+pub const SERIAL_IPC_LEN: usize = 128;
+/// How a `SerialWrite` or `SerialRead` went
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone, Eq, PartialEq)]
+pub enum SerialResult {
+    /// not handled yet
+    Pending,
+    Ok,
+    /// the port's buffer is full because the host isn't reading; nothing was written
+    WouldBlock,
+    /// the serial port isn't enabled, or the host hasn't configured the device
+    NotConnected,
+}
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone)]
+pub struct SerialData {
+    pub data: [u8; SERIAL_IPC_LEN],
+    /// the bytes to write in `data`; on return, the number written or read
+    pub len: u32,
+    /// filled in by the server
+    pub result: SerialResult,
+}
+
+/// Most steps a keyboard macro can hold
+pub const MAX_MACRO_STEPS: usize = 64;
+/// Most keys a macro step can press at once, besides modifiers; the boot keyboard report's limit
+pub const MACRO_STEP_KEYS: usize = 6;
+/// One step of a keyboard macro: press the keys, hold them down for `hold_ms`, release them all,
+/// then wait `gap_ms` before the next step.
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone, Default, Eq, PartialEq)]
+pub struct MacroStep {
+    /// keyboard usage codes; a code of 0 marks an unused slot
+    pub codes: [u8; MACRO_STEP_KEYS],
+    /// modifier keys, in the layout of the modifier byte of a key report: bit 0 is Left Control,
+    /// on through bit 7 for Right GUI
+    pub modifier: u8,
+    pub hold_ms: u32,
+    pub gap_ms: u32,
+}
+/// How a keyboard macro ended
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone, Eq, PartialEq)]
+pub enum MacroOutcome {
+    /// not played yet
+    Pending,
+    /// every step was played
+    Completed,
+    /// stopped with `StopMacro` at the step with this index, which is also how many steps were
+    /// played in full
+    Aborted(u32),
+    /// the host deconfigured the device (or the keyboard view was switched away) at the step with
+    /// this index
+    Deconfigured(u32),
+    /// another macro was already playing, so this one wasn't started
+    Busy,
+}
+/// Most regions `DumpAllocations` reports; more than there are endpoint buffers to allocate
+pub const MAX_ALLOCATIONS: usize = 64;
+/// The regions allocated in the descriptor memory, in order of offset. Only the first `len` are
+/// valid; `total` is how many there were, in case that's more than fit.
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone)]
+pub struct AllocationList {
+    pub offsets: [u32; MAX_ALLOCATIONS],
+    pub lengths: [u32; MAX_ALLOCATIONS],
+    pub len: u32,
+    pub total: u32,
+}
+
+/// The steps of a keyboard macro. Only the first `len` are valid.
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone)]
+pub struct KeyMacro {
+    pub steps: [MacroStep; MAX_MACRO_STEPS],
+    pub len: u32,
+    /// filled in by the server when the macro is over
+    pub outcome: MacroOutcome,
+}
+
+/// Most entries a key remap table can hold
+pub const MAX_KEY_REMAPS: usize = 32;
+/// (from, to) pairs of keyboard usage codes, stored as parallel arrays. Only the first `len`
+/// entries are valid.
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone)]
+pub struct KeyRemapTable {
+    pub from: [u8; MAX_KEY_REMAPS],
+    pub to: [u8; MAX_KEY_REMAPS],
+    pub len: u32,
+}
+
 #[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Copy, Clone)]
 pub struct U2fMsgIpc {
     /// All U2F protocol messages are 64 bytes
@@ -63,6 +302,50 @@ pub enum U2fCode {
     Denied,
 }
 
+/// How a `RemoteWakeup` went
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WakeupResult {
+    /// Resume signaling went out on the bus
+    Sent = 0,
+    /// The link isn't suspended, so there's nothing to wake; nothing was done
+    NotSuspended = 1,
+    /// The host hasn't enabled remote wakeup on the device, so it mustn't signal; nothing was done
+    NotArmed = 2,
+    /// The USB core can't drive resume signaling
+    Unsupported = 3,
+}
+
+/// The most recent reason an enumeration attempt went wrong, as seen from the device side.
+/// A stall on EP0 is also how the stack refuses requests it doesn't support (e.g. a device
+/// qualifier query), so a stall on its own isn't necessarily fatal; it's most telling when
+/// the link is stuck short of `Configured`.
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EnumError {
+    /// Nothing has gone wrong since the server started
+    None = 0,
+    /// EP0 stalled before the host assigned an address, i.e. while fetching the device descriptor
+    StallOnGetDescriptor = 1,
+    /// EP0 stalled after the address was assigned, while fetching configuration or setting it
+    StallAfterAddress = 2,
+    /// The host repeatedly reset the device after talking to it, without ever assigning an address
+    SetAddressTimeout = 3,
+    /// A control transfer didn't fit in the EP0 buffer
+    ControlOverflow = 4,
+}
+
+/// HID interfaces that can be enabled on the composite (`FidoKbd`) view of the device core.
+/// The discriminants are bit positions, so a set of interfaces travels as a single scalar.
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HidInterface {
+    Keyboard = 0x1,
+    Mouse = 0x2,
+    Fido = 0x4,
+    /// A CDC-ACM serial port. Not HID, but enabled the same way.
+    Serial = 0x8,
+    /// Consumer control, for media keys such as volume and play/pause
+    Consumer = 0x10,
+}
+
 #[derive(Eq, PartialEq, Copy, Clone)]
 #[repr(usize)]
 pub enum UsbDeviceType {
@@ -0,0 +1,41 @@
+use embedded_time::Clock;
+use std::convert::TryInto;
+
+pub struct EmbeddedClock {
+    start: std::time::Instant,
+}
+impl EmbeddedClock {
+    pub fn new() -> EmbeddedClock {
+        EmbeddedClock { start: std::time::Instant::now() }
+    }
+    /// Milliseconds since the clock was created. This is carried as a u64 so it does not wrap
+    /// within any plausible uptime; it saturates rather than panics if it somehow overflows.
+    pub fn elapsed_ms(&self) -> u64 {
+        self.start.elapsed().as_millis().try_into().unwrap_or(u64::MAX)
+    }
+}
+
+impl Clock for EmbeddedClock {
+    type T = u64;
+    const SCALING_FACTOR: embedded_time::fraction::Fraction = <embedded_time::fraction::Fraction>::new(1, 1_000);
+
+    fn try_now(&self) -> Result<embedded_time::Instant<Self>, embedded_time::clock::Error> {
+        Ok(embedded_time::Instant::new(self.elapsed_ms()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_advances() {
+        let clock = EmbeddedClock::new();
+        let first = clock.try_now().unwrap();
+        let first_ms = clock.elapsed_ms();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        let second = clock.try_now().unwrap();
+        assert!(second > first);
+        assert!(clock.elapsed_ms() >= first_ms + 20);
+    }
+}
@@ -4,11 +4,28 @@ pub struct SpinalUdcDescriptor {}
 
 pub struct UdcEpStatus {}
 
+/// The address the simulated host assigns once the device core is connected
+pub const SIMULATED_DEVICE_ADDRESS: u8 = 7;
+
 pub struct SpinalUsbMgmt {
+    address: u8,
+    vbus: bool,
 }
 impl SpinalUsbMgmt {
     pub fn print_regs(&self) {}
-    pub fn connect_device_core(&mut self, _state: bool) {}
+    /// There's no bus in hosted mode: connecting the device core stands in for a whole enumeration,
+    /// and disconnecting it for a bus reset.
+    pub fn connect_device_core(&mut self, state: bool) {
+        self.address = if state { SIMULATED_DEVICE_ADDRESS } else { 0 };
+    }
+    pub fn device_address(&self) -> u8 {self.address}
+    /// Hosted mode behaves as if it were plugged in until told otherwise.
+    pub fn vbus_present(&self) -> bool {self.vbus}
+    pub fn set_simulated_vbus(&mut self, present: bool) {
+        self.vbus = present;
+    }
+    /// There's no host to wake in hosted mode.
+    pub fn signal_resume(&mut self) -> bool {false}
     pub fn is_device_connected(&self) -> bool {false}
     pub fn disable_debug(&mut self, _disable: bool) {}
     pub fn get_disable_debug(&self) -> bool {false}
@@ -30,7 +47,7 @@ impl SpinalUsbDevice {
         SpinalUsbDevice {}
     }
     pub fn get_iface(&self) -> SpinalUsbMgmt {
-        SpinalUsbMgmt {}
+        SpinalUsbMgmt { address: 0, vbus: true }
     }
     pub fn print_ep_stats(&self) {}
 
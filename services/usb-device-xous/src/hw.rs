@@ -1,7 +1,7 @@
 use usb_device::bus::PollResult;
 use utralib::generated::*;
 use crate::*;
-use core::{sync::atomic::{AtomicPtr, Ordering, AtomicUsize, AtomicU16}, mem::size_of};
+use core::{sync::atomic::{AtomicPtr, Ordering, AtomicUsize, AtomicU16, AtomicBool}, mem::size_of};
 use std::sync::{Arc, Mutex};
 use usb_device::{class_prelude::*, Result, UsbDirection};
 use std::collections::BTreeMap;
@@ -97,6 +97,18 @@ impl SpinalUsbMgmt {
             false
         }
     }
+    /// The address the host assigned with SetAddress, or 0 if it hasn't yet. The register also
+    /// carries the core's address-latch trigger bit, which is masked off here.
+    pub fn device_address(&self) -> u8 {
+        (self.regs.address() & 0x7F) as u8
+    }
+    /// Drives resume signaling (a K state, held for 1-15ms per the USB spec) onto the bus to wake
+    /// a suspended host. The SpinalHDL UDC in the current gateware has no control for this: its
+    /// config register only switches the pullup and interrupts. Until one is added, this does
+    /// nothing and returns `false`.
+    pub fn signal_resume(&mut self) -> bool {
+        false
+    }
     pub fn disable_debug(&mut self, disable: bool) {
         if disable {
             self.csr.wfo(utra::usbdev::USBDISABLE_USBDISABLE, 1);
@@ -200,6 +212,10 @@ pub struct SpinalUsbDevice {
     address: AtomicUsize,
     // bit vector to track if a read is allowed. This prevents a race condition between polled reads and interrupted reads.
     read_allowed: AtomicU16,
+    // enumeration diagnostics: whether a SETUP arrived since the last reset, and how many resets in a row
+    // came after SETUP traffic without an address ever being assigned
+    setup_seen: AtomicBool,
+    unaddressed_resets: AtomicUsize,
 }
 impl SpinalUsbDevice {
     pub fn new(sid: xous::SID) -> SpinalUsbDevice {
@@ -235,6 +251,8 @@ impl SpinalUsbDevice {
             tt: ticktimer_server::Ticktimer::new().unwrap(),
             address: AtomicUsize::new(0),
             read_allowed: AtomicU16::new(0),
+            setup_seen: AtomicBool::new(false),
+            unaddressed_resets: AtomicUsize::new(0),
         };
 
         xous::claim_interrupt(
@@ -269,6 +287,8 @@ impl SpinalUsbDevice {
             tt: ticktimer_server::Ticktimer::new().unwrap(),
             address: AtomicUsize::new(0),
             read_allowed: AtomicU16::new(0),
+            setup_seen: AtomicBool::new(false),
+            unaddressed_resets: AtomicUsize::new(0),
         }
     }
     pub fn get_iface(&self) -> SpinalUsbMgmt {
@@ -352,6 +372,11 @@ impl SpinalUsbDevice {
     pub fn dealloc_region(&mut self, offset: u32) -> bool {
         dealloc_inner(&mut self.view.allocs.lock().unwrap(), offset)
     }
+    /// Shared handle to the allocation list, so the region map can still be inspected after the
+    /// device has been handed off to a `UsbBusAllocator`.
+    pub(crate) fn alloc_tracker(&self) -> Arc::<Mutex::<BTreeMap<u32, u32>>> {
+        self.view.allocs.clone()
+    }
     pub(crate) fn descriptor_from_status(&self, ep_status: &UdcEpStatus) -> SpinalUdcDescriptor {
         SpinalUdcDescriptor::new(
             unsafe{ self.usb.as_mut_ptr().add(
@@ -558,6 +583,13 @@ impl UsbBus for SpinalUsbDevice {
     /// initialized as specified.
     fn reset(&self) {
         log::info!("USB reset");
+        // some hosts reset once after the first descriptor fetch as a matter of course, so only
+        // repeated resets without an address count as the host giving up on us.
+        if self.setup_seen.swap(false, Ordering::SeqCst) && self.address.load(Ordering::SeqCst) == 0 {
+            if self.unaddressed_resets.fetch_add(1, Ordering::SeqCst) + 1 >= 2 {
+                record_enum_error(EnumError::SetAddressTimeout);
+            }
+        }
         self.regs.set_address(0x0); // this does *not* require the trigger
         self.address.store(0, Ordering::SeqCst);
         self.ep0_out_reset();
@@ -640,6 +672,9 @@ impl UsbBus for SpinalUsbDevice {
     fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
         if let Some((head_offset, max_len)) = self.view.ep_allocs[ep_addr.index()] {
             if buf.len() > max_len {
+                if ep_addr.index() == 0 {
+                    record_enum_error(EnumError::ControlOverflow);
+                }
                 Err(UsbError::BufferOverflow)
             } else {
                 #[cfg(feature="mjolnir")] // mjolnir is so powerful, one must halt the USB core entirely for it to be weilded
@@ -764,8 +799,10 @@ impl UsbBus for SpinalUsbDevice {
                 // hard coded to 8 bytes in hardware
                 if buf.len() < 8 {
                     log::info!("ep0 read would overflow, aborting");
+                    record_enum_error(EnumError::ControlOverflow);
                     return Err(UsbError::BufferOverflow)
                 }
+                self.setup_seen.store(true, Ordering::SeqCst);
                 // setup data is in a special, fixed location
                 buf[..8].copy_from_slice(&self.get_setup());
                 log::debug!("ep0 read: {:x?}", &buf[..8]);
@@ -777,6 +814,7 @@ impl UsbBus for SpinalUsbDevice {
                     log::info!("USB address set to {} + trigger", buf[2]);
                     self.regs.set_address(0x200 | buf[2] as u32);
                     self.address.store(buf[2] as usize, Ordering::SeqCst);
+                    self.unaddressed_resets.store(0, Ordering::SeqCst);
                 }
                 Ok(8)
             } else {
@@ -906,6 +944,8 @@ impl UsbBus for SpinalUsbDevice {
         if statcheck.force_stall() != stalled {
             if ep_addr.index() != 0 {
                 log::info!("set_stalled ep{}->{} dir {:?}", ep_addr.index(), stalled, ep_addr.direction());
+            } else if stalled {
+                record_enum_error(ep0_stall_cause(self.address.load(Ordering::SeqCst)));
             }
             self.udc_hard_halt(ep_addr.index());
             let mut ep_status = self.status_read_volatile(ep_addr.index());
@@ -2,11 +2,12 @@
 
 pub mod api;
 pub use api::*;
-use xous::{CID, send_message, Message};
+use xous::{CID, send_message, Message, msg_scalar_unpack};
 use num_traits::*;
 pub use usb_device::device::UsbDeviceState;
 pub use usbd_human_interface_device::device::keyboard::KeyboardLedsReport;
 pub use usbd_human_interface_device::page::Keyboard as UsbKeyCode;
+pub use usbd_human_interface_device::page::Consumer as UsbConsumerCode;
 use packed_struct::PackedStruct;
 use xous_ipc::Buffer;
 pub use usbd_human_interface_device::device::fido::RawFidoMsg;
@@ -14,6 +15,8 @@ pub use usbd_human_interface_device::device::fido::RawFidoMsg;
 #[derive(Debug)]
 pub struct UsbHid {
     conn: CID,
+    link_sid: Option<xous::SID>,
+    led_sid: Option<xous::SID>,
 }
 impl UsbHid {
     pub fn new() -> Self {
@@ -21,7 +24,9 @@ impl UsbHid {
         REFCOUNT.fetch_add(1, Ordering::Relaxed);
         let conn = xns.request_connection_blocking(api::SERVER_NAME_USB_DEVICE).expect("Can't connect to USB device server");
         UsbHid {
-            conn
+            conn,
+            link_sid: None,
+            led_sid: None,
         }
     }
     /// used to query if the HID core was able to start. Mainly to handle edge cases between updates.
@@ -163,17 +168,31 @@ impl UsbHid {
             )
         ) {
             Ok(xous::Result::Scalar1(code)) => {
-                match code {
-                    0 => UsbDeviceState::Default,
-                    1 => UsbDeviceState::Addressed,
-                    2 => UsbDeviceState::Configured,
-                    3 => UsbDeviceState::Suspend,
-                    _ => panic!("Internal error: illegal status code")
-                }
+                link_state_from_scalar(code).expect("Internal error: illegal status code")
             }
             _ => panic!("Internal error: illegal return type"),
         }
     }
+    /// Has the scalar message `id` sent to `cid` each time the link status changes, with the new
+    /// state in arg1 and the old one in arg2; decode them with `link_state_from_scalar`. A cable
+    /// pull shows up as a change to `UsbDeviceState::Suspend`.
+    pub fn hook_link_status_callback(&mut self, id: u32, cid: CID) -> Result<(), xous::Error> {
+        if self.link_sid.is_none() {
+            let sid = xous::create_server().unwrap();
+            self.link_sid = Some(sid);
+            let sid_tuple = sid.to_u32();
+            xous::create_thread_4(event_cb_server, sid_tuple.0 as usize, sid_tuple.1 as usize, sid_tuple.2 as usize, sid_tuple.3 as usize).unwrap();
+            let hookdata = ScalarHook {
+                sid: sid_tuple,
+                id,
+                cid,
+            };
+            let buf = Buffer::into_buf(hookdata).or(Err(xous::Error::InternalError))?;
+            buf.lend(self.conn, Opcode::RegisterLinkStatusCallback.to_u32().unwrap()).map(|_|())
+        } else {
+            Err(xous::Error::MemoryInUse) // can't hook it twice
+        }
+    }
     /// Sends up to three keyboard codes at once as defined by USB HID usage tables;
     /// see See [Universal Serial Bus (USB) HID Usage Tables Version 1.12](<https://www.usb.org/sites/default/files/documents/hut1_12v2.pdf>):
     /// If the vector is empty, you get an all-key-up situation
@@ -201,6 +220,161 @@ impl UsbHid {
             _ => Err(xous::Error::UseBeforeInit),
         }
     }
+    /// Like `send_keycode`, but returns as soon as the codes are queued rather than once they've
+    /// been typed; the server sends them out as fast as the host polls for them. Fails with
+    /// `ServerQueueFull` if the queue is at its depth (see `set_key_queue_depth`), and the press
+    /// is dropped.
+    pub fn enqueue_keys(&self, code: Vec<UsbKeyCode>, auto_keyup: bool) -> Result<(), xous::Error> {
+        if code.len() > 3 {
+            log::warn!("Excess keycodes ignored");
+        }
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::EnqueueKeys.to_usize().unwrap(),
+                if code.len() >= 1 {code[0] as usize} else {0},
+                if code.len() >= 2 {code[1] as usize} else {0},
+                if code.len() >= 3 {code[2] as usize} else {0},
+                if auto_keyup { 1 } else { 0 }
+            )
+        ) {
+            Ok(xous::Result::Scalar1(code)) => {
+                match code {
+                    0 => Ok(()),
+                    2 => Err(xous::Error::ServerQueueFull),
+                    // indicates that we aren't connected to a host to send characters
+                    _ => Err(xous::Error::UseBeforeInit),
+                }
+            }
+            _ => Err(xous::Error::UseBeforeInit),
+        }
+    }
+    /// Sets how many key presses `enqueue_keys` holds waiting to go out before it refuses more
+    pub fn set_key_queue_depth(&self, depth: usize) -> Result<(), xous::Error> {
+        send_message(
+            self.conn,
+            Message::new_scalar(
+                Opcode::SetKeyQueueDepth.to_usize().unwrap(),
+                depth,
+                0, 0, 0
+            )
+        ).map(|_| ())
+    }
+    /// Moves the mouse pointer by `dx`, `dy`, with whatever buttons `mouse_buttons` last set
+    /// still held down, so a move while a button is down drags. Positive `dy` is down.
+    pub fn mouse_move(&self, dx: i8, dy: i8) -> Result<(), xous::Error> {
+        self.send_mouse(Opcode::MouseMove, dx as u8 as usize, dy as u8 as usize)
+    }
+    /// Presses the buttons set in `buttons` (a mask of `MOUSE_BUTTON_*`) and releases the rest.
+    pub fn mouse_buttons(&self, buttons: u8) -> Result<(), xous::Error> {
+        self.send_mouse(Opcode::MouseButton, buttons as usize, 0)
+    }
+    /// Presses and releases `buttons`.
+    pub fn mouse_click(&self, buttons: u8) -> Result<(), xous::Error> {
+        self.mouse_buttons(buttons)?;
+        self.mouse_buttons(0)
+    }
+    fn send_mouse(&self, opcode: Opcode, arg1: usize, arg2: usize) -> Result<(), xous::Error> {
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                opcode.to_usize().unwrap(),
+                arg1, arg2, 0, 0
+            )
+        ) {
+            Ok(xous::Result::Scalar1(0)) => Ok(()),
+            // not connected to a host, or the mouse interface isn't enabled
+            _ => Err(xous::Error::UseBeforeInit),
+        }
+    }
+    /// Sends a consumer control (media key) code, e.g. `UsbConsumerCode::VolumeIncrement`. As with
+    /// `send_keycode`, `auto_keyup` releases it after the auto-up delay; otherwise it stays down
+    /// until `UsbConsumerCode::Unassigned` is sent.
+    pub fn send_consumer_code(&self, code: UsbConsumerCode, auto_keyup: bool) -> Result<(), xous::Error> {
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::SendConsumerCode.to_usize().unwrap(),
+                code as u16 as usize,
+                if auto_keyup { 1 } else { 0 },
+                0, 0
+            )
+        ) {
+            Ok(xous::Result::Scalar1(0)) => Ok(()),
+            // not connected to a host, or the consumer control interface isn't enabled
+            _ => Err(xous::Error::UseBeforeInit),
+        }
+    }
+    /// Sets how long `send_keycode` holds keys down before releasing them when `auto_keyup`
+    /// is set. Slow hosts may miss short keypresses, and long ones may register twice. The default
+    /// is 30ms, as before the delay was configurable, and the server clamps the value to 5..=100ms.
+    pub fn set_autoup_delay(&self, ms: u32) -> Result<(), xous::Error> {
+        send_message(
+            self.conn,
+            Message::new_scalar(
+                Opcode::SetAutoUpDelay.to_usize().unwrap(),
+                ms as usize,
+                0, 0, 0
+            )
+        ).map(|_| ())
+    }
+    /// While `enable` is set, `send_str` paces its keystrokes against a fixed schedule and keeps
+    /// the USB server runnable between them, so competing activity on the system shows up less as
+    /// uneven typing. This burns CPU for the length of each string; Xous has no thread priorities,
+    /// so this is the closest the server can come to a priority boost.
+    pub fn set_burst_priority(&self, enable: bool) -> Result<(), xous::Error> {
+        send_message(
+            self.conn,
+            Message::new_scalar(
+                Opcode::SetBurstPriority.to_usize().unwrap(),
+                if enable { 1 } else { 0 },
+                0, 0, 0
+            )
+        ).map(|_| ())
+    }
+    /// With `Some`, `send_str` types runs of the same character as a single keypress held down
+    /// for the host's key repeat to fill in, which is much quicker for long runs. `typematic` has
+    /// to match the host's repeat delay and rate, or runs come out too long or too short, so the
+    /// default is `None`: every character pressed and released on its own.
+    pub fn set_type_repeat_mode(&self, typematic: Option<TypeRepeat>) -> Result<(), xous::Error> {
+        let (delay_ms, interval_ms) = typematic.map_or((0, 0), |t| (t.delay_ms as usize, t.interval_ms as usize));
+        send_message(
+            self.conn,
+            Message::new_scalar(
+                Opcode::SetTypeRepeatMode.to_usize().unwrap(),
+                delay_ms, interval_ms, 0, 0
+            )
+        ).map(|_| ())
+    }
+    /// Substitutes `to` for `from` in every key report sent from now on, replacing any previous
+    /// remap table. Intended for fixing up non-standard hardware, e.g. swapping Ctrl and Caps Lock
+    /// takes two entries. At most `MAX_KEY_REMAPS` pairs are accepted.
+    pub fn set_key_remap(&self, pairs: &[(UsbKeyCode, UsbKeyCode)]) -> Result<(), xous::Error> {
+        if pairs.len() > MAX_KEY_REMAPS {
+            return Err(xous::Error::OutOfMemory);
+        }
+        let mut table = KeyRemapTable {
+            from: [0; MAX_KEY_REMAPS],
+            to: [0; MAX_KEY_REMAPS],
+            len: pairs.len() as u32,
+        };
+        for (i, &(from, to)) in pairs.iter().enumerate() {
+            table.from[i] = from as u8;
+            table.to[i] = to as u8;
+        }
+        let buf = Buffer::into_buf(table).or(Err(xous::Error::InternalError))?;
+        buf.lend(self.conn, Opcode::SetKeyRemap.to_u32().unwrap()).map(|_| ())
+    }
+    /// Stops remapping key reports; codes are sent exactly as given.
+    pub fn clear_key_remap(&self) -> Result<(), xous::Error> {
+        send_message(
+            self.conn,
+            Message::new_scalar(
+                Opcode::ClearKeyRemap.to_usize().unwrap(),
+                0, 0, 0, 0
+            )
+        ).map(|_| ())
+    }
     pub fn send_str(&self, s: &str) -> Result<usize, xous::Error> {
         let serializer = UsbString {
             s: xous_ipc::String::<4000>::from_str(s),
@@ -215,6 +389,82 @@ impl UsbHid {
             None => Err(xous::Error::UseBeforeInit),
         }
     }
+    /// Plays `steps` back as a keyboard macro, with each step's hold and gap timed by the server.
+    /// Blocks until the macro is over, which is early if `stop_macro` is called from another
+    /// thread or the host stops listening. Only one macro plays at a time; at most
+    /// `MAX_MACRO_STEPS` steps are accepted.
+    pub fn play_macro(&self, steps: &[MacroStep]) -> Result<MacroOutcome, xous::Error> {
+        if steps.len() > MAX_MACRO_STEPS {
+            return Err(xous::Error::OutOfMemory);
+        }
+        let mut key_macro = KeyMacro {
+            steps: [MacroStep::default(); MAX_MACRO_STEPS],
+            len: steps.len() as u32,
+            outcome: MacroOutcome::Pending,
+        };
+        key_macro.steps[..steps.len()].copy_from_slice(steps);
+        let mut buf = Buffer::into_buf(key_macro).or(Err(xous::Error::InternalError))?;
+        buf.lend_mut(self.conn, Opcode::PlayMacro.to_u32().unwrap()).or(Err(xous::Error::InternalError))?;
+        let returned = buf.to_original::<KeyMacro, _>().or(Err(xous::Error::InternalError))?;
+        match returned.outcome {
+            MacroOutcome::Pending => Err(xous::Error::InternalError),
+            outcome => Ok(outcome),
+        }
+    }
+    /// Stops the macro being played, if any, after releasing whatever keys it holds down.
+    pub fn stop_macro(&self) -> Result<(), xous::Error> {
+        send_message(
+            self.conn,
+            Message::new_scalar(
+                Opcode::StopMacro.to_usize().unwrap(),
+                0, 0, 0, 0
+            )
+        ).map(|_| ())
+    }
+    /// Queues up to `SERIAL_IPC_LEN` bytes of `data` to go to the host on the serial port, and
+    /// returns how many were taken. If the host isn't reading the port and none could be taken,
+    /// this fails with `ServerQueueFull` rather than dropping them; try again later. Fails with
+    /// `UseBeforeInit` if the serial interface isn't enabled or the host hasn't configured the device.
+    pub fn serial_write(&self, data: &[u8]) -> Result<usize, xous::Error> {
+        let len = data.len().min(SERIAL_IPC_LEN);
+        let mut serial_data = SerialData {
+            data: [0; SERIAL_IPC_LEN],
+            len: len as u32,
+            result: SerialResult::Pending,
+        };
+        serial_data.data[..len].copy_from_slice(&data[..len]);
+        let returned = self.serial_transfer(Opcode::SerialWrite, serial_data)?;
+        Ok(returned.len as usize)
+    }
+    /// Takes up to `buf.len()` bytes sent by the host on the serial port, at most `SERIAL_IPC_LEN`
+    /// at a time, and returns how many. Returns 0 if nothing is waiting.
+    pub fn serial_read(&self, buf: &mut [u8]) -> Result<usize, xous::Error> {
+        let serial_data = SerialData {
+            data: [0; SERIAL_IPC_LEN],
+            len: 0,
+            result: SerialResult::Pending,
+        };
+        match self.serial_transfer(Opcode::SerialRead, serial_data) {
+            Ok(returned) => {
+                let len = (returned.len as usize).min(buf.len());
+                buf[..len].copy_from_slice(&returned.data[..len]);
+                Ok(len)
+            }
+            Err(xous::Error::ServerQueueFull) => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+    fn serial_transfer(&self, opcode: Opcode, serial_data: SerialData) -> Result<SerialData, xous::Error> {
+        let mut buf = Buffer::into_buf(serial_data).or(Err(xous::Error::InternalError))?;
+        buf.lend_mut(self.conn, opcode.to_u32().unwrap()).or(Err(xous::Error::InternalError))?;
+        let returned = buf.to_original::<SerialData, _>().or(Err(xous::Error::InternalError))?;
+        match returned.result {
+            SerialResult::Ok => Ok(returned),
+            SerialResult::WouldBlock => Err(xous::Error::ServerQueueFull),
+            SerialResult::NotConnected => Err(xous::Error::UseBeforeInit),
+            SerialResult::Pending => Err(xous::Error::InternalError),
+        }
+    }
     pub fn get_led_state(&self) -> Result<KeyboardLedsReport, xous::Error> {
         match send_message(
             self.conn,
@@ -232,6 +482,306 @@ impl UsbHid {
             _ => panic!("Internal error: illegal return type"),
         }
     }
+    /// Has the scalar message `id` sent to `cid` each time the host changes the keyboard LEDs, with
+    /// the new state in arg1 and the old one in arg2, each a packed `KeyboardLedsReport` byte.
+    /// `get_led_state` keeps working alongside it.
+    pub fn hook_led_callback(&mut self, id: u32, cid: CID) -> Result<(), xous::Error> {
+        if self.led_sid.is_none() {
+            let sid = xous::create_server().unwrap();
+            self.led_sid = Some(sid);
+            let sid_tuple = sid.to_u32();
+            xous::create_thread_4(event_cb_server, sid_tuple.0 as usize, sid_tuple.1 as usize, sid_tuple.2 as usize, sid_tuple.3 as usize).unwrap();
+            let hookdata = ScalarHook {
+                sid: sid_tuple,
+                id,
+                cid,
+            };
+            let buf = Buffer::into_buf(hookdata).or(Err(xous::Error::InternalError))?;
+            buf.lend(self.conn, Opcode::RegisterLedCallback.to_u32().unwrap()).map(|_|())
+        } else {
+            Err(xous::Error::MemoryInUse) // can't hook it twice
+        }
+    }
+    /// Blocks until the host changes the keyboard LEDs (e.g. Caps Lock is toggled), and returns
+    /// the new state, or `None` if `timeout_ms` passes first. A timeout of 0 waits indefinitely.
+    pub fn wait_led_change(&self, timeout_ms: u32) -> Result<Option<KeyboardLedsReport>, xous::Error> {
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::WaitLedChange.to_usize().unwrap(),
+                timeout_ms as usize, 0, 0, 0
+            )
+        ) {
+            Ok(xous::Result::Scalar2(code, 0)) => {
+                match KeyboardLedsReport::unpack(&[code as u8]) {
+                    Ok(r) => Ok(Some(r)),
+                    Err(_) => Err(xous::Error::InternalError),
+                }
+            }
+            Ok(xous::Result::Scalar2(_, _)) => Ok(None),
+            _ => Err(xous::Error::InternalError),
+        }
+    }
+    /// Hands the service `report` as if the host had just sent it as a keyboard LED
+    /// (output) report. Only available in hosted mode, where there is no host.
+    #[cfg(not(target_os = "xous"))]
+    pub fn inject_led_report(&self, report: KeyboardLedsReport) -> Result<(), xous::Error> {
+        let code = report.pack().or(Err(xous::Error::InternalError))?;
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::InjectLedReport.to_usize().unwrap(),
+                code[0] as usize, 0, 0, 0
+            )
+        ) {
+            Ok(xous::Result::Scalar1(1)) => Ok(()),
+            _ => Err(xous::Error::InternalError),
+        }
+    }
+    /// Returns the device-side millisecond clock that drives HID report timing. This is
+    /// useful for correlating host-observed report timing with what the device thinks it did.
+    pub fn get_clock_millis(&self) -> Result<u64, xous::Error> {
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::GetClockMillis.to_usize().unwrap(),
+                0, 0, 0, 0
+            )
+        ) {
+            Ok(xous::Result::Scalar2(lsw, msw)) => Ok(lsw as u64 | (msw as u64) << 32),
+            _ => Err(xous::Error::InternalError),
+        }
+    }
+    /// Mirror the host's Caps Lock state onto GPIO `pin` (0-7), or stop doing so with `None`.
+    /// Only the follower's own pin is driven; the other GPIO pins keep their direction and output.
+    pub fn set_caps_follower(&self, pin: Option<u8>) -> Result<(), xous::Error> {
+        let (pin, enable) = match pin {
+            Some(pin) => (pin as usize, 1),
+            None => (0, 0),
+        };
+        send_message(
+            self.conn,
+            Message::new_scalar(
+                Opcode::SetCapsFollower.to_usize().unwrap(),
+                pin, enable, 0, 0
+            )
+        ).map(|_| ())
+    }
+    /// Chooses the HID interfaces exposed when the `FidoKbd` core is selected, and re-enumerates if
+    /// the set changed. Fails with `InvalidLimit` if the set is empty, or if its endpoints don't fit
+    /// in the device core's descriptor memory; the previous set stays in effect in that case.
+    ///
+    /// Every change rebuilds the USB stacks, and the server only allows a handful of rebuilds
+    /// (shared with `set_ep0_max_packet_size` and `set_device_descriptor`) before it refuses them,
+    /// also with `InvalidLimit`. These are meant to be set once, at provisioning, not toggled.
+    pub fn set_enabled_interfaces(&self, interfaces: &[HidInterface]) -> Result<(), xous::Error> {
+        let bits = interfaces.iter().fold(0, |acc, &iface| acc | iface as usize);
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::SetEnabledInterfaces.to_usize().unwrap(),
+                bits, 0, 0, 0
+            )
+        ) {
+            Ok(xous::Result::Scalar1(1)) => Ok(()),
+            Ok(xous::Result::Scalar1(_)) => Err(xous::Error::InvalidLimit),
+            _ => Err(xous::Error::InternalError),
+        }
+    }
+    /// Sets bMaxPacketSize0, the EP0 max packet size advertised in the device descriptor, and
+    /// re-enumerates if it changed. Only 8, 16, 32 and 64 are valid for a full-speed device.
+    /// Some hosts are picky about this value; the default is 8. Like `set_enabled_interfaces`,
+    /// a change counts against the server's rebuild budget, and fails with `InvalidLimit` once
+    /// that is spent.
+    pub fn set_ep0_max_packet_size(&self, size: u8) -> Result<(), xous::Error> {
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::SetEp0MaxPacketSize.to_usize().unwrap(),
+                size as usize, 0, 0, 0
+            )
+        ) {
+            Ok(xous::Result::Scalar1(1)) => Ok(()),
+            Ok(xous::Result::Scalar1(_)) => Err(xous::Error::InvalidLimit),
+            _ => Err(xous::Error::InternalError),
+        }
+    }
+    /// Replaces the VID/PID and strings the device identifies itself with, for firmware that
+    /// ships as something other than a Precursor. A `serial` of `None` keeps the serial number
+    /// derived from the SoC's DNA. Strings are limited to 64 bytes. The server holds the setting
+    /// until it restarts. To keep it across reboots, also store it with `userprefs`'
+    /// `set_usb_device_descriptor`: the status service sets it again once the PDDB is mounted,
+    /// and the device comes up as a Precursor until then.
+    ///
+    /// The host only reads these when it enumerates the device, so if the device core is connected,
+    /// it's disconnected and reconnected for the change to take effect. This drops anything in flight.
+    /// A change counts against the same rebuild budget as `set_enabled_interfaces`, and fails with
+    /// `InvalidLimit` once that is spent; the previous descriptor stays in effect in that case.
+    pub fn set_device_descriptor(
+        &self,
+        vid: u16,
+        pid: u16,
+        manufacturer: &str,
+        product: &str,
+        serial: Option<&str>,
+    ) -> Result<(), xous::Error> {
+        let too_long = |s: &str| s.len() > 64;
+        if too_long(manufacturer) || too_long(product) || serial.map_or(false, too_long) {
+            return Err(xous::Error::InvalidString);
+        }
+        let request = DescriptorRequest {
+            descriptor: DeviceDescriptor {
+                vid,
+                pid,
+                manufacturer: xous_ipc::String::<64>::from_str(manufacturer),
+                product: xous_ipc::String::<64>::from_str(product),
+                serial: serial.map(|s| xous_ipc::String::<64>::from_str(s)),
+            },
+            result: DescriptorResult::Pending,
+        };
+        let mut buf = Buffer::into_buf(request).or(Err(xous::Error::InternalError))?;
+        buf.lend_mut(self.conn, Opcode::SetDeviceDescriptor.to_u32().unwrap()).or(Err(xous::Error::InternalError))?;
+        let returned = buf.to_original::<DescriptorRequest, _>().or(Err(xous::Error::InternalError))?;
+        match returned.result {
+            DescriptorResult::Ok => Ok(()),
+            DescriptorResult::RebuildLimit => Err(xous::Error::InvalidLimit),
+            DescriptorResult::InvalidString => Err(xous::Error::InvalidString),
+            DescriptorResult::Pending => Err(xous::Error::InternalError),
+        }
+    }
+    pub fn get_ep0_max_packet_size(&self) -> Result<u8, xous::Error> {
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::GetEp0MaxPacketSize.to_usize().unwrap(),
+                0, 0, 0, 0
+            )
+        ) {
+            Ok(xous::Result::Scalar1(size)) => Ok(size as u8),
+            _ => Err(xous::Error::InternalError),
+        }
+    }
+    /// Returns the most recent problem the device saw while the host was enumerating it. Useful
+    /// for diagnosing hosts that leave the link stuck short of `Configured`.
+    pub fn get_last_enum_error(&self) -> Result<EnumError, xous::Error> {
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::GetLastEnumError.to_usize().unwrap(),
+                0, 0, 0, 0
+            )
+        ) {
+            Ok(xous::Result::Scalar1(code)) => FromPrimitive::from_usize(code).ok_or(xous::Error::InternalError),
+            _ => Err(xous::Error::InternalError),
+        }
+    }
+    /// Asks a suspended host to wake up, e.g. on a keypress. This is only allowed if the host
+    /// enabled remote wakeup on the device; anything but `WakeupResult::Sent` means nothing went
+    /// out on the bus. Note that the current gateware can't drive resume signaling, so the device
+    /// doesn't offer remote wakeup to the host yet, and this reports `NotArmed` when suspended.
+    pub fn remote_wakeup(&self) -> Result<WakeupResult, xous::Error> {
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::RemoteWakeup.to_usize().unwrap(),
+                0, 0, 0, 0
+            )
+        ) {
+            Ok(xous::Result::Scalar1(code)) => FromPrimitive::from_usize(code).ok_or(xous::Error::InternalError),
+            _ => Err(xous::Error::InternalError),
+        }
+    }
+    /// Returns the address the host assigned to the device during enumeration, or 0 if it
+    /// hasn't assigned one yet. Useful for telling apart an enumeration that fails at SetAddress
+    /// from one that gets an address and then fails to configure.
+    pub fn get_device_address(&self) -> Result<u8, xous::Error> {
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::GetDeviceAddress.to_usize().unwrap(),
+                0, 0, 0, 0
+            )
+        ) {
+            Ok(xous::Result::Scalar1(address)) => Ok(address as u8),
+            _ => Err(xous::Error::InternalError),
+        }
+    }
+    /// Returns `true` if VBUS is present, i.e. the device is plugged into a powered port. This
+    /// doesn't depend on the host having enumerated the device.
+    pub fn get_vbus_state(&self) -> Result<bool, xous::Error> {
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::GetVbusState.to_usize().unwrap(),
+                0, 0, 0, 0
+            )
+        ) {
+            Ok(xous::Result::Scalar1(present)) => Ok(present != 0),
+            _ => Err(xous::Error::InternalError),
+        }
+    }
+    /// Sets the VBUS state reported by the hosted backend, for exercising power management
+    /// code off-target. Ignored on hardware.
+    pub fn set_simulated_vbus(&self, present: bool) -> Result<(), xous::Error> {
+        send_message(
+            self.conn,
+            Message::new_scalar(
+                Opcode::SetSimulatedVbus.to_usize().unwrap(),
+                if present { 1 } else { 0 }, 0, 0, 0
+            )
+        ).map(|_| ())
+    }
+    /// Checks whether an endpoint buffer of `bytes` (rounded up to the core's 16-byte alignment)
+    /// could still be allocated in the descriptor memory, without allocating it. Returns the
+    /// offset the buffer would land at, or `None` if the memory is exhausted.
+    pub fn can_allocate(&self, bytes: u32) -> Result<Option<u32>, xous::Error> {
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::CanAllocate.to_usize().unwrap(),
+                bytes as usize, 0, 0, 0
+            )
+        ) {
+            Ok(xous::Result::Scalar2(ok, offset)) => Ok(if ok != 0 { Some(offset as u32) } else { None }),
+            _ => Err(xous::Error::InternalError),
+        }
+    }
+    /// Releases the region at `offset` in the descriptor memory. The offset must be one the
+    /// allocator handed out, as listed by `dump_allocations`. Only regions that aren't in use can
+    /// be freed: the endpoint buffers of the interfaces currently built, and the control pipe,
+    /// are refused with `AccessDenied`, and are instead released all together when the interfaces
+    /// are rebuilt (e.g. by `set_enabled_interfaces`). Returns `false` if nothing was allocated at
+    /// `offset`.
+    pub fn free_endpoint(&self, offset: u32) -> Result<bool, xous::Error> {
+        match send_message(
+            self.conn,
+            Message::new_blocking_scalar(
+                Opcode::FreeEndpoint.to_usize().unwrap(),
+                offset as usize, 0, 0, 0
+            )
+        ) {
+            Ok(xous::Result::Scalar1(2)) => Err(xous::Error::AccessDenied),
+            Ok(xous::Result::Scalar1(freed)) => Ok(freed != 0),
+            _ => Err(xous::Error::InternalError),
+        }
+    }
+    /// Lists the regions allocated in the descriptor memory as (offset, length) pairs, in order
+    /// of offset. Lengths include the descriptor that heads each buffer.
+    pub fn dump_allocations(&self) -> Result<Vec<(u32, u32)>, xous::Error> {
+        let list = AllocationList {
+            offsets: [0; MAX_ALLOCATIONS],
+            lengths: [0; MAX_ALLOCATIONS],
+            len: 0,
+            total: 0,
+        };
+        let mut buf = Buffer::into_buf(list).or(Err(xous::Error::InternalError))?;
+        buf.lend_mut(self.conn, Opcode::DumpAllocations.to_u32().unwrap()).or(Err(xous::Error::InternalError))?;
+        let list = buf.to_original::<AllocationList, _>().or(Err(xous::Error::InternalError))?;
+        if list.total > list.len {
+            log::warn!("{} of {} allocations not listed", list.total - list.len, list.total);
+        }
+        Ok(list.offsets.iter().zip(list.lengths.iter()).take(list.len as usize).map(|(&o, &l)| (o, l)).collect())
+    }
     pub fn u2f_wait_incoming(&self) -> Result<RawFidoMsg, xous::Error> {
         let req = U2fMsgIpc {
             data: [0; 64],
@@ -271,8 +821,53 @@ impl UsbHid {
 
 use core::sync::atomic::{AtomicU32, Ordering};
 static REFCOUNT: AtomicU32 = AtomicU32::new(0);
+/// Turns a link status, as returned by `LinkStatus` or passed to a link status callback, back
+/// into a `UsbDeviceState`
+pub fn link_state_from_scalar(code: usize) -> Option<UsbDeviceState> {
+    match code {
+        0 => Some(UsbDeviceState::Default),
+        1 => Some(UsbDeviceState::Addressed),
+        2 => Some(UsbDeviceState::Configured),
+        3 => Some(UsbDeviceState::Suspend),
+        _ => None,
+    }
+}
+
+/// handles callback messages that indicate the link status or LED state changed, in the library user's process space.
+fn event_cb_server(sid0: usize, sid1: usize, sid2: usize, sid3: usize) {
+    let sid = xous::SID::from_u32(sid0 as u32, sid1 as u32, sid2 as u32, sid3 as u32);
+    loop {
+        let msg = xous::receive_message(sid).unwrap();
+        match FromPrimitive::from_usize(msg.body.id()) {
+            Some(EventCallback::Event) => msg_scalar_unpack!(msg, cid, id, new, old, {
+                // pass the new and old states on to the CID with the ID memorized in the original hook
+                send_message(cid as u32,
+                    Message::new_scalar(id, new, old, 0, 0)
+                ).unwrap();
+            }),
+            Some(EventCallback::Drop) => {
+                break; // this exits the loop and kills the thread
+            }
+            None => (),
+        }
+    }
+    xous::destroy_server(sid).unwrap();
+}
+
+fn drop_conn(sid: xous::SID) {
+    let cid = xous::connect(sid).unwrap();
+    xous::send_message(cid,
+        Message::new_scalar(EventCallback::Drop.to_usize().unwrap(), 0, 0, 0, 0)).unwrap();
+    unsafe{xous::disconnect(cid).unwrap();}
+}
 impl Drop for UsbHid {
     fn drop(&mut self) {
+        if let Some(sid) = self.link_sid.take() {
+            drop_conn(sid);
+        }
+        if let Some(sid) = self.led_sid.take() {
+            drop_conn(sid);
+        }
         if REFCOUNT.fetch_sub(1, Ordering::Relaxed) == 1 {
             unsafe{xous::disconnect(self.conn).unwrap();}
         }
@@ -8,6 +8,8 @@ mod main_hosted;
 
 mod api;
 mod mappings;
+mod clock;
+use clock::*;
 
 use api::*;
 #[cfg(any(feature="precursor", feature="renode"))]
@@ -16,7 +18,6 @@ mod hw;
 use hw::*;
 #[cfg(any(feature="precursor", feature="renode"))]
 mod spinal_udc;
-#[cfg(any(feature="precursor", feature="renode"))]
 use packed_struct::PackedStructSlice;
 #[cfg(any(feature="precursor", feature="renode"))]
 use spinal_udc::*;
@@ -26,9 +27,22 @@ mod hosted;
 #[cfg(not(target_os = "xous"))]
 use hosted::*;
 
-use std::collections::BTreeMap;
-#[cfg(any(feature="precursor", feature="renode"))]
+use std::collections::{BTreeMap, VecDeque};
 use num_traits::*;
+use usb_device::prelude::*;
+use usb_device::class_prelude::*;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use usb_device_xous::KeyboardLedsReport;
+use usbd_human_interface_device::prelude::*;
+use usbd_human_interface_device::device::keyboard::NKROBootKeyboardInterface;
+use usbd_human_interface_device::device::mouse::{BootMouseInterface, BootMouseReport};
+use usbd_human_interface_device::device::fido::RawFidoInterface;
+use usbd_human_interface_device::device::consumer::{ConsumerControlInterface, MultipleConsumerReport};
+use usbd_human_interface_device::page::Keyboard;
+use usbd_human_interface_device::page::Consumer;
+use usbd_serial::SerialPort;
+use num_enum::FromPrimitive as EnumFromPrimitive;
 
 fn main() -> ! {
     #[cfg(any(feature="precursor", feature="renode"))]
@@ -37,6 +51,861 @@ fn main() -> ! {
     main_hosted::main_hosted();
 }
 
+/// Default time keys are held down before an auto-up release, in ms
+pub(crate) const AUTOUP_DELAY_DEFAULT_MS: usize = 30;
+pub(crate) const AUTOUP_DELAY_MIN_MS: usize = 5;
+pub(crate) const AUTOUP_DELAY_MAX_MS: usize = 100;
+/// Bound a requested auto-up delay to something a host can plausibly work with
+pub(crate) fn clamp_autoup_delay(ms: usize) -> usize {
+    ms.max(AUTOUP_DELAY_MIN_MS).min(AUTOUP_DELAY_MAX_MS)
+}
+/// The auto-up delay as set by `SetAutoUpDelay`, and the pacing of the `SendKeyCode` keystrokes
+/// that it controls
+pub(crate) struct AutoUp {
+    delay_ms: usize,
+}
+impl AutoUp {
+    pub fn new() -> Self {
+        AutoUp { delay_ms: AUTOUP_DELAY_DEFAULT_MS }
+    }
+    /// Handles `SetAutoUpDelay`, returning the delay actually in force after clamping
+    pub fn set_delay(&mut self, ms: usize) -> usize {
+        self.delay_ms = clamp_autoup_delay(ms);
+        self.delay_ms
+    }
+    pub fn delay_ms(&self) -> usize {
+        self.delay_ms
+    }
+    /// Plays one `SendKeyCode`: the key-down `report`, held for the delay, then with `autoup` the
+    /// key-up and the delay again, so that back-to-back keystrokes come out evenly spaced.
+    pub fn keystroke(&self, codes: &[Keyboard], autoup: bool, mut report: impl FnMut(&[Keyboard]), mut sleep: impl FnMut(usize)) {
+        report(codes);
+        sleep(self.delay_ms);
+        if autoup {
+            report(&[]);
+            sleep(self.delay_ms);
+        }
+    }
+}
+
+/// Last `EnumError` seen by the bus implementation, stored as its discriminant so that it can be
+/// updated from the poll path without threading state through the `usb-device` stack.
+static LAST_ENUM_ERROR: AtomicUsize = AtomicUsize::new(0);
+#[cfg_attr(not(any(feature="precursor", feature="renode")), allow(dead_code))]
+pub(crate) fn record_enum_error(err: EnumError) {
+    log::warn!("enumeration error: {:?}", err);
+    LAST_ENUM_ERROR.store(err.to_usize().unwrap(), Ordering::SeqCst);
+}
+pub(crate) fn last_enum_error() -> EnumError {
+    FromPrimitive::from_usize(LAST_ENUM_ERROR.load(Ordering::SeqCst)).unwrap_or(EnumError::None)
+}
+/// Until the host assigns an address, the only request it can have made is for the device descriptor
+#[cfg_attr(not(any(feature="precursor", feature="renode")), allow(dead_code))]
+pub(crate) fn ep0_stall_cause(address: usize) -> EnumError {
+    if address == 0 {
+        EnumError::StallOnGetDescriptor
+    } else {
+        EnumError::StallAfterAddress
+    }
+}
+
+/// Number of GPIO pins the caps lock follower can drive
+pub(crate) const CAPS_FOLLOWER_PINS: u8 = 8;
+/// Mirrors the host's Caps Lock state onto a GPIO pin, so an indicator can follow it without
+/// any client having to watch the LED state. Only the follower's own pin is driven; the rest of
+/// the port is left to whoever else uses it.
+pub(crate) struct CapsFollower {
+    pin: Option<u8>,
+    last: Option<bool>,
+}
+impl CapsFollower {
+    pub fn new() -> Self {
+        CapsFollower { pin: None, last: None }
+    }
+    /// Select the pin to drive, or `None` to stop following. Returns `false` for pins that don't exist.
+    pub fn set_pin(&mut self, pin: Option<u8>) -> bool {
+        if pin.map_or(false, |p| p >= CAPS_FOLLOWER_PINS) {
+            return false;
+        }
+        self.pin = pin;
+        // force the next report through, so the pin picks up the current state
+        self.last = None;
+        true
+    }
+    pub fn pin(&self) -> Option<u8> {
+        self.pin
+    }
+    /// Call with the Caps Lock bit of every LED report from the host. Returns the GPIO output
+    /// value and the mask of the one bit it applies to, if the follower is enabled and Caps Lock
+    /// changed; the other pins belong to whoever else drives the port.
+    pub fn update(&mut self, caps_lock: bool) -> Option<(u8, u8)> {
+        let pin = self.pin?;
+        if self.last == Some(caps_lock) {
+            return None;
+        }
+        self.last = Some(caps_lock);
+        Some((if caps_lock { 1 << pin } else { 0 }, 1 << pin))
+    }
+}
+
+/// Handle an LED (output) report from the host: record it as the current LED state, and pass
+/// it on to the caps lock follower. Returns the GPIO output value and mask to apply, if any.
+pub(crate) fn apply_led_report(
+    report: KeyboardLedsReport,
+    led_state: &mut KeyboardLedsReport,
+    caps_follower: &mut CapsFollower
+) -> Option<(u8, u8)> {
+    log::info!("keyboard LEDs: {:?}", report);
+    let dout = caps_follower.update(report.caps_lock);
+    *led_state = report;
+    dout
+}
+/// Callers blocked in `WaitLedChange`. Each gets a serial number, so that its timeout can find
+/// it again if the LEDs don't change first.
+pub(crate) struct LedWaiters<T> {
+    waiters: Vec<(usize, T)>,
+    next_serial: usize,
+}
+impl<T> LedWaiters<T> {
+    pub fn new() -> Self {
+        LedWaiters { waiters: Vec::new(), next_serial: 0 }
+    }
+    pub fn park(&mut self, waiter: T) -> usize {
+        let serial = self.next_serial;
+        self.next_serial = self.next_serial.wrapping_add(1);
+        self.waiters.push((serial, waiter));
+        serial
+    }
+    /// Removes the waiter with `serial`, if it's still waiting
+    pub fn expire(&mut self, serial: usize) -> Option<T> {
+        let index = self.waiters.iter().position(|(s, _)| *s == serial)?;
+        Some(self.waiters.remove(index).1)
+    }
+    /// Given the LED state code before and after a report was applied, hands back every waiter
+    /// that should now be completed with the new state.
+    pub fn notify(&mut self, before: usize, after: usize) -> Vec<T> {
+        if before == after {
+            Vec::new()
+        } else {
+            self.waiters.drain(..).map(|(_, waiter)| waiter).collect()
+        }
+    }
+}
+/// Starts the clock on a `WaitLedChange`: after `timeout_ms`, the server is sent a `LedWaitTimeout`
+/// for `serial`, which is ignored if the waiter has already been completed.
+pub(crate) fn spawn_led_wait_timeout(cid: xous::CID, serial: usize, timeout_ms: usize) {
+    std::thread::spawn(move || {
+        let tt = ticktimer_server::Ticktimer::new().unwrap();
+        tt.sleep_ms(timeout_ms).unwrap();
+        xous::send_message(cid,
+            xous::Message::new_scalar(Opcode::LedWaitTimeout.to_usize().unwrap(), serial, 0, 0, 0)
+        ).expect("couldn't expire WaitLedChange");
+    });
+}
+/// The LED state in the same one-byte encoding the host uses, as returned by `GetLedState`
+pub(crate) fn led_state_code(led_state: &KeyboardLedsReport) -> usize {
+    let mut code = [0u8; 1];
+    led_state.pack_to_slice(&mut code).unwrap();
+    code[0] as usize
+}
+
+/// Substitutions applied to every usage code on its way out to the host, e.g. to swap Ctrl and
+/// Caps Lock for users with non-standard hardware. Codes not in the table pass through unchanged,
+/// and each code is looked up once, so a pair of entries swaps two keys rather than chaining.
+pub(crate) struct KeyRemap {
+    map: BTreeMap<u8, u8>,
+}
+impl KeyRemap {
+    pub fn new() -> Self {
+        KeyRemap { map: BTreeMap::new() }
+    }
+    /// Replaces the whole table. If a code appears more than once as a source, the last entry wins.
+    pub fn set(&mut self, table: &KeyRemapTable) {
+        let len = (table.len as usize).min(MAX_KEY_REMAPS);
+        self.map = table.from[..len].iter().copied().zip(table.to[..len].iter().copied()).collect();
+    }
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+    pub fn remap(&self, code: Keyboard) -> Keyboard {
+        match self.map.get(&(code as u8)) {
+            Some(&to) => Keyboard::from_primitive(to),
+            None => code,
+        }
+    }
+}
+/// Turns the scalar args of a `SendKeyCode` message into the codes for the key report. A code
+/// of 0 marks an unused slot.
+pub(crate) fn keycode_report(codes: &[usize], remap: &KeyRemap) -> Vec<Keyboard> {
+    codes.iter()
+        .filter(|&&code| code != 0)
+        .map(|&code| remap.remap(Keyboard::from_primitive(code as u8)))
+        .collect()
+}
+/// Turns the scalar arg of a `SendConsumerCode` message into a consumer report. A code of 0 is
+/// the all-keys-up report.
+pub(crate) fn consumer_report(code: usize) -> MultipleConsumerReport {
+    MultipleConsumerReport {
+        codes: [Consumer::from_primitive(code as u16), Consumer::Unassigned, Consumer::Unassigned, Consumer::Unassigned],
+    }
+}
+/// Orderly teardown for `Quit`. Every key is released first, so a host doesn't keep auto-repeating
+/// a key that was down when the service went away; then the device core is taken off the bus, so
+/// the host sees a clean disconnect instead of a device that stops answering.
+pub(crate) fn drain_for_quit(mut write_report: impl FnMut(&[Keyboard]), mut disconnect: impl FnMut()) {
+    write_report(&[]);
+    disconnect();
+}
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct ScalarCallback {
+    server_to_cb_cid: xous::CID,
+    cb_to_client_cid: xous::CID,
+    cb_to_client_id: u32,
+}
+pub(crate) fn do_hook(hookdata: ScalarHook, cb_conns: &mut [Option<ScalarCallback>; 32]) {
+    let (s0, s1, s2, s3) = hookdata.sid;
+    let sid = xous::SID::from_u32(s0, s1, s2, s3);
+    let server_to_cb_cid = xous::connect(sid).unwrap();
+    let cb_dat = Some(ScalarCallback {
+        server_to_cb_cid,
+        cb_to_client_cid: hookdata.cid,
+        cb_to_client_id: hookdata.id,
+    });
+    let mut found = false;
+    for entry in cb_conns.iter_mut() {
+        if entry.is_none() {
+            *entry = cb_dat;
+            found = true;
+            break;
+        }
+    }
+    if !found {
+        log::error!("ran out of space registering callback");
+    }
+}
+pub(crate) fn unhook(cb_conns: &mut [Option<ScalarCallback>; 32]) {
+    for entry in cb_conns.iter_mut() {
+        if let Some(scb) = entry {
+            xous::send_message(scb.server_to_cb_cid,
+                xous::Message::new_blocking_scalar(EventCallback::Drop.to_usize().unwrap(), 0, 0, 0, 0)
+            ).unwrap();
+            unsafe{xous::disconnect(scb.server_to_cb_cid).unwrap();}
+        }
+        *entry = None;
+    }
+}
+/// Passes `arg1` and `arg2` on to each subscriber, e.g. the new and old link status
+pub(crate) fn send_event(cb_conns: &[Option<ScalarCallback>; 32], arg1: usize, arg2: usize) {
+    for entry in cb_conns.iter() {
+        if let Some(scb) = entry {
+            match xous::try_send_message(scb.server_to_cb_cid,
+                xous::Message::new_scalar(EventCallback::Event.to_usize().unwrap(),
+                   scb.cb_to_client_cid as usize, scb.cb_to_client_id as usize, arg1, arg2)
+            ) {
+                Ok(_) => {},
+                Err(xous::Error::ServerQueueFull) => {
+                    // a flapping link can outrun a slow subscriber; it can always catch up with
+                    // `LinkStatus` or `GetLedState`
+                    log::warn!("Attempted to send event, but destination queue is full. Event was dropped: {:?}", scb);
+                }
+                Err(xous::Error::ServerNotFound) => {
+                    log::warn!("Event callback subscriber has died. Event was dropped: {:?}", scb);
+                }
+                Err(e) => {
+                    log::error!("Callback error {:?}: {:?}", e, scb);
+                }
+            }
+        }
+    }
+}
+/// The link state as last told to `RegisterLinkStatusCallback` subscribers, so they only hear
+/// about changes
+pub(crate) struct LinkWatch {
+    last: UsbDeviceState,
+}
+impl LinkWatch {
+    pub fn new(state: UsbDeviceState) -> Self {
+        LinkWatch { last: state }
+    }
+    /// Returns the new and old states if `state` differs from the one last seen
+    pub fn update(&mut self, state: UsbDeviceState) -> Option<(UsbDeviceState, UsbDeviceState)> {
+        if state == self.last {
+            None
+        } else {
+            let old = core::mem::replace(&mut self.last, state);
+            Some((state, old))
+        }
+    }
+}
+
+/// The rules for `RemoteWakeup`: a device may only signal resume to a host that is suspended, and
+/// that has enabled remote wakeup with a SetFeature. `signal` drives the signaling, and returns
+/// whether the core could.
+pub(crate) fn remote_wakeup(state: UsbDeviceState, armed: bool, signal: impl FnOnce() -> bool) -> WakeupResult {
+    if state != UsbDeviceState::Suspend {
+        WakeupResult::NotSuspended
+    } else if !armed {
+        WakeupResult::NotArmed
+    } else if signal() {
+        WakeupResult::Sent
+    } else {
+        WakeupResult::Unsupported
+    }
+}
+
+/// Most key presses `EnqueueKeys` holds waiting to go out, until `SetKeyQueueDepth` says otherwise
+pub(crate) const KEY_QUEUE_DEPTH_DEFAULT: usize = 64;
+/// Key presses from `EnqueueKeys`, waiting for the keyboard endpoint to take them. A report goes
+/// out each time the host polls the endpoint, so the caller never waits on a keypress.
+///
+/// An auto-up press is followed by an all-keys-up report only where one is needed: if the next
+/// press shares no key with it, the report that presses the new keys also lets go of the old ones,
+/// which the host reads just the same. A key pressed twice in a row still gets its release in
+/// between, or the host would see it held down the whole time.
+pub(crate) struct KeyQueue {
+    pending: VecDeque<([usize; 3], bool)>,
+    depth: usize,
+    /// the keys of an auto-up press that went out, and are still down
+    held: Option<[usize; 3]>,
+}
+impl KeyQueue {
+    pub fn new(depth: usize) -> Self {
+        KeyQueue { pending: VecDeque::new(), depth, held: None }
+    }
+    /// Queues a press of up to three codes, as for `SendKeyCode`. Returns false, leaving the
+    /// queue as it was, if it already holds `depth` presses.
+    pub fn push(&mut self, codes: [usize; 3], autoup: bool) -> bool {
+        if self.pending.len() >= self.depth {
+            return false;
+        }
+        self.pending.push_back((codes, autoup));
+        true
+    }
+    /// Shrinking the depth below what's already queued doesn't drop anything; it only refuses
+    /// new presses until the queue drains.
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+    }
+    /// True when there's nothing left to send, not even a release
+    pub fn is_idle(&self) -> bool {
+        self.pending.is_empty() && self.held.is_none()
+    }
+    /// Forgets everything queued, for when the host goes away: there's no one to release keys to.
+    pub fn clear(&mut self) {
+        self.pending.clear();
+        self.held = None;
+    }
+    fn releasing(&self) -> bool {
+        match (self.pending.front(), self.held) {
+            (_, None) => false,
+            (None, Some(_)) => true,
+            (Some((codes, _)), Some(held)) => codes.iter().any(|&c| c != 0 && held.contains(&c)),
+        }
+    }
+    /// The codes of the next report to write, if any; an all-zero report is a release
+    pub fn next_report(&self) -> Option<[usize; 3]> {
+        if self.releasing() {
+            Some([0; 3])
+        } else {
+            self.pending.front().map(|&(codes, _)| codes)
+        }
+    }
+    /// Moves past the report `next_report` gave, once it has been written
+    pub fn sent(&mut self) {
+        if self.releasing() {
+            self.held = None;
+        } else if let Some((codes, autoup)) = self.pending.pop_front() {
+            self.held = if autoup { Some(codes) } else { None };
+        }
+    }
+}
+/// Writes queued key reports until the keyboard endpoint stops taking them
+pub(crate) fn pump_key_queue<B: UsbBus>(
+    queue: &mut KeyQueue,
+    keyboard: &mut NKROBootKeyboardInterface<'_, B, EmbeddedClock>,
+    remap: &KeyRemap,
+) {
+    while let Some(codes) = queue.next_report() {
+        match keyboard.write_report(&keycode_report(&codes, remap)) {
+            // the endpoint is still busy with the last report; try again on the next poll
+            Err(UsbError::WouldBlock) => break,
+            // drop the report, rather than have it wedge the queue
+            Err(e) => log::warn!("queued key report not sent: {:?}", e),
+            Ok(_) => {}
+        }
+        queue.sent();
+    }
+    keyboard.tick().ok();
+}
+
+/// Gap between the key-down and key-up events of a `SendString`, in ms
+pub(crate) const STRING_KEY_INTERVAL_MS: usize = 30;
+/// How long before an event's deadline a boosted burst stops sleeping and starts yielding, in ms
+pub(crate) const BURST_SPIN_MARGIN_MS: u64 = 4;
+/// Paces the key-down/key-up events of a burst such as a `SendString`.
+///
+/// Xous has no thread priorities to raise: the scheduler hands quanta round-robin to whichever
+/// threads are runnable. A thread in `sleep_ms()` isn't runnable until the ticktimer server has
+/// run and replied to it, so under load every wakeup can land late by however long other servers
+/// hold the CPU, and pacing each event relative to the previous one lets that lateness show up as
+/// uneven typing. With burst priority on, events are instead paced against a schedule fixed from
+/// the start of the burst, and the last few ms before each deadline are spent in `yield_slice()`
+/// rather than asleep: the thread stays runnable, so it gets the CPU back at the next quantum
+/// instead of after a round trip through the ticktimer. The price is CPU time burned while the
+/// burst lasts, which is why it's opt-in and only applies for the length of one burst.
+pub(crate) struct BurstPacer {
+    boosted: bool,
+    interval_ms: u64,
+    next_ms: u64,
+}
+impl BurstPacer {
+    pub fn new(boosted: bool, interval_ms: usize, now_ms: u64) -> Self {
+        BurstPacer { boosted, interval_ms: interval_ms as u64, next_ms: now_ms + interval_ms as u64 }
+    }
+    /// Waits out the gap after an event. `now` reads the millisecond clock, `sleep` blocks for a
+    /// number of ms, and `yield_slice` hands the rest of the current quantum to the scheduler.
+    pub fn wait(&mut self, now: impl Fn() -> u64, mut sleep: impl FnMut(usize), mut yield_slice: impl FnMut()) {
+        if !self.boosted {
+            sleep(self.interval_ms as usize);
+            return;
+        }
+        let deadline = self.next_ms;
+        let start = now();
+        if deadline > start + BURST_SPIN_MARGIN_MS {
+            sleep((deadline - start - BURST_SPIN_MARGIN_MS) as usize);
+        }
+        while now() < deadline {
+            yield_slice();
+        }
+        // if a wakeup came in late, restart the schedule from here rather than bunching the
+        // following events together to catch up
+        self.next_ms = deadline.max(now()) + self.interval_ms;
+    }
+}
+
+/// The SpinalHDL core has no VBUS sense of its own, so presence is read off the XADC VBUS
+/// channel. Anything over 1.5V counts as plugged in, the same cut-off the sleep commands use.
+pub(crate) const VBUS_PRESENT_MV: u32 = 1500;
+#[cfg_attr(not(any(feature="precursor", feature="renode")), allow(dead_code))]
+pub(crate) fn vbus_present(xadc_vbus: u16) -> bool {
+    // XADC VBUS codes are in units of 5.03mV
+    (xadc_vbus as u32 * 503) / 100 > VBUS_PRESENT_MV
+}
+
+/// Turns the scalar args of a `MouseMove` or `MouseButton` message into a mouse report. The
+/// relative motion travels as the two's complement byte of an `i8`.
+pub(crate) fn mouse_report(buttons: usize, dx: usize, dy: usize) -> BootMouseReport {
+    BootMouseReport {
+        buttons: buttons as u8,
+        x: dx as u8 as i8,
+        y: dy as u8 as i8,
+    }
+}
+
+/// How a read or write on the serial port went, as the (result, bytes moved) of a `SerialData`
+#[cfg_attr(not(any(feature="precursor", feature="renode")), allow(dead_code))]
+pub(crate) fn serial_result(transfer: usb_device::Result<usize>) -> (SerialResult, u32) {
+    match transfer {
+        Ok(count) => (SerialResult::Ok, count as u32),
+        Err(UsbError::WouldBlock) => (SerialResult::WouldBlock, 0),
+        Err(e) => {
+            log::warn!("serial port error: {:?}", e);
+            (SerialResult::NotConnected, 0)
+        }
+    }
+}
+
+/// The steps of typing out a string with `SendString`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum TypeEvent {
+    /// press the key(s) for a character, which types it this many times before the release
+    Press(char, usize),
+    /// keep the keys down this many ms, on top of the usual keystroke interval
+    Hold(usize),
+    Release,
+}
+/// Plans the key events for typing `s`. Every character gets its own press and release unless
+/// `repeat` is set, in which case a run of the same character is one press, held long enough for
+/// the host's typematic to type the rest. That only works if `repeat` matches the host's settings,
+/// so discrete presses remain the default.
+pub(crate) fn type_events(s: &str, repeat: Option<TypeRepeat>) -> Vec<TypeEvent> {
+    let mut events = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(ch) = chars.next() {
+        let mut count = 1;
+        if repeat.is_some() {
+            while chars.peek() == Some(&ch) {
+                chars.next();
+                count += 1;
+            }
+        }
+        events.push(TypeEvent::Press(ch, count));
+        events.push(TypeEvent::Hold(repeat.map_or(0, |r| r.hold_ms(count))));
+        events.push(TypeEvent::Release);
+    }
+    events
+}
+
+/// Keyboard usage code of Left Control, the first of the eight modifier keys
+const MODIFIER_USAGE_BASE: u8 = 0xE0;
+/// The key report for a macro step: its modifier keys, then the rest
+pub(crate) fn macro_step_report(step: &MacroStep, remap: &KeyRemap) -> Vec<Keyboard> {
+    let modifiers = (0..8u8)
+        .filter(|bit| step.modifier & (1 << bit) != 0)
+        .map(|bit| MODIFIER_USAGE_BASE + bit);
+    let keys = step.codes.iter().copied().filter(|&code| code != 0);
+    modifiers.chain(keys).map(|code| remap.remap(Keyboard::from_primitive(code))).collect()
+}
+/// Longest the macro player sleeps without checking for a `StopMacro`, in ms
+pub(crate) const MACRO_STOP_POLL_MS: u64 = 20;
+/// Plays back the steps of a `PlayMacro`. `send(i, true)` writes the key report for step `i`, and
+/// `send(i, false)` the all-keys-up report that ends it; either returns `false` if the device
+/// can't take reports anymore. `now` reads the millisecond clock, `sleep` blocks for a number of
+/// ms, and `stopped` checks for a `StopMacro`.
+///
+/// Every press and release is timed against a schedule fixed when the macro starts, so a late
+/// wakeup delays one event rather than everything after it.
+pub(crate) fn play_macro(
+    steps: &[MacroStep],
+    now: impl Fn() -> u64,
+    mut sleep: impl FnMut(usize),
+    mut send: impl FnMut(usize, bool) -> bool,
+    stopped: impl Fn() -> bool,
+) -> MacroOutcome {
+    // returns `false` if the macro was stopped before `deadline` came around
+    let mut wait_until = |deadline: u64| -> bool {
+        loop {
+            if stopped() {
+                return false;
+            }
+            let time = now();
+            if time >= deadline {
+                return true;
+            }
+            sleep((deadline - time).min(MACRO_STOP_POLL_MS) as usize);
+        }
+    };
+    let mut deadline = now();
+    for (i, step) in steps.iter().enumerate() {
+        if !send(i, true) {
+            return MacroOutcome::Deconfigured(i as u32);
+        }
+        deadline += step.hold_ms as u64;
+        let held = wait_until(deadline);
+        // the keys come up even if the macro was stopped, so the host isn't left auto-repeating them
+        if !send(i, false) {
+            return MacroOutcome::Deconfigured(i as u32);
+        }
+        if !held {
+            return MacroOutcome::Aborted(i as u32);
+        }
+        deadline += step.gap_ms as u64;
+        if !wait_until(deadline) {
+            return MacroOutcome::Aborted(i as u32 + 1);
+        }
+    }
+    MacroOutcome::Completed
+}
+/// How a `MacroDone` message carries a `MacroOutcome`
+pub(crate) fn macro_outcome_to_scalar(outcome: MacroOutcome) -> (usize, usize) {
+    match outcome {
+        MacroOutcome::Pending => (0, 0),
+        MacroOutcome::Completed => (1, 0),
+        MacroOutcome::Aborted(step) => (2, step as usize),
+        MacroOutcome::Deconfigured(step) => (3, step as usize),
+        MacroOutcome::Busy => (4, 0),
+    }
+}
+pub(crate) fn macro_outcome_from_scalar(code: usize, step: usize) -> MacroOutcome {
+    match code {
+        1 => MacroOutcome::Completed,
+        2 => MacroOutcome::Aborted(step as u32),
+        3 => MacroOutcome::Deconfigured(step as u32),
+        4 => MacroOutcome::Busy,
+        _ => MacroOutcome::Pending,
+    }
+}
+/// Starts a thread playing `steps`, which hands each key report to the server with a
+/// `MacroReport` and sends a `MacroDone` once the macro is over. Setting `stop` cuts it short.
+pub(crate) fn spawn_macro_player(cid: xous::CID, steps: Vec<MacroStep>, stop: Arc<AtomicBool>) {
+    std::thread::spawn(move || {
+        let tt = ticktimer_server::Ticktimer::new().unwrap();
+        let outcome = play_macro(
+            &steps,
+            || tt.elapsed_ms(),
+            |ms| { tt.sleep_ms(ms).ok(); },
+            |step, press| {
+                match xous::send_message(cid,
+                    xous::Message::new_blocking_scalar(Opcode::MacroReport.to_usize().unwrap(), step, if press { 1 } else { 0 }, 0, 0)
+                ) {
+                    Ok(xous::Result::Scalar1(sent)) => sent != 0,
+                    _ => false,
+                }
+            },
+            || stop.load(Ordering::SeqCst),
+        );
+        let (code, step) = macro_outcome_to_scalar(outcome);
+        xous::send_message(cid,
+            xous::Message::new_scalar(Opcode::MacroDone.to_usize().unwrap(), code, step, 0, 0)
+        ).expect("couldn't finish PlayMacro");
+    });
+}
+
+/// The bMaxPacketSize0 values a full-speed device may advertise
+pub(crate) const EP0_MAX_PACKET_SIZES: [u8; 4] = [8, 16, 32, 64];
+/// Matches the `usb-device` stack's own default
+pub(crate) const EP0_MAX_PACKET_SIZE_DEFAULT: u8 = 8;
+pub(crate) fn valid_ep0_max_packet_size(size: usize) -> Option<u8> {
+    EP0_MAX_PACKET_SIZES.iter().copied().find(|&s| s as usize == size)
+}
+/// The VID/PID and strings the device identifies itself with
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct DeviceIdentity {
+    vid: u16,
+    pid: u16,
+    manufacturer: &'static str,
+    product: &'static str,
+    serial_number: &'static str,
+    /// the serial number to fall back to when a descriptor doesn't override it
+    default_serial_number: &'static str,
+}
+impl DeviceIdentity {
+    /// Precursor's own identity, with `serial_number` as the serial
+    pub fn new(serial_number: &str) -> Self {
+        let serial_number = leak_str(serial_number);
+        DeviceIdentity {
+            vid: 0x1209,
+            pid: 0x3613,
+            manufacturer: "Kosagi",
+            product: "Precursor",
+            serial_number,
+            default_serial_number: serial_number,
+        }
+    }
+    /// The identity described by `descriptor`, or `None` if one of its strings isn't valid UTF-8.
+    /// The device holds on to its strings for as long as it lives, and it's rebuilt rather than
+    /// dropped, so new strings are leaked; strings that match the current ones are reused, so
+    /// sending the same descriptor again costs nothing.
+    pub fn with_descriptor(&self, descriptor: &DeviceDescriptor) -> Option<Self> {
+        let serial_number = match descriptor.serial {
+            Some(serial) => {
+                let serial = serial.as_str().ok()?;
+                if serial == self.default_serial_number {
+                    self.default_serial_number
+                } else {
+                    reuse_or_leak_str(self.serial_number, serial)
+                }
+            }
+            None => self.default_serial_number,
+        };
+        Some(DeviceIdentity {
+            vid: descriptor.vid,
+            pid: descriptor.pid,
+            manufacturer: reuse_or_leak_str(self.manufacturer, descriptor.manufacturer.as_str().ok()?),
+            product: reuse_or_leak_str(self.product, descriptor.product.as_str().ok()?),
+            serial_number,
+            default_serial_number: self.default_serial_number,
+        })
+    }
+}
+fn leak_str(s: &str) -> &'static str {
+    Box::leak(s.to_string().into_boxed_str())
+}
+/// `current` if it already reads `s`, otherwise a leaked copy of `s`
+fn reuse_or_leak_str(current: &'static str, s: &str) -> &'static str {
+    if current == s { current } else { leak_str(s) }
+}
+/// Builds the device-level part of a USB stack, shared by every view of the hardware.
+/// Classes must be built on `alloc` before this is called.
+#[cfg_attr(not(any(feature="precursor", feature="renode")), allow(dead_code))]
+pub(crate) fn build_device<'a, B: UsbBus>(
+    alloc: &'a UsbBusAllocator<B>,
+    identity: &DeviceIdentity,
+    ep0_max_packet_size: u8,
+    iads: bool,
+) -> UsbDevice<'a, B> {
+    let builder = UsbDeviceBuilder::new(alloc, UsbVidPid(identity.vid, identity.pid))
+        .manufacturer(identity.manufacturer)
+        .product(identity.product)
+        .serial_number(identity.serial_number)
+        .max_packet_size_0(ep0_max_packet_size);
+    // a function made of more than one interface, i.e. the serial port, has to be grouped
+    // with an interface association descriptor for the host to bind one driver to all of it
+    if iads {
+        builder.composite_with_iads().build()
+    } else {
+        builder.build()
+    }
+}
+
+/// The HID interfaces enabled on the composite view, kept as a bitmask of `HidInterface`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) struct InterfaceSet(usize);
+impl Default for InterfaceSet {
+    /// Keyboard, mouse and media keys, alongside FIDO
+    fn default() -> Self {
+        InterfaceSet(
+            HidInterface::Keyboard as usize | HidInterface::Mouse as usize | HidInterface::Fido as usize
+            | HidInterface::Consumer as usize
+        )
+    }
+}
+#[cfg_attr(not(any(feature="precursor", feature="renode")), allow(dead_code))]
+impl InterfaceSet {
+    /// Interfaces in the order they are built, and so the order they appear in the configuration descriptor.
+    /// New interfaces go on the end, so the ones already there keep their interface numbers.
+    const ORDER: [HidInterface; 5] = [
+        HidInterface::Keyboard, HidInterface::Mouse, HidInterface::Fido, HidInterface::Serial, HidInterface::Consumer
+    ];
+
+    /// Accepts a bitmask from the IPC interface. Unknown bits are rejected, as is the empty set:
+    /// a configuration with no interfaces can't do anything useful for the host.
+    pub fn from_bits(bits: usize) -> Option<Self> {
+        let known = Self::ORDER.iter().fold(0, |acc, &iface| acc | iface as usize);
+        if bits == 0 || bits & !known != 0 {
+            None
+        } else {
+            Some(InterfaceSet(bits))
+        }
+    }
+    #[allow(dead_code)]
+    pub fn bits(&self) -> usize {
+        self.0
+    }
+    pub fn contains(&self, iface: HidInterface) -> bool {
+        self.0 & iface as usize != 0
+    }
+    #[allow(dead_code)]
+    pub fn insert(&mut self, iface: HidInterface) {
+        self.0 |= iface as usize;
+    }
+    #[allow(dead_code)]
+    pub fn remove(&mut self, iface: HidInterface) {
+        self.0 &= !(iface as usize);
+    }
+    pub fn iter(&self) -> impl Iterator<Item = HidInterface> + '_ {
+        Self::ORDER.iter().copied().filter(move |&iface| self.contains(iface))
+    }
+    /// Max packet sizes of the endpoints each interface allocates with its `default_config()`
+    fn endpoint_sizes(iface: HidInterface) -> &'static [u32] {
+        match iface {
+            // interrupt IN for key reports, interrupt OUT for the LED report
+            HidInterface::Keyboard => &[32, 8],
+            HidInterface::Mouse => &[8],
+            // U2F frames are always 64 bytes, in both directions
+            HidInterface::Fido => &[64, 64],
+            // interrupt IN for line state notifications, then bulk IN and OUT for the data
+            HidInterface::Serial => &[8, 64, 64],
+            // interrupt IN for consumer reports
+            HidInterface::Consumer => &[8],
+        }
+    }
+    /// Whether the device built for this set needs interface association descriptors
+    pub fn needs_iads(&self) -> bool {
+        self.contains(HidInterface::Serial)
+    }
+    /// Replays the allocations a build of this set would make against an empty allocator, so an
+    /// oversized set can be refused before the running stacks are torn down. `alloc_ep` panics
+    /// on failure deep inside the class builders, so this has to be checked up front.
+    #[cfg(any(feature="precursor", feature="renode"))]
+    pub fn fits(&self, ep0_max_packet_size: u8) -> bool {
+        let sizes: Vec<u32> = self.iter().flat_map(|iface| Self::endpoint_sizes(iface).iter().copied()).collect();
+        // endpoint 0 is reserved for control transfers
+        if sizes.len() > NUM_ENDPOINTS - 1 {
+            return false;
+        }
+        let mut allocs = BTreeMap::<u32, u32>::new();
+        alloc_inner(&mut allocs, ep0_max_packet_size as u32).is_some()
+            && sizes.iter().all(|&size| alloc_inner(&mut allocs, size).is_some())
+    }
+}
+
+pub(crate) type KeyboardClass<'a, B> = UsbHidClass<B, frunk::HList!(NKROBootKeyboardInterface<'a, B, EmbeddedClock>)>;
+pub(crate) type MouseClass<'a, B> = UsbHidClass<B, frunk::HList!(BootMouseInterface<'a, B>)>;
+pub(crate) type FidoClass<'a, B> = UsbHidClass<B, frunk::HList!(RawFidoInterface<'a, B>)>;
+pub(crate) type ConsumerClass<'a, B> = UsbHidClass<B, frunk::HList!(ConsumerControlInterface<'a, B>)>;
+/// The classes making up one build of an `InterfaceSet`. Each interface is its own class, so
+/// leaving one out doesn't change the type of the stack, and the stack can be rebuilt in place
+/// whenever the set changes.
+#[cfg_attr(not(any(feature="precursor", feature="renode")), allow(dead_code))]
+pub(crate) struct InterfaceStack<'a, B: UsbBus> {
+    pub keyboard: Option<KeyboardClass<'a, B>>,
+    pub mouse: Option<MouseClass<'a, B>>,
+    pub fido: Option<FidoClass<'a, B>>,
+    pub serial: Option<SerialPort<'a, B>>,
+    pub consumer: Option<ConsumerClass<'a, B>>,
+}
+#[cfg_attr(not(any(feature="precursor", feature="renode")), allow(dead_code))]
+impl<'a, B: UsbBus> InterfaceStack<'a, B> {
+    /// Builds the classes for `set` on `alloc`. The set should have passed `InterfaceSet::fits()`
+    /// for the allocator's hardware; the device itself is built afterwards with `build_device()`.
+    pub fn build(set: InterfaceSet, alloc: &'a UsbBusAllocator<B>, clock: &'a EmbeddedClock) -> Self {
+        let mut stack = InterfaceStack { keyboard: None, mouse: None, fido: None, serial: None, consumer: None };
+        for iface in set.iter() {
+            match iface {
+                HidInterface::Keyboard => stack.keyboard = Some(
+                    UsbHidClassBuilder::new()
+                        .add_interface(NKROBootKeyboardInterface::default_config(clock))
+                        .build(alloc)
+                ),
+                HidInterface::Mouse => stack.mouse = Some(
+                    UsbHidClassBuilder::new()
+                        .add_interface(BootMouseInterface::default_config())
+                        .build(alloc)
+                ),
+                HidInterface::Fido => stack.fido = Some(
+                    UsbHidClassBuilder::new()
+                        .add_interface(RawFidoInterface::default_config())
+                        .build(alloc)
+                ),
+                HidInterface::Serial => stack.serial = Some(SerialPort::new(alloc)),
+                HidInterface::Consumer => stack.consumer = Some(
+                    UsbHidClassBuilder::new()
+                        .add_interface(ConsumerControlInterface::default_config())
+                        .build(alloc)
+                ),
+            }
+        }
+        stack
+    }
+    /// The enabled classes, in descriptor order, ready to hand to `UsbDevice::poll()`
+    pub fn classes(&mut self) -> Vec<&mut dyn UsbClass<B>> {
+        let mut classes: Vec<&mut dyn UsbClass<B>> = Vec::new();
+        if let Some(keyboard) = self.keyboard.as_mut() {
+            classes.push(keyboard);
+        }
+        if let Some(mouse) = self.mouse.as_mut() {
+            classes.push(mouse);
+        }
+        if let Some(fido) = self.fido.as_mut() {
+            classes.push(fido);
+        }
+        if let Some(serial) = self.serial.as_mut() {
+            classes.push(serial);
+        }
+        if let Some(consumer) = self.consumer.as_mut() {
+            classes.push(consumer);
+        }
+        classes
+    }
+    pub fn keyboard(&mut self) -> Option<&mut NKROBootKeyboardInterface<'a, B, EmbeddedClock>> {
+        self.keyboard.as_mut().map(|k| k.interface::<NKROBootKeyboardInterface<'_, _, _,>, _>())
+    }
+    pub fn mouse(&mut self) -> Option<&mut BootMouseInterface<'a, B>> {
+        self.mouse.as_mut().map(|m| m.interface::<BootMouseInterface<'_, _>, _>())
+    }
+    pub fn consumer(&mut self) -> Option<&mut ConsumerControlInterface<'a, B>> {
+        self.consumer.as_mut().map(|c| c.interface::<ConsumerControlInterface<'_, _>, _>())
+    }
+    pub fn fido(&mut self) -> Option<&mut RawFidoInterface<'a, B>> {
+        self.fido.as_mut().map(|f| f.interface::<RawFidoInterface<'_, _>, _>())
+    }
+}
+
+// Note that the reservation below the free space does not depend on bMaxPacketSize0: SETUP packets
+// land in a fixed 8-byte slot, and the EP0 OUT descriptor only ever accepts zero-length status
+// packets. The EP0 IN buffer is taken from the free space, sized to the configured bMaxPacketSize0.
 #[cfg(any(feature="precursor", feature="renode"))]
 pub(crate) const START_OFFSET: u32 = 0x0048 + 8 + 16; // align spinal free space to 16-byte boundary + 16 bytes for EP0 read
 #[cfg(any(feature="precursor", feature="renode"))]
@@ -57,6 +926,37 @@ pub(crate) fn alloc_inner(allocs: &mut BTreeMap<u32, u32>, requested: u32) -> Op
         return None;
     }
     let with_descriptor = requested + 16; // the descriptor takes 3 words; add 4 because of the alignment requirement
+    let alloc_offset = find_gap(allocs, with_descriptor)?;
+    allocs.insert(alloc_offset, with_descriptor);
+    Some(alloc_offset)
+}
+/// Like `alloc_inner`, but places the region in the smallest hole it fits rather than the first,
+/// leaving the larger holes for larger requests. Under churn of mixed sizes this fragments the
+/// memory less, so a big request late in the game is less likely to fail for want of a hole.
+#[cfg(any(feature="precursor", feature="renode"))]
+#[allow(dead_code)]
+pub(crate) fn alloc_best_fit(allocs: &mut BTreeMap<u32, u32>, requested: u32) -> Option<u32> {
+    if requested == 0 {
+        return None;
+    }
+    let with_descriptor = requested + 16;
+    let alloc_offset = find_best_gap(allocs, with_descriptor)?;
+    allocs.insert(alloc_offset, with_descriptor);
+    Some(alloc_offset)
+}
+/// Dry-run of `alloc_inner`: reports the offset at which `requested` bytes (rounded up to the
+/// 16-byte alignment of the core) would land, without touching `allocs`.
+#[cfg(any(feature="precursor", feature="renode"))]
+pub(crate) fn can_alloc(allocs: &BTreeMap<u32, u32>, requested: u32) -> Option<u32> {
+    if requested == 0 || requested > END_OFFSET {
+        return None;
+    }
+    let rounded = (requested + 0xF) & !0xF;
+    find_gap(allocs, rounded + 16)
+}
+/// Walks the allocation list looking for the first hole that fits `with_descriptor` bytes.
+#[cfg(any(feature="precursor", feature="renode"))]
+fn find_gap(allocs: &BTreeMap<u32, u32>, with_descriptor: u32) -> Option<u32> {
     let mut alloc_offset = START_OFFSET;
     for (&offset, &length) in allocs.iter() {
         // round length up to the nearest 16-byte increment
@@ -72,22 +972,869 @@ pub(crate) fn alloc_inner(allocs: &mut BTreeMap<u32, u32>, requested: u32) -> Op
         alloc_offset = offset + length;
     }
     if alloc_offset + with_descriptor <= END_OFFSET {
-        allocs.insert(alloc_offset, with_descriptor);
         Some(alloc_offset)
     } else {
         None
     }
 }
-#[allow(dead_code)]
+/// Walks the whole allocation list, including the space after the last region, for the smallest
+/// hole that fits `with_descriptor` bytes.
+#[cfg(any(feature="precursor", feature="renode"))]
+fn find_best_gap(allocs: &BTreeMap<u32, u32>, with_descriptor: u32) -> Option<u32> {
+    let mut best: Option<(u32, u32)> = None; // (offset, size) of the best hole so far
+    let mut consider = |start: u32, end: u32| {
+        if end >= start && end - start >= with_descriptor && best.map_or(true, |(_, size)| end - start < size) {
+            best = Some((start, end - start));
+        }
+    };
+    let mut alloc_offset = START_OFFSET;
+    for (&offset, &length) in allocs.iter() {
+        // round length up to the nearest 16-byte increment, as `find_gap` does
+        let length = if length & 0xF == 0 { length } else { (length + 16) & !0xF };
+        assert!(offset >= alloc_offset, "allocated regions overlap");
+        consider(alloc_offset, offset);
+        alloc_offset = offset + length;
+    }
+    consider(alloc_offset, END_OFFSET);
+    best.map(|(offset, _)| offset)
+}
+/// Frees the region allocated at `offset`, which must be exactly an offset `alloc_inner` returned.
+/// Returns `true` if there was a region there to free.
+#[cfg(any(feature="precursor", feature="renode"))]
 pub(crate) fn dealloc_inner(allocs: &mut BTreeMap<u32, u32>, offset: u32) -> bool {
     allocs.remove(&offset).is_some()
 }
+/// The offsets of the regions in use by whatever is currently built on the allocator: the endpoint
+/// buffers of the interface stack and the device's control pipe. Taken right after a build.
+#[cfg(any(feature="precursor", feature="renode"))]
+pub(crate) fn live_regions(allocs: &BTreeMap<u32, u32>) -> std::collections::BTreeSet<u32> {
+    allocs.keys().copied().collect()
+}
+/// Frees the region at `offset` on behalf of `FreeEndpoint`. Regions in `live` are refused, since
+/// freeing a buffer an endpoint still uses would let the next allocation overlap it; those are only
+/// ever released all together, when the stack is rebuilt on a fresh allocator. Returns `None` if
+/// the region is live, otherwise whether there was a region to free.
+#[cfg(any(feature="precursor", feature="renode"))]
+pub(crate) fn dealloc_unless_live(
+    allocs: &mut BTreeMap<u32, u32>,
+    live: &std::collections::BTreeSet<u32>,
+    offset: u32
+) -> Option<bool> {
+    if live.contains(&offset) {
+        None
+    } else {
+        Some(dealloc_inner(allocs, offset))
+    }
+}
+
+/// Reasons an allocator snapshot can't be restored. Each carries the offending offset.
+#[cfg(any(feature="precursor", feature="renode"))]
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum AllocError {
+    /// Region is not on a 16-byte boundary
+    Misaligned(u32),
+    /// Region starts inside the previous region
+    Overlap(u32),
+    /// Region is empty, or falls outside of `START_OFFSET..END_OFFSET`
+    OutOfRange(u32),
+}
+/// Capture the allocator state as a list of (offset, length) pairs, e.g. to attach to a bug report.
+#[cfg(any(feature="precursor", feature="renode"))]
+pub(crate) fn snapshot(allocs: &BTreeMap<u32, u32>) -> Vec<(u32, u32)> {
+    allocs.iter().map(|(&offset, &length)| (offset, length)).collect()
+}
+/// Packs a snapshot into the reply to `DumpAllocations`, keeping as many regions as fit.
+#[cfg(any(feature="precursor", feature="renode"))]
+pub(crate) fn allocation_list(snap: &[(u32, u32)]) -> AllocationList {
+    let mut list = AllocationList {
+        offsets: [0; MAX_ALLOCATIONS],
+        lengths: [0; MAX_ALLOCATIONS],
+        len: 0,
+        total: snap.len() as u32,
+    };
+    for (i, &(offset, length)) in snap.iter().take(MAX_ALLOCATIONS).enumerate() {
+        list.offsets[i] = offset;
+        list.lengths[i] = length;
+        list.len += 1;
+    }
+    list
+}
+/// Replace the allocator state with a previously captured snapshot. The snapshot is checked
+/// against the invariants `alloc_inner` maintains, and `allocs` is left untouched if it fails.
+#[cfg(any(feature="precursor", feature="renode"))]
+#[allow(dead_code)]
+pub(crate) fn restore(allocs: &mut BTreeMap<u32, u32>, snap: &[(u32, u32)]) -> Result<(), AllocError> {
+    let mut restored = BTreeMap::<u32, u32>::new();
+    for &(offset, length) in snap.iter() {
+        if offset & 0xF != 0 {
+            return Err(AllocError::Misaligned(offset));
+        }
+        if length == 0 || offset < START_OFFSET || offset.checked_add(length).map_or(true, |end| end > END_OFFSET) {
+            return Err(AllocError::OutOfRange(offset));
+        }
+        if restored.insert(offset, length).is_some() {
+            return Err(AllocError::Overlap(offset));
+        }
+    }
+    // same rule as `alloc_inner`: each region ends at its length rounded up to the next 16 bytes
+    let mut last_end = START_OFFSET;
+    for (&offset, &length) in restored.iter() {
+        if offset < last_end {
+            return Err(AllocError::Overlap(offset));
+        }
+        last_end = offset + if length & 0xF == 0 { length } else { (length + 16) & !0xF };
+    }
+    *allocs = restored;
+    Ok(())
+}
 
 // run with `cargo test -- --nocapture --test-threads=1`:
 #[cfg(test)]
 mod tests {
     use super::*;
     #[test]
+    fn test_autoup_delay() {
+        assert_eq!(clamp_autoup_delay(AUTOUP_DELAY_DEFAULT_MS), AUTOUP_DELAY_DEFAULT_MS);
+        assert_eq!(clamp_autoup_delay(100), 100);
+        // anything longer than that is more likely to trip the host's key repeat than to help
+        assert_eq!(clamp_autoup_delay(101), AUTOUP_DELAY_MAX_MS);
+        assert_eq!(clamp_autoup_delay(500), AUTOUP_DELAY_MAX_MS);
+        assert_eq!(clamp_autoup_delay(0), AUTOUP_DELAY_MIN_MS);
+        assert_eq!(clamp_autoup_delay(usize::MAX), AUTOUP_DELAY_MAX_MS);
+    }
+    #[test]
+    fn test_autoup_timing() {
+        let mut autoup = AutoUp::new();
+        assert_eq!(autoup.delay_ms(), AUTOUP_DELAY_DEFAULT_MS);
+        for &(requested, expected) in [(45, 45), (1, AUTOUP_DELAY_MIN_MS), (1000, AUTOUP_DELAY_MAX_MS)].iter() {
+            assert_eq!(autoup.set_delay(requested), expected);
+            // play a keystroke against a simulated clock, noting when each report goes out
+            let time = std::cell::Cell::new(1000u64);
+            let mut reports = Vec::new();
+            autoup.keystroke(&[Keyboard::A], true,
+                |codes| reports.push((time.get(), codes.to_vec())),
+                |ms| time.set(time.get() + ms as u64),
+            );
+            // the key-up follows the key-down by the configured delay, and the next keystroke
+            // can't start until as long again after that
+            assert_eq!(reports, vec![(1000, vec![Keyboard::A]), (1000 + expected as u64, vec![])],
+                "delay set to {}ms", requested);
+            assert_eq!(time.get(), 1000 + 2 * expected as u64);
+        }
+        // without auto-up the key stays down, and only the first delay applies
+        let time = std::cell::Cell::new(0u64);
+        let mut reports = Vec::new();
+        autoup.keystroke(&[Keyboard::A], false, |codes| reports.push(codes.to_vec()), |ms| time.set(time.get() + ms as u64));
+        assert_eq!(reports, vec![vec![Keyboard::A]]);
+        assert_eq!(time.get(), AUTOUP_DELAY_MAX_MS as u64);
+    }
+    #[test]
+    fn test_enum_error() {
+        assert_eq!(last_enum_error(), EnumError::None);
+        // a simulated stall while the host is still fetching the device descriptor
+        record_enum_error(ep0_stall_cause(0));
+        assert_eq!(last_enum_error(), EnumError::StallOnGetDescriptor);
+        // later errors replace earlier ones
+        record_enum_error(ep0_stall_cause(12));
+        assert_eq!(last_enum_error(), EnumError::StallAfterAddress);
+        record_enum_error(EnumError::SetAddressTimeout);
+        assert_eq!(last_enum_error(), EnumError::SetAddressTimeout);
+    }
+    #[test]
+    fn test_caps_follower() {
+        let mut follower = CapsFollower::new();
+        // disabled by default
+        assert_eq!(follower.update(true), None);
+
+        assert!(follower.set_pin(Some(3)));
+        // a simulated Caps Lock report drives the pin, but only when the state changes
+        assert_eq!(follower.update(true), Some((1 << 3, 1 << 3)));
+        assert_eq!(follower.update(true), None);
+        assert_eq!(follower.update(false), Some((0, 1 << 3)));
+        assert_eq!(follower.update(false), None);
+
+        // moving the pin re-applies the current state on the next report
+        assert!(follower.set_pin(Some(5)));
+        assert_eq!(follower.update(false), Some((0, 1 << 5)));
+        assert_eq!(follower.update(true), Some((1 << 5, 1 << 5)));
+
+        assert!(!follower.set_pin(Some(CAPS_FOLLOWER_PINS)));
+        assert_eq!(follower.pin(), Some(5));
+        assert!(follower.set_pin(None));
+        assert_eq!(follower.update(false), None);
+    }
+    #[test]
+    fn test_inject_led_report() {
+        let mut led_state = KeyboardLedsReport::default();
+        let mut follower = CapsFollower::new();
+        assert!(follower.set_pin(Some(2)));
+        assert_eq!(led_state_code(&led_state), 0);
+
+        // Caps Lock is bit 1 of the boot keyboard LED report
+        let report = KeyboardLedsReport::unpack_from_slice(&[0x02]).unwrap();
+        assert_eq!(apply_led_report(report, &mut led_state, &mut follower), Some((1 << 2, 1 << 2)));
+        assert!(led_state.caps_lock);
+        assert!(!led_state.num_lock);
+        assert_eq!(led_state_code(&led_state), 0x02);
+
+        // Num Lock on top of Caps Lock doesn't move the follower
+        let report = KeyboardLedsReport::unpack_from_slice(&[0x03]).unwrap();
+        assert_eq!(apply_led_report(report, &mut led_state, &mut follower), None);
+        assert_eq!(led_state_code(&led_state), 0x03);
+    }
+    #[test]
+    fn test_key_remap() {
+        let mut remap = KeyRemap::new();
+        let caps = Keyboard::CapsLock as usize;
+        // identity by default
+        assert_eq!(keycode_report(&[caps, 0, 0], &remap), vec![Keyboard::CapsLock]);
+
+        let mut table = KeyRemapTable { from: [0; MAX_KEY_REMAPS], to: [0; MAX_KEY_REMAPS], len: 2 };
+        table.from[0] = Keyboard::CapsLock as u8;
+        table.to[0] = Keyboard::LeftControl as u8;
+        table.from[1] = Keyboard::LeftControl as u8;
+        table.to[1] = Keyboard::CapsLock as u8;
+        remap.set(&table);
+        assert_eq!(keycode_report(&[caps, 0, 0], &remap), vec![Keyboard::LeftControl]);
+        // a swap, not a chain; other codes are untouched
+        assert_eq!(
+            keycode_report(&[Keyboard::LeftControl as usize, Keyboard::A as usize, 0], &remap),
+            vec![Keyboard::CapsLock, Keyboard::A]
+        );
+        // entries past `len` are ignored
+        table.len = 1;
+        remap.set(&table);
+        assert_eq!(keycode_report(&[Keyboard::LeftControl as usize], &remap), vec![Keyboard::LeftControl]);
+
+        remap.clear();
+        assert_eq!(keycode_report(&[caps], &remap), vec![Keyboard::CapsLock]);
+    }
+    #[test]
+    fn test_wait_led_change() {
+        let mut led_state = KeyboardLedsReport::default();
+        let mut caps_follower = CapsFollower::new();
+        let mut waiters = LedWaiters::<&str>::new();
+        let first = waiters.park("first");
+        let second = waiters.park("second");
+
+        // a report that doesn't change anything leaves everyone waiting
+        let before = led_state_code(&led_state);
+        apply_led_report(KeyboardLedsReport::default(), &mut led_state, &mut caps_follower);
+        assert!(waiters.notify(before, led_state_code(&led_state)).is_empty());
+
+        // the first one gives up; its timeout finds it exactly once
+        assert_eq!(waiters.expire(first), Some("first"));
+        assert_eq!(waiters.expire(first), None);
+
+        // caps lock goes on: the remaining waiter is released, and sees the new state
+        let before = led_state_code(&led_state);
+        let report = KeyboardLedsReport::unpack_from_slice(&[0x02]).unwrap();
+        apply_led_report(report, &mut led_state, &mut caps_follower);
+        assert_eq!(waiters.notify(before, led_state_code(&led_state)), vec!["second"]);
+        assert_eq!(led_state_code(&led_state), 0x02);
+        // its timeout arrives late, and finds nothing to do
+        assert_eq!(waiters.expire(second), None);
+    }
+    #[test]
+    fn test_drain_for_quit() {
+        // record the report and disconnect the way the hosted implementation sees them, in order
+        let events = std::cell::RefCell::new(Vec::new());
+        drain_for_quit(
+            |codes| events.borrow_mut().push(Some(codes.to_vec())),
+            || events.borrow_mut().push(None),
+        );
+        assert_eq!(events.into_inner(), vec![Some(Vec::<Keyboard>::new()), None]);
+    }
+    /// Types a long string against a simulated scheduler where every sleep wakes up late by a random
+    /// 0-10ms, as it would with other servers competing for the CPU, and returns the variance of the
+    /// gaps between key events.
+    fn burst_gap_variance(boosted: bool) -> f64 {
+        use rand_chacha::ChaCha8Rng;
+        use rand_chacha::rand_core::SeedableRng;
+        use rand_chacha::rand_core::RngCore;
+        let rng = std::cell::RefCell::new(ChaCha8Rng::seed_from_u64(0x5eed));
+        let time = std::cell::Cell::new(1000u64);
+        let mut pacer = BurstPacer::new(boosted, STRING_KEY_INTERVAL_MS, time.get());
+        let mut events = vec![time.get()];
+        for _ in 0..400 {
+            pacer.wait(
+                || time.get(),
+                |ms| time.set(time.get() + ms as u64 + rng.borrow_mut().next_u32() as u64 % 11),
+                || time.set(time.get() + 1), // a yield gets the CPU back after one quantum
+            );
+            events.push(time.get());
+        }
+        let gaps: Vec<f64> = events.windows(2).map(|w| (w[1] - w[0]) as f64).collect();
+        let mean = gaps.iter().sum::<f64>() / gaps.len() as f64;
+        assert!(gaps.iter().all(|&g| g >= STRING_KEY_INTERVAL_MS as f64));
+        gaps.iter().map(|g| (g - mean) * (g - mean)).sum::<f64>() / gaps.len() as f64
+    }
+    #[test]
+    fn test_burst_priority_steadies_timing() {
+        let normal = burst_gap_variance(false);
+        let boosted = burst_gap_variance(true);
+        assert!(boosted < normal / 1.5, "inter-key variance: normal {:.2}, boosted {:.2}", normal, boosted);
+    }
+    #[test]
+    fn test_type_repeat() {
+        let repeat = TypeRepeat::default();
+        // one press, held for the typematic to produce the other three, and one release
+        assert_eq!(
+            type_events("aaaa", Some(repeat)),
+            vec![TypeEvent::Press('a', 4), TypeEvent::Hold(repeat.hold_ms(4)), TypeEvent::Release]
+        );
+        assert_eq!(repeat.hold_ms(4), 500 + 2 * 33 + 16);
+        // by default, every character is pressed and released on its own
+        let discrete = type_events("aaaa", None);
+        assert_eq!(discrete.len(), 12);
+        assert!(discrete.chunks(3).all(|keystroke| keystroke == [TypeEvent::Press('a', 1), TypeEvent::Hold(0), TypeEvent::Release]));
+        // only runs of the same character are coalesced
+        assert_eq!(
+            type_events("abba", Some(repeat)).iter().filter_map(|e| match e {
+                TypeEvent::Press(ch, count) => Some((*ch, *count)),
+                _ => None,
+            }).collect::<Vec<_>>(),
+            vec![('a', 1), ('b', 2), ('a', 1)]
+        );
+        assert_eq!(type_events("", Some(repeat)), vec![]);
+    }
+    /// Plays `steps` against a simulated clock where every sleep wakes up 1ms late, returning the
+    /// outcome and the (time, report) of each key report sent. `stop_at` is when a `StopMacro`
+    /// comes in, and `configured` says whether the device takes the report for a step.
+    fn run_macro(
+        steps: &[MacroStep],
+        stop_at: Option<u64>,
+        configured: impl Fn(usize) -> bool,
+    ) -> (MacroOutcome, Vec<(u64, Vec<Keyboard>)>) {
+        let time = std::cell::Cell::new(1000u64);
+        let mut reports = Vec::new();
+        let remap = KeyRemap::new();
+        let outcome = play_macro(
+            steps,
+            || time.get(),
+            |ms| time.set(time.get() + ms as u64 + 1),
+            |i, press| {
+                if !configured(i) {
+                    return false;
+                }
+                let codes = if press { macro_step_report(&steps[i], &remap) } else { Vec::new() };
+                reports.push((time.get() - 1000, codes));
+                true
+            },
+            || stop_at.map_or(false, |t| time.get() >= 1000 + t),
+        );
+        (outcome, reports)
+    }
+    #[test]
+    fn test_play_macro() {
+        let steps = [
+            // Ctrl+Shift+Esc
+            MacroStep { codes: [0x29, 0, 0, 0, 0, 0], modifier: 0b0011, hold_ms: 50, gap_ms: 100 },
+            MacroStep { codes: [0x04, 0x05, 0, 0, 0, 0], modifier: 0, hold_ms: 30, gap_ms: 0 },
+            MacroStep { codes: [0x28, 0, 0, 0, 0, 0], modifier: 0, hold_ms: 300, gap_ms: 10 },
+        ];
+        let (outcome, reports) = run_macro(&steps, None, |_| true);
+        assert_eq!(outcome, MacroOutcome::Completed);
+        let codes: Vec<Vec<Keyboard>> = reports.iter().map(|(_, codes)| codes.clone()).collect();
+        assert_eq!(codes, vec![
+            vec![Keyboard::LeftControl, Keyboard::LeftShift, Keyboard::Escape],
+            vec![],
+            vec![Keyboard::A, Keyboard::B],
+            vec![],
+            vec![Keyboard::ReturnEnter],
+            vec![],
+        ]);
+        // each event lands within a ms of its place in the schedule: late wakeups don't add up,
+        // even across the 300ms hold, which takes several sleeps to wait out
+        let schedule = [0, 50, 150, 180, 180, 480];
+        for (&(at, _), &expected) in reports.iter().zip(schedule.iter()) {
+            assert!(at >= expected && at <= expected + 1, "event at {}ms, expected {}ms", at, expected);
+        }
+
+        // stopped during the hold of the third step: its keys still come up
+        let (outcome, reports) = run_macro(&steps, Some(250), |_| true);
+        assert_eq!(outcome, MacroOutcome::Aborted(2));
+        assert_eq!(reports.len(), 6);
+        assert!(reports.last().unwrap().0 < 480);
+        // stopped in the gap after the first step
+        let (outcome, reports) = run_macro(&steps, Some(100), |_| true);
+        assert_eq!(outcome, MacroOutcome::Aborted(1));
+        assert_eq!(reports.len(), 2);
+        // the host went away before the second step
+        let (outcome, reports) = run_macro(&steps, None, |i| i < 1);
+        assert_eq!(outcome, MacroOutcome::Deconfigured(1));
+        assert_eq!(reports.len(), 2);
+
+        for outcome in vec![MacroOutcome::Completed, MacroOutcome::Aborted(2), MacroOutcome::Deconfigured(63), MacroOutcome::Busy] {
+            let (code, step) = macro_outcome_to_scalar(outcome);
+            assert_eq!(macro_outcome_from_scalar(code, step), outcome);
+        }
+    }
+    #[test]
+    fn test_remote_wakeup() {
+        let mut signaled = 0;
+        assert_eq!(remote_wakeup(UsbDeviceState::Configured, true, || { signaled += 1; true }), WakeupResult::NotSuspended);
+        assert_eq!(remote_wakeup(UsbDeviceState::Suspend, false, || { signaled += 1; true }), WakeupResult::NotArmed);
+        // the bus is only touched when the host is both suspended and armed
+        assert_eq!(signaled, 0);
+        assert_eq!(remote_wakeup(UsbDeviceState::Suspend, true, || { signaled += 1; true }), WakeupResult::Sent);
+        assert_eq!(remote_wakeup(UsbDeviceState::Suspend, true, || { signaled += 1; false }), WakeupResult::Unsupported);
+        assert_eq!(signaled, 2);
+        // the hosted core has no bus to signal on
+        let mut usbmgmt = SpinalUsbDevice {}.get_iface();
+        assert_eq!(remote_wakeup(UsbDeviceState::Suspend, true, || usbmgmt.signal_resume()), WakeupResult::Unsupported);
+    }
+    #[test]
+    fn test_link_watch() {
+        let mut watch = LinkWatch::new(UsbDeviceState::Default);
+        assert_eq!(watch.update(UsbDeviceState::Default), None);
+        assert_eq!(watch.update(UsbDeviceState::Addressed), Some((UsbDeviceState::Addressed, UsbDeviceState::Default)));
+        assert_eq!(watch.update(UsbDeviceState::Configured), Some((UsbDeviceState::Configured, UsbDeviceState::Addressed)));
+        assert_eq!(watch.update(UsbDeviceState::Configured), None);
+        // a cable pull shows up as a suspend, and plugging back in resumes where it left off
+        assert_eq!(watch.update(UsbDeviceState::Suspend), Some((UsbDeviceState::Suspend, UsbDeviceState::Configured)));
+        assert_eq!(watch.update(UsbDeviceState::Configured), Some((UsbDeviceState::Configured, UsbDeviceState::Suspend)));
+    }
+    #[test]
+    fn test_key_queue() {
+        fn drain(queue: &mut KeyQueue) -> Vec<[usize; 3]> {
+            let mut reports = Vec::new();
+            while let Some(report) = queue.next_report() {
+                reports.push(report);
+                queue.sent();
+            }
+            reports
+        }
+        let mut queue = KeyQueue::new(4);
+        assert!(queue.is_idle());
+        assert_eq!(queue.next_report(), None);
+        // "abba": the release between a and b is folded into the press of b, but b pressed
+        // twice needs one in between
+        for &code in [0x04, 0x05, 0x05, 0x04].iter() {
+            assert!(queue.push([code, 0, 0], true));
+        }
+        assert!(!queue.push([0x06, 0, 0], true));
+        assert_eq!(drain(&mut queue), vec![
+            [0x04, 0, 0], [0x05, 0, 0], [0, 0, 0], [0x05, 0, 0], [0x04, 0, 0], [0, 0, 0],
+        ]);
+        assert!(queue.is_idle());
+
+        // keys held without auto-up stay down until the caller queues a release
+        assert!(queue.push([0x04, 0, 0], false));
+        assert!(queue.push([0x04, 0, 0], false));
+        assert!(queue.push([0, 0, 0], false));
+        assert_eq!(drain(&mut queue), vec![[0x04, 0, 0], [0x04, 0, 0], [0, 0, 0]]);
+
+        // a report the endpoint didn't take is offered again
+        assert!(queue.push([0x04, 0, 0], true));
+        assert_eq!(queue.next_report(), Some([0x04, 0, 0]));
+        assert_eq!(queue.next_report(), Some([0x04, 0, 0]));
+        queue.sent();
+        assert!(!queue.is_idle());
+        // the release is still owed after the queue empties, and a new press sharing a key waits for it
+        assert!(queue.push([0x05, 0x04, 0], true));
+        assert_eq!(drain(&mut queue), vec![[0, 0, 0], [0x05, 0x04, 0], [0, 0, 0]]);
+
+        // a smaller depth leaves what's queued alone
+        for _ in 0..3 {
+            assert!(queue.push([0x04, 0, 0], true));
+        }
+        queue.set_depth(1);
+        assert!(!queue.push([0x04, 0, 0], true));
+        queue.clear();
+        assert!(queue.is_idle());
+        assert!(queue.push([0x04, 0, 0], true));
+    }
+    #[test]
+    fn test_device_address() {
+        let mut usbmgmt = SpinalUsbDevice {}.get_iface();
+        assert_eq!(usbmgmt.device_address(), 0);
+        usbmgmt.connect_device_core(true);
+        assert_ne!(usbmgmt.device_address(), 0);
+        // a disconnect is a bus reset, which takes the address away again
+        usbmgmt.connect_device_core(false);
+        assert_eq!(usbmgmt.device_address(), 0);
+    }
+    #[test]
+    fn test_mouse_report() {
+        // relative motion goes over IPC as a byte, and comes back out with its sign
+        let report = mouse_report(MOUSE_BUTTON_LEFT as usize, (-5i8) as u8 as usize, 127i8 as u8 as usize);
+        assert_eq!((report.buttons, report.x, report.y), (MOUSE_BUTTON_LEFT, -5, 127));
+        let report = mouse_report((MOUSE_BUTTON_RIGHT | MOUSE_BUTTON_MIDDLE) as usize, (-128i8) as u8 as usize, 0);
+        assert_eq!((report.buttons, report.x, report.y), (0x6, -128, 0));
+    }
+    #[test]
+    fn test_vbus_state() {
+        let mut usbmgmt = SpinalUsbDevice {}.get_iface();
+        assert!(usbmgmt.vbus_present());
+        usbmgmt.set_simulated_vbus(false);
+        assert!(!usbmgmt.vbus_present());
+        // VBUS is independent of enumeration
+        usbmgmt.connect_device_core(true);
+        assert!(!usbmgmt.vbus_present());
+        usbmgmt.set_simulated_vbus(true);
+        assert!(usbmgmt.vbus_present());
+
+        // 5V reads as present, a floating pin doesn't
+        assert!(vbus_present(994));
+        assert!(!vbus_present(0));
+        assert!(!vbus_present(298));
+    }
+    #[test]
+    fn test_ep0_max_packet_size() {
+        for size in EP0_MAX_PACKET_SIZES {
+            assert_eq!(valid_ep0_max_packet_size(size as usize), Some(size));
+        }
+        for size in [0, 1, 7, 9, 24, 63, 65, 128, 512] {
+            assert_eq!(valid_ep0_max_packet_size(size), None);
+        }
+    }
+
+    use usb_device::UsbDirection;
+    /// Just enough of a bus to feed a SETUP packet to EP0 and capture what the device sends back.
+    struct MockBus {
+        state: std::sync::Arc<std::sync::Mutex<MockState>>,
+        next_ep: AtomicUsize,
+    }
+    #[derive(Default)]
+    struct MockState {
+        setup: Option<[u8; 8]>,
+        ep0_in: Vec<u8>,
+        /// an EP0 IN packet has been written and not yet reported as complete
+        ep0_in_pending: bool,
+    }
+    impl UsbBus for MockBus {
+        fn alloc_ep(
+            &mut self,
+            ep_dir: UsbDirection,
+            ep_addr: Option<EndpointAddress>,
+            _ep_type: EndpointType,
+            _max_packet_size: u16,
+            _interval: u8,
+        ) -> usb_device::Result<EndpointAddress> {
+            Ok(ep_addr.unwrap_or_else(||
+                EndpointAddress::from_parts(self.next_ep.fetch_add(1, Ordering::SeqCst) + 1, ep_dir)
+            ))
+        }
+        fn enable(&mut self) {}
+        fn reset(&self) {}
+        fn set_device_address(&self, _addr: u8) {}
+        fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> usb_device::Result<usize> {
+            if ep_addr.index() == 0 {
+                let mut state = self.state.lock().unwrap();
+                state.ep0_in.extend_from_slice(buf);
+                state.ep0_in_pending = true;
+            }
+            Ok(buf.len())
+        }
+        fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> usb_device::Result<usize> {
+            match (ep_addr.index(), self.state.lock().unwrap().setup.take()) {
+                (0, Some(setup)) => {
+                    buf[..8].copy_from_slice(&setup);
+                    Ok(8)
+                }
+                _ => Err(UsbError::WouldBlock),
+            }
+        }
+        fn set_ep0_out(&self) {}
+        fn set_stalled(&self, _ep_addr: EndpointAddress, _stalled: bool) {}
+        fn is_stalled(&self, _ep_addr: EndpointAddress) -> bool { false }
+        fn suspend(&self) {}
+        fn resume(&self) {}
+        fn poll(&self) -> usb_device::bus::PollResult {
+            let mut state = self.state.lock().unwrap();
+            // every packet is "sent" as soon as it's written, so multi-packet transfers keep moving
+            let ep_in_complete = if std::mem::take(&mut state.ep0_in_pending) { 1 } else { 0 };
+            let ep_setup = if state.setup.is_some() { 1 } else { 0 };
+            if ep_in_complete | ep_setup != 0 {
+                usb_device::bus::PollResult::Data { ep_out: 0, ep_in_complete, ep_setup }
+            } else {
+                usb_device::bus::PollResult::None
+            }
+        }
+    }
+
+    #[test]
+    fn test_ep0_max_packet_size_descriptor() {
+        for size in EP0_MAX_PACKET_SIZES {
+            let state = std::sync::Arc::new(std::sync::Mutex::new(MockState::default()));
+            let alloc = UsbBusAllocator::new(MockBus { state: state.clone(), next_ep: AtomicUsize::new(0) });
+            let mut usb_dev = build_device(&alloc, &DeviceIdentity::new("0123"), size, false);
+            // GET_DESCRIPTOR(DEVICE), 18 bytes
+            state.lock().unwrap().setup = Some([0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 18, 0]);
+            usb_dev.poll(&mut []);
+            let sent = state.lock().unwrap().ep0_in.clone();
+            assert!(sent.len() >= 8, "no device descriptor was sent for size {}", size);
+            assert_eq!(sent[1], 0x01, "not a device descriptor");
+            // bMaxPacketSize0
+            assert_eq!(sent[7], size);
+        }
+    }
+    /// Builds `set` on a mock bus and has the host read the configuration descriptor, returning the
+    /// (bInterfaceClass, bInterfaceSubClass, bInterfaceProtocol) of each interface in it, and the
+    /// number of interface association descriptors.
+    fn configuration_interfaces(set: InterfaceSet) -> (Vec<(u8, u8, u8)>, usize) {
+        let state = std::sync::Arc::new(std::sync::Mutex::new(MockState::default()));
+        let alloc = UsbBusAllocator::new(MockBus { state: state.clone(), next_ep: AtomicUsize::new(0) });
+        let clock = EmbeddedClock::new();
+        let mut stack = InterfaceStack::build(set, &alloc, &clock);
+        let mut usb_dev = build_device(&alloc, &DeviceIdentity::new("0123"), 8, set.needs_iads());
+        // GET_DESCRIPTOR(CONFIGURATION), up to 255 bytes
+        state.lock().unwrap().setup = Some([0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 0xFF, 0]);
+        for _ in 0..32 {
+            usb_dev.poll(&mut stack.classes());
+        }
+        let sent = state.lock().unwrap().ep0_in.clone();
+        assert!(sent.len() >= 9 && sent[1] == 0x02, "no configuration descriptor was sent");
+        assert_eq!(sent.len(), u16::from_le_bytes([sent[2], sent[3]]) as usize, "configuration descriptor was truncated");
+        // walk the descriptors
+        let mut interfaces = Vec::new();
+        let mut iads = 0;
+        let mut i = 0;
+        while i + 1 < sent.len() && sent[i] != 0 {
+            match sent[i + 1] {
+                0x04 => interfaces.push((sent[i + 5], sent[i + 6], sent[i + 7])),
+                0x0B => iads += 1,
+                _ => {}
+            }
+            i += sent[i] as usize;
+        }
+        (interfaces, iads)
+    }
+    #[test]
+    fn test_device_descriptor() {
+        let precursor = DeviceIdentity::new("0123");
+        let mut descriptor = DeviceDescriptor {
+            vid: 0x1234,
+            pid: 0xabcd,
+            manufacturer: xous_ipc::String::<64>::from_str("Example"),
+            product: xous_ipc::String::<64>::from_str("Gadget"),
+            serial: Some(xous_ipc::String::<64>::from_str("SN42")),
+        };
+        let identity = precursor.with_descriptor(&descriptor).unwrap();
+        assert_eq!((identity.vid, identity.pid, identity.manufacturer, identity.product, identity.serial_number),
+            (0x1234, 0xabcd, "Example", "Gadget", "SN42"));
+        // without an override, the serial number goes back to the default, even from an overridden one
+        descriptor.serial = None;
+        assert_eq!(identity.with_descriptor(&descriptor).unwrap().serial_number, "0123");
+        // sending the same strings again reuses them rather than leaking new copies
+        descriptor.serial = Some(xous_ipc::String::<64>::from_str("SN42"));
+        let again = identity.with_descriptor(&descriptor).unwrap();
+        assert_eq!(again, identity);
+        assert!(std::ptr::eq(again.manufacturer, identity.manufacturer));
+        assert!(std::ptr::eq(again.product, identity.product));
+        assert!(std::ptr::eq(again.serial_number, identity.serial_number));
+
+        // the host sees the new VID/PID in the device descriptor
+        let state = std::sync::Arc::new(std::sync::Mutex::new(MockState::default()));
+        let alloc = UsbBusAllocator::new(MockBus { state: state.clone(), next_ep: AtomicUsize::new(0) });
+        let mut usb_dev = build_device(&alloc, &identity, 8, false);
+        // GET_DESCRIPTOR(DEVICE), 18 bytes
+        state.lock().unwrap().setup = Some([0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 18, 0]);
+        for _ in 0..4 {
+            usb_dev.poll(&mut []);
+        }
+        let sent = state.lock().unwrap().ep0_in.clone();
+        assert_eq!(sent.len(), 18);
+        assert_eq!(u16::from_le_bytes([sent[8], sent[9]]), 0x1234);
+        assert_eq!(u16::from_le_bytes([sent[10], sent[11]]), 0xabcd);
+    }
+    #[test]
+    fn test_interface_set() {
+        assert_eq!(InterfaceSet::from_bits(0), None);
+        assert_eq!(InterfaceSet::from_bits(0x20), None);
+        let mut set = InterfaceSet::default();
+        assert!(set.contains(HidInterface::Keyboard) && set.contains(HidInterface::Mouse) && set.contains(HidInterface::Fido));
+        assert!(set.contains(HidInterface::Consumer));
+        set.remove(HidInterface::Consumer);
+        set.remove(HidInterface::Mouse);
+        assert!(!set.contains(HidInterface::Mouse));
+        set.insert(HidInterface::Mouse);
+        set.remove(HidInterface::Fido);
+        assert_eq!(InterfaceSet::from_bits(set.bits()), Some(set));
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![HidInterface::Keyboard, HidInterface::Mouse]);
+        for size in EP0_MAX_PACKET_SIZES {
+            assert!(InterfaceSet::from_bits(0x7).unwrap().fits(size));
+            // the serial port's three endpoints fit alongside everything else
+            assert!(InterfaceSet::from_bits(0x1F).unwrap().fits(size));
+        }
+
+        // rebuild with keyboard + mouse, and read back the interfaces the host would see
+        let alloc = UsbBusAllocator::new(MockBus { state: Default::default(), next_ep: AtomicUsize::new(0) });
+        let clock = EmbeddedClock::new();
+        let mut stack = InterfaceStack::build(set, &alloc, &clock);
+        assert!(stack.keyboard().is_some());
+        assert!(stack.mouse().is_some());
+        assert!(stack.fido().is_none());
+        let (interfaces, iads) = configuration_interfaces(set);
+        assert_eq!(iads, 0);
+        // HID boot keyboard, then HID boot mouse
+        assert_eq!(interfaces, vec![(0x03, 0x01, 0x01), (0x03, 0x01, 0x02)]);
+    }
+    #[test]
+    fn test_serial_interface() {
+        let set = InterfaceSet::from_bits(HidInterface::Keyboard as usize | HidInterface::Serial as usize).unwrap();
+        assert!(set.needs_iads() && !InterfaceSet::default().needs_iads());
+        let (interfaces, iads) = configuration_interfaces(set);
+        // HID boot keyboard, then the CDC-ACM control and data interfaces, grouped by an IAD
+        assert_eq!(interfaces, vec![(0x03, 0x01, 0x01), (0x02, 0x02, 0x00), (0x0A, 0x00, 0x00)]);
+        assert_eq!(iads, 1);
+
+        assert_eq!(serial_result(Ok(5)), (SerialResult::Ok, 5));
+        // a full buffer is reported, not dropped
+        assert_eq!(serial_result(Err(UsbError::WouldBlock)), (SerialResult::WouldBlock, 0));
+        assert_eq!(serial_result(Err(UsbError::InvalidState)), (SerialResult::NotConnected, 0));
+    }
+    #[test]
+    fn test_consumer_interface() {
+        let report = consumer_report(Consumer::VolumeIncrement as usize);
+        assert_eq!(report.codes, [Consumer::VolumeIncrement, Consumer::Unassigned, Consumer::Unassigned, Consumer::Unassigned]);
+        assert_eq!(consumer_report(0).codes, [Consumer::Unassigned; 4]);
+
+        let set = InterfaceSet::default();
+        let alloc = UsbBusAllocator::new(MockBus { state: Default::default(), next_ep: AtomicUsize::new(0) });
+        let clock = EmbeddedClock::new();
+        let mut stack = InterfaceStack::build(set, &alloc, &clock);
+        assert!(stack.consumer().is_some());
+        assert_eq!(stack.classes().len(), 4);
+        // consumer control isn't a boot device, and comes after the interfaces that were already there
+        let (interfaces, _) = configuration_interfaces(set);
+        assert_eq!(interfaces.len(), 4);
+        assert_eq!(interfaces[3], (0x03, 0x00, 0x00));
+    }
+    #[test]
+    fn test_alloc_snapshot() {
+        let mut allocs = BTreeMap::<u32, u32>::new();
+        alloc_inner(&mut allocs, 64).unwrap();
+        let hole = alloc_inner(&mut allocs, 30).unwrap();
+        alloc_inner(&mut allocs, 128).unwrap();
+        dealloc_inner(&mut allocs, hole);
+        let snap = snapshot(&allocs);
+
+        // a restored snapshot allocates exactly as the original would
+        let mut replay = BTreeMap::<u32, u32>::new();
+        assert_eq!(restore(&mut replay, &snap), Ok(()));
+        assert_eq!(replay, allocs);
+        assert_eq!(alloc_inner(&mut replay, 30), alloc_inner(&mut allocs, 30));
+
+        // overlapping, misaligned, and out-of-range snapshots are rejected and leave the state alone
+        let before = replay.clone();
+        let overlap = [(START_OFFSET, 80), (START_OFFSET + 64, 32)];
+        assert_eq!(restore(&mut replay, &overlap), Err(AllocError::Overlap(START_OFFSET + 64)));
+        let duplicate = [(START_OFFSET, 32), (START_OFFSET, 32)];
+        assert_eq!(restore(&mut replay, &duplicate), Err(AllocError::Overlap(START_OFFSET)));
+        let misaligned = [(START_OFFSET + 8, 32)];
+        assert_eq!(restore(&mut replay, &misaligned), Err(AllocError::Misaligned(START_OFFSET + 8)));
+        let past_end = [(END_OFFSET - 16, 32)];
+        assert_eq!(restore(&mut replay, &past_end), Err(AllocError::OutOfRange(END_OFFSET - 16)));
+        assert_eq!(replay, before);
+    }
+    #[test]
+    fn test_can_alloc() {
+        let mut allocs = BTreeMap::<u32, u32>::new();
+        alloc_inner(&mut allocs, 64).unwrap();
+        let before = allocs.clone();
+
+        // a dry run reports the same offset the real allocation would get, without claiming it
+        let probe = can_alloc(&allocs, 30);
+        assert!(probe.is_some());
+        assert_eq!(allocs, before);
+        assert_eq!(alloc_inner(&mut allocs, 32), probe);
+
+        // anything past the end of the descriptor memory can't fit
+        let before = allocs.clone();
+        assert_eq!(can_alloc(&allocs, END_OFFSET - START_OFFSET), None);
+        assert_eq!(can_alloc(&allocs, u32::MAX), None);
+        assert_eq!(can_alloc(&allocs, 0), None);
+        assert_eq!(allocs, before);
+    }
+    #[test]
+    fn test_free_endpoint() {
+        let mut allocs = BTreeMap::<u32, u32>::new();
+        assert_eq!(alloc_inner(&mut allocs, 64), Some(START_OFFSET));
+        assert_eq!(alloc_inner(&mut allocs, 128), Some(START_OFFSET + 80));
+        assert_eq!(alloc_inner(&mut allocs, 64), Some(START_OFFSET + 80 + 144));
+        assert_eq!(snapshot(&allocs), vec![
+            (START_OFFSET, 80), (START_OFFSET + 80, 144), (START_OFFSET + 80 + 144, 80),
+        ]);
+
+        // free the middle region: it drops out of the dump, and only the exact offset frees it
+        assert_eq!(dealloc_inner(&mut allocs, START_OFFSET + 96), false);
+        assert_eq!(dealloc_inner(&mut allocs, START_OFFSET + 80), true);
+        assert_eq!(dealloc_inner(&mut allocs, START_OFFSET + 80), false);
+        assert_eq!(snapshot(&allocs), vec![(START_OFFSET, 80), (START_OFFSET + 80 + 144, 80)]);
+
+        // something too big for the hole goes after the last region...
+        assert_eq!(alloc_inner(&mut allocs, 256), Some(START_OFFSET + 80 + 144 + 80));
+        // ...and smaller ones fill the hole from the bottom, until it's full
+        assert_eq!(alloc_inner(&mut allocs, 32), Some(START_OFFSET + 80));
+        assert_eq!(alloc_inner(&mut allocs, 64), Some(START_OFFSET + 80 + 48));
+        assert_eq!(alloc_inner(&mut allocs, 32), Some(START_OFFSET + 80 + 144 + 80 + 272));
+
+        // the dump keeps the regions in order, and says how many didn't fit
+        let list = allocation_list(&snapshot(&allocs));
+        assert_eq!(list.len, 6);
+        assert_eq!(list.total, 6);
+        assert_eq!(&list.offsets[..3], &[START_OFFSET, START_OFFSET + 80, START_OFFSET + 128]);
+        assert_eq!(&list.lengths[..3], &[80, 48, 80]);
+        let many: Vec<(u32, u32)> = (0..MAX_ALLOCATIONS as u32 + 2).map(|i| (START_OFFSET + i * 32, 32)).collect();
+        let list = allocation_list(&many);
+        assert_eq!(list.len as usize, MAX_ALLOCATIONS);
+        assert_eq!(list.total as usize, MAX_ALLOCATIONS + 2);
+    }
+    #[test]
+    fn test_free_endpoint_live() {
+        let mut allocs = BTreeMap::<u32, u32>::new();
+        let ep = alloc_inner(&mut allocs, 64).unwrap();
+        let live = live_regions(&allocs);
+        // a region allocated after the build, e.g. by a dry run that went too far
+        let stray = alloc_inner(&mut allocs, 64).unwrap();
+
+        // the built stack's buffer can't be freed out from under it
+        assert_eq!(dealloc_unless_live(&mut allocs, &live, ep), None);
+        assert_eq!(snapshot(&allocs), vec![(ep, 80), (stray, 80)]);
+        // so nothing new can land on top of it
+        assert_eq!(alloc_inner(&mut allocs, 32), Some(stray + 80));
+
+        // anything else frees as usual, and its hole is reused
+        assert_eq!(dealloc_unless_live(&mut allocs, &live, stray), Some(true));
+        assert_eq!(dealloc_unless_live(&mut allocs, &live, stray), Some(false));
+        assert_eq!(alloc_inner(&mut allocs, 64), Some(stray));
+    }
+    #[test]
+    fn test_alloc_best_fit() {
+        // on an empty map, or with only the tail free, both strategies agree, down to the rounding
+        let mut first = BTreeMap::<u32, u32>::new();
+        let mut best = BTreeMap::<u32, u32>::new();
+        for &size in [64, 30, 128, 8].iter() {
+            assert_eq!(alloc_best_fit(&mut best, size), alloc_inner(&mut first, size));
+        }
+        assert_eq!(best, first);
+        assert_eq!(alloc_best_fit(&mut best, 0), None);
+
+        // fill the memory, then free a 160-byte region at the bottom and an 80-byte one above it
+        let mut allocs = BTreeMap::<u32, u32>::new();
+        assert_eq!(alloc_inner(&mut allocs, 144), Some(START_OFFSET));
+        assert_eq!(alloc_inner(&mut allocs, 64), Some(START_OFFSET + 160));
+        assert_eq!(alloc_inner(&mut allocs, 64), Some(START_OFFSET + 240));
+        assert_eq!(alloc_inner(&mut allocs, 64), Some(START_OFFSET + 320));
+        let rest = END_OFFSET - (START_OFFSET + 400) - 16;
+        assert_eq!(alloc_inner(&mut allocs, rest), Some(START_OFFSET + 400));
+        assert!(dealloc_inner(&mut allocs, START_OFFSET));
+        assert!(dealloc_inner(&mut allocs, START_OFFSET + 240));
+
+        // first-fit splits the big hole for the small request, and then the big request has
+        // nowhere to go, though 240 bytes are free in all
+        let mut first = allocs.clone();
+        assert_eq!(alloc_inner(&mut first, 64), Some(START_OFFSET));
+        assert_eq!(alloc_inner(&mut first, 144), None);
+        // best-fit puts the small request in the small hole, and both fit
+        let mut best = allocs.clone();
+        assert_eq!(alloc_best_fit(&mut best, 64), Some(START_OFFSET + 240));
+        assert_eq!(alloc_best_fit(&mut best, 144), Some(START_OFFSET));
+        assert_eq!(alloc_best_fit(&mut best, 16), None);
+    }
+    #[test]
     fn test_alloc() {
         use rand_chacha::ChaCha8Rng;
         use rand_chacha::rand_core::SeedableRng;
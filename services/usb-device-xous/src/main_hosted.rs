@@ -90,6 +90,30 @@ pub(crate) fn main_hosted() -> ! {
     let mut fido_listener_pid: Option<NonZeroU8> = None;
     let mut fido_rx_queue = VecDeque::<[u8; 64]>::new();
 
+    let clock = EmbeddedClock::new();
+    let mut autoup_timing = AutoUp::new();
+    let mut burst_priority = false;
+    let mut type_repeat: Option<TypeRepeat> = None;
+    let mut ep0_max_packet_size = EP0_MAX_PACKET_SIZE_DEFAULT;
+    // there's no SoC DNA to take a serial number from in hosted mode
+    let mut identity = DeviceIdentity::new("0");
+    let mut enabled = InterfaceSet::default();
+    // there's no host to send LED reports in hosted mode; they only arrive through InjectLedReport
+    let mut led_state = KeyboardLedsReport::default();
+    let mut caps_follower = CapsFollower::new();
+    let mut key_remap = KeyRemap::new();
+    let mut key_queue = KeyQueue::new(KEY_QUEUE_DEPTH_DEFAULT);
+    // there's no link to change state in hosted mode, so subscribers are only ever told to go away
+    let mut link_cb_conns: [Option<ScalarCallback>; 32] = [None; 32];
+    let mut led_cb_conns: [Option<ScalarCallback>; 32] = [None; 32];
+    let mut led_waiters = LedWaiters::<xous::MessageSender>::new();
+    // buttons held down with `MouseButton`, which carry over into the reports for `MouseMove`
+    let mut mouse_buttons = 0usize;
+    // the caller of the macro being played, held until it's over, and the steps the player thread refers to
+    let mut macro_caller: Option<xous::MessageEnvelope> = None;
+    let mut macro_steps: Vec<MacroStep> = Vec::new();
+    let macro_stop = Arc::new(AtomicBool::new(false));
+
     let mut lockstatus_force_update = true; // some state to track if we've been through a susupend/resume, to help out the status thread with its UX update after a restart-from-cold
 
     loop {
@@ -240,19 +264,285 @@ pub(crate) fn main_hosted() -> ! {
             Some(Opcode::LinkStatus) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
                 xous::return_scalar(msg.sender, 0).unwrap();
             }),
-            Some(Opcode::SendKeyCode) => {
-                xous::return_scalar(msg.sender, 1).unwrap();
+            Some(Opcode::RegisterLinkStatusCallback) => {
+                let buffer = unsafe { Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
+                let hookdata = buffer.to_original::<ScalarHook, _>().unwrap();
+                do_hook(hookdata, &mut link_cb_conns);
+            }
+            Some(Opcode::RegisterLedCallback) => {
+                let buffer = unsafe { Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
+                let hookdata = buffer.to_original::<ScalarHook, _>().unwrap();
+                do_hook(hookdata, &mut led_cb_conns);
             }
+            Some(Opcode::SendKeyCode) => msg_blocking_scalar_unpack!(msg, code0, code1, code2, autoup, {
+                // the reports go to the log, with the same key-down/key-up pacing as on hardware
+                autoup_timing.keystroke(&keycode_report(&[code0, code1, code2], &key_remap), autoup == 1,
+                    |codes| log::debug!("key report: {:?}", codes),
+                    |ms| { tt.sleep_ms(ms).ok(); },
+                );
+                xous::return_scalar(msg.sender, 1).unwrap();
+            }),
+            Some(Opcode::SetAutoUpDelay) => msg_scalar_unpack!(msg, ms, _, _, _, {
+                autoup_timing.set_delay(ms);
+            }),
+            Some(Opcode::SetBurstPriority) => msg_scalar_unpack!(msg, enable, _, _, _, {
+                burst_priority = enable != 0;
+            }),
+            Some(Opcode::SetTypeRepeatMode) => msg_scalar_unpack!(msg, delay_ms, interval_ms, _, _, {
+                type_repeat = if delay_ms != 0 && interval_ms != 0 {
+                    Some(TypeRepeat { delay_ms: delay_ms as u32, interval_ms: interval_ms as u32 })
+                } else {
+                    None
+                };
+            }),
+            Some(Opcode::EnqueueKeys) => msg_blocking_scalar_unpack!(msg, code0, code1, code2, autoup, {
+                if key_queue.push([code0, code1, code2], autoup == 1) {
+                    // no host to poll for them: the reports go out, to the log, right away
+                    while let Some(codes) = key_queue.next_report() {
+                        log::debug!("queued key report: {:?}", keycode_report(&codes, &key_remap));
+                        key_queue.sent();
+                    }
+                    xous::return_scalar(msg.sender, 0).unwrap();
+                } else {
+                    xous::return_scalar(msg.sender, 2).unwrap();
+                }
+            }),
+            Some(Opcode::SetKeyQueueDepth) => msg_scalar_unpack!(msg, depth, _, _, _, {
+                key_queue.set_depth(depth);
+            }),
+            Some(Opcode::SendConsumerCode) => msg_blocking_scalar_unpack!(msg, code, autoup, _, _, {
+                log::debug!("consumer report: {:?}", consumer_report(code).codes);
+                // mimic the key-down/key-up pacing of the hardware implementation
+                if autoup == 1 {
+                    tt.sleep_ms(autoup_timing.delay_ms() * 2).ok();
+                } else {
+                    tt.sleep_ms(autoup_timing.delay_ms()).ok();
+                }
+                xous::return_scalar(msg.sender, 1).unwrap();
+            }),
             Some(Opcode::SendString) => {
                 let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
                 let usb_send = buffer.to_original::<api::UsbString, _>().unwrap(); // suppress mut warning on hosted mode
+                // mimic the key-down/key-up pacing of the hardware implementation
+                let mut pacer = BurstPacer::new(burst_priority, STRING_KEY_INTERVAL_MS, clock.elapsed_ms());
+                for event in type_events(usb_send.s.as_str().unwrap(), type_repeat) {
+                    match event {
+                        TypeEvent::Press(_, _) => {}
+                        TypeEvent::Hold(ms) => {
+                            if ms > 0 {
+                                tt.sleep_ms(ms).ok();
+                            }
+                            pacer.wait(|| clock.elapsed_ms(), |ms| { tt.sleep_ms(ms).ok(); }, xous::yield_slice);
+                        }
+                        TypeEvent::Release => pacer.wait(|| clock.elapsed_ms(), |ms| { tt.sleep_ms(ms).ok(); }, xous::yield_slice),
+                    }
+                }
                 buffer.replace(usb_send).unwrap();
             }
-            Some(Opcode::GetLedState) => {
+            Some(Opcode::PlayMacro) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut key_macro = buffer.to_original::<KeyMacro, _>().unwrap();
+                if macro_caller.is_some() {
+                    key_macro.outcome = MacroOutcome::Busy;
+                    buffer.replace(key_macro).unwrap();
+                } else {
+                    let len = (key_macro.len as usize).min(MAX_MACRO_STEPS);
+                    macro_steps = key_macro.steps[..len].to_vec();
+                    macro_stop.store(false, Ordering::SeqCst);
+                    spawn_macro_player(cid, macro_steps.clone(), macro_stop.clone());
+                    // no reply now: the caller stays blocked until the player sends `MacroDone`
+                    macro_caller = Some(msg);
+                }
+            }
+            Some(Opcode::StopMacro) => {
+                macro_stop.store(true, Ordering::SeqCst);
+            }
+            Some(Opcode::MacroReport) => msg_blocking_scalar_unpack!(msg, step, press, _, _, {
+                match macro_steps.get(step) {
+                    Some(step) => {
+                        let codes = if press != 0 { macro_step_report(step, &key_remap) } else { Vec::new() };
+                        log::debug!("macro key report: {:?}", codes);
+                        xous::return_scalar(msg.sender, 1).unwrap();
+                    }
+                    None => xous::return_scalar(msg.sender, 0).unwrap(),
+                }
+            }),
+            Some(Opcode::MacroDone) => msg_scalar_unpack!(msg, code, step, _, _, {
+                let outcome = macro_outcome_from_scalar(code, step);
+                log::info!("macro finished: {:?}", outcome);
+                if let Some(mut caller) = macro_caller.take() {
+                    let mut buffer = unsafe { Buffer::from_memory_message_mut(caller.body.memory_message_mut().unwrap()) };
+                    let mut key_macro = buffer.to_original::<KeyMacro, _>().unwrap();
+                    key_macro.outcome = outcome;
+                    buffer.replace(key_macro).unwrap();
+                    // dropping `caller` returns the buffer and unblocks the caller
+                }
+                macro_steps.clear();
+            }),
+            Some(Opcode::MouseMove) | Some(Opcode::MouseButton) => msg_blocking_scalar_unpack!(msg, arg1, arg2, _, _, {
+                let report = match opcode {
+                    Some(Opcode::MouseButton) => {
+                        mouse_buttons = arg1;
+                        mouse_report(mouse_buttons, 0, 0)
+                    }
+                    _ => mouse_report(mouse_buttons, arg1, arg2),
+                };
+                log::debug!("mouse report: buttons {:x} x {} y {}", report.buttons, report.x, report.y);
                 xous::return_scalar(msg.sender, 0).unwrap();
+            }),
+            Some(Opcode::SerialWrite) | Some(Opcode::SerialRead) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut serial_data = buffer.to_original::<SerialData, _>().unwrap();
+                if matches!(opcode, Some(Opcode::SerialWrite)) {
+                    // there's no host to read the port in hosted mode, so it goes to the log
+                    let len = (serial_data.len as usize).min(SERIAL_IPC_LEN);
+                    log::info!("serial: {}", String::from_utf8_lossy(&serial_data.data[..len]));
+                    serial_data.result = SerialResult::Ok;
+                    serial_data.len = len as u32;
+                } else {
+                    serial_data.result = SerialResult::WouldBlock;
+                    serial_data.len = 0;
+                }
+                buffer.replace(serial_data).unwrap();
             }
+            Some(Opcode::GetLedState) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                xous::return_scalar(msg.sender, led_state_code(&led_state)).unwrap();
+            }),
+            Some(Opcode::WaitLedChange) => {
+                let timeout_ms = msg.body.scalar_message().unwrap().arg1;
+                // no reply now: the caller stays blocked until the LEDs change or the timeout fires
+                let serial = led_waiters.park(msg.sender);
+                if timeout_ms != 0 {
+                    spawn_led_wait_timeout(cid, serial, timeout_ms);
+                }
+            }
+            Some(Opcode::LedWaitTimeout) => msg_scalar_unpack!(msg, serial, _, _, _, {
+                if let Some(sender) = led_waiters.expire(serial) {
+                    xous::return_scalar2(sender, led_state_code(&led_state), 1).unwrap();
+                }
+            }),
+            Some(Opcode::InjectLedReport) => msg_blocking_scalar_unpack!(msg, code, _, _, _, {
+                match KeyboardLedsReport::unpack_from_slice(&[code as u8]) {
+                    Ok(report) => {
+                        let before = led_state_code(&led_state);
+                        if let Some((dout, mask)) = apply_led_report(report, &mut led_state, &mut caps_follower) {
+                            llio.gpio_data_out_masked(dout, mask).ok();
+                        }
+                        for sender in led_waiters.notify(before, led_state_code(&led_state)) {
+                            xous::return_scalar2(sender, led_state_code(&led_state), 0).unwrap();
+                        }
+                        if led_state_code(&led_state) != before {
+                            send_event(&led_cb_conns, led_state_code(&led_state), before);
+                        }
+                        xous::return_scalar(msg.sender, 1).unwrap();
+                    }
+                    Err(_) => xous::return_scalar(msg.sender, 0).unwrap(),
+                }
+            }),
+            Some(Opcode::SetKeyRemap) => {
+                let buffer = unsafe { Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
+                let table = buffer.to_original::<KeyRemapTable, _>().unwrap();
+                key_remap.set(&table);
+                log::info!("key remap set with {} entries", table.len);
+            }
+            Some(Opcode::ClearKeyRemap) => {
+                key_remap.clear();
+                log::info!("key remap cleared");
+            }
+            Some(Opcode::SetEp0MaxPacketSize) => msg_blocking_scalar_unpack!(msg, size, _, _, _, {
+                match valid_ep0_max_packet_size(size) {
+                    Some(size) => {
+                        ep0_max_packet_size = size;
+                        xous::return_scalar(msg.sender, 1).unwrap();
+                    }
+                    None => xous::return_scalar(msg.sender, 0).unwrap(),
+                }
+            }),
+            Some(Opcode::SetDeviceDescriptor) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut request = buffer.to_original::<DescriptorRequest, _>().unwrap();
+                request.result = match identity.with_descriptor(&request.descriptor) {
+                    Some(new_identity) => {
+                        log::info!("device descriptor {:?} -> {:?}", identity, new_identity);
+                        identity = new_identity;
+                        DescriptorResult::Ok
+                    }
+                    None => {
+                        log::warn!("device descriptor has a string that isn't UTF-8; refused");
+                        DescriptorResult::InvalidString
+                    }
+                };
+                buffer.replace(request).unwrap();
+            }
+            Some(Opcode::GetEp0MaxPacketSize) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                xous::return_scalar(msg.sender, ep0_max_packet_size as usize).unwrap();
+            }),
+            Some(Opcode::SetCapsFollower) => msg_scalar_unpack!(msg, pin, enable, _, _, {
+                let pin = if enable != 0 { Some(pin as u8) } else { None };
+                let previous = caps_follower.pin();
+                if caps_follower.set_pin(pin) {
+                    // only the follower's own pin changes direction, and a pin it let go of goes back to
+                    // being an input; the rest of the port is left as it was
+                    if let Some(previous) = previous.filter(|&previous| Some(previous) != pin) {
+                        llio.gpio_data_direction_masked(0, 1 << previous).ok();
+                    }
+                    if let Some(pin) = pin {
+                        llio.gpio_data_direction_masked(1 << pin, 1 << pin).ok();
+                    }
+                } else {
+                    log::warn!("caps lock follower: no such GPIO pin {}", pin.unwrap_or(0));
+                }
+            }),
+            Some(Opcode::SetEnabledInterfaces) => msg_blocking_scalar_unpack!(msg, bits, _, _, _, {
+                // no endpoint memory to run out of in hosted mode, so any valid set is accepted
+                match InterfaceSet::from_bits(bits) {
+                    Some(set) => {
+                        enabled = set;
+                        log::info!("enabled interfaces: {:?}", enabled.iter().collect::<Vec<_>>());
+                        xous::return_scalar(msg.sender, 1).unwrap();
+                    }
+                    None => xous::return_scalar(msg.sender, 0).unwrap(),
+                }
+            }),
+            Some(Opcode::GetLastEnumError) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                // there's no bus in hosted mode, so this only changes if something calls record_enum_error()
+                xous::return_scalar(msg.sender, last_enum_error().to_usize().unwrap()).unwrap();
+            }),
+            Some(Opcode::GetDeviceAddress) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                xous::return_scalar(msg.sender, usbmgmt.device_address() as usize).unwrap();
+            }),
+            Some(Opcode::RemoteWakeup) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                // the simulated link never suspends
+                let result = remote_wakeup(UsbDeviceState::Default, false, || usbmgmt.signal_resume());
+                xous::return_scalar(msg.sender, result.to_usize().unwrap()).unwrap();
+            }),
+            Some(Opcode::GetVbusState) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                xous::return_scalar(msg.sender, if usbmgmt.vbus_present() { 1 } else { 0 }).unwrap();
+            }),
+            Some(Opcode::SetSimulatedVbus) => msg_scalar_unpack!(msg, present, _, _, _, {
+                usbmgmt.set_simulated_vbus(present != 0);
+            }),
+            Some(Opcode::CanAllocate) => msg_blocking_scalar_unpack!(msg, _bytes, _, _, _, {
+                // there's no descriptor memory in hosted mode
+                xous::return_scalar2(msg.sender, 0, 0).unwrap();
+            }),
+            Some(Opcode::FreeEndpoint) => msg_blocking_scalar_unpack!(msg, _offset, _, _, _, {
+                // nothing was allocated, so there's nothing to free
+                xous::return_scalar(msg.sender, 0).unwrap();
+            }),
+            Some(Opcode::DumpAllocations) => {
+                // the caller's empty list goes back as-is
+            }
+            Some(Opcode::GetClockMillis) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                let now = clock.elapsed_ms();
+                xous::return_scalar2(msg.sender, now as u32 as usize, (now >> 32) as usize).unwrap();
+            }),
             Some(Opcode::Quit) => {
                 log::warn!("Quit received, goodbye world!");
+                drain_for_quit(
+                    |codes| log::info!("key report on quit: {:?}", codes),
+                    || usbmgmt.connect_device_core(false),
+                );
                 break;
             },
             None => {
@@ -261,6 +551,8 @@ pub(crate) fn main_hosted() -> ! {
         }
     }
     // clean up our program
+    unhook(&mut link_cb_conns);
+    unhook(&mut led_cb_conns);
     log::trace!("main loop exit, destroying servers");
     xns.unregister_server(usbdev_sid).unwrap();
     xous::destroy_server(usbdev_sid).unwrap();
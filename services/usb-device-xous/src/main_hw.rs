@@ -12,34 +12,13 @@ use core::num::NonZeroU8;
 use usb_device::prelude::*;
 use usb_device::class_prelude::*;
 use usbd_human_interface_device::page::Keyboard;
-use usbd_human_interface_device::device::keyboard::NKROBootKeyboardInterface;
 use usbd_human_interface_device::prelude::*;
-use num_enum::FromPrimitive as EnumFromPrimitive;
 
-use embedded_time::Clock;
 use std::convert::TryInto;
 use keyboard::KeyMap;
 use xous_ipc::Buffer;
 use std::collections::VecDeque;
 
-pub struct EmbeddedClock {
-    start: std::time::Instant,
-}
-impl EmbeddedClock {
-    pub fn new() -> EmbeddedClock {
-        EmbeddedClock { start: std::time::Instant::now() }
-    }
-}
-
-impl Clock for EmbeddedClock {
-    type T = u64;
-    const SCALING_FACTOR: embedded_time::fraction::Fraction = <embedded_time::fraction::Fraction>::new(1, 1_000);
-
-    fn try_now(&self) -> Result<embedded_time::Instant<Self>, embedded_time::clock::Error> {
-        Ok(embedded_time::Instant::new(self.start.elapsed().as_millis().try_into().unwrap()))
-    }
-}
-
 /// Time allowed for switchover between device core types. It's longer because some hosts
 /// get really confused when you have the same VID/PID show up with a different set of endpoints.
 const EXTENDED_CORE_RESET_MS: usize = 4000;
@@ -50,6 +29,76 @@ enum Views {
     FidoOnly = 1,
 }
 
+/// How many times the stacks may be rebuilt after start-up. An allocator is frozen once a device is
+/// built on it, so every rebuild needs fresh ones, and the ones replaced can't be reclaimed while the
+/// classes built on them are borrowed from them; they are leaked instead. The budget caps what repeated
+/// reconfiguration can cost the heap; a change that needs a rebuild past it is refused.
+const MAX_STACK_REBUILDS: usize = 8;
+
+/// Hands out fresh allocators for rebuilding the stacks, each on a pristine view of the hardware,
+/// until `MAX_STACK_REBUILDS` is spent.
+struct StackRebuilder {
+    template: SpinalUsbDevice,
+    rebuilds_left: usize,
+}
+impl StackRebuilder {
+    fn new(template: SpinalUsbDevice) -> Self {
+        StackRebuilder { template, rebuilds_left: MAX_STACK_REBUILDS }
+    }
+    fn can_rebuild(&self) -> bool {
+        self.rebuilds_left > 0
+    }
+    fn fresh_alloc(&self) -> &'static UsbBusAllocator<SpinalUsbDevice> {
+        Box::leak(Box::new(UsbBusAllocator::new(self.template.clone_unalloc())))
+    }
+}
+
+/// Rebuilds the `FidoWithKbd` view, and the `FidoOnly` view if `fido` is given, on fresh allocators
+/// after `enabled`, `identity` or `ep0_max_packet_size` changed. Returns the handle on the new
+/// composite allocation list and the regions the build took, or `None`, leaving the stacks as they
+/// were, once the rebuild budget is spent. The caller re-enumerates afterwards.
+fn rebuild_stacks<'a>(
+    rebuilder: &mut StackRebuilder,
+    (enabled, identity, ep0_max_packet_size): (InterfaceSet, &DeviceIdentity, u8),
+    clock: &'a EmbeddedClock,
+    composite: &mut InterfaceStack<'a, SpinalUsbDevice>,
+    usb_dev: &mut UsbDevice<'a, SpinalUsbDevice>,
+    fido: Option<(&mut FidoClass<'a, SpinalUsbDevice>, &mut UsbDevice<'a, SpinalUsbDevice>)>,
+) -> Option<(Arc<std::sync::Mutex<BTreeMap<u32, u32>>>, std::collections::BTreeSet<u32>)> {
+    if !rebuilder.can_rebuild() {
+        log::warn!("USB stacks already rebuilt {} times; refusing to rebuild again", MAX_STACK_REBUILDS);
+        return None;
+    }
+    rebuilder.rebuilds_left -= 1;
+    let fidokbd_alloc = rebuilder.fresh_alloc();
+    let fidokbd_allocs = fidokbd_alloc.alloc_tracker();
+    *composite = InterfaceStack::build(enabled, fidokbd_alloc, clock);
+    *usb_dev = build_device(fidokbd_alloc, identity, ep0_max_packet_size, enabled.needs_iads());
+    let live_allocs = live_regions(&fidokbd_allocs.lock().unwrap());
+    if let Some((fido_class, fido_dev)) = fido {
+        let fido_alloc = rebuilder.fresh_alloc();
+        *fido_class = UsbHidClassBuilder::new()
+            .add_interface(
+                RawFidoInterface::default_config()
+            )
+            .build(fido_alloc);
+        *fido_dev = build_device(fido_alloc, identity, ep0_max_packet_size, false);
+    }
+    Some((fidokbd_allocs, live_allocs))
+}
+
+/// Drops the device core off the bus for long enough that the host forgets it, and brings it back,
+/// so that the host enumerates whatever was rebuilt from scratch. Does nothing if it isn't connected.
+fn reenumerate(usbmgmt: &mut SpinalUsbMgmt, tt: &ticktimer_server::Ticktimer) {
+    if usbmgmt.is_device_connected() {
+        usbmgmt.ll_reset(true);
+        tt.sleep_ms(1000).ok();
+        usbmgmt.ll_connect_device_core(true);
+        tt.sleep_ms(EXTENDED_CORE_RESET_MS).ok();
+        usbmgmt.ll_reset(false);
+    }
+}
+
 pub(crate) fn main_hw() -> ! {
     log_server::init_wait().unwrap();
     log::set_max_level(log::LevelFilter::Info);
@@ -63,7 +112,8 @@ pub(crate) fn main_hw() -> ! {
     let native_kbd = keyboard::Keyboard::new(&xns).unwrap();
     let native_map = native_kbd.get_keymap().unwrap();
 
-    let serial_number = format!("{:x}", llio.soc_dna().unwrap());
+    // the serial number is the SoC's DNA, unless overridden with `SetDeviceDescriptor`
+    let mut identity = DeviceIdentity::new(&format!("{:x}", llio.soc_dna().unwrap()));
     let minimum_ver = SemVer {maj: 0, min: 9, rev: 8, extra: 20, commit: None};
     let soc_ver = llio.soc_gitrev().unwrap();
     if soc_ver < minimum_ver {
@@ -118,8 +168,14 @@ pub(crate) fn main_hw() -> ! {
     // before doing any allocs, clone a copy of the hardware access structure so we can build a second
     // view into the hardware with only FIDO descriptors
     let usb_fido_dev = usb_fidokbd_dev.clone_unalloc();
+    // an allocator is frozen once a device is built on it, so keep a pristine view around for
+    // rebuilding the stacks when the device-level configuration changes
+    let mut rebuilder = StackRebuilder::new(usb_fidokbd_dev.clone_unalloc());
+    let mut ep0_max_packet_size = EP0_MAX_PACKET_SIZE_DEFAULT;
     // track which view is visible on the device core
     let mut view = Views::FidoWithKbd;
+    // the interfaces that make up the `FidoWithKbd` view
+    let mut enabled = InterfaceSet::default();
 
     // register a suspend/resume listener
     let cid = xous::connect(usbdev_sid).expect("couldn't create suspend callback connection");
@@ -130,26 +186,20 @@ pub(crate) fn main_hw() -> ! {
         cid
     ).expect("couldn't create suspend/resume object");
 
+    // keep a handle on the allocation list of the composite view, for dry-run allocation queries
+    let mut fidokbd_allocs = usb_fidokbd_dev.alloc_tracker();
     let usb_alloc = UsbBusAllocator::new(usb_fidokbd_dev);
     let clock = EmbeddedClock::new();
 
-    let mut composite = UsbHidClassBuilder::new()
-        .add_interface(
-            NKROBootKeyboardInterface::default_config(&clock),
-        )
-        .add_interface(
-            RawFidoInterface::default_config()
-        )
-        .build(&usb_alloc);
+    let mut composite = InterfaceStack::build(enabled, &usb_alloc, &clock);
 
-    let mut usb_dev = UsbDeviceBuilder::new(&usb_alloc, UsbVidPid(0x1209, 0x3613))
-        .manufacturer("Kosagi")
-        .product("Precursor")
-        .serial_number(&serial_number)
-        .build();
-    let keyboard = composite.interface::<NKROBootKeyboardInterface<'_, _, _,>, _>();
-    keyboard.write_report(&Vec::<Keyboard>::new()).ok();
-    keyboard.tick().ok();
+    let mut usb_dev = build_device(&usb_alloc, &identity, ep0_max_packet_size, enabled.needs_iads());
+    // what the build above took from the descriptor memory; FreeEndpoint won't release any of it
+    let mut live_allocs = live_regions(&fidokbd_allocs.lock().unwrap());
+    if let Some(keyboard) = composite.keyboard() {
+        keyboard.write_report(&Vec::<Keyboard>::new()).ok();
+        keyboard.tick().ok();
+    }
 
     let fido_alloc = UsbBusAllocator::new(usb_fido_dev);
     let mut fido_class = UsbHidClassBuilder::new()
@@ -158,11 +208,7 @@ pub(crate) fn main_hw() -> ! {
         )
         .build(&fido_alloc);
 
-    let mut fido_dev = UsbDeviceBuilder::new(&fido_alloc, UsbVidPid(0x1209, 0x3613))
-    .manufacturer("Kosagi")
-    .product("Precursor")
-    .serial_number(&serial_number)
-    .build();
+    let mut fido_dev = build_device(&fido_alloc, &identity, ep0_max_packet_size, false);
 
     let mut led_state: KeyboardLedsReport = KeyboardLedsReport::default();
     let mut fido_listener: Option<xous::MessageEnvelope> = None;
@@ -173,6 +219,22 @@ pub(crate) fn main_hw() -> ! {
 
     let mut lockstatus_force_update = true; // some state to track if we've been through a susupend/resume, to help out the status thread with its UX update after a restart-from-cold
     let mut was_suspend = true;
+    let mut autoup_timing = AutoUp::new();
+    let mut burst_priority = false;
+    let mut type_repeat: Option<TypeRepeat> = None;
+    let mut caps_follower = CapsFollower::new();
+    let mut key_remap = KeyRemap::new();
+    let mut key_queue = KeyQueue::new(KEY_QUEUE_DEPTH_DEFAULT);
+    let mut link_cb_conns: [Option<ScalarCallback>; 32] = [None; 32];
+    let mut link_watch = LinkWatch::new(usb_dev.state());
+    let mut led_cb_conns: [Option<ScalarCallback>; 32] = [None; 32];
+    let mut led_waiters = LedWaiters::<xous::MessageSender>::new();
+    // buttons held down with `MouseButton`, which carry over into the reports for `MouseMove`
+    let mut mouse_buttons = 0usize;
+    // the caller of the macro being played, held until it's over, and the steps the player thread refers to
+    let mut macro_caller: Option<xous::MessageEnvelope> = None;
+    let mut macro_steps: Vec<MacroStep> = Vec::new();
+    let macro_stop = Arc::new(AtomicBool::new(false));
 
     loop {
         let mut msg = xous::receive_message(usbdev_sid).unwrap();
@@ -200,6 +262,13 @@ pub(crate) fn main_hw() -> ! {
                 }
                 // resume2 brings us to our last application state
                 usbmgmt.xous_resume2();
+                let link = match view {
+                    Views::FidoWithKbd => usb_dev.state(),
+                    Views::FidoOnly => fido_dev.state(),
+                };
+                if let Some((new, old)) = link_watch.update(link) {
+                    send_event(&link_cb_conns, new as usize, old as usize);
+                }
                 lockstatus_force_update = true; // notify the status bar that yes, it does need to redraw the lock status, even if the value hasn't changed since the last read
             }),
             Some(Opcode::IsSocCompatible) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
@@ -249,12 +318,17 @@ pub(crate) fn main_hw() -> ! {
                     assert_eq!(u2f_ipc.code, U2fCode::Tx, "Expected U2fCode::Tx in wrapper");
                     u2f_msg.packet.copy_from_slice(&u2f_ipc.data);
                     let u2f = match view {
-                        Views::FidoWithKbd => composite.interface::<RawFidoInterface<'_, _>, _>(),
-                        Views::FidoOnly => fido_class.interface::<RawFidoInterface<'_, _>, _>(),
+                        Views::FidoWithKbd => composite.fido(),
+                        Views::FidoOnly => Some(fido_class.interface::<RawFidoInterface<'_, _>, _>()),
                     };
-                    u2f.write_report(&u2f_msg).ok();
-                    log::debug!("sent U2F packet {:x?}", u2f_ipc.data);
-                    u2f_ipc.code = U2fCode::TxAck;
+                    if let Some(u2f) = u2f {
+                        u2f.write_report(&u2f_msg).ok();
+                        log::debug!("sent U2F packet {:x?}", u2f_ipc.data);
+                        u2f_ipc.code = U2fCode::TxAck;
+                    } else {
+                        log::warn!("U2F packet dropped: FIDO interface is not enabled");
+                        u2f_ipc.code = U2fCode::Denied;
+                    }
                 } else {
                     u2f_ipc.code = U2fCode::Denied;
                 }
@@ -263,16 +337,25 @@ pub(crate) fn main_hw() -> ! {
             Some(Opcode::UsbIrqHandler) => {
                 let maybe_u2f = match view {
                     Views::FidoWithKbd => {
-                        if usb_dev.poll(&mut [&mut composite]) {
-                            let keyboard = composite.interface::<NKROBootKeyboardInterface<'_, _, _,>, _>();
-                            match keyboard.read_report() {
-                                Ok(l) => {
-                                    log::info!("keyboard LEDs: {:?}", l);
-                                    led_state = l;
+                        if usb_dev.poll(&mut composite.classes()) {
+                            if let Some(keyboard) = composite.keyboard() {
+                                match keyboard.read_report() {
+                                    Ok(l) => {
+                                        let before = led_state_code(&led_state);
+                                        if let Some((dout, mask)) = apply_led_report(l, &mut led_state, &mut caps_follower) {
+                                            llio.gpio_data_out_masked(dout, mask).ok();
+                                        }
+                                        for sender in led_waiters.notify(before, led_state_code(&led_state)) {
+                                            xous::return_scalar2(sender, led_state_code(&led_state), 0).unwrap();
+                                        }
+                                        if led_state_code(&led_state) != before {
+                                            send_event(&led_cb_conns, led_state_code(&led_state), before);
+                                        }
+                                    }
+                                    Err(e) => log::trace!("KEYB ERR: {:?}", e),
                                 }
-                                Err(e) => log::trace!("KEYB ERR: {:?}", e),
                             }
-                            Some(composite.interface::<RawFidoInterface<'_, _>, _>())
+                            composite.fido()
                         } else {
                             None
                         }
@@ -306,12 +389,24 @@ pub(crate) fn main_hw() -> ! {
                         Err(e) => log::trace!("U2F ERR: {:?}", e),
                     }
                 }
+                if let Views::FidoWithKbd = view {
+                    if usb_dev.state() == UsbDeviceState::Configured {
+                        if let Some(keyboard) = composite.keyboard() {
+                            pump_key_queue(&mut key_queue, keyboard, &key_remap);
+                        }
+                    } else {
+                        key_queue.clear();
+                    }
+                }
 
-                let is_suspend = match view {
-                    Views::FidoWithKbd => usb_dev.state() == UsbDeviceState::Suspend,
-                    Views::FidoOnly => fido_dev.state() == UsbDeviceState::Suspend,
+                let link = match view {
+                    Views::FidoWithKbd => usb_dev.state(),
+                    Views::FidoOnly => fido_dev.state(),
                 };
-                if is_suspend {
+                if let Some((new, old)) = link_watch.update(link) {
+                    send_event(&link_cb_conns, new as usize, old as usize);
+                }
+                if link == UsbDeviceState::Suspend {
                     log::info!("suspend detected");
                     if was_suspend == false {
                         // FIDO listener needs to know when USB was unplugged, so that it can reset state per FIDO2 spec
@@ -478,36 +573,80 @@ pub(crate) fn main_hw() -> ! {
                     Views::FidoOnly => xous::return_scalar(msg.sender, fido_dev.state() as usize).unwrap(),
                 }
             }),
+            Some(Opcode::RegisterLinkStatusCallback) => {
+                let buffer = unsafe { Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
+                let hookdata = buffer.to_original::<ScalarHook, _>().unwrap();
+                do_hook(hookdata, &mut link_cb_conns);
+            }
+            Some(Opcode::RegisterLedCallback) => {
+                let buffer = unsafe { Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
+                let hookdata = buffer.to_original::<ScalarHook, _>().unwrap();
+                do_hook(hookdata, &mut led_cb_conns);
+            }
             Some(Opcode::SendKeyCode) => msg_blocking_scalar_unpack!(msg, code0, code1, code2, autoup, {
                 match view {
-                    Views::FidoWithKbd => {
+                    Views::FidoWithKbd if composite.keyboard.is_some() => {
                         if usb_dev.state() == UsbDeviceState::Configured {
-                            let mut codes = Vec::<Keyboard>::new();
-                            if code0 != 0 {
-                                codes.push(Keyboard::from_primitive(code0 as u8));
-                            }
-                            if code1 != 0 {
-                                codes.push(Keyboard::from_primitive(code1 as u8));
-                            }
-                            if code2 != 0 {
-                                codes.push(Keyboard::from_primitive(code2 as u8));
+                            let codes = keycode_report(&[code0, code1, code2], &key_remap);
+                            let keyboard = composite.keyboard().unwrap();
+                            autoup_timing.keystroke(&codes, autoup == 1,
+                                |codes| {
+                                    keyboard.write_report(codes).ok();
+                                    keyboard.tick().ok();
+                                },
+                                |ms| { tt.sleep_ms(ms).ok(); },
+                            );
+                            xous::return_scalar(msg.sender, 0).unwrap();
+                        } else {
+                            xous::return_scalar(msg.sender, 1).unwrap();
+                        }
+                    }
+                    _ => {
+                        xous::return_scalar(msg.sender, 1).unwrap();
+                    }
+                }
+            }),
+            Some(Opcode::EnqueueKeys) => msg_blocking_scalar_unpack!(msg, code0, code1, code2, autoup, {
+                match view {
+                    Views::FidoWithKbd if composite.keyboard.is_some() => {
+                        if usb_dev.state() == UsbDeviceState::Configured {
+                            if key_queue.push([code0, code1, code2], autoup == 1) {
+                                // an idle endpoint won't interrupt to ask for the first report
+                                pump_key_queue(&mut key_queue, composite.keyboard().unwrap(), &key_remap);
+                                xous::return_scalar(msg.sender, 0).unwrap();
+                            } else {
+                                log::warn!("key queue full, press dropped");
+                                xous::return_scalar(msg.sender, 2).unwrap();
                             }
-                            let auto_up = if autoup == 1 {true} else {false};
-                            let keyboard = composite.interface::<NKROBootKeyboardInterface<'_, _, _,>, _>();
-                            keyboard.write_report(&codes).ok();
-                            keyboard.tick().ok();
-                            tt.sleep_ms(30).ok();
-                            if auto_up {
-                                keyboard.write_report(&[]).ok(); // this is the key-up
-                                keyboard.tick().ok();
-                                tt.sleep_ms(30).ok();
+                        } else {
+                            xous::return_scalar(msg.sender, 1).unwrap();
+                        }
+                    }
+                    _ => {
+                        xous::return_scalar(msg.sender, 1).unwrap();
+                    }
+                }
+            }),
+            Some(Opcode::SetKeyQueueDepth) => msg_scalar_unpack!(msg, depth, _, _, _, {
+                key_queue.set_depth(depth);
+            }),
+            Some(Opcode::SendConsumerCode) => msg_blocking_scalar_unpack!(msg, code, autoup, _, _, {
+                match view {
+                    Views::FidoWithKbd if composite.consumer.is_some() => {
+                        if usb_dev.state() == UsbDeviceState::Configured {
+                            let consumer = composite.consumer().unwrap();
+                            consumer.write_report(&consumer_report(code)).ok();
+                            tt.sleep_ms(autoup_timing.delay_ms()).ok();
+                            if autoup == 1 {
+                                consumer.write_report(&consumer_report(0)).ok(); // this is the key-up
+                                tt.sleep_ms(autoup_timing.delay_ms()).ok();
                             }
                             xous::return_scalar(msg.sender, 0).unwrap();
                         } else {
                             xous::return_scalar(msg.sender, 1).unwrap();
                         }
                     }
-                    Views::FidoOnly => {
+                    _ => {
                         xous::return_scalar(msg.sender, 1).unwrap();
                     }
                 }
@@ -517,21 +656,36 @@ pub(crate) fn main_hw() -> ! {
                 let mut usb_send = buffer.to_original::<api::UsbString, _>().unwrap();
                 let mut sent = 0;
                 match view {
-                    Views::FidoWithKbd => {
-                        for ch in usb_send.s.as_str().unwrap().chars() {
-                            // ASSUME: user's keyboard type matches the preference on their Precursor device.
-                            let codes = match native_map {
-                                KeyMap::Dvorak => mappings::char_to_hid_code_dvorak(ch),
-                                _ => mappings::char_to_hid_code_us101(ch),
-                            };
-                            let keyboard = composite.interface::<NKROBootKeyboardInterface<'_, _, _,>, _>();
-                            keyboard.write_report(&codes).ok();
-                            keyboard.tick().ok();
-                            tt.sleep_ms(30).ok();
-                            keyboard.write_report(&[]).ok(); // this is the key-up
-                            keyboard.tick().ok();
-                            tt.sleep_ms(30).ok();
-                            sent += 1;
+                    Views::FidoWithKbd if composite.keyboard.is_some() => {
+                        let mut pacer = BurstPacer::new(burst_priority, STRING_KEY_INTERVAL_MS, clock.elapsed_ms());
+                        let mut pressed = 0;
+                        for event in type_events(usb_send.s.as_str().unwrap(), type_repeat) {
+                            let keyboard = composite.keyboard().unwrap();
+                            match event {
+                                TypeEvent::Press(ch, count) => {
+                                    // ASSUME: user's keyboard type matches the preference on their Precursor device.
+                                    let codes = match native_map {
+                                        KeyMap::Dvorak => mappings::char_to_hid_code_dvorak(ch),
+                                        _ => mappings::char_to_hid_code_us101(ch),
+                                    };
+                                    let codes: Vec<Keyboard> = codes.into_iter().map(|code| key_remap.remap(code)).collect();
+                                    keyboard.write_report(&codes).ok();
+                                    keyboard.tick().ok();
+                                    pressed = count as u32;
+                                }
+                                TypeEvent::Hold(ms) => {
+                                    if ms > 0 {
+                                        tt.sleep_ms(ms).ok();
+                                    }
+                                    pacer.wait(|| clock.elapsed_ms(), |ms| { tt.sleep_ms(ms).ok(); }, xous::yield_slice);
+                                }
+                                TypeEvent::Release => {
+                                    keyboard.write_report(&[]).ok(); // this is the key-up
+                                    keyboard.tick().ok();
+                                    pacer.wait(|| clock.elapsed_ms(), |ms| { tt.sleep_ms(ms).ok(); }, xous::yield_slice);
+                                    sent += pressed;
+                                }
+                            }
                         }
                     }
                     _ => {} // do nothing; will report that 0 characters were sent
@@ -539,13 +693,322 @@ pub(crate) fn main_hw() -> ! {
                 usb_send.sent = Some(sent);
                 buffer.replace(usb_send).unwrap();
             }
+            Some(Opcode::PlayMacro) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut key_macro = buffer.to_original::<KeyMacro, _>().unwrap();
+                if macro_caller.is_some() {
+                    key_macro.outcome = MacroOutcome::Busy;
+                    buffer.replace(key_macro).unwrap();
+                } else {
+                    let len = (key_macro.len as usize).min(MAX_MACRO_STEPS);
+                    macro_steps = key_macro.steps[..len].to_vec();
+                    macro_stop.store(false, Ordering::SeqCst);
+                    spawn_macro_player(cid, macro_steps.clone(), macro_stop.clone());
+                    // no reply now: the caller stays blocked until the player sends `MacroDone`
+                    macro_caller = Some(msg);
+                }
+            }
+            Some(Opcode::StopMacro) => {
+                macro_stop.store(true, Ordering::SeqCst);
+            }
+            Some(Opcode::MacroReport) => msg_blocking_scalar_unpack!(msg, step, press, _, _, {
+                let mut sent = false;
+                if view == Views::FidoWithKbd && usb_dev.state() == UsbDeviceState::Configured {
+                    if let (Some(keyboard), Some(step)) = (composite.keyboard(), macro_steps.get(step)) {
+                        let codes = if press != 0 { macro_step_report(step, &key_remap) } else { Vec::new() };
+                        keyboard.write_report(&codes).ok();
+                        keyboard.tick().ok();
+                        sent = true;
+                    }
+                }
+                xous::return_scalar(msg.sender, if sent { 1 } else { 0 }).unwrap();
+            }),
+            Some(Opcode::MacroDone) => msg_scalar_unpack!(msg, code, step, _, _, {
+                let outcome = macro_outcome_from_scalar(code, step);
+                log::info!("macro finished: {:?}", outcome);
+                if let Some(mut caller) = macro_caller.take() {
+                    let mut buffer = unsafe { Buffer::from_memory_message_mut(caller.body.memory_message_mut().unwrap()) };
+                    let mut key_macro = buffer.to_original::<KeyMacro, _>().unwrap();
+                    key_macro.outcome = outcome;
+                    buffer.replace(key_macro).unwrap();
+                    // dropping `caller` returns the buffer and unblocks the caller
+                }
+                macro_steps.clear();
+            }),
+            Some(Opcode::MouseMove) | Some(Opcode::MouseButton) => msg_blocking_scalar_unpack!(msg, arg1, arg2, _, _, {
+                let report = match opcode {
+                    Some(Opcode::MouseButton) => {
+                        mouse_buttons = arg1;
+                        mouse_report(mouse_buttons, 0, 0)
+                    }
+                    _ => mouse_report(mouse_buttons, arg1, arg2),
+                };
+                match view {
+                    Views::FidoWithKbd if composite.mouse.is_some() && usb_dev.state() == UsbDeviceState::Configured => {
+                        composite.mouse().unwrap().write_report(&report).ok();
+                        xous::return_scalar(msg.sender, 0).unwrap();
+                    }
+                    _ => {
+                        xous::return_scalar(msg.sender, 1).unwrap();
+                    }
+                }
+            }),
+            Some(Opcode::SerialWrite) | Some(Opcode::SerialRead) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut serial_data = buffer.to_original::<SerialData, _>().unwrap();
+                let port = match view {
+                    Views::FidoWithKbd if usb_dev.state() == UsbDeviceState::Configured => composite.serial.as_mut(),
+                    _ => None,
+                };
+                let (result, len) = match port {
+                    Some(port) => serial_result(if matches!(opcode, Some(Opcode::SerialWrite)) {
+                        port.write(&serial_data.data[..(serial_data.len as usize).min(SERIAL_IPC_LEN)])
+                    } else {
+                        port.read(&mut serial_data.data)
+                    }),
+                    None => (SerialResult::NotConnected, 0),
+                };
+                serial_data.result = result;
+                serial_data.len = len;
+                buffer.replace(serial_data).unwrap();
+            }
             Some(Opcode::GetLedState) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
-                let mut code = [0u8; 1];
-                led_state.pack_to_slice(&mut code).unwrap();
-                xous::return_scalar(msg.sender, code[0] as usize).unwrap();
+                xous::return_scalar(msg.sender, led_state_code(&led_state)).unwrap();
+            }),
+            Some(Opcode::WaitLedChange) => {
+                let timeout_ms = msg.body.scalar_message().unwrap().arg1;
+                // no reply now: the caller stays blocked until the LEDs change or the timeout fires
+                let serial = led_waiters.park(msg.sender);
+                if timeout_ms != 0 {
+                    spawn_led_wait_timeout(cid, serial, timeout_ms);
+                }
+            }
+            Some(Opcode::LedWaitTimeout) => msg_scalar_unpack!(msg, serial, _, _, _, {
+                if let Some(sender) = led_waiters.expire(serial) {
+                    xous::return_scalar2(sender, led_state_code(&led_state), 1).unwrap();
+                }
+            }),
+            Some(Opcode::SetAutoUpDelay) => msg_scalar_unpack!(msg, ms, _, _, _, {
+                log::info!("auto-up delay set to {}ms", autoup_timing.set_delay(ms));
+            }),
+            Some(Opcode::SetBurstPriority) => msg_scalar_unpack!(msg, enable, _, _, _, {
+                burst_priority = enable != 0;
+                log::info!("burst priority {}", if burst_priority { "on" } else { "off" });
+            }),
+            Some(Opcode::SetTypeRepeatMode) => msg_scalar_unpack!(msg, delay_ms, interval_ms, _, _, {
+                type_repeat = if delay_ms != 0 && interval_ms != 0 {
+                    Some(TypeRepeat { delay_ms: delay_ms as u32, interval_ms: interval_ms as u32 })
+                } else {
+                    None
+                };
+                log::info!("type repeat mode {:?}", type_repeat);
+            }),
+            Some(Opcode::SetKeyRemap) => {
+                let buffer = unsafe { Buffer::from_memory_message(msg.body.memory_message().unwrap()) };
+                let table = buffer.to_original::<KeyRemapTable, _>().unwrap();
+                key_remap.set(&table);
+                log::info!("key remap set with {} entries", table.len);
+            }
+            Some(Opcode::ClearKeyRemap) => {
+                key_remap.clear();
+                log::info!("key remap cleared");
+            }
+            Some(Opcode::SetEp0MaxPacketSize) => msg_blocking_scalar_unpack!(msg, size, _, _, _, {
+                match valid_ep0_max_packet_size(size) {
+                    Some(size) => {
+                        let rebuilt = size == ep0_max_packet_size || match rebuild_stacks(
+                            &mut rebuilder,
+                            (enabled, &identity, size),
+                            &clock,
+                            &mut composite,
+                            &mut usb_dev,
+                            Some((&mut fido_class, &mut fido_dev)),
+                        ) {
+                            Some((allocs, live)) => {
+                                log::info!("bMaxPacketSize0 {} -> {}", ep0_max_packet_size, size);
+                                ep0_max_packet_size = size;
+                                fidokbd_allocs = allocs;
+                                live_allocs = live;
+                                // the allocation pass rewrote the endpoint descriptors of whichever view
+                                // was built last; re-enumerate so the host sees a consistent device
+                                reenumerate(&mut usbmgmt, &tt);
+                                true
+                            }
+                            None => false,
+                        };
+                        xous::return_scalar(msg.sender, if rebuilt { 1 } else { 0 }).unwrap();
+                    }
+                    None => {
+                        log::warn!("invalid bMaxPacketSize0 requested: {}", size);
+                        xous::return_scalar(msg.sender, 0).unwrap();
+                    }
+                }
+            }),
+            Some(Opcode::SetDeviceDescriptor) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let mut request = buffer.to_original::<DescriptorRequest, _>().unwrap();
+                // check the budget first, so that a refused change doesn't leak its strings
+                request.result = if !rebuilder.can_rebuild() {
+                    log::warn!("USB stacks already rebuilt {} times; device descriptor refused", MAX_STACK_REBUILDS);
+                    DescriptorResult::RebuildLimit
+                } else {
+                    match identity.with_descriptor(&request.descriptor) {
+                        Some(new_identity) if new_identity != identity => {
+                            // both views advertise the identity, so both are rebuilt
+                            match rebuild_stacks(
+                                &mut rebuilder,
+                                (enabled, &new_identity, ep0_max_packet_size),
+                                &clock,
+                                &mut composite,
+                                &mut usb_dev,
+                                Some((&mut fido_class, &mut fido_dev)),
+                            ) {
+                                Some((allocs, live)) => {
+                                    log::info!("device descriptor {:?} -> {:?}", identity, new_identity);
+                                    identity = new_identity;
+                                    fidokbd_allocs = allocs;
+                                    live_allocs = live;
+                                    // hosts cache descriptors by the identity they enumerated, so make them forget
+                                    // it before coming back as the new one
+                                    reenumerate(&mut usbmgmt, &tt);
+                                    DescriptorResult::Ok
+                                }
+                                None => DescriptorResult::RebuildLimit,
+                            }
+                        }
+                        Some(_) => DescriptorResult::Ok,
+                        None => {
+                            log::warn!("device descriptor has a string that isn't UTF-8; refused");
+                            DescriptorResult::InvalidString
+                        }
+                    }
+                };
+                buffer.replace(request).unwrap();
+            }
+            Some(Opcode::GetEp0MaxPacketSize) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                xous::return_scalar(msg.sender, ep0_max_packet_size as usize).unwrap();
+            }),
+            Some(Opcode::SetCapsFollower) => msg_scalar_unpack!(msg, pin, enable, _, _, {
+                let pin = if enable != 0 { Some(pin as u8) } else { None };
+                let previous = caps_follower.pin();
+                if caps_follower.set_pin(pin) {
+                    // only the follower's own pin changes direction, and a pin it let go of goes back to
+                    // being an input; the rest of the port is left as it was
+                    if let Some(previous) = previous.filter(|&previous| Some(previous) != pin) {
+                        llio.gpio_data_direction_masked(0, 1 << previous).ok();
+                    }
+                    if let Some(pin) = pin {
+                        llio.gpio_data_direction_masked(1 << pin, 1 << pin).ok();
+                    }
+                } else {
+                    log::warn!("caps lock follower: no such GPIO pin {}", pin.unwrap_or(0));
+                }
+            }),
+            Some(Opcode::SetEnabledInterfaces) => msg_blocking_scalar_unpack!(msg, bits, _, _, _, {
+                match InterfaceSet::from_bits(bits) {
+                    Some(set) if set.fits(ep0_max_packet_size) => {
+                        let rebuilt = set == enabled || match rebuild_stacks(
+                            &mut rebuilder,
+                            (set, &identity, ep0_max_packet_size),
+                            &clock,
+                            &mut composite,
+                            &mut usb_dev,
+                            None,
+                        ) {
+                            Some((allocs, live)) => {
+                                log::info!("enabled interfaces {:?} -> {:?}", enabled.iter().collect::<Vec<_>>(), set.iter().collect::<Vec<_>>());
+                                enabled = set;
+                                fidokbd_allocs = allocs;
+                                live_allocs = live;
+                                // the build rewrote endpoint descriptors that the FIDO-only view shares, so
+                                // re-enumerate whichever view is on the bus
+                                reenumerate(&mut usbmgmt, &tt);
+                                true
+                            }
+                            None => false,
+                        };
+                        xous::return_scalar(msg.sender, if rebuilt { 1 } else { 0 }).unwrap();
+                    }
+                    _ => {
+                        log::warn!("can't enable interface set {:x}", bits);
+                        xous::return_scalar(msg.sender, 0).unwrap();
+                    }
+                }
+            }),
+            Some(Opcode::GetLastEnumError) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                xous::return_scalar(msg.sender, last_enum_error().to_usize().unwrap()).unwrap();
+            }),
+            Some(Opcode::GetDeviceAddress) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                xous::return_scalar(msg.sender, usbmgmt.device_address() as usize).unwrap();
+            }),
+            Some(Opcode::RemoteWakeup) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                let result = match view {
+                    Views::FidoWithKbd => remote_wakeup(usb_dev.state(), usb_dev.remote_wakeup_enabled(), || usbmgmt.signal_resume()),
+                    Views::FidoOnly => remote_wakeup(fido_dev.state(), fido_dev.remote_wakeup_enabled(), || usbmgmt.signal_resume()),
+                };
+                if result != WakeupResult::Sent {
+                    log::info!("remote wakeup not signaled: {:?}", result);
+                }
+                xous::return_scalar(msg.sender, result.to_usize().unwrap()).unwrap();
+            }),
+            Some(Opcode::GetVbusState) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                let present = match llio.adc_vbus() {
+                    Ok(adc) => vbus_present(adc),
+                    Err(e) => {
+                        log::warn!("couldn't read VBUS: {:?}", e);
+                        false
+                    }
+                };
+                xous::return_scalar(msg.sender, if present { 1 } else { 0 }).unwrap();
+            }),
+            Some(Opcode::SetSimulatedVbus) => msg_scalar_unpack!(msg, _, _, _, _, {
+                log::warn!("simulated VBUS is only available in hosted mode");
+            }),
+            Some(Opcode::CanAllocate) => msg_blocking_scalar_unpack!(msg, bytes, _, _, _, {
+                match can_alloc(&fidokbd_allocs.lock().unwrap(), bytes as u32) {
+                    Some(offset) => xous::return_scalar2(msg.sender, 1, offset as usize).unwrap(),
+                    None => xous::return_scalar2(msg.sender, 0, 0).unwrap(),
+                }
+            }),
+            Some(Opcode::FreeEndpoint) => msg_blocking_scalar_unpack!(msg, offset, _, _, _, {
+                let code = match dealloc_unless_live(&mut fidokbd_allocs.lock().unwrap(), &live_allocs, offset as u32) {
+                    Some(true) => 1,
+                    Some(false) => 0,
+                    None => {
+                        log::warn!("FreeEndpoint: region at 0x{:x} is in use by the current interfaces", offset);
+                        2
+                    }
+                };
+                xous::return_scalar(msg.sender, code).unwrap();
+            }),
+            Some(Opcode::DumpAllocations) => {
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(msg.body.memory_message_mut().unwrap()) };
+                let list = allocation_list(&snapshot(&fidokbd_allocs.lock().unwrap()));
+                buffer.replace(list).unwrap();
+            }
+            Some(Opcode::GetClockMillis) => msg_blocking_scalar_unpack!(msg, _, _, _, _, {
+                let now = clock.elapsed_ms();
+                xous::return_scalar2(msg.sender, now as u32 as usize, (now >> 32) as usize).unwrap();
             }),
             Some(Opcode::Quit) => {
                 log::warn!("Quit received, goodbye world!");
+                drain_for_quit(
+                    |codes| {
+                        if view == Views::FidoWithKbd && usb_dev.state() == UsbDeviceState::Configured {
+                            if let Some(keyboard) = composite.keyboard() {
+                                keyboard.write_report(codes).ok();
+                                keyboard.tick().ok();
+                                // give the host a chance to poll the report before the core drops off the bus
+                                tt.sleep_ms(autoup_timing.delay_ms()).ok();
+                            }
+                        }
+                    },
+                    || {
+                        if usbmgmt.is_device_connected() {
+                            usbmgmt.connect_device_core(false);
+                        }
+                    },
+                );
                 break;
             },
             None => {
@@ -554,6 +1017,8 @@ pub(crate) fn main_hw() -> ! {
         }
     }
     // clean up our program
+    unhook(&mut link_cb_conns);
+    unhook(&mut led_cb_conns);
     log::trace!("main loop exit, destroying servers");
     xns.unregister_server(usbdev_sid).unwrap();
     xous::destroy_server(usbdev_sid).unwrap();
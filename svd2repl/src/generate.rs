@@ -1,6 +1,6 @@
 use quick_xml::events::Event;
 use quick_xml::Reader;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::io::{BufRead, BufReader, Read, Write};
 
 #[derive(Debug)]
@@ -20,6 +20,7 @@ pub struct Field {
     lsb: usize,
     #[allow(dead_code)]
     msb: usize,
+    access: Option<String>,
 }
 
 #[derive(Default, Debug)]
@@ -32,6 +33,7 @@ pub struct Register {
     description: Option<String>,
     #[allow(dead_code)]
     fields: Vec<Field>,
+    access: Option<String>,
 }
 
 #[derive(Default, Debug)]
@@ -127,6 +129,7 @@ fn generate_field<T: BufRead>(reader: &mut Reader<T>) -> Result<Field, ParseErro
     let mut name = None;
     let mut lsb = None;
     let mut msb = None;
+    let mut access = None;
     loop {
         match reader.read_event(&mut buf) {
             Ok(Event::Start(ref e)) => {
@@ -137,6 +140,7 @@ fn generate_field<T: BufRead>(reader: &mut Reader<T>) -> Result<Field, ParseErro
                     "name" => name = Some(extract_contents(reader)?),
                     "lsb" => lsb = Some(parse_usize(extract_contents(reader)?.as_bytes())?),
                     "msb" => msb = Some(parse_usize(extract_contents(reader)?.as_bytes())?),
+                    "access" => access = Some(extract_contents(reader)?),
                     _ => (),
                 }
             }
@@ -154,6 +158,7 @@ fn generate_field<T: BufRead>(reader: &mut Reader<T>) -> Result<Field, ParseErro
         name: name.ok_or(ParseError::MissingValue)?,
         lsb: lsb.ok_or(ParseError::MissingValue)?,
         msb: msb.ok_or(ParseError::MissingValue)?,
+        access,
     })
 }
 
@@ -188,6 +193,7 @@ fn generate_register<T: BufRead>(reader: &mut Reader<T>) -> Result<Register, Par
     let mut offset = None;
     let description = None;
     let mut fields = vec![];
+    let mut access = None;
     loop {
         match reader.read_event(&mut buf) {
             Ok(Event::Start(ref e)) => {
@@ -200,6 +206,7 @@ fn generate_register<T: BufRead>(reader: &mut Reader<T>) -> Result<Register, Par
                         offset = Some(parse_usize(extract_contents(reader)?.as_bytes())?)
                     }
                     "fields" => generate_fields(reader, &mut fields)?,
+                    "access" => access = Some(extract_contents(reader)?),
                     _ => (),
                 }
             }
@@ -218,6 +225,7 @@ fn generate_register<T: BufRead>(reader: &mut Reader<T>) -> Result<Register, Par
         offset: offset.ok_or(ParseError::MissingValue)?,
         description,
         fields,
+        access,
     })
 }
 
@@ -486,6 +494,10 @@ fn parse_vendor_extensions<T: BufRead>(
     Ok(())
 }
 
+/// Renode numbers the VexRiscv's external interrupt lines from 1000, so SVD interrupt `n`
+/// is CPU input `1000 + n`.
+const CPU_IRQ_BASE: usize = 1000;
+
 fn print_header<U: Write>(out: &mut U) -> std::io::Result<()> {
     let s = r####"// Renode Platform file generated by svd2repl
 // This file is automatically generated
@@ -515,7 +527,7 @@ sysbus:
 
 fn print_memory_regions<U: Write>(
     regions: &[MemoryRegion],
-    cs_peripherals: &HashMap<&str, &str>,
+    cs_peripherals: &BTreeMap<&str, &str>,
     out: &mut U,
 ) -> std::io::Result<()> {
     writeln!(out, "// Physical base addresses of memory regions")?;
@@ -557,12 +569,25 @@ fn print_memory_regions<U: Write>(
 fn print_peripherals<U: Write>(
     peripherals: &[Peripheral],
     regions: &[MemoryRegion],
-    cs_peripherals: &HashMap<&str, &str>,
+    cs_peripherals: &BTreeMap<&str, &str>,
     constants: &[Constant],
     out: &mut U,
 ) -> std::io::Result<()> {
     writeln!(out, "// Platform Peripherals")?;
 
+    // The peripherals behind each interrupt line, in the order they appear. The Renode
+    // models only have the one `IRQ` output, so only the first interrupt of each counts.
+    let mut irq_sharers: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    for peripheral in peripherals {
+        let lc_name = peripheral.name.to_lowercase();
+        if !cs_peripherals.contains_key(lc_name.as_str()) {
+            continue;
+        }
+        if let Some(irq) = peripheral.interrupt.first() {
+            irq_sharers.entry(irq.value).or_default().push(lc_name);
+        }
+    }
+
     for peripheral in peripherals {
         let lc_name = peripheral.name.to_lowercase();
         // let peripheral_size = if peripheral.size < 4096 {
@@ -604,9 +629,16 @@ fn print_peripherals<U: Write>(
 
             // Note: we skip the `size` value here, since peripherals don't have a known size.
 
-            // Add the interrupt, if one exists.
-            if let Some(irq) = peripheral.interrupt.get(0) {
-                writeln!(out, "    IRQ -> cpu @ {}", 1000 + irq.value)?;
+            // Add the interrupt, if one exists. A line shared with another peripheral goes
+            // through that line's combiner rather than straight to the CPU.
+            if let Some(irq) = peripheral.interrupt.first() {
+                match irq_sharers.get(&irq.value) {
+                    Some(sharers) if sharers.len() > 1 => {
+                        let input = sharers.iter().position(|n| *n == lc_name).unwrap();
+                        writeln!(out, "    IRQ -> irq{}_combiner @ {}", irq.value, input)?;
+                    }
+                    _ => writeln!(out, "    IRQ -> cpu @ {}", CPU_IRQ_BASE + irq.value)?,
+                }
             }
         } else {
             writeln!(
@@ -617,6 +649,16 @@ fn print_peripherals<U: Write>(
         }
         writeln!(out)?;
     }
+
+    // Two peripherals driving one CPU input would overwrite each other's level, so each
+    // shared line ORs its peripherals together first.
+    for (irq, sharers) in irq_sharers.iter().filter(|(_, s)| s.len() > 1) {
+        writeln!(out, "// Interrupt {} is shared by {}", irq, sharers.join(", "))?;
+        writeln!(out, "irq{}_combiner: Miscellaneous.CombinedInput", irq)?;
+        writeln!(out, "    numberOfInputs: {}", sharers.len())?;
+        writeln!(out, "    OutputLine -> cpu @ {}", CPU_IRQ_BASE + irq)?;
+        writeln!(out)?;
+    }
     writeln!(out)?;
 
     Ok(())
@@ -647,10 +689,77 @@ pub fn parse_svd<T: Read>(src: T) -> Result<Description, ParseError> {
     Ok(description)
 }
 
+/// Returns the SHA-256 of a generated .repl file as a lowercase hex string. Output is
+/// written in SVD order and all lookup tables are ordered maps, so the same SVD always
+/// produces the same bytes and hence the same hash.
+pub fn repl_hash(repl: &[u8]) -> String {
+    use sha2::Digest;
+    let digest = sha2::Sha256::digest(repl);
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Writes every register field in the description as a JSON array of
+/// `{peripheral, register, field, bit_offset, bit_width, access}` objects, one per
+/// line, in SVD order. A field without its own access inherits its register's, and
+/// falls back to the SVD default of read-write.
+pub fn write_fields_json<U: Write>(description: &Description, out: &mut U) -> std::io::Result<()> {
+    writeln!(out, "[")?;
+    let mut first = true;
+    for peripheral in &description.peripherals {
+        for register in &peripheral.registers {
+            for field in &register.fields {
+                let access = field
+                    .access
+                    .as_deref()
+                    .or(register.access.as_deref())
+                    .unwrap_or("read-write");
+                if !first {
+                    writeln!(out, ",")?;
+                }
+                first = false;
+                write!(
+                    out,
+                    "  {{\"peripheral\": {}, \"register\": {}, \"field\": {}, \"bit_offset\": {}, \"bit_width\": {}, \"access\": {}}}",
+                    json_string(&peripheral.name),
+                    json_string(&register.name),
+                    json_string(&field.name),
+                    field.lsb,
+                    (field.msb + 1).saturating_sub(field.lsb),
+                    json_string(access)
+                )?;
+            }
+        }
+    }
+    if !first {
+        writeln!(out)?;
+    }
+    writeln!(out, "]")?;
+    Ok(())
+}
+
 pub fn generate<T: Read, U: Write>(src: T, dest: &mut U) -> Result<(), ParseError> {
     let description = parse_svd(src)?;
+    generate_repl(&description, dest)
+}
 
-    let mut cs_peripherals = HashMap::new();
+/// Writes the .repl for an already-parsed description, so the same parse can feed other outputs.
+pub fn generate_repl<U: Write>(description: &Description, dest: &mut U) -> Result<(), ParseError> {
+    let mut cs_peripherals = BTreeMap::new();
     cs_peripherals.insert("app_uart", "UART.LiteX_UART");
     cs_peripherals.insert("console", "UART.LiteX_UART");
     cs_peripherals.insert("btevents", "GPIOPort.Betrusted.BtEvents");
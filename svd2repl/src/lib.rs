@@ -12,4 +12,130 @@ mod tests {
         let mut dest = File::create("target/example.rs").unwrap();
         generate(src, &mut dest).unwrap();
     }
+
+    pub(crate) const MINIMAL_SVD: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<device>
+  <peripherals>
+    <peripheral>
+      <name>UART</name>
+      <baseAddress>0xF0001000</baseAddress>
+      <size>0x100</size>
+      <registers>
+        <register>
+          <name>RXTX</name>
+          <addressOffset>0x0000</addressOffset>
+          <fields>
+            <field>
+              <name>rxtx</name>
+              <msb>7</msb>
+              <lsb>0</lsb>
+            </field>
+          </fields>
+        </register>
+      </registers>
+      <interrupt>
+        <name>uart</name>
+        <value>2</value>
+      </interrupt>
+    </peripheral>
+    <peripheral>
+      <name>TIMER0</name>
+      <baseAddress>0xF0002000</baseAddress>
+      <size>0x100</size>
+    </peripheral>
+  </peripherals>
+  <vendorExtensions>
+    <memoryRegions>
+      <memoryRegion>
+        <name>SRAM</name>
+        <baseAddress>0x10000000</baseAddress>
+        <size>0x00020000</size>
+      </memoryRegion>
+    </memoryRegions>
+    <constants>
+      <constant name="CONFIG_CLOCK_FREQUENCY" value="100000000" />
+    </constants>
+  </vendorExtensions>
+</device>
+"#;
+
+    /// CONSOLE and APP_UART share interrupt 3, while UART has interrupt 2 to itself
+    const SHARED_IRQ_SVD: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<device>
+  <peripherals>
+    <peripheral>
+      <name>UART</name>
+      <baseAddress>0xF0001000</baseAddress>
+      <size>0x100</size>
+      <interrupt>
+        <name>uart</name>
+        <value>2</value>
+      </interrupt>
+    </peripheral>
+    <peripheral>
+      <name>CONSOLE</name>
+      <baseAddress>0xF0002000</baseAddress>
+      <size>0x100</size>
+      <interrupt>
+        <name>console</name>
+        <value>3</value>
+      </interrupt>
+    </peripheral>
+    <peripheral>
+      <name>APP_UART</name>
+      <baseAddress>0xF0003000</baseAddress>
+      <size>0x100</size>
+      <interrupt>
+        <name>app_uart</name>
+        <value>3</value>
+      </interrupt>
+    </peripheral>
+  </peripherals>
+  <vendorExtensions>
+    <memoryRegions>
+    </memoryRegions>
+  </vendorExtensions>
+</device>
+"#;
+
+    #[test]
+    fn shared_irq() {
+        let mut repl = Vec::new();
+        generate(SHARED_IRQ_SVD.as_bytes(), &mut repl).unwrap();
+        let repl = String::from_utf8(repl).unwrap();
+        // an unshared line goes straight to the CPU
+        assert!(repl.contains("uart: UART.LiteX_UART @ sysbus 0xf0001000\n    IRQ -> cpu @ 1002\n"));
+        // a shared one goes through a combiner, one input per peripheral
+        assert!(repl.contains("console: UART.LiteX_UART @ sysbus 0xf0002000\n    IRQ -> irq3_combiner @ 0\n"));
+        assert!(repl.contains("app_uart: UART.LiteX_UART @ sysbus 0xf0003000\n    IRQ -> irq3_combiner @ 1\n"));
+        assert!(repl.contains(
+            "irq3_combiner: Miscellaneous.CombinedInput\n    numberOfInputs: 2\n    OutputLine -> cpu @ 1003\n"
+        ));
+        assert!(!repl.contains("irq2_combiner"));
+    }
+
+    #[test]
+    fn fields_json() {
+        let description = parse_svd(MINIMAL_SVD.as_bytes()).unwrap();
+        let mut json = Vec::new();
+        write_fields_json(&description, &mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.starts_with("[\n") && json.ends_with("]\n"));
+        assert!(json.contains(
+            r#"{"peripheral": "UART", "register": "RXTX", "field": "rxtx", "bit_offset": 0, "bit_width": 8, "access": "read-write"}"#
+        ));
+        // registers without fields contribute nothing
+        assert!(!json.contains("TIMER0"));
+    }
+
+    #[test]
+    fn hash_is_stable() {
+        let mut first = Vec::new();
+        generate(MINIMAL_SVD.as_bytes(), &mut first).unwrap();
+        let mut second = Vec::new();
+        generate(MINIMAL_SVD.as_bytes(), &mut second).unwrap();
+        assert_eq!(repl_hash(&first), repl_hash(&second));
+        assert_eq!(repl_hash(&first).len(), 64);
+        assert_ne!(repl_hash(&first), repl_hash(b""));
+    }
 }
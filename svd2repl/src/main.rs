@@ -1,17 +1,48 @@
 mod generate;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    if std::env::args().count() != 3 {
-        println!("Usage: svd2repl <input SVD> <output repl>");
+    let mut print_hash = false;
+    let mut fields_json = None;
+    let mut args = vec![];
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        if arg == "--print-hash" {
+            print_hash = true;
+        } else if arg == "--fields-json" {
+            fields_json = argv.next();
+            if fields_json.is_none() {
+                args.clear(); // fall through to the usage message
+                break;
+            }
+        } else {
+            args.push(arg);
+        }
+    }
+    if args.len() != 2 {
+        println!("Usage: svd2repl [--print-hash] [--fields-json <output json>] <input SVD> <output repl>");
         return Ok(())
     }
-    let svd_filename = std::env::args().nth(1).ok_or("Must specify SVD input filename")?;
-    let generated_filename = std::env::args().nth(2).ok_or("Must specify destination utralib filename")?;
+    let svd_filename = &args[0];
+    let generated_filename = &args[1];
 
     let src_file = std::fs::File::open(svd_filename).expect("couldn't open src file");
-    let mut dest_file = std::fs::File::create(generated_filename).expect("couldn't open dest file");
+    let description = generate::parse_svd(src_file)?;
+
+    // generate into memory first, so the hash covers exactly what is written out
+    let mut repl = Vec::new();
+    generate::generate_repl(&description, &mut repl)?;
+    std::fs::write(generated_filename, &repl).expect("couldn't write dest file");
 
-    generate::generate(src_file, &mut dest_file)?;
+    if let Some(json_filename) = fields_json {
+        let mut json = Vec::new();
+        generate::write_fields_json(&description, &mut json)?;
+        std::fs::write(json_filename, &json).expect("couldn't write fields json file");
+    }
+
+    if print_hash {
+        // stderr, so stdout stays clean for piping
+        eprintln!("{}", generate::repl_hash(&repl));
+    }
 
     Ok(())
 }